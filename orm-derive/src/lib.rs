@@ -1,168 +1,1259 @@
 #![forbid(unsafe_code)]
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Fields, LitStr};
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote, ToTokens};
+use syn::{
+    parse_macro_input, spanned::Spanned, Attribute, Data, DataEnum, DataStruct, DeriveInput,
+    Fields, Ident, LitStr, Member,
+};
 
-#[proc_macro_derive(Object, attributes(table_name, column_name))]
+#[proc_macro_derive(
+    Object,
+    attributes(
+        table_name,
+        table_case,
+        column_name,
+        rename_all,
+        discriminator,
+        trigger,
+        temporary,
+        default,
+        managed,
+        generated,
+        unique,
+        index,
+        bool_as,
+        label,
+        primary_key,
+        version,
+        created_at,
+        updated_at,
+        belongs_to,
+        skip,
+        after_load,
+        serialize_with,
+        deserialize_with,
+        compress
+    )
+)]
 pub fn derive_object(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
-    if let Data::Struct(ref data) = input.data {
-        let type_name = &input.ident;
-        let table_name = match parse_table_name(&input) {
-            Ok(s) => s,
-            Err(err) => return err.to_compile_error().into(),
-        };
-
-        let mut field_as_value = vec![];
-        let mut field_from_value = vec![];
-        let mut field_entries = vec![];
-        for (i, field) in data.fields.iter().enumerate() {
-            let column_name = match parse_column_name(field) {
-                Ok(s) => s,
-                Err(err) => return err.to_compile_error().into(),
-            };
+    let res = match input.data {
+        Data::Struct(ref data) => derive_struct(&input, data),
+        Data::Enum(ref data) => derive_enum(&input, data),
+        Data::Union(_) => Err(syn::Error::new(
+            input.ident.span(),
+            "Only structs and enums can derive `Object`",
+        )),
+    };
 
-            let field_name = field
-                .ident
-                .as_ref()
-                .map_or("unnamed_field".to_string(), |ident| ident.to_string());
-
-            let field_type = &field.ty;
-            let as_val = match &field.ident {
-                Some(ident) => quote! {
-                    <#field_type as orm::AsDataType>::as_value(&self.#ident),
-                },
-                None => quote! {
-                    <#field_type as orm::AsDataType>::as_value(&self.#i),
-                },
-            };
-            field_as_value.push(as_val);
-
-            let from_val = match &field.ident {
-                Some(ident) => quote! {
-                    #ident: <#field_type as orm::AsDataType>::from_value(&row[#i]),
-                },
-                None => quote! {
-                    <#field_type as orm::AsDataType>::from_value(&row[#i]),
-                },
-            };
-            field_from_value.push(from_val);
+    match res {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
 
-            field_entries.push(quote! {
-                orm::object::Field {
-                    attr_name: #field_name,
-                    column_name: #column_name,
-                    column_type: <#field_type as orm::AsDataType>::DATA_TYPE,
-                },
+#[proc_macro_derive(DbEnum, attributes(db_enum))]
+pub fn derive_db_enum(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let res = match input.data {
+        Data::Enum(ref data) => derive_field_enum(&input, data),
+        _ => Err(syn::Error::new(
+            input.ident.span(),
+            "Only enums can derive `DbEnum`",
+        )),
+    };
 
-            });
+    match res {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+// An enum of unit variants stored in a single column, either as its discriminant or its name.
+fn derive_field_enum(input: &DeriveInput, data: &DataEnum) -> syn::Result<TokenStream2> {
+    let type_name = &input.ident;
+    let as_text = parse_db_enum_as_text(input)?;
+    for variant in data.variants.iter() {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(syn::Error::new(
+                variant.span(),
+                "`DbEnum` can only be derived for enums with unit variants",
+            ));
         }
+    }
+    let variants = data
+        .variants
+        .iter()
+        .map(|variant| &variant.ident)
+        .collect::<Vec<_>>();
 
-        let as_row = quote! {
-            fn as_row(&self) -> orm::storage::Row {
+    let (data_type, as_value, from_value) = if as_text {
+        let names = variants
+            .iter()
+            .map(|ident| ident.to_string())
+            .collect::<Vec<_>>();
+        (
+            quote! { orm::data::DataType::String },
+            quote! {
+                orm::data::Value::String(::std::borrow::Cow::Borrowed(match *self {
+                    #(Self::#variants => #names,)*
+                }))
+            },
+            quote! {
+                let name = <String as orm::AsDataType>::from_value(value)?;
+                match name.as_str() {
+                    #(#names => Ok(Self::#variants),)*
+                    _ => Err(orm::Error::invalid_value(
+                        concat!("a variant of ", stringify!(#type_name)),
+                        format!("'{}'", name),
+                    )),
+                }
+            },
+        )
+    } else {
+        (
+            quote! { orm::data::DataType::Int64 },
+            quote! {
+                orm::data::Value::Int64(match *self {
+                    #(Self::#variants => Self::#variants as i64,)*
+                })
+            },
+            quote! {
+                let discriminant = <i64 as orm::AsDataType>::from_value(value)?;
+                #(
+                    if discriminant == Self::#variants as i64 {
+                        return Ok(Self::#variants);
+                    }
+                )*
+                Err(orm::Error::invalid_value(
+                    concat!("a discriminant of ", stringify!(#type_name)),
+                    discriminant,
+                ))
+            },
+        )
+    };
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics orm::AsDataType for #type_name #ty_generics #where_clause {
+            const DATA_TYPE: orm::data::DataType = #data_type;
+
+            fn as_value(&self) -> orm::data::Value<'_> {
+                #as_value
+            }
+
+            fn from_value(value: &orm::data::Value) -> orm::Result<Self> {
+                #from_value
+            }
+        }
+    })
+}
+
+fn derive_struct(input: &DeriveInput, data: &DataStruct) -> syn::Result<TokenStream2> {
+    let type_name = &input.ident;
+    let table_name = parse_table_name(input)?;
+    let triggers = parse_triggers(input)?;
+    let temporary = has_flag(&input.attrs, "temporary")?;
+    let after_load = parse_after_load(input)?;
+    let rename_all = parse_rename_all(input)?;
+    let fields = parse_fields(&data.fields, None, rename_all)?;
+    let stored = stored_fields(&fields);
+    let label = marked_column(stored.clone(), "label", |field| field.label)?;
+    let primary_key = marked_column(stored.clone(), "primary_key", |field| field.primary_key)?;
+    let version = marked_column(stored.clone(), "version", |field| field.version)?;
+
+    let field_entries = stored.clone().map(|field| field.entry(false));
+    let set_column_arms = stored.clone().enumerate().map(|(index, field)| {
+        let member = &field.member;
+        let value = field.value_from(quote! { &value }, index);
+        quote! { #index => self.#member = #value, }
+    });
+    let skipped = fields
+        .iter()
+        .filter(|field| field.skip)
+        .map(|field| &field.member);
+    let field_as_value = stored.map(|field| {
+        let member = &field.member;
+        field.as_value(quote! { &self.#member })
+    });
+    let field_from_value = fields_from_values(&fields, 0);
+
+    let constructor = match data.fields {
+        Fields::Named(_) => quote! { Self {#(#field_from_value)*} },
+        Fields::Unnamed(_) => quote! { Self (#(#field_from_value)*) },
+        Fields::Unit => quote! { Self },
+    };
+
+    Ok(quote! {
+        impl #type_name {
+            pub const TABLE: &'static str = #table_name;
+        }
+
+        impl orm::Object for #type_name {
+            fn as_row(&self) -> orm::storage::Row<'_> {
                 vec![#(#field_as_value)*]
             }
-        };
 
-        let from_row = match data.fields {
-            Fields::Named(_) => {
-                quote! {
-                    fn from_row(row: orm::storage::Row) -> Self {
-                        Self {#(#field_from_value)*}
-                    }
+            #[allow(unused_mut)]
+            fn from_row(row: orm::storage::Row) -> orm::Result<Self> {
+                let mut obj = #constructor;
+                #after_load
+                Ok(obj)
+            }
+
+            #[allow(unused_mut)]
+            fn update_from_row(&mut self, row: orm::storage::Row) -> orm::Result<()> {
+                let mut obj = #constructor;
+                #(::std::mem::swap(&mut obj.#skipped, &mut self.#skipped);)*
+                #after_load
+                *self = obj;
+                Ok(())
+            }
+
+            #[allow(unused_variables)]
+            fn set_column(
+                &mut self,
+                index: usize,
+                value: orm::data::Value,
+            ) -> orm::Result<()> {
+                match index {
+                    #(#set_column_arms)*
+                    _ => {}
                 }
+                Ok(())
             }
-            Fields::Unnamed(_) => {
-                quote! {
-                    fn from_row(row: orm::storage::Row) -> Self {
-                        Self (#(#field_from_value)*)
-                    }
+
+            const SCHEMA: orm::Schema = orm::Schema {
+                type_name: stringify!(#type_name),
+                table_name: #table_name,
+                triggers: &[#(#triggers),*],
+                temporary: #temporary,
+                label: #label,
+                primary_key: #primary_key,
+                version: #version,
+
+                fields: &[#(#field_entries)* ],
+            };
+        }
+    })
+}
+
+// Enums are stored single-table-inheritance style: a discriminator column holding the variant
+// name, followed by the union of all variants' fields. Fields of inactive variants are NULL.
+fn derive_enum(input: &DeriveInput, data: &DataEnum) -> syn::Result<TokenStream2> {
+    let type_name = &input.ident;
+    let table_name = parse_table_name(input)?;
+    let triggers = parse_triggers(input)?;
+    let temporary = has_flag(&input.attrs, "temporary")?;
+    let discriminator = parse_discriminator(input)?;
+    let after_load = parse_after_load(input)?;
+    let rename_all = parse_rename_all(input)?;
+
+    let mut variants = vec![];
+    let mut column_names = vec![discriminator.clone()];
+    for variant in data.variants.iter() {
+        let fields = parse_fields(&variant.fields, Some(&variant.ident), rename_all)?;
+        for field in fields.iter().filter(|field| !field.skip) {
+            for (marked, attribute) in [
+                (field.primary_key, "primary_key"),
+                (field.version, "version"),
+                (field.stamp == Some(false), "created_at"),
+                (field.stamp == Some(true), "updated_at"),
+            ] {
+                if marked {
+                    return Err(syn::Error::new(
+                        field.span,
+                        format!("`{}` attribute is not supported for enums", attribute),
+                    ));
                 }
             }
-            Fields::Unit => {
-                quote! {
-                    fn from_row(row: orm::storage::Row) -> Self {
-                        Self
-                    }
+            if column_names.contains(&field.column_name) {
+                return Err(syn::Error::new(
+                    field.span,
+                    format!(
+                        "Column `{}` is already used by another variant, \
+                        rename it with `column_name` attribute",
+                        field.column_name
+                    ),
+                ));
+            }
+            column_names.push(field.column_name.clone());
+        }
+        variants.push((variant, fields));
+    }
+    let column_count = column_names.len();
+    let label = marked_column(
+        variants
+            .iter()
+            .flat_map(|(_, fields)| stored_fields(fields)),
+        "label",
+        |field| field.label,
+    )?;
+
+    let field_entries = variants
+        .iter()
+        .flat_map(|(_, fields)| stored_fields(fields).map(|field| field.entry(true)));
+
+    let mut as_row_arms = vec![];
+    let mut from_row_arms = vec![];
+    let mut set_column_arms = vec![];
+    // swap `#[skip]`-ped fields of a new object with the old one of the same variant
+    let mut keep_skipped_arms = vec![];
+    let mut offset = 1;
+    for (variant, fields) in variants.iter() {
+        let variant_ident = &variant.ident;
+        let variant_name = variant_ident.to_string();
+        let bindings = fields
+            .iter()
+            .enumerate()
+            .map(|(i, field)| match field.skip {
+                true => quote! { _ },
+                false => format_ident!("field_{}", i).to_token_stream(),
+            })
+            .collect::<Vec<_>>();
+        let stored_count = stored_fields(fields).count();
+
+        let pattern = match variant.fields {
+            Fields::Named(_) => {
+                let members = fields.iter().map(|field| &field.member);
+                quote! { Self::#variant_ident { #(#members: #bindings),* } }
+            }
+            Fields::Unnamed(_) => quote! { Self::#variant_ident ( #(#bindings),* ) },
+            Fields::Unit => quote! { Self::#variant_ident },
+        };
+
+        let nulls_before = (1..offset).map(|_| quote! { orm::data::Value::Null, });
+        let values = fields
+            .iter()
+            .zip(bindings.iter())
+            .filter(|(field, _)| !field.skip)
+            .map(|(field, binding)| field.as_value(quote! { #binding }));
+        let nulls_after =
+            (offset + stored_count..column_count).map(|_| quote! { orm::data::Value::Null, });
+        as_row_arms.push(quote! {
+            #pattern => vec![
+                orm::data::Value::String(::std::borrow::Cow::from(#variant_name)),
+                #(#nulls_before)*
+                #(#values)*
+                #(#nulls_after)*
+            ],
+        });
+
+        let field_from_value = fields_from_values(fields, offset);
+        let constructor = match variant.fields {
+            Fields::Named(_) => quote! { Self::#variant_ident {#(#field_from_value)*} },
+            Fields::Unnamed(_) => quote! { Self::#variant_ident (#(#field_from_value)*) },
+            Fields::Unit => quote! { Self::#variant_ident },
+        };
+        from_row_arms.push(quote! {
+            #variant_name => #constructor,
+        });
+
+        for (index, field) in stored_fields(fields).enumerate() {
+            let member = &field.member;
+            let column = offset + index;
+            let value = field.value_from(quote! { &value }, column);
+            set_column_arms.push(quote! {
+                (Self::#variant_ident { #member: target, .. }, #column) => *target = #value,
+            });
+        }
+        let skipped = fields
+            .iter()
+            .filter(|field| field.skip)
+            .map(|field| &field.member)
+            .collect::<Vec<_>>();
+        if !skipped.is_empty() {
+            let new = (0..skipped.len()).map(|i| format_ident!("new_{}", i));
+            let old = (0..skipped.len()).map(|i| format_ident!("old_{}", i));
+            let (new_swapped, old_swapped) = (new.clone(), old.clone());
+            keep_skipped_arms.push(quote! {
+                (
+                    Self::#variant_ident { #(#skipped: #new,)* .. },
+                    Self::#variant_ident { #(#skipped: #old,)* .. },
+                ) => {
+                    #(::std::mem::swap(#new_swapped, #old_swapped);)*
                 }
+            });
+        }
+
+        offset += stored_count;
+    }
+
+    let as_row = if as_row_arms.is_empty() {
+        quote! { match *self {} }
+    } else {
+        quote! { match self { #(#as_row_arms)* } }
+    };
+
+    let construct = quote! {
+        let variant = <String as orm::AsDataType>::from_value(&row[0])
+            .map_err(|err| err.in_column(&Self::SCHEMA, 0))?;
+        let mut obj = match variant.as_str() {
+            #(#from_row_arms)*
+            _ => {
+                return Err(orm::Error::invalid_value(
+                    concat!("a variant of ", stringify!(#type_name)),
+                    format!("'{}'", variant),
+                )
+                .in_column(&Self::SCHEMA, 0))
             }
         };
+    };
+
+    Ok(quote! {
+        impl #type_name {
+            pub const TABLE: &'static str = #table_name;
+        }
+
+        impl orm::Object for #type_name {
+            fn as_row(&self) -> orm::storage::Row<'_> {
+                #as_row
+            }
+
+            #[allow(unused_mut)]
+            fn from_row(row: orm::storage::Row) -> orm::Result<Self> {
+                #construct
+                #after_load
+                Ok(obj)
+            }
+
+            #[allow(unused_mut, unreachable_patterns)]
+            fn update_from_row(&mut self, row: orm::storage::Row) -> orm::Result<()> {
+                #construct
+                match (&mut obj, &mut *self) {
+                    #(#keep_skipped_arms)*
+                    _ => {}
+                }
+                #after_load
+                *self = obj;
+                Ok(())
+            }
+
+            #[allow(unused_variables, unreachable_patterns)]
+            fn set_column(
+                &mut self,
+                index: usize,
+                value: orm::data::Value,
+            ) -> orm::Result<()> {
+                // the discriminator and columns of other variants are left alone
+                match (self, index) {
+                    #(#set_column_arms)*
+                    _ => {}
+                }
+                Ok(())
+            }
 
-        let schema = quote! {
             const SCHEMA: orm::Schema = orm::Schema {
                 type_name: stringify!(#type_name),
                 table_name: #table_name,
+                triggers: &[#(#triggers),*],
+                temporary: #temporary,
+                label: #label,
+                primary_key: None,
+                version: None,
 
-                fields: &[#(#field_entries)* ],
+                fields: &[
+                    orm::object::Field::new(
+                        "variant",
+                        #discriminator,
+                        orm::data::DataType::String,
+                    ),
+                    #(#field_entries)*
+                ],
             };
-        };
+        }
+    })
+}
+
+struct FieldInfo {
+    member: Member,
+    ty: syn::Type,
+    attr_name: String,
+    column_name: String,
+    default: Option<String>,
+    managed: bool,
+    // (expression, stored) of a generated column
+    generated: Option<(String, bool)>,
+    unique: bool,
+    indexed: bool,
+    // wrapper type storing the field in an alternative encoding, see `#[bool_as]`
+    wrapper: Option<TokenStream2>,
+    // (serialize, deserialize) functions converting the field to and from its stored form
+    conversion: Option<(syn::Path, syn::Path)>,
+    // stored gzip-compressed in a BLOB column
+    compress: bool,
+    label: bool,
+    // used as the object's id, see `Schema::primary_key`
+    primary_key: bool,
+    // incremented by every update, see `Schema::version`
+    version: bool,
+    // `Some(on_update)` for `#[created_at]` (false) and `#[updated_at]` (true) fields
+    stamp: Option<bool>,
+    // the type whose id the field holds, set by `#[belongs_to(Type)]`
+    references: Option<syn::Type>,
+    // not stored at all, constructed with `Default::default()` on load
+    skip: bool,
+    // function constructing a skipped field instead of `Default::default()`
+    skip_default: Option<syn::Path>,
+    span: proc_macro2::Span,
+}
 
+impl FieldInfo {
+    fn entry(&self, nullable: bool) -> TokenStream2 {
+        let attr_name = &self.attr_name;
+        let column_name = &self.column_name;
+        let data_type = self.data_type();
+        let nullable = self.nullable(nullable);
+        let default = match &self.default {
+            Some(default) => quote! { Some(#default) },
+            None => quote! { None },
+        };
+        let managed = self.managed;
+        let (generated, generated_stored) = match &self.generated {
+            Some((expr, stored)) => (quote! { Some(#expr) }, *stored),
+            None => (quote! { None }, false),
+        };
+        let unique = self.unique;
+        let indexed = self.indexed;
+        let field_type = &self.ty;
+        let stamp = match self.stamp {
+            Some(on_update) => quote! {
+                Some(orm::object::Stamp {
+                    on_update: #on_update,
+                    value: orm::data::timestamp_value::<#field_type>,
+                })
+            },
+            None => quote! { None },
+        };
+        let references = match &self.references {
+            Some(parent) => quote! { Some(|| &<#parent as orm::Object>::SCHEMA) },
+            None => quote! { None },
+        };
         quote! {
-            impl orm::Object for #type_name {
-                #as_row
+            orm::object::Field {
+                nullable: #nullable,
+                default: #default,
+                managed: #managed,
+                generated: #generated,
+                generated_stored: #generated_stored,
+                unique: #unique,
+                indexed: #indexed,
+                stamp: #stamp,
+                references: #references,
+                ..orm::object::Field::new(
+                    #attr_name,
+                    #column_name,
+                    #data_type,
+                )
+            },
+        }
+    }
 
-                #from_row
+    // Fields of enum variants are always nullable, other ones only if their type is, e.g. `Option`.
+    fn nullable(&self, always: bool) -> TokenStream2 {
+        let field_type = &self.ty;
+        match (&self.wrapper, &self.conversion) {
+            _ if always => quote! { true },
+            _ if self.compress => quote! { false },
+            (_, Some((serialize, _))) => quote! { orm::data::serialized_nullable(#serialize) },
+            (Some(wrapper), _) => quote! { <#wrapper as orm::AsDataType>::NULLABLE },
+            (None, None) => quote! { <#field_type as orm::AsDataType>::NULLABLE },
+        }
+    }
 
-                #schema
+    fn data_type(&self) -> TokenStream2 {
+        let field_type = &self.ty;
+        match (&self.wrapper, &self.conversion) {
+            _ if self.compress => quote! { orm::data::DataType::Bytes },
+            (_, Some((serialize, _))) => quote! { orm::data::serialized_type(#serialize) },
+            (Some(wrapper), _) => quote! { <#wrapper as orm::AsDataType>::DATA_TYPE },
+            (None, None) => quote! { <#field_type as orm::AsDataType>::DATA_TYPE },
+        }
+    }
+
+    // `field_ref` is an expression evaluating to a reference to the field.
+    fn as_value(&self, field_ref: TokenStream2) -> TokenStream2 {
+        let field_type = &self.ty;
+        match (&self.wrapper, &self.conversion) {
+            _ if self.compress => quote! {
+                orm::__compress!(#field_ref),
+            },
+            (_, Some((serialize, _))) => quote! {
+                orm::data::serialize_with(#serialize, #field_ref),
+            },
+            (Some(wrapper), _) => quote! {
+                #wrapper(*#field_ref).into_value(),
+            },
+            (None, None) => quote! {
+                <#field_type as orm::AsDataType>::as_value(#field_ref),
+            },
+        }
+    }
+
+    // Initializer of the field in a constructor, reading the `index`-th value of the row.
+    fn read_value_tokens(&self, index: usize) -> TokenStream2 {
+        let value = match &self.skip_default {
+            _ if !self.skip => self.value_from(quote! { &row[#index] }, index),
+            Some(default) => quote! { #default() },
+            None => quote! { ::std::default::Default::default() },
+        };
+        match &self.member {
+            Member::Named(ident) => quote! { #ident: #value, },
+            Member::Unnamed(_) => quote! { #value, },
+        }
+    }
+
+    // Converts the value of the `index`-th column into the field, `value_ref` is an expression
+    // evaluating to a reference to the value.
+    fn value_from(&self, value_ref: TokenStream2, index: usize) -> TokenStream2 {
+        let field_type = &self.ty;
+        match (&self.wrapper, &self.conversion) {
+            _ if self.compress => read_value(quote! { orm::__decompress!(#value_ref) }, index),
+            (_, Some((_, deserialize))) => read_value(
+                quote! { orm::data::deserialize_with(#deserialize, #value_ref) },
+                index,
+            ),
+            (Some(wrapper), _) => {
+                let value = read_value(
+                    quote! { <#wrapper as orm::AsDataType>::from_value(#value_ref) },
+                    index,
+                );
+                quote! { #value.0 }
             }
+            (None, None) => read_value(
+                quote! { <#field_type as orm::AsDataType>::from_value(#value_ref) },
+                index,
+            ),
         }
-        .into()
-    } else {
-        syn::Error::new(input.ident.span(), "Only structs can derive `Object`")
-            .to_compile_error()
-            .into()
     }
 }
 
+// Unwraps the result of converting the `index`-th value of the row, attributing an error to
+// the field.
+fn read_value(result: TokenStream2, index: usize) -> TokenStream2 {
+    quote! {
+        #result.map_err(|err| err.in_column(&Self::SCHEMA, #index))?
+    }
+}
+
+fn stored_fields(fields: &[FieldInfo]) -> impl Iterator<Item = &FieldInfo> + Clone {
+    fields.iter().filter(|field| !field.skip)
+}
+
+// Initializers of all the fields, reading stored ones from the row starting at `offset`.
+fn fields_from_values(fields: &[FieldInfo], offset: usize) -> Vec<TokenStream2> {
+    let mut index = offset;
+    fields
+        .iter()
+        .map(|field| {
+            let res = field.read_value_tokens(index);
+            if !field.skip {
+                index += 1;
+            }
+            res
+        })
+        .collect()
+}
+
+fn parse_fields(
+    fields: &Fields,
+    variant: Option<&Ident>,
+    rename_all: Option<RenameRule>,
+) -> syn::Result<Vec<FieldInfo>> {
+    let mut res = vec![];
+    for (i, field) in fields.iter().enumerate() {
+        let (skip, skip_default) = parse_skip(field)?;
+        let column_name = match skip {
+            true => String::new(),
+            false => parse_column_name(field, rename_all)?,
+        };
+        let default = parse_default(field)?;
+        let generated = parse_generated(field)?;
+        if default.is_some() && generated.is_some() {
+            return Err(syn::Error::new(
+                field.span(),
+                "`default` and `generated` attributes can't be used together",
+            ));
+        }
+        let managed =
+            default.is_some() || generated.is_some() || has_flag(&field.attrs, "managed")?;
+        let unique = has_flag(&field.attrs, "unique")?;
+        let indexed = has_flag(&field.attrs, "index")?;
+        let wrapper = parse_bool_as(field)?;
+        let conversion = parse_conversion(field)?;
+        let compress = has_flag(&field.attrs, "compress")?;
+        if [wrapper.is_some(), conversion.is_some(), compress]
+            .iter()
+            .filter(|used| **used)
+            .count()
+            > 1
+        {
+            return Err(syn::Error::new(
+                field.span(),
+                "Only one of `bool_as`, `serialize_with`/`deserialize_with` and `compress` \
+                attributes can be used on a field",
+            ));
+        }
+        let label = has_flag(&field.attrs, "label")?;
+        let primary_key = has_flag(&field.attrs, "primary_key")?;
+        let version = has_flag(&field.attrs, "version")?;
+        if primary_key && version {
+            return Err(syn::Error::new(
+                field.span(),
+                "`primary_key` and `version` attributes can't be used together",
+            ));
+        }
+        let stamp = match (
+            has_flag(&field.attrs, "created_at")?,
+            has_flag(&field.attrs, "updated_at")?,
+        ) {
+            (true, true) => {
+                return Err(syn::Error::new(
+                    field.span(),
+                    "`created_at` and `updated_at` attributes can't be used together",
+                ))
+            }
+            (created_at, updated_at) => (created_at || updated_at).then_some(updated_at),
+        };
+        if stamp.is_some() && (primary_key || version) {
+            return Err(syn::Error::new(
+                field.span(),
+                "A timestamp field can't be a `primary_key` or a `version`",
+            ));
+        }
+        // timestamps may be of any type implementing `orm::data::Timestamp`
+        let plain = !managed && !skip && !compress && wrapper.is_none() && conversion.is_none();
+        if stamp.is_some() && !plain {
+            return Err(syn::Error::new(
+                field.span(),
+                "A timestamp field should be stored as is",
+            ));
+        }
+        let references = parse_belongs_to(field)?;
+        if references.is_some() && skip {
+            return Err(syn::Error::new(
+                field.span(),
+                "`belongs_to` and `skip` attributes can't be used together",
+            ));
+        }
+        for (marked, attribute) in [(primary_key, "primary_key"), (version, "version")] {
+            let is_i64 = matches!(&field.ty, syn::Type::Path(ty) if ty.path.is_ident("i64"));
+            if marked && !(is_i64 && plain) {
+                return Err(syn::Error::new(
+                    field.span(),
+                    format!("`{}` field should be a plain `i64` stored as is", attribute),
+                ));
+            }
+        }
+
+        let field_name = field
+            .ident
+            .as_ref()
+            .map_or("unnamed_field".to_string(), |ident| ident.to_string());
+        let attr_name = match variant {
+            Some(variant) => format!("{}::{}", variant, field_name),
+            None => field_name,
+        };
+
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(syn::Index::from(i)),
+        };
+
+        res.push(FieldInfo {
+            member,
+            ty: field.ty.clone(),
+            attr_name,
+            column_name,
+            default,
+            managed,
+            generated,
+            unique,
+            indexed,
+            wrapper,
+            conversion,
+            compress,
+            label,
+            primary_key,
+            version,
+            stamp,
+            references,
+            skip,
+            skip_default,
+            span: field.span(),
+        });
+    }
+
+    Ok(res)
+}
+
+// The column of the only field marked with `attribute`, if any.
+fn marked_column<'a>(
+    fields: impl Iterator<Item = &'a FieldInfo>,
+    attribute: &str,
+    is_marked: impl Fn(&FieldInfo) -> bool,
+) -> syn::Result<TokenStream2> {
+    let mut marked = None;
+    for field in fields.filter(|field| is_marked(field)) {
+        if marked.is_some() {
+            return Err(syn::Error::new(
+                field.span,
+                format!(
+                    "Only one field can be marked with `{}` attribute",
+                    attribute
+                ),
+            ));
+        }
+        marked = Some(&field.column_name);
+    }
+
+    Ok(match marked {
+        Some(column_name) => quote! { Some(#column_name) },
+        None => quote! { None },
+    })
+}
+
+fn parse_after_load(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    Ok(match has_flag(&input.attrs, "after_load")? {
+        true => quote! { orm::object::AfterLoad::after_load(&mut obj); },
+        false => quote! {},
+    })
+}
+
+// Whether a `DbEnum` is stored by variant name, as set by `#[db_enum(as = "text")]`.
+fn parse_db_enum_as_text(input: &DeriveInput) -> syn::Result<bool> {
+    let mut as_text = false;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("db_enum"))
+    {
+        let usage = || {
+            syn::Error::new(
+                attr.span(),
+                "Incorrect format for using `db_enum` attribute. \
+                Usage: `#[db_enum(as = \"text\")]` or `#[db_enum(as = \"integer\")]`",
+            )
+        };
+        let list = match &attr.meta {
+            syn::Meta::List(list) => list,
+            _ => return Err(usage()),
+        };
+        let encoding = list
+            .parse_args_with(|input: syn::parse::ParseStream| {
+                input.parse::<syn::Token![as]>()?;
+                input.parse::<syn::Token![=]>()?;
+                input.parse::<LitStr>()
+            })
+            .map_err(|_| usage())?;
+        as_text = match encoding.value().as_str() {
+            "text" => true,
+            "integer" => false,
+            _ => {
+                return Err(syn::Error::new(
+                    encoding.span(),
+                    "Unknown enum encoding, expected \"text\" or \"integer\"",
+                ))
+            }
+        };
+    }
+
+    Ok(as_text)
+}
+
+// An explicit `#[table_name(...)]` wins over `table_case`.
 fn parse_table_name(input: &DeriveInput) -> syn::Result<String> {
-    let type_name = &input.ident;
-    let mut table_name = type_name.to_string();
-    for attr in &input.attrs {
-        match &attr.meta {
-            syn::Meta::List(list) if attr.path().is_ident("table_name") => {
-                table_name = match list.parse_args::<LitStr>() {
-                    Ok(lit) => lit.value(),
-                    Err(_) => {
+    let mut table_name = input.ident.to_string();
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("table_case"))
+    {
+        let usage = || {
+            syn::Error::new(
+                attr.span(),
+                "Incorrect format for using `table_case` attribute. \
+                Usage: `#[table_case(plural_snake)]` or `#[table_case(snake)]`",
+            )
+        };
+        let case = match &attr.meta {
+            syn::Meta::List(list) => list.parse_args::<Ident>().map_err(|_| usage())?,
+            _ => return Err(usage()),
+        };
+        let snake = to_snake_case(&input.ident.to_string());
+        table_name = match case.to_string().as_str() {
+            "snake" => snake,
+            "plural_snake" => pluralize(&snake),
+            _ => {
+                return Err(syn::Error::new(
+                    case.span(),
+                    "Unknown table case, expected `snake` or `plural_snake`",
+                ))
+            }
+        };
+    }
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("table_name"))
+    {
+        table_name = parse_string_arg(attr, "#[table_name(\"MyTable\")]")?;
+    }
+
+    Ok(table_name)
+}
+
+// `OrderItem` -> `order_item`, `HTTPRequest` -> `http_request`.
+fn to_snake_case(name: &str) -> String {
+    let chars = name.chars().collect::<Vec<_>>();
+    let mut res = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let after_lower = !chars[i - 1].is_uppercase() && chars[i - 1] != '_';
+            let before_lower = chars.get(i + 1).is_some_and(|next| next.is_lowercase());
+            if after_lower || (chars[i - 1].is_uppercase() && before_lower) {
+                res.push('_');
+            }
+        }
+        res.extend(c.to_lowercase());
+    }
+    res
+}
+
+// Plain English rules only: `company` -> `companies`, `address` -> `addresses`, `user` -> `users`.
+fn pluralize(word: &str) -> String {
+    let consonant_y = word
+        .strip_suffix('y')
+        .filter(|stem| stem.chars().last().is_some_and(|c| !"aeiou".contains(c)));
+    if let Some(stem) = consonant_y {
+        format!("{}ies", stem)
+    } else if ["s", "x", "z", "ch", "sh"]
+        .iter()
+        .any(|suffix| word.ends_with(suffix))
+    {
+        format!("{}es", word)
+    } else {
+        format!("{}s", word)
+    }
+}
+
+fn parse_discriminator(input: &DeriveInput) -> syn::Result<String> {
+    let mut discriminator = "variant".to_string();
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("discriminator"))
+    {
+        discriminator = parse_string_arg(attr, "#[discriminator(\"kind\")]")?;
+    }
+
+    Ok(discriminator)
+}
+
+fn parse_triggers(input: &DeriveInput) -> syn::Result<Vec<String>> {
+    input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("trigger"))
+        .map(|attr| {
+            parse_string_arg(
+                attr,
+                "#[trigger(\"AFTER INSERT ON MyTable BEGIN ... END\")]",
+            )
+        })
+        .collect()
+}
+
+fn parse_default(field: &syn::Field) -> syn::Result<Option<String>> {
+    let mut default = None;
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("default"))
+    {
+        default = Some(parse_string_arg(attr, "#[default(\"CURRENT_TIMESTAMP\")]")?);
+    }
+
+    Ok(default)
+}
+
+fn parse_generated(field: &syn::Field) -> syn::Result<Option<(String, bool)>> {
+    let usage = "#[generated(\"first || ' ' || last\", stored)]";
+    let mut generated = None;
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("generated"))
+    {
+        let list = match &attr.meta {
+            syn::Meta::List(list) => list,
+            _ => {
+                return Err(syn::Error::new(
+                    attr.span(),
+                    format!(
+                        "Incorrect format for using `generated` attribute. Usage: `{}`",
+                        usage
+                    ),
+                ))
+            }
+        };
+        // the expression, optionally followed by `stored` or `virtual` (the default)
+        generated = Some(list.parse_args_with(|input: syn::parse::ParseStream| {
+            let expr = input.parse::<LitStr>()?.value();
+            let mut stored = false;
+            if input.parse::<Option<syn::Token![,]>>()?.is_some() {
+                let kind = <Ident as syn::ext::IdentExt>::parse_any(input)?;
+                stored = match kind.to_string().as_str() {
+                    "stored" => true,
+                    "virtual" => false,
+                    _ => {
                         return Err(syn::Error::new(
-                            list.span(),
-                            "Attribute argument should be a single string literal",
-                        ));
+                            kind.span(),
+                            "Expected `stored` or `virtual`",
+                        ))
                     }
                 };
             }
+            Ok((expr, stored))
+        })?);
+    }
+
+    Ok(generated)
+}
+
+// `#[skip]` or `#[skip(default = "path::to::function")]`.
+fn parse_skip(field: &syn::Field) -> syn::Result<(bool, Option<syn::Path>)> {
+    let mut skip = false;
+    let mut default = None;
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("skip"))
+    {
+        skip = true;
+        let list = match &attr.meta {
+            syn::Meta::Path(_) => continue,
+            syn::Meta::List(list) => list,
+            syn::Meta::NameValue(_) => {
+                return Err(syn::Error::new(
+                    attr.span(),
+                    "Incorrect format for using `skip` attribute. \
+                    Usage: `#[skip]` or `#[skip(default = \"path::to::function\")]`",
+                ))
+            }
+        };
+
+        let arg = list.parse_args::<syn::MetaNameValue>()?;
+        let function = match &arg.value {
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(lit),
+                ..
+            }) if arg.path.is_ident("default") => lit.value(),
+            _ => {
+                return Err(syn::Error::new(
+                    arg.span(),
+                    "Expected `default = \"path::to::function\"`",
+                ))
+            }
+        };
+        default =
+            Some(syn::parse_str::<syn::Path>(&function).map_err(|_| {
+                syn::Error::new(arg.span(), format!("`{}` is not a path", function))
+            })?);
+    }
+
+    Ok((skip, default))
+}
+
+fn parse_bool_as(field: &syn::Field) -> syn::Result<Option<TokenStream2>> {
+    let mut wrapper = None;
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("bool_as"))
+    {
+        wrapper = match parse_string_arg(attr, "#[bool_as(\"YN\")]")?.as_str() {
+            "YN" => Some(quote! { orm::data::YesNoBool }),
+            "text" => Some(quote! { orm::data::TextBool }),
             _ => {
                 return Err(syn::Error::new(
                     attr.span(),
-                    "Incorrect format for using `table_name` attribute. Usage: `#[table_name(\"MyTable\")]`"));
+                    "Unknown bool encoding, expected \"YN\" or \"text\"",
+                ))
             }
-        }
+        };
     }
 
-    Ok(table_name)
+    Ok(wrapper)
+}
+
+fn parse_belongs_to(field: &syn::Field) -> syn::Result<Option<syn::Type>> {
+    let mut parent = None;
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("belongs_to"))
+    {
+        let usage = || {
+            syn::Error::new(
+                attr.span(),
+                "Incorrect format for using `belongs_to` attribute. Usage: `#[belongs_to(User)]`",
+            )
+        };
+        parent = match &attr.meta {
+            syn::Meta::List(list) => Some(list.parse_args::<syn::Type>().map_err(|_| usage())?),
+            _ => return Err(usage()),
+        };
+    }
+
+    Ok(parent)
 }
 
-fn parse_column_name(field: &syn::Field) -> syn::Result<String> {
-    let mut column_name = field.ident.as_ref().map(|ident| ident.to_string());
+fn parse_conversion(field: &syn::Field) -> syn::Result<Option<(syn::Path, syn::Path)>> {
+    let mut serialize = None;
+    let mut deserialize = None;
+    for attr in field.attrs.iter() {
+        let (path, usage) = if attr.path().is_ident("serialize_with") {
+            (&mut serialize, "#[serialize_with(\"path::to::function\")]")
+        } else if attr.path().is_ident("deserialize_with") {
+            (
+                &mut deserialize,
+                "#[deserialize_with(\"path::to::function\")]",
+            )
+        } else {
+            continue;
+        };
+
+        let function = parse_string_arg(attr, usage)?;
+        *path =
+            Some(syn::parse_str::<syn::Path>(&function).map_err(|_| {
+                syn::Error::new(attr.span(), format!("`{}` is not a path", function))
+            })?);
+    }
 
-    for attr in &field.attrs {
+    match (serialize, deserialize) {
+        (Some(serialize), Some(deserialize)) => Ok(Some((serialize, deserialize))),
+        (None, None) => Ok(None),
+        _ => Err(syn::Error::new(
+            field.span(),
+            "`serialize_with` and `deserialize_with` attributes should be used together",
+        )),
+    }
+}
+
+fn has_flag(attrs: &[Attribute], name: &str) -> syn::Result<bool> {
+    let mut res = false;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident(name)) {
         match &attr.meta {
-            syn::Meta::List(list) if attr.path().is_ident("column_name") => {
-                column_name = match list.parse_args::<LitStr>() {
-                    Ok(lit) => Some(lit.value()),
-                    Err(_) => {
-                        return Err(syn::Error::new(
-                            list.span(),
-                            "Attribute argument should be a single string literal",
-                        ));
-                    }
-                };
+            syn::Meta::Path(_) => res = true,
+            _ => {
+                return Err(syn::Error::new(
+                    attr.span(),
+                    format!(
+                        "`{}` attribute takes no arguments. Usage: `#[{}]`",
+                        name, name
+                    ),
+                ))
             }
+        }
+    }
+
+    Ok(res)
+}
+
+fn parse_string_arg(attr: &Attribute, usage: &str) -> syn::Result<String> {
+    match &attr.meta {
+        syn::Meta::List(list) => match list.parse_args::<LitStr>() {
+            Ok(lit) => Ok(lit.value()),
+            Err(_) => Err(syn::Error::new(
+                list.span(),
+                "Attribute argument should be a single string literal",
+            )),
+        },
+        _ => Err(syn::Error::new(
+            attr.span(),
+            format!(
+                "Incorrect format for using `{}` attribute. Usage: `{}`",
+                attr.path().get_ident().unwrap(),
+                usage
+            ),
+        )),
+    }
+}
+
+// Casing of the default column names, set by `#[rename_all(...)]`.
+#[derive(Clone, Copy)]
+enum RenameRule {
+    Camel,
+    Pascal,
+    Snake,
+}
+
+impl RenameRule {
+    // `name` is a field name, which is expected to be in snake_case.
+    fn apply(self, name: &str) -> String {
+        let words = name.split('_').filter(|word| !word.is_empty());
+        match self {
+            RenameRule::Snake => words.collect::<Vec<_>>().join("_"),
+            RenameRule::Pascal => words.map(capitalize).collect(),
+            RenameRule::Camel => words
+                .enumerate()
+                .map(|(i, word)| match i {
+                    0 => word.to_string(),
+                    _ => capitalize(word),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn parse_rename_all(input: &DeriveInput) -> syn::Result<Option<RenameRule>> {
+    let mut rule = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("rename_all"))
+    {
+        rule = match parse_string_arg(attr, "#[rename_all(\"camelCase\")]")?.as_str() {
+            "camelCase" => Some(RenameRule::Camel),
+            "PascalCase" => Some(RenameRule::Pascal),
+            "snake_case" => Some(RenameRule::Snake),
             _ => {
                 return Err(syn::Error::new(
                     attr.span(),
-                    "Incorrect format for using `column_name` attribute. Usage: `#[column_name(\"MyColumn\")]`"));
+                    "Unknown casing, expected \"camelCase\", \"PascalCase\" or \"snake_case\"",
+                ))
             }
+        };
+    }
+
+    Ok(rule)
+}
+
+// An explicit `#[column_name(...)]` wins over `rename_all`.
+fn parse_column_name(field: &syn::Field, rename_all: Option<RenameRule>) -> syn::Result<String> {
+    let mut column_name = field.ident.as_ref().map(|ident| {
+        let name = ident.to_string();
+        match rename_all {
+            Some(rule) => rule.apply(&name),
+            None => name,
         }
+    });
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("column_name"))
+    {
+        column_name = Some(parse_string_arg(attr, "#[column_name(\"MyColumn\")]")?);
     }
 
     match column_name {
@@ -1,39 +1,524 @@
 #![forbid(unsafe_code)]
-use crate::{storage::StorageTransaction, Result, Transaction};
-use std::path::Path;
+use crate::{
+    storage::{SqliteTransaction, StorageTransaction},
+    Error, Result, Transaction,
+};
+use std::{
+    path::Path,
+    rc::Rc,
+    time::{Duration, SystemTime},
+};
 
 ////////////////////////////////////////////////////////////////////////////////
 
-trait StorageConnection {
-    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>>;
+pub(crate) trait StorageConnection {
+    // With `database`, tables are looked up in the attached database of that name.
+    fn new_transaction(
+        &mut self,
+        settings: &Settings,
+        database: Option<&str>,
+    ) -> Result<Box<dyn StorageTransaction + '_>>;
+
+    fn attach(&self, path: &Path, database: &str) -> Result<()>;
+    fn detach(&self, database: &str) -> Result<()>;
+
+    fn user_version(&self) -> Result<i32>;
+    fn set_user_version(&self, version: i32) -> Result<()>;
+
+    fn supports_delete_limit(&self) -> bool;
+
+    fn in_transaction(&self) -> bool;
 }
 
 impl StorageConnection for rusqlite::Connection {
-    fn new_transaction(&mut self) -> Result<Box<dyn StorageTransaction + '_>> {
-        Ok(Box::new(self.transaction()?))
+    fn new_transaction(
+        &mut self,
+        settings: &Settings,
+        database: Option<&str>,
+    ) -> Result<Box<dyn StorageTransaction + '_>> {
+        Ok(Box::new(SqliteTransaction::new(
+            self.transaction()?,
+            settings.lenient_reads,
+            settings.log_sql_values,
+            &settings.table_prefix,
+            database,
+        )))
+    }
+
+    fn attach(&self, path: &Path, database: &str) -> Result<()> {
+        self.execute(
+            "ATTACH DATABASE ? AS ?",
+            [path.to_string_lossy().as_ref(), database],
+        )?;
+        Ok(())
+    }
+
+    fn detach(&self, database: &str) -> Result<()> {
+        self.execute("DETACH DATABASE ?", [database])?;
+        Ok(())
+    }
+
+    fn user_version(&self) -> Result<i32> {
+        Ok(self.pragma_query_value(None, "user_version", |row| row.get(0))?)
+    }
+
+    fn set_user_version(&self, version: i32) -> Result<()> {
+        Ok(self.pragma_update(None, "user_version", version)?)
+    }
+
+    // `DELETE ... LIMIT` is only parsed by SQLite built with SQLITE_ENABLE_UPDATE_DELETE_LIMIT,
+    // otherwise preparing it fails with a syntax error before the table is even looked up.
+    fn supports_delete_limit(&self) -> bool {
+        match self.prepare("DELETE FROM orm_delete_limit_probe LIMIT 1") {
+            Ok(_) => true,
+            Err(err) => !err.to_string().contains("syntax error"),
+        }
+    }
+
+    fn in_transaction(&self) -> bool {
+        !self.is_autocommit()
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+// Connection-wide options every transaction of the connection works with.
+#[derive(Clone)]
+pub(crate) struct Settings {
+    pub delete_limit: bool,
+    pub auto_create_tables: bool,
+    pub max_changes: Option<usize>,
+    pub max_cached: Option<usize>,
+    pub lenient_reads: bool,
+    pub log_sql_values: bool,
+    pub defer_inserts: bool,
+    // prepended to the table names of all objects, see `ConnectionBuilder::table_prefix`
+    pub table_prefix: String,
+    // the source of the current time for timestamp fields
+    pub clock: Rc<dyn Fn() -> SystemTime>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
 pub struct Connection {
     inner: Box<dyn StorageConnection>,
+    settings: Settings,
 }
 
 impl Connection {
+    fn new(inner: Box<dyn StorageConnection>) -> Self {
+        let settings = Settings {
+            delete_limit: inner.supports_delete_limit(),
+            auto_create_tables: true,
+            max_changes: None,
+            max_cached: None,
+            lenient_reads: false,
+            log_sql_values: false,
+            defer_inserts: false,
+            table_prefix: String::new(),
+            clock: Rc::new(SystemTime::now),
+        };
+        Self { inner, settings }
+    }
+
+    pub fn builder() -> ConnectionBuilder {
+        ConnectionBuilder::new()
+    }
+
+    // Unlike SQLite by default, connections enforce foreign keys, e.g. of `#[belongs_to]` fields.
+    fn sqlite(conn: rusqlite::Connection) -> Result<Self> {
+        conn.pragma_update(None, "foreign_keys", true)?;
+        Ok(Self::new(Box::new(conn)))
+    }
+
     pub fn open_sqlite_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        Ok(Self {
-            inner: Box::new(rusqlite::Connection::open(path)?),
-        })
+        Self::sqlite(rusqlite::Connection::open(path)?)
     }
 
     pub fn open_in_memory() -> Result<Self> {
-        Ok(Self {
-            inner: Box::new(rusqlite::Connection::open_in_memory()?),
-        })
+        Self::sqlite(rusqlite::Connection::open_in_memory()?)
+    }
+
+    // A connection to a fresh storage which lives in memory without SQLite, for fast tests of
+    // code built on `Transaction` and `Tx`. It doesn't run SQL, so raw statements and filters
+    // are not supported.
+    #[cfg(feature = "test-backend")]
+    pub fn open_mock() -> Self {
+        Self::new(Box::new(crate::memory::MemoryConnection::default()))
+    }
+
+    // All connections opened with the same `name` share one in-memory database, which lives
+    // while at least one of them is open. Any name can be used, it is escaped in the URI.
+    pub fn open_shared_memory(name: &str) -> Result<Self> {
+        let uri = format!("file:{}?mode=memory&cache=shared", uri_path(name));
+        let flags = rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE
+            | rusqlite::OpenFlags::SQLITE_OPEN_CREATE
+            | rusqlite::OpenFlags::SQLITE_OPEN_URI;
+        Self::sqlite(rusqlite::Connection::open_with_flags(uri, flags)?)
+    }
+
+    // Tables are created on first use by default. With this turned off, using an object whose
+    // table doesn't exist fails with `MissingTable`, e.g. when a migration wasn't run.
+    // Applies to transactions started afterwards.
+    pub fn auto_create_tables(&mut self, enabled: bool) {
+        self.settings.auto_create_tables = enabled;
+    }
+
+    // Limits the number of objects a transaction can create, modify or delete before it is
+    // committed (or checkpointed). Past the limit its operations fail with `TransactionTooLarge`,
+    // which guards against loops that forget to commit. Applies to transactions started afterwards.
+    pub fn transaction_size_limit(&mut self, limit: Option<usize>) {
+        self.settings.max_changes = limit;
+    }
+
+    // Limits the number of objects a transaction keeps cached. When objects are added past
+    // the limit, the least recently used ones are dropped and read again when needed, which
+    // keeps long scans (e.g. with `paged_stream`) from holding the whole table in memory. Objects
+    // with pending changes or live `Tx` handles are never dropped, so the cache may still grow
+    // past the limit while they are held. Applies to transactions started afterwards.
+    pub fn object_cache_limit(&mut self, limit: Option<usize>) {
+        self.settings.max_cached = limit;
+    }
+
+    // Reads are strict by default: a value stored with a type other than the field's one is
+    // an `UnexpectedType` error. In lenient mode such values are converted when it makes sense
+    // (e.g. text '42' for an integer field), which helps with loosely typed legacy databases.
+    // Applies to transactions started afterwards.
+    pub fn lenient_reads(&mut self, enabled: bool) {
+        self.settings.lenient_reads = enabled;
+    }
+
+    // With the `tracing` feature every statement is logged at the debug level along with
+    // the number of its parameters. Their values may hold personal data, so they are logged only
+    // when enabled here. Applies to transactions started afterwards.
+    pub fn log_sql_values(&mut self, enabled: bool) {
+        self.settings.log_sql_values = enabled;
+    }
+
+    // With deferred inserts `create` only caches the object, and its row is inserted at commit
+    // (or checkpoint, or savepoint) like modifications are written, so an object created and
+    // deleted in the same transaction never reaches the database. Until then the object has
+    // a provisional negative id, which `Tx::id` returns, and queries don't see it. Applies to
+    // transactions started afterwards.
+    pub fn defer_inserts(&mut self, enabled: bool) {
+        self.settings.defer_inserts = enabled;
+    }
+
+    // Replaces the system clock which `#[created_at]` and `#[updated_at]` fields are stamped
+    // with, e.g. by a fixed time in tests. Applies to transactions started afterwards.
+    pub fn clock(&mut self, clock: impl Fn() -> SystemTime + 'static) {
+        self.settings.clock = Rc::new(clock);
+    }
+
+    // Attaches another database file under the name `database`, so that transactions started
+    // with `transaction_in` can work with its tables. SQLite doesn't allow attaching or detaching
+    // while a transaction is open.
+    pub fn attach<P: AsRef<Path>>(&mut self, path: P, database: &str) -> Result<()> {
+        self.inner.attach(path.as_ref(), database)
+    }
+
+    pub fn detach(&mut self, database: &str) -> Result<()> {
+        self.inner.detach(database)
+    }
+
+    // An integer stored in the database header for the application's own use, 0 in a new
+    // database. It is handy for tracking the schema version without a dedicated table.
+    pub fn user_version(&self) -> Result<i32> {
+        self.inner.user_version()
+    }
+
+    pub fn set_user_version(&mut self, version: i32) -> Result<()> {
+        self.inner.set_user_version(version)
+    }
+
+    // Tells whether a transaction is open on the underlying connection. A live `Transaction`
+    // borrows the connection mutably, so the only open one this can see is a leaked one (e.g.
+    // with `mem::forget`), which was never committed or rolled back. A dropped one is rolled back.
+    pub fn in_transaction(&self) -> bool {
+        self.inner.in_transaction()
     }
 
     pub fn new_transaction(&mut self) -> Result<Transaction<'_>> {
-        Ok(Transaction::new(self.inner.new_transaction()?))
+        Ok(Transaction::new(
+            self.inner.new_transaction(&self.settings, None)?,
+            self.settings.clone(),
+            None,
+        ))
+    }
+
+    // The name is attached to everything logged by the transaction (see the `tracing` feature),
+    // which helps to correlate logs of interleaved transactions.
+    pub fn transaction_named(&mut self, name: &str) -> Result<Transaction<'_>> {
+        Ok(Transaction::new(
+            self.inner.new_transaction(&self.settings, None)?,
+            self.settings.clone(),
+            Some(name),
+        ))
+    }
+
+    // A transaction working with the tables of the attached `database` (see `attach`), so that
+    // the same types can be stored in several databases, e.g. one per tenant. Tables of
+    // `#[temporary]` types are not affected, as they always live in the temporary database.
+    pub fn transaction_in(&mut self, database: &str) -> Result<Transaction<'_>> {
+        Ok(Transaction::new(
+            self.inner.new_transaction(&self.settings, Some(database))?,
+            self.settings.clone(),
+            None,
+        ))
+    }
+
+    // Runs `f` in a new transaction and commits it. When `f` or the commit fails with
+    // `LockConflict`, the transaction is rolled back and `f` is run again in a fresh one, up to
+    // `max_attempts` runs in total, sleeping `backoff` before the first retry and twice as long
+    // before each next one. Other errors are returned right away. As `f` may run several times,
+    // it shouldn't have side effects outside of the transaction.
+    pub fn with_retry<R>(
+        &mut self,
+        max_attempts: u32,
+        backoff: Duration,
+        mut f: impl FnMut(&Transaction) -> Result<R>,
+    ) -> Result<R> {
+        let mut delay = backoff;
+        let mut attempt = 1;
+        loop {
+            let res = self.new_transaction().and_then(|tx| match f(&tx) {
+                Ok(value) => tx.commit().map(|_| value),
+                // a failed rollback shouldn't hide why `f` failed
+                Err(err) => {
+                    let _ = tx.rollback();
+                    Err(err)
+                }
+            });
+
+            match res {
+                Err(Error::LockConflict) if attempt < max_attempts => {
+                    std::thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+// `name` escaped to be the path of a `file:` URI, where `?`, `#` and `%` have a meaning of
+// their own.
+fn uri_path(name: &str) -> String {
+    let mut res = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                res.push(byte as char)
+            }
+            _ => res.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    res
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// `Wal` lets readers work alongside a writer instead of being blocked by it, which greatly
+// reduces `LockConflict`s between processes. It is persistent: the database stays in WAL mode
+// for every connection once it was switched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    fn as_sql(self) -> &'static str {
+        match self {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+            JournalMode::Memory => "MEMORY",
+            JournalMode::Wal => "WAL",
+            JournalMode::Off => "OFF",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+// Collects all the options of a connection and applies them when it is opened: pragmas are run
+// once per connection, before any transaction is started. Options which are not set keep
+// the defaults of SQLite and `Connection`.
+#[derive(Clone, Default)]
+pub struct ConnectionBuilder {
+    busy_timeout: Option<Duration>,
+    journal_mode: Option<JournalMode>,
+    synchronous: Option<Synchronous>,
+    foreign_keys: Option<bool>,
+    cache_size: Option<i64>,
+
+    auto_create_tables: Option<bool>,
+    transaction_size_limit: Option<usize>,
+    object_cache_limit: Option<usize>,
+    lenient_reads: Option<bool>,
+    log_sql_values: Option<bool>,
+    defer_inserts: Option<bool>,
+    table_prefix: Option<String>,
+    clock: Option<Rc<dyn Fn() -> SystemTime>>,
+}
+
+impl ConnectionBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // How long to wait for a lock held by another connection before failing with `LockConflict`.
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = Some(timeout);
+        self
+    }
+
+    pub fn journal_mode(mut self, mode: JournalMode) -> Self {
+        self.journal_mode = Some(mode);
+        self
+    }
+
+    pub fn synchronous(mut self, level: Synchronous) -> Self {
+        self.synchronous = Some(level);
+        self
+    }
+
+    // Foreign keys are enforced unless disabled here, see `Connection::open_in_memory`.
+    pub fn foreign_keys(mut self, enabled: bool) -> Self {
+        self.foreign_keys = Some(enabled);
+        self
+    }
+
+    // Positive values are numbers of pages, negative ones are sizes in KiB, as in SQLite.
+    pub fn cache_size(mut self, size: i64) -> Self {
+        self.cache_size = Some(size);
+        self
+    }
+
+    // See `Connection::auto_create_tables`.
+    pub fn auto_create_tables(mut self, enabled: bool) -> Self {
+        self.auto_create_tables = Some(enabled);
+        self
+    }
+
+    // See `Connection::transaction_size_limit`.
+    pub fn transaction_size_limit(mut self, limit: usize) -> Self {
+        self.transaction_size_limit = Some(limit);
+        self
+    }
+
+    // See `Connection::object_cache_limit`. Unlike `cache_size`, which is SQLite's page cache,
+    // this limits the objects cached by transactions.
+    pub fn object_cache_limit(mut self, limit: usize) -> Self {
+        self.object_cache_limit = Some(limit);
+        self
+    }
+
+    // See `Connection::lenient_reads`.
+    pub fn lenient_reads(mut self, enabled: bool) -> Self {
+        self.lenient_reads = Some(enabled);
+        self
+    }
+
+    // See `Connection::log_sql_values`.
+    pub fn log_sql_values(mut self, enabled: bool) -> Self {
+        self.log_sql_values = Some(enabled);
+        self
+    }
+
+    // See `Connection::defer_inserts`.
+    pub fn defer_inserts(mut self, enabled: bool) -> Self {
+        self.defer_inserts = Some(enabled);
+        self
+    }
+
+    // Prepended to the table names of all objects, along with the names of their indexes and
+    // triggers, e.g. to keep several applications in one database. The SQL of `#[trigger]`
+    // attributes and of raw queries isn't rewritten, it has to use the prefixed names itself.
+    pub fn table_prefix(mut self, prefix: &str) -> Self {
+        self.table_prefix = Some(prefix.to_string());
+        self
+    }
+
+    // See `Connection::clock`.
+    pub fn clock(mut self, clock: impl Fn() -> SystemTime + 'static) -> Self {
+        self.clock = Some(Rc::new(clock));
+        self
+    }
+
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<Connection> {
+        self.build(rusqlite::Connection::open(path)?)
+    }
+
+    pub fn open_in_memory(&self) -> Result<Connection> {
+        self.build(rusqlite::Connection::open_in_memory()?)
+    }
+
+    fn build(&self, conn: rusqlite::Connection) -> Result<Connection> {
+        // the busy timeout goes first, as changing the journal mode may need to wait for a lock
+        if let Some(timeout) = self.busy_timeout {
+            conn.busy_timeout(timeout)?;
+        }
+        if let Some(mode) = self.journal_mode {
+            // the pragma returns the resulting mode, e.g. in-memory databases ignore WAL
+            conn.pragma_update_and_check(None, "journal_mode", mode.as_sql(), |_| Ok(()))?;
+        }
+        if let Some(level) = self.synchronous {
+            conn.pragma_update(None, "synchronous", level.as_sql())?;
+        }
+        conn.pragma_update(None, "foreign_keys", self.foreign_keys.unwrap_or(true))?;
+        if let Some(size) = self.cache_size {
+            conn.pragma_update(None, "cache_size", size)?;
+        }
+
+        let mut res = Connection::new(Box::new(conn));
+        if let Some(enabled) = self.auto_create_tables {
+            res.auto_create_tables(enabled);
+        }
+        if let Some(limit) = self.transaction_size_limit {
+            res.transaction_size_limit(Some(limit));
+        }
+        if let Some(limit) = self.object_cache_limit {
+            res.object_cache_limit(Some(limit));
+        }
+        if let Some(enabled) = self.lenient_reads {
+            res.lenient_reads(enabled);
+        }
+        if let Some(enabled) = self.log_sql_values {
+            res.log_sql_values(enabled);
+        }
+        if let Some(enabled) = self.defer_inserts {
+            res.defer_inserts(enabled);
+        }
+        if let Some(prefix) = &self.table_prefix {
+            res.settings.table_prefix = prefix.clone();
+        }
+        if let Some(clock) = &self.clock {
+            res.settings.clock = clock.clone();
+        }
+        Ok(res)
     }
 }
@@ -1,5 +1,6 @@
 #![forbid(unsafe_code)]
-use std::{borrow::Cow, fmt};
+use crate::error::{Error, Result};
+use std::{borrow::Cow, fmt, num::TryFromIntError, time::SystemTime};
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -12,6 +13,20 @@ impl From<i64> for ObjectId {
     }
 }
 
+impl From<ObjectId> for i64 {
+    fn from(value: ObjectId) -> Self {
+        value.0
+    }
+}
+
+impl TryFrom<u64> for ObjectId {
+    type Error = TryFromIntError;
+
+    fn try_from(value: u64) -> std::result::Result<Self, Self::Error> {
+        Ok(ObjectId(i64::try_from(value)?))
+    }
+}
+
 impl fmt::Display for ObjectId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0)
@@ -19,7 +34,7 @@ impl fmt::Display for ObjectId {
 }
 
 impl ObjectId {
-    pub fn into_i64(&self) -> i64 {
+    pub fn into_i64(self) -> i64 {
         self.0
     }
 }
@@ -37,35 +52,163 @@ pub enum DataType {
 
 ////////////////////////////////////////////////////////////////////////////////
 
+#[derive(Clone, PartialEq)]
 pub enum Value<'a> {
     String(Cow<'a, str>),
     Bytes(Cow<'a, [u8]>),
     Int64(i64),
     Float64(f64),
     Bool(bool),
+    Null,
+}
+
+impl Value<'_> {
+    pub fn into_owned(self) -> Value<'static> {
+        match self {
+            Value::String(s) => Value::String(Cow::Owned(s.into_owned())),
+            Value::Bytes(bytes) => Value::Bytes(Cow::Owned(bytes.into_owned())),
+            Value::Int64(x) => Value::Int64(x),
+            Value::Float64(x) => Value::Float64(x),
+            Value::Bool(x) => Value::Bool(x),
+            Value::Null => Value::Null,
+        }
+    }
+}
+
+impl fmt::Display for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{}", s),
+            Value::Bytes(bytes) => write!(f, "<{} bytes>", bytes.len()),
+            Value::Int64(x) => write!(f, "{}", x),
+            Value::Float64(x) => write!(f, "{}", x),
+            Value::Bool(x) => write!(f, "{}", x),
+            Value::Null => write!(f, "NULL"),
+        }
+    }
+}
+
+// Bytes are shown as hex, only the first `MAX_DEBUG_BYTES` of them, so that a large blob
+// doesn't flood the logs.
+impl fmt::Debug for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "String({:?})", s),
+            Value::Bytes(bytes) => {
+                write!(f, "Bytes(")?;
+                for byte in bytes.iter().take(MAX_DEBUG_BYTES) {
+                    write!(f, "{:02x}", byte)?;
+                }
+                if bytes.len() > MAX_DEBUG_BYTES {
+                    write!(f, "... {} bytes", bytes.len())?;
+                }
+                write!(f, ")")
+            }
+            Value::Int64(x) => write!(f, "Int64({})", x),
+            Value::Float64(x) => write!(f, "Float64({:?})", x),
+            Value::Bool(x) => write!(f, "Bool({})", x),
+            Value::Null => write!(f, "Null"),
+        }
+    }
+}
+
+const MAX_DEBUG_BYTES: usize = 32;
+
+// A value which can be hashed, to match rows by a column (as `Transaction::apply_graph` and
+// upserts do). Floats are compared by their bits.
+#[derive(PartialEq, Eq, Hash)]
+pub(crate) enum ValueKey {
+    String(String),
+    Bytes(Vec<u8>),
+    Int64(i64),
+    Float64(u64),
+    Bool(bool),
+    Null,
+}
+
+impl From<&Value<'_>> for ValueKey {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::String(s) => ValueKey::String(s.to_string()),
+            Value::Bytes(bytes) => ValueKey::Bytes(bytes.to_vec()),
+            Value::Int64(x) => ValueKey::Int64(*x),
+            Value::Float64(x) => ValueKey::Float64(x.to_bits()),
+            Value::Bool(x) => ValueKey::Bool(*x),
+            Value::Null => ValueKey::Null,
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
-pub trait AsDataType {
+pub trait AsDataType: Sized {
     const DATA_TYPE: DataType;
+    // whether the column may hold NULL, which only `Option` fields accept
+    const NULLABLE: bool = false;
 
-    fn as_value(&self) -> Value;
-    fn from_value(value: &Value) -> Self;
+    fn as_value(&self) -> Value<'_>;
+    // Fails with `Error::InvalidValue` if the value can't be represented by the type.
+    fn from_value(value: &Value) -> Result<Self>;
+}
+
+// A value of a type other than `expected`.
+fn unexpected<T>(expected: DataType, value: &Value) -> Result<T> {
+    Err(Error::invalid_value(
+        format!("{:?}", expected),
+        describe(value),
+    ))
+}
+
+fn describe(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("String '{}'", s),
+        Value::Null => "NULL".to_string(),
+        value => format!(
+            "{} {}",
+            match value {
+                Value::Bytes(_) => "Bytes",
+                Value::Int64(_) => "Int64",
+                Value::Float64(_) => "Float64",
+                _ => "Bool",
+            },
+            value
+        ),
+    }
 }
 
 impl AsDataType for String {
     const DATA_TYPE: DataType = DataType::String;
 
-    fn as_value(&self) -> Value {
+    fn as_value(&self) -> Value<'_> {
         Value::String(std::borrow::Cow::from(self))
     }
 
-    fn from_value(value: &Value) -> Self {
-        if let Value::String(s) = value {
-            s.clone().into_owned()
-        } else {
-            panic!("not expected type")
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::String(s) => Ok(s.clone().into_owned()),
+            value => unexpected(Self::DATA_TYPE, value),
+        }
+    }
+}
+
+// Stored as a one-character string.
+impl AsDataType for char {
+    const DATA_TYPE: DataType = DataType::String;
+
+    fn as_value(&self) -> Value<'_> {
+        Value::String(Cow::Owned(self.to_string()))
+    }
+
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::String(s) => {
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(c),
+                    _ => Err(Error::invalid_value("a single character", describe(value))),
+                }
+            }
+            value => unexpected(Self::DATA_TYPE, value),
         }
     }
 }
@@ -73,15 +216,14 @@ impl AsDataType for String {
 impl AsDataType for Vec<u8> {
     const DATA_TYPE: DataType = DataType::Bytes;
 
-    fn as_value(&self) -> Value {
+    fn as_value(&self) -> Value<'_> {
         Value::Bytes(std::borrow::Cow::from(self))
     }
 
-    fn from_value(value: &Value) -> Self {
-        if let Value::Bytes(b) = value {
-            b.clone().into_owned()
-        } else {
-            panic!("not expected type")
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::Bytes(b) => Ok(b.clone().into_owned()),
+            value => unexpected(Self::DATA_TYPE, value),
         }
     }
 }
@@ -89,31 +231,70 @@ impl AsDataType for Vec<u8> {
 impl AsDataType for i64 {
     const DATA_TYPE: DataType = DataType::Int64;
 
-    fn as_value(&self) -> Value {
+    fn as_value(&self) -> Value<'_> {
         Value::Int64(*self)
     }
 
-    fn from_value(value: &Value) -> Self {
-        if let Value::Int64(x) = value {
-            *x
-        } else {
-            panic!("not expected type")
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::Int64(x) => Ok(*x),
+            value => unexpected(Self::DATA_TYPE, value),
         }
     }
 }
 
+// Narrower integers are stored as `Int64` as well. A stored value which doesn't fit into
+// the field's type is not wrapped: reading it fails, like reading a value of another type.
+macro_rules! impl_narrow_int {
+    ($($int:ty),*) => {$(
+        impl AsDataType for $int {
+            const DATA_TYPE: DataType = DataType::Int64;
+
+            fn as_value(&self) -> Value<'_> {
+                Value::Int64(i64::from(*self))
+            }
+
+            fn from_value(value: &Value) -> Result<Self> {
+                match value {
+                    Value::Int64(x) => <$int>::try_from(*x).map_err(|_| {
+                        Error::invalid_value(stringify!($int), format!("Int64 {}", x))
+                    }),
+                    value => unexpected(Self::DATA_TYPE, value),
+                }
+            }
+        }
+    )*};
+}
+
+impl_narrow_int!(i32, u32, i16, u16, u8);
+
 impl AsDataType for f64 {
     const DATA_TYPE: DataType = DataType::Float64;
 
-    fn as_value(&self) -> Value {
+    fn as_value(&self) -> Value<'_> {
         Value::Float64(*self)
     }
 
-    fn from_value(value: &Value) -> Self {
-        if let Value::Float64(x) = value {
-            *x
-        } else {
-            panic!("not expected type")
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::Float64(x) => Ok(*x),
+            value => unexpected(Self::DATA_TYPE, value),
+        }
+    }
+}
+
+// Stored as `Float64`, which represents every `f32` exactly, so the value survives unchanged.
+impl AsDataType for f32 {
+    const DATA_TYPE: DataType = DataType::Float64;
+
+    fn as_value(&self) -> Value<'_> {
+        Value::Float64(f64::from(*self))
+    }
+
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::Float64(x) => Ok(*x as f32),
+            value => unexpected(Self::DATA_TYPE, value),
         }
     }
 }
@@ -121,15 +302,399 @@ impl AsDataType for f64 {
 impl AsDataType for bool {
     const DATA_TYPE: DataType = DataType::Bool;
 
-    fn as_value(&self) -> Value {
+    fn as_value(&self) -> Value<'_> {
         Value::Bool(*self)
     }
 
-    fn from_value(value: &Value) -> Self {
-        if let Value::Bool(x) = value {
-            *x
-        } else {
-            panic!("not expected type")
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::Bool(x) => Ok(*x),
+            value => unexpected(Self::DATA_TYPE, value),
+        }
+    }
+}
+
+impl<T: AsDataType> AsDataType for Option<T> {
+    const DATA_TYPE: DataType = T::DATA_TYPE;
+    const NULLABLE: bool = true;
+
+    fn as_value(&self) -> Value<'_> {
+        match self {
+            Some(x) => x.as_value(),
+            None => Value::Null,
+        }
+    }
+
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::Null => Ok(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Wrappers storing `bool` in legacy text encodings, used by `#[bool_as(...)]` fields.
+
+pub struct YesNoBool(pub bool);
+
+impl YesNoBool {
+    pub fn into_value(self) -> Value<'static> {
+        Value::String(Cow::Borrowed(if self.0 { "Y" } else { "N" }))
+    }
+}
+
+impl AsDataType for YesNoBool {
+    const DATA_TYPE: DataType = DataType::String;
+
+    fn as_value(&self) -> Value<'_> {
+        Self(self.0).into_value()
+    }
+
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::String(s) if s == "Y" => Ok(Self(true)),
+            Value::String(s) if s == "N" => Ok(Self(false)),
+            value => Err(Error::invalid_value("'Y' or 'N'", describe(value))),
         }
     }
 }
+
+pub struct TextBool(pub bool);
+
+impl TextBool {
+    pub fn into_value(self) -> Value<'static> {
+        Value::String(Cow::Borrowed(if self.0 { "true" } else { "false" }))
+    }
+}
+
+impl AsDataType for TextBool {
+    const DATA_TYPE: DataType = DataType::String;
+
+    fn as_value(&self) -> Value<'_> {
+        Self(self.0).into_value()
+    }
+
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::String(s) if s == "true" => Ok(Self(true)),
+            Value::String(s) if s == "false" => Ok(Self(false)),
+            value => Err(Error::invalid_value("'true' or 'false'", describe(value))),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Glue for `#[serialize_with(...)]` / `#[deserialize_with(...)]` fields: the column type and the
+// stored representation `S` are inferred from the signatures of the conversion functions.
+
+pub const fn serialized_type<T: ?Sized, S: AsDataType>(_serialize: fn(&T) -> S) -> DataType {
+    S::DATA_TYPE
+}
+
+pub const fn serialized_nullable<T: ?Sized, S: AsDataType>(_serialize: fn(&T) -> S) -> bool {
+    S::NULLABLE
+}
+
+pub fn serialize_with<T: ?Sized, S: AsDataType>(
+    serialize: fn(&T) -> S,
+    field: &T,
+) -> Value<'static> {
+    serialize(field).as_value().into_owned()
+}
+
+pub fn deserialize_with<T, S: AsDataType>(deserialize: fn(S) -> T, value: &Value) -> Result<T> {
+    S::from_value(value).map(deserialize)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Types of `#[created_at]` and `#[updated_at]` fields, which are set to the current time of
+// the connection's clock. An `i64` holds seconds since the Unix epoch.
+pub trait Timestamp: AsDataType {
+    fn from_system_time(time: SystemTime) -> Self;
+}
+
+impl Timestamp for i64 {
+    fn from_system_time(time: SystemTime) -> Self {
+        match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs() as i64),
+        }
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl Timestamp for chrono::NaiveDateTime {
+    fn from_system_time(time: SystemTime) -> Self {
+        chrono::DateTime::<chrono::Utc>::from(time).naive_utc()
+    }
+}
+
+pub fn timestamp_value<T: Timestamp>(time: SystemTime) -> Value<'static> {
+    T::from_system_time(time).as_value().into_owned()
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Dates and times are stored as text in the format of SQLite's own date and time functions
+// (`YYYY-MM-DD HH:MM:SS.SSS`), so they sort chronologically and can be compared with
+// `CURRENT_TIMESTAMP` or passed to `datetime()`. The fraction of a second is written only if
+// it is non-zero, and the ISO 8601 `T` separator is accepted on read as well.
+
+#[cfg(feature = "chrono")]
+const DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+#[cfg(feature = "chrono")]
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+#[cfg(feature = "chrono")]
+impl AsDataType for chrono::NaiveDateTime {
+    const DATA_TYPE: DataType = DataType::String;
+
+    fn as_value(&self) -> Value<'_> {
+        Value::String(Cow::Owned(self.format(DATETIME_FORMAT).to_string()))
+    }
+
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::String(s) => chrono::NaiveDateTime::parse_from_str(s, DATETIME_FORMAT)
+                .or_else(|_| chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f"))
+                .map_err(|_| Error::invalid_value("date and time", describe(value))),
+            value => unexpected(Self::DATA_TYPE, value),
+        }
+    }
+}
+
+#[cfg(feature = "chrono")]
+impl AsDataType for chrono::NaiveDate {
+    const DATA_TYPE: DataType = DataType::String;
+
+    fn as_value(&self) -> Value<'_> {
+        Value::String(Cow::Owned(self.format(DATE_FORMAT).to_string()))
+    }
+
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::String(s) => chrono::NaiveDate::parse_from_str(s, DATE_FORMAT)
+                .map_err(|_| Error::invalid_value("date", describe(value))),
+            value => unexpected(Self::DATA_TYPE, value),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// UUIDs are stored as their 16 bytes, which is more than twice as compact as the hex text.
+
+#[cfg(feature = "uuid")]
+impl AsDataType for uuid::Uuid {
+    const DATA_TYPE: DataType = DataType::Bytes;
+
+    fn as_value(&self) -> Value<'_> {
+        Value::Bytes(Cow::Borrowed(self.as_bytes()))
+    }
+
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::Bytes(bytes) => uuid::Uuid::from_slice(bytes)
+                .map_err(|_| Error::invalid_value("16 bytes of UUID", describe(value))),
+            value => unexpected(Self::DATA_TYPE, value),
+        }
+    }
+}
+
+// Stored as text, which keeps the value exact where REAL would round it. The text is the
+// canonical form, e.g. `0.30` keeps its scale, so it compares as a string, not as a number.
+#[cfg(feature = "decimal")]
+impl AsDataType for rust_decimal::Decimal {
+    const DATA_TYPE: DataType = DataType::String;
+
+    fn as_value(&self) -> Value<'_> {
+        Value::String(Cow::Owned(self.to_string()))
+    }
+
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::String(s) => s
+                .parse()
+                .map_err(|_| Error::invalid_value("a decimal number", describe(value))),
+            value => unexpected(Self::DATA_TYPE, value),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// A field holding any serializable value, e.g. a map of free-form metadata, stored as JSON text.
+// A blanket implementation for serde types would conflict with the ones above, hence the wrapper.
+// `T` must always serialize into JSON: writing a value which can't (e.g. a map with non-string
+// keys) panics, as `as_value` has no way to fail.
+
+#[cfg(feature = "json")]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Json<T>(pub T);
+
+#[cfg(feature = "json")]
+impl<T> std::ops::Deref for Json<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[cfg(feature = "json")]
+impl<T> std::ops::DerefMut for Json<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+#[cfg(feature = "json")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> AsDataType for Json<T> {
+    const DATA_TYPE: DataType = DataType::String;
+
+    // Only values which can't be represented in JSON fail to serialize (e.g. a map with
+    // non-string keys), which is a bug in the type rather than a runtime condition.
+    fn as_value(&self) -> Value<'_> {
+        match serde_json::to_string(&self.0) {
+            Ok(json) => Value::String(Cow::Owned(json)),
+            Err(err) => panic!("value can't be serialized into JSON: {}", err),
+        }
+    }
+
+    fn from_value(value: &Value) -> Result<Self> {
+        match value {
+            Value::String(s) => serde_json::from_str(s).map(Json).map_err(|err| {
+                Error::invalid_value("JSON", format!("{} ({})", describe(value), err))
+            }),
+            value => unexpected(Self::DATA_TYPE, value),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Used by `#[compress]` fields: the value of a `String` or `Vec<u8>` field is stored gzip-compressed.
+// Empty values are stored as an empty BLOB.
+
+#[cfg(feature = "compress")]
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for String {}
+    impl Sealed for Vec<u8> {}
+}
+
+// Types of the fields which can be `#[compress]`-ed.
+#[cfg(feature = "compress")]
+pub trait Compressible: Sized + sealed::Sealed {
+    fn as_bytes(&self) -> &[u8];
+    fn from_bytes(bytes: Vec<u8>) -> Result<Self>;
+}
+
+#[cfg(feature = "compress")]
+impl Compressible for String {
+    fn as_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        String::from_utf8(bytes).map_err(|err| {
+            Error::invalid_value(
+                "UTF-8 text",
+                format!("decompressed data which is not UTF-8 ({})", err),
+            )
+        })
+    }
+}
+
+#[cfg(feature = "compress")]
+impl Compressible for Vec<u8> {
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        Ok(bytes)
+    }
+}
+
+#[cfg(feature = "compress")]
+pub fn compress<T: Compressible>(field: &T) -> Value<'static> {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let data = field.as_bytes();
+    if data.is_empty() {
+        return Value::Bytes(Cow::Owned(vec![]));
+    }
+
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    let compressed = encoder
+        .write_all(data)
+        .and_then(|_| encoder.finish())
+        .expect("compression into memory should not fail");
+    Value::Bytes(Cow::Owned(compressed))
+}
+
+#[cfg(feature = "compress")]
+pub fn decompress<T: Compressible>(value: &Value) -> Result<T> {
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    let data = match value {
+        Value::Bytes(bytes) => bytes,
+        value => return unexpected(DataType::Bytes, value),
+    };
+    let mut raw = vec![];
+    if !data.is_empty() {
+        if let Err(err) = GzDecoder::new(&data[..]).read_to_end(&mut raw) {
+            return Err(Error::invalid_value(
+                "gzip-compressed data",
+                format!("corrupt data ({})", err),
+            ));
+        }
+    }
+    T::from_bytes(raw)
+}
+
+// The derive compresses fields through these, so that a `#[compress]` field fails to compile
+// with a clear message when the feature is off.
+#[cfg(feature = "compress")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __compress {
+    ($field:expr) => {
+        $crate::data::compress($field)
+    };
+}
+
+#[cfg(feature = "compress")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __decompress {
+    ($value:expr) => {
+        $crate::data::decompress($value)
+    };
+}
+
+#[cfg(not(feature = "compress"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __compress {
+    ($field:expr) => {
+        compile_error!("`#[compress]` fields need the `compress` feature of `orm`")
+    };
+}
+
+#[cfg(not(feature = "compress"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __decompress {
+    ($value:expr) => {
+        compile_error!("`#[compress]` fields need the `compress` feature of `orm`")
+    };
+}
@@ -0,0 +1,76 @@
+#![forbid(unsafe_code)]
+use crate::data::DataType;
+
+////////////////////////////////////////////////////////////////////////////////
+
+// The parts of SQL which differ between databases. Statements of the storage layer are built
+// through it, so that another backend can reuse them with its own dialect.
+pub(crate) trait Dialect {
+    // The type of a column holding values of `data_type`.
+    fn column_type(&self, data_type: DataType) -> &'static str;
+    // The definition of the synthetic id column, for tables without a `#[primary_key]` field.
+    fn id_column(&self, column: &str) -> String;
+    // The type and constraints of a `#[primary_key]` column.
+    fn primary_key_clause(&self) -> &'static str;
+    // The declared type of the id column, be it synthetic or a `#[primary_key]` field.
+    fn id_type(&self) -> &'static str;
+    // The parameter number `index` (starting from 1) of a statement.
+    fn placeholder(&self, index: usize) -> String;
+    // Inserts a row where every column gets its default value.
+    fn insert_defaults(&self, table: &str) -> String;
+}
+
+// `count` comma-separated parameters, numbered starting from `first`.
+pub(crate) fn placeholders(dialect: &dyn Dialect, first: usize, count: usize) -> String {
+    (first..first + count)
+        .map(|index| dialect.placeholder(index))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Parameters of a multi-row VALUES clause: `rows` parenthesized groups of `row_len` each.
+pub(crate) fn row_placeholders(dialect: &dyn Dialect, rows: usize, row_len: usize) -> String {
+    (0..rows)
+        .map(|i| format!("({})", placeholders(dialect, i * row_len + 1, row_len)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+pub(crate) struct Sqlite;
+
+impl Dialect for Sqlite {
+    fn column_type(&self, data_type: DataType) -> &'static str {
+        match data_type {
+            DataType::String => "TEXT",
+            DataType::Bytes => "BLOB",
+            DataType::Int64 => "BIGINT",
+            DataType::Float64 => "REAL",
+            DataType::Bool => "TINYINT",
+        }
+    }
+
+    // without AUTOINCREMENT the ids of deleted rows may be reused
+    fn id_column(&self, column: &str) -> String {
+        format!("{} INTEGER PRIMARY KEY AUTOINCREMENT", column)
+    }
+
+    // exactly `INTEGER PRIMARY KEY` makes the column an alias of the rowid
+    fn primary_key_clause(&self) -> &'static str {
+        "INTEGER PRIMARY KEY"
+    }
+
+    fn id_type(&self) -> &'static str {
+        "INTEGER"
+    }
+
+    // parameters are always bound in order, so they don't need numbers
+    fn placeholder(&self, _index: usize) -> String {
+        "?".to_string()
+    }
+
+    fn insert_defaults(&self, table: &str) -> String {
+        format!("INSERT INTO {} DEFAULT VALUES", table)
+    }
+}
@@ -15,13 +15,46 @@ pub enum Error {
     #[error(transparent)]
     UnexpectedType(Box<UnexpectedTypeError>),
     #[error(transparent)]
+    InvalidValue(Box<InvalidValueError>),
+    #[error(transparent)]
     MissingColumn(Box<MissingColumnError>),
+    #[error(transparent)]
+    UnknownColumn(Box<UnknownColumnError>),
+    #[error(transparent)]
+    TableExists(Box<TableExistsError>),
+    #[error(transparent)]
+    UniqueViolation(Box<UniqueViolationError>),
+    #[error(transparent)]
+    ConstraintViolation(Box<ConstraintViolationError>),
+    #[error(transparent)]
+    ForeignKeyViolation(Box<ForeignKeyViolationError>),
+    #[error(transparent)]
+    MissingTable(Box<MissingTableError>),
+    #[error(transparent)]
+    MissingIndex(Box<MissingIndexError>),
+    #[error(transparent)]
+    UnsupportedMigration(Box<UnsupportedMigrationError>),
+    #[error(transparent)]
+    ConcurrentModification(Box<ConcurrentModificationError>),
+    #[error(transparent)]
+    TransactionTooLarge(Box<TransactionTooLargeError>),
+    #[error(transparent)]
+    Borrowed(Box<BorrowedError>),
     #[error("database is locked")]
     LockConflict,
-    #[error("storage error: {0}")]
-    Storage(#[source] Box<dyn std::error::Error>),
+    #[error("database or disk is full")]
+    DiskFull,
+    #[error("disk I/O error")]
+    IoFailure,
+    #[error(transparent)]
+    Storage(Box<StorageError>),
 }
 
+// extended result codes, which libsqlite3-sys doesn't export when built against the system library
+const SQLITE_CONSTRAINT_FOREIGNKEY: i32 = rusqlite::ffi::SQLITE_CONSTRAINT | (3 << 8);
+const SQLITE_CONSTRAINT_PRIMARYKEY: i32 = rusqlite::ffi::SQLITE_CONSTRAINT | (6 << 8);
+const SQLITE_CONSTRAINT_UNIQUE: i32 = rusqlite::ffi::SQLITE_CONSTRAINT | (8 << 8);
+
 impl<'a> From<ErrorWithCtx<'a, rusqlite::Error>> for Error {
     fn from(err: ErrorWithCtx<'a, rusqlite::Error>) -> Self {
         let context = err.ctx;
@@ -35,30 +68,77 @@ impl<'a> From<ErrorWithCtx<'a, rusqlite::Error>> for Error {
                 _,
             ) => Error::LockConflict,
 
-            rusqlite::Error::SqliteFailure(_, Some(text))
-                if text.contains("no such column:") || text.contains("has no column named") =>
-            {
-                let column_name = match text.find("no such column: ") {
-                    Some(ind) => text[ind..].strip_prefix("no such column: ").unwrap(),
-                    None => {
-                        let ind = text.find("has no column named ").unwrap();
-                        text[ind..].strip_prefix("has no column named ").unwrap()
-                    }
-                };
-                dbg!(&column_name);
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code: rusqlite::ErrorCode::DiskFull,
+                    ..
+                },
+                _,
+            ) => Error::DiskFull,
 
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code: rusqlite::ErrorCode::SystemIoFailure,
+                    ..
+                },
+                _,
+            ) => Error::IoFailure,
+
+            // errors of raw SQL aren't attributed to any object, so they are left as is
+            rusqlite::Error::SqliteFailure(_, Some(ref text))
+                if context.schema.is_some() && missing_column_name(text).is_some() =>
+            {
                 let schema = context
                     .schema
                     .expect("Schema should be provided to context");
-                let field = get_field_by_name(schema, column_name);
-
-                Error::MissingColumn(Box::new({
-                    MissingColumnError {
-                        type_name: schema.type_name,
-                        attr_name: field.attr_name,
-                        table_name: schema.table_name,
-                        column_name: field.column_name,
-                    }
+                match missing_column_name(text).and_then(|name| find_field(schema, name)) {
+                    Some(field) => Error::missing_column(schema, &field),
+                    None => Error::Storage(Box::new(StorageError {
+                        source: Box::new(err.err),
+                        sql: context.sql.map(str::to_string),
+                    })),
+                }
+            }
+
+            rusqlite::Error::SqliteFailure(_, Some(text))
+                if text.starts_with("table ") && text.ends_with(" already exists") =>
+            {
+                let table_name = text
+                    .trim_start_matches("table ")
+                    .trim_end_matches(" already exists")
+                    .trim_matches(|c| c == '"' || c == '`');
+
+                Error::TableExists(Box::new(TableExistsError {
+                    table_name: table_name.to_string(),
+                }))
+            }
+
+            // "UNIQUE constraint failed: Table.a, Table.b", several columns for a composite key
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    extended_code: SQLITE_CONSTRAINT_UNIQUE | SQLITE_CONSTRAINT_PRIMARYKEY,
+                    ..
+                },
+                Some(ref text),
+            ) if text.contains(" constraint failed: ") => {
+                let mut table_name = "";
+                let columns = text
+                    .split(" constraint failed: ")
+                    .nth(1)
+                    .unwrap_or_default()
+                    .split(", ")
+                    .map(|column| match column.split_once('.') {
+                        Some((table, column)) => {
+                            table_name = table;
+                            column
+                        }
+                        None => column,
+                    })
+                    .collect::<Vec<_>>();
+
+                Error::UniqueViolation(Box::new(UniqueViolationError {
+                    table_name: table_name.to_string(),
+                    column_name: columns.join(", "),
                 }))
             }
 
@@ -72,7 +152,9 @@ impl<'a> From<ErrorWithCtx<'a, rusqlite::Error>> for Error {
                     .type_name,
             })),
 
-            rusqlite::Error::InvalidColumnType(_, column_name, got_type) => {
+            rusqlite::Error::InvalidColumnType(_, column_name, got_type)
+                if context.schema.is_some() =>
+            {
                 let schema = context
                     .schema
                     .expect("Schema should be provided to context");
@@ -85,10 +167,39 @@ impl<'a> From<ErrorWithCtx<'a, rusqlite::Error>> for Error {
                     column_name: field.column_name,
                     expected_type: field.column_type,
                     got_type: got_type.to_string(),
+                    got_value: context.got_value,
                 }))
             }
 
-            _ => Error::Storage(Box::new(err.err)),
+            // SQLite doesn't tell which key it is
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    extended_code: SQLITE_CONSTRAINT_FOREIGNKEY,
+                    ..
+                },
+                _,
+            ) => Error::ForeignKeyViolation(Box::new(ForeignKeyViolationError {
+                type_name: context.schema.map(|schema| schema.type_name),
+                table_name: context.schema.map(|schema| schema.table_name),
+            })),
+
+            // CHECK, NOT NULL or a trigger's RAISE(ABORT, ...)
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code: rusqlite::ErrorCode::ConstraintViolation,
+                    ..
+                },
+                text,
+            ) => Error::ConstraintViolation(Box::new(ConstraintViolationError {
+                message: text.unwrap_or_else(|| "constraint failed".to_string()),
+                type_name: context.schema.map(|schema| schema.type_name),
+                table_name: context.schema.map(|schema| schema.table_name),
+            })),
+
+            err => Error::Storage(Box::new(StorageError {
+                source: Box::new(err),
+                sql: context.sql.map(str::to_string),
+            })),
         }
     }
 }
@@ -106,6 +217,91 @@ impl Error {
             type_name,
         }))
     }
+
+    pub(crate) fn borrowed(object_id: ObjectId, type_name: &'static str) -> Error {
+        Error::Borrowed(Box::new(BorrowedError {
+            object_id,
+            type_name,
+        }))
+    }
+
+    pub(crate) fn missing_column(schema: &Schema, field: &Field) -> Error {
+        Error::MissingColumn(Box::new(MissingColumnError {
+            type_name: schema.type_name,
+            attr_name: field.attr_name,
+            table_name: schema.table_name,
+            column_name: field.column_name,
+        }))
+    }
+
+    pub(crate) fn missing_table(schema: &Schema) -> Error {
+        Error::MissingTable(Box::new(MissingTableError {
+            type_name: schema.type_name,
+            table_name: schema.table_name,
+        }))
+    }
+
+    pub(crate) fn missing_index(schema: &Schema, index_name: &str) -> Error {
+        Error::MissingIndex(Box::new(MissingIndexError {
+            type_name: schema.type_name,
+            table_name: schema.table_name,
+            index_name: index_name.to_string(),
+        }))
+    }
+
+    // For `AsDataType::from_value` of a value which can't be represented by the type, `expected`
+    // and `got` describe what was expected and the actual value.
+    pub fn invalid_value(expected: impl ToString, got: impl ToString) -> Error {
+        Error::InvalidValue(Box::new(InvalidValueError {
+            expected: expected.to_string(),
+            got: got.to_string(),
+        }))
+    }
+
+    // Attributes an `InvalidValue` error to the field of `schema` read from the `index`-th value
+    // of a row, turning it into `UnexpectedType`. Used by `from_row` of derived objects.
+    pub fn in_column(self, schema: &Schema, index: usize) -> Error {
+        match (self, schema.fields.get(index)) {
+            (Error::InvalidValue(err), Some(field)) => {
+                // e.g. an integer out of range of the field's narrower type
+                let got_type = match err.expected == format!("{:?}", field.column_type) {
+                    true => err.got,
+                    false => format!("{}, which isn't {}", err.got, err.expected),
+                };
+                Error::UnexpectedType(Box::new(UnexpectedTypeError {
+                    type_name: schema.type_name,
+                    attr_name: field.attr_name,
+                    table_name: schema.table_name,
+                    column_name: field.column_name,
+                    expected_type: field.column_type,
+                    got_type,
+                    got_value: None,
+                }))
+            }
+            (err, _) => err,
+        }
+    }
+
+    pub(crate) fn unsupported_migration(
+        schema: &Schema,
+        field: &Field,
+        reason: &'static str,
+    ) -> Error {
+        Error::UnsupportedMigration(Box::new(UnsupportedMigrationError {
+            type_name: schema.type_name,
+            table_name: schema.table_name,
+            column_name: field.column_name,
+            reason,
+        }))
+    }
+
+    pub(crate) fn unknown_column(schema: &Schema, column_name: &str) -> Error {
+        Error::UnknownColumn(Box::new(UnknownColumnError {
+            type_name: schema.type_name,
+            table_name: schema.table_name,
+            column_name: column_name.to_string(),
+        }))
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -122,7 +318,8 @@ pub struct NotFoundError {
 #[derive(Error, Debug)]
 #[error(
     "invalid type for {type_name}::{attr_name}: expected equivalent of {expected_type:?}, \
-    got {got_type} (table: {table_name}, column: {column_name})"
+    got {got_type}{} (table: {table_name}, column: {column_name})",
+    .got_value.as_ref().map(|value| format!(" {}", value)).unwrap_or_default()
 )]
 pub struct UnexpectedTypeError {
     pub type_name: &'static str,
@@ -131,6 +328,16 @@ pub struct UnexpectedTypeError {
     pub column_name: &'static str,
     pub expected_type: DataType,
     pub got_type: String,
+    pub got_value: Option<String>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error("invalid value: expected {expected}, got {got}")]
+pub struct InvalidValueError {
+    pub expected: String,
+    pub got: String,
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -147,6 +354,140 @@ pub struct MissingColumnError {
     pub column_name: &'static str,
 }
 
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error("{type_name} has no attribute stored in column {column_name} (table: {table_name})")]
+pub struct UnknownColumnError {
+    pub type_name: &'static str,
+    pub table_name: &'static str,
+    pub column_name: String,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error("table {table_name} already exists, possibly with a different schema")]
+pub struct TableExistsError {
+    pub table_name: String,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error("value of {column_name} is not unique (table: {table_name})")]
+pub struct UniqueViolationError {
+    pub table_name: String,
+    // names of the columns of the violated constraint, separated with ", "
+    pub column_name: String,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error(
+    "{message}{}",
+    .table_name.map(|table_name| format!(" (table: {})", table_name)).unwrap_or_default()
+)]
+pub struct ConstraintViolationError {
+    // SQLite's message, e.g. "CHECK constraint failed: price > 0"
+    pub message: String,
+    // the object being written, unknown for raw SQL
+    pub type_name: Option<&'static str>,
+    pub table_name: Option<&'static str>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error(
+    "foreign key constraint failed{}",
+    .table_name.map(|table_name| format!(" (table: {})", table_name)).unwrap_or_default()
+)]
+pub struct ForeignKeyViolationError {
+    // the object being written or deleted, unknown for raw SQL and for deferred keys checked
+    // at commit
+    pub type_name: Option<&'static str>,
+    pub table_name: Option<&'static str>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error("table {table_name} for {type_name} doesn't exist and automatic creation is disabled")]
+pub struct MissingTableError {
+    pub type_name: &'static str,
+    pub table_name: &'static str,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error("index {index_name} of table {table_name} for {type_name} doesn't exist")]
+pub struct MissingIndexError {
+    pub type_name: &'static str,
+    pub table_name: &'static str,
+    pub index_name: String,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error(
+    "can't add column {column_name} to the existing table {table_name} for {type_name}: {reason}"
+)]
+pub struct UnsupportedMigrationError {
+    pub type_name: &'static str,
+    pub table_name: &'static str,
+    pub column_name: &'static str,
+    pub reason: &'static str,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error(
+    "object was modified concurrently: type '{type_name}', id {object_id}, \
+    column {column_name} doesn't hold the expected value"
+)]
+pub struct ConcurrentModificationError {
+    pub object_id: ObjectId,
+    pub type_name: &'static str,
+    pub column_name: &'static str,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error("transaction exceeded the limit of {limit} changed objects, commit it in parts")]
+pub struct TransactionTooLargeError {
+    pub limit: usize,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error("object is borrowed: type '{type_name}', id {object_id}")]
+pub struct BorrowedError {
+    pub object_id: ObjectId,
+    pub type_name: &'static str,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Error, Debug)]
+#[error(
+    "storage error: {source}{}",
+    .sql.as_ref().map(|sql| format!(" (sql: {})", sql)).unwrap_or_default()
+)]
+pub struct StorageError {
+    #[source]
+    pub source: Box<dyn std::error::Error>,
+    pub sql: Option<String>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
 pub(crate) struct ErrorWithCtx<'a, E> {
     err: E,
     ctx: ErrorCtx<'a>,
@@ -162,6 +503,44 @@ impl<E> ErrorWithCtx<'_, E> {
 pub(crate) struct ErrorCtx<'a> {
     pub schema: Option<&'a Schema>,
     pub object_id: Option<ObjectId>,
+    pub got_value: Option<String>,
+    pub sql: Option<&'a str>,
+}
+
+// Extracts the column name from SQLite's "no such column: X" and "table T has no column named X"
+// messages. The name may be quoted and qualified with a table, and may be followed by more text.
+fn missing_column_name(text: &str) -> Option<&str> {
+    let rest = ["no such column: ", "has no column named "]
+        .iter()
+        .find_map(|marker| text.find(marker).map(|ind| &text[ind + marker.len()..]))?;
+
+    let token = rest.split_whitespace().next()?;
+    let name = token.trim_matches(|c| matches!(c, '"' | '`' | '[' | ']'));
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+// The field stored in `column_name`, either as is or without the table qualifier. Columns
+// which don't belong to the schema (e.g. referenced by a trigger) aren't attributed to it.
+fn find_field(schema: &Schema, column_name: &str) -> Option<Field> {
+    let unqualified = column_name
+        .rsplit_once('.')
+        .map_or(column_name, |(_, name)| name)
+        .trim_matches(|c| matches!(c, '"' | '`' | '[' | ']'));
+
+    if let Some(field) = schema
+        .field(column_name)
+        .or_else(|| schema.field(unqualified))
+    {
+        return Some(field.clone());
+    }
+
+    [column_name, unqualified]
+        .contains(&schema.id_column())
+        .then(|| Field::new("id", schema.id_column(), DataType::Int64))
 }
 
 fn get_field_by_name(schema: &Schema, column_name: &str) -> Field {
@@ -171,11 +550,7 @@ fn get_field_by_name(schema: &Schema, column_name: &str) -> Field {
         }
     }
 
-    Field {
-        attr_name: "id",
-        column_name: "id",
-        column_type: DataType::Int64,
-    }
+    Field::new("id", "id", DataType::Int64)
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -1,19 +1,26 @@
 #![forbid(unsafe_code)]
 mod connection;
+mod dialect;
 mod error;
+#[cfg(feature = "test-backend")]
+mod memory;
+mod query;
+mod relation;
 mod transaction;
 
 pub mod data;
 pub mod object;
 pub mod storage;
 
-pub use connection::Connection;
+pub use connection::{Connection, ConnectionBuilder, JournalMode, Synchronous};
 pub use data::ObjectId;
 pub use error::{Error, Result};
-pub use object::Object;
-pub use transaction::{ObjectState, Transaction, Tx};
+pub use object::{AfterLoad, Object};
+pub use query::{Nulls, Op, Order, Query, Scope, SelectOptions};
+pub use relation::BelongsTo;
+pub use transaction::{CommitStats, GraphChanges, ObjectState, Savepoint, Transaction, Tx};
 
 pub use data::AsDataType;
-pub use object::Schema;
+pub use object::{Schema, SchemaDiff, TypeMismatch};
 
-pub use orm_derive::Object;
+pub use orm_derive::{DbEnum, Object};
@@ -0,0 +1,1131 @@
+#![forbid(unsafe_code)]
+use crate::{
+    connection::{Settings, StorageConnection},
+    data::Value,
+    dialect::{Dialect, Sqlite},
+    error::{Error, Result, StorageError, TableExistsError, UniqueViolationError},
+    object::{Field, Schema},
+    query::{Nulls, Op, Order},
+    storage::{index_name, unique_index_name, IndexHint, Row, RowSlice, StorageTransaction},
+    ObjectId,
+};
+use std::{
+    cell::{Cell, RefCell},
+    cmp::Ordering,
+    collections::HashMap,
+    path::Path,
+};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// A storage keeping tables in memory, for tests which shouldn't touch disk. Objects can be
+// created, read, updated and deleted, and transactions and savepoints are honored. There is no
+// SQL engine behind it: the WHERE clauses built by `Transaction` and `Query` are evaluated by
+// `Filter`, but raw statements are not supported, and defaults and generated columns are not
+// computed.
+#[derive(Default)]
+pub(crate) struct MemoryConnection {
+    tables: Tables,
+    user_version: Cell<i32>,
+}
+
+type Tables = HashMap<String, Table>;
+
+#[derive(Clone, Default)]
+struct Table {
+    columns: Vec<&'static str>,
+    // ordered by id
+    rows: Vec<(ObjectId, Row<'static>)>,
+    last_id: i64,
+}
+
+impl Table {
+    fn position(&self, id: ObjectId) -> std::result::Result<usize, usize> {
+        self.rows
+            .binary_search_by_key(&id.into_i64(), |(id, _)| id.into_i64())
+    }
+
+    // Positions of the schema's fields among the table's columns.
+    fn layout(&self, schema: &Schema) -> Result<Vec<usize>> {
+        schema
+            .fields
+            .iter()
+            .map(|field| {
+                self.column(field.column_name)
+                    .ok_or_else(|| Error::missing_column(schema, field))
+            })
+            .collect()
+    }
+
+    fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| *column == name)
+    }
+
+    fn read(&self, schema: &Schema, row: &RowSlice<'static>) -> Result<Row<'static>> {
+        Ok(self
+            .layout(schema)?
+            .into_iter()
+            .map(|index| row[index].clone())
+            .collect())
+    }
+
+    // Fails if another row holds the same value in a unique column.
+    fn check_unique(&self, schema: &Schema, id: ObjectId, row: &RowSlice) -> Result<()> {
+        let unique = schema
+            .fields
+            .iter()
+            .enumerate()
+            .filter(|(_, field)| field.unique || schema.primary_key == Some(field.column_name));
+        for (i, field) in unique {
+            if row[i] == Value::Null {
+                continue;
+            }
+            let index = self.column(field.column_name).unwrap();
+            if self
+                .rows
+                .iter()
+                .any(|(other, stored)| *other != id && stored[index] == row[i])
+            {
+                return Err(Error::UniqueViolation(Box::new(UniqueViolationError {
+                    table_name: schema.table_name.to_string(),
+                    column_name: field.column_name.to_string(),
+                })));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl StorageConnection for MemoryConnection {
+    fn new_transaction(
+        &mut self,
+        _settings: &Settings,
+        database: Option<&str>,
+    ) -> Result<Box<dyn StorageTransaction + '_>> {
+        if database.is_some() {
+            return Err(unsupported("attached databases"));
+        }
+        Ok(Box::new(MemoryTransaction {
+            tables: RefCell::new(self.tables.clone()),
+            savepoints: RefCell::new(vec![]),
+            committed: RefCell::new(&mut self.tables),
+        }))
+    }
+
+    fn attach(&self, _path: &Path, _database: &str) -> Result<()> {
+        Err(unsupported("attached databases"))
+    }
+
+    fn detach(&self, _database: &str) -> Result<()> {
+        Err(unsupported("attached databases"))
+    }
+
+    fn user_version(&self) -> Result<i32> {
+        Ok(self.user_version.get())
+    }
+
+    fn set_user_version(&self, version: i32) -> Result<()> {
+        self.user_version.set(version);
+        Ok(())
+    }
+
+    fn supports_delete_limit(&self) -> bool {
+        false
+    }
+
+    // a transaction can't outlive the borrow of its connection
+    fn in_transaction(&self) -> bool {
+        false
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Works on a copy of the tables, which replaces the connection's ones at commit.
+struct MemoryTransaction<'a> {
+    tables: RefCell<Tables>,
+    savepoints: RefCell<Vec<(String, Tables)>>,
+    committed: RefCell<&'a mut Tables>,
+}
+
+impl MemoryTransaction<'_> {
+    fn with_table<R>(&self, schema: &Schema, f: impl FnOnce(&mut Table) -> Result<R>) -> Result<R> {
+        match self.tables.borrow_mut().get_mut(schema.table_name) {
+            Some(table) => f(table),
+            None => Err(Error::missing_table(schema)),
+        }
+    }
+
+    // Ids of the rows matching the clause, in its order.
+    fn matching(
+        &self,
+        schema: &Schema,
+        where_clause: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<ObjectId>> {
+        let filter = Filter::parse(where_clause, params)?;
+        self.with_table(schema, |table| filter.apply(schema, table))
+    }
+
+    fn savepoint_position(&self, name: &str) -> Result<usize> {
+        self.savepoints
+            .borrow()
+            .iter()
+            .rposition(|(savepoint, _)| savepoint == name)
+            .ok_or_else(|| storage_error(format!("no such savepoint: {}", name)))
+    }
+}
+
+impl StorageTransaction for MemoryTransaction<'_> {
+    // the types of `existing_columns` are those SQLite would declare
+    fn dialect(&self) -> &'static dyn Dialect {
+        &Sqlite
+    }
+
+    fn table_exists(&self, schema: &Schema) -> Result<bool> {
+        Ok(self.tables.borrow().contains_key(schema.table_name))
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        let mut tables = self.tables.borrow_mut();
+        if tables.contains_key(schema.table_name) {
+            return Err(Error::TableExists(Box::new(TableExistsError {
+                table_name: schema.table_name.to_string(),
+            })));
+        }
+        let table = Table {
+            columns: schema.column_names().collect(),
+            ..Default::default()
+        };
+        tables.insert(schema.table_name.to_string(), table);
+        Ok(())
+    }
+
+    // columns are only ever added from fields, so their types are those of the fields
+    fn existing_columns(&self, schema: &Schema) -> Result<Vec<(String, String)>> {
+        self.with_table(schema, |table| {
+            let id = match schema.primary_key {
+                Some(_) => None,
+                None => Some((schema.id_column(), Sqlite.id_type())),
+            };
+            let columns = table.columns.iter().map(|column| {
+                let column_type = schema
+                    .field(column)
+                    .map_or("", |field| schema.column_type(&Sqlite, field));
+                (*column, column_type)
+            });
+            Ok(id
+                .into_iter()
+                .chain(columns)
+                .map(|(column, column_type)| (column.to_string(), column_type.to_string()))
+                .collect())
+        })
+    }
+
+    fn add_column(&self, schema: &Schema, field: &Field) -> Result<()> {
+        self.with_table(schema, |table| {
+            table.columns.push(field.column_name);
+            for (_, row) in table.rows.iter_mut() {
+                row.push(Value::Null);
+            }
+            Ok(())
+        })
+    }
+
+    fn create_indexes(&self, _schema: &Schema) -> Result<()> {
+        Ok(())
+    }
+
+    // there are no indexes in memory, so the ones SQLite would have are assumed to exist
+    fn index_exists(&self, schema: &Schema, index: &str) -> Result<bool> {
+        self.with_table(schema, |_| {
+            Ok(schema.fields.iter().any(|field| {
+                (field.indexed && index == index_name(schema.table_name, field.column_name))
+                    || (field.unique
+                        && index == unique_index_name(schema.table_name, field.column_name))
+            }))
+        })
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        self.tables.borrow_mut().remove(schema.table_name);
+        Ok(())
+    }
+
+    fn insert_row(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+    ) -> Result<(ObjectId, Option<Row<'static>>)> {
+        self.with_table(schema, |table| {
+            let key = schema.primary_key.map(|key| {
+                schema
+                    .column_names()
+                    .position(|column| column == key)
+                    .expect("primary key should be one of the fields")
+            });
+            let id = match key.map(|index| &row[index]) {
+                Some(Value::Int64(id)) => ObjectId::from(*id),
+                Some(_) => return Err(unsupported("primary keys other than integers")),
+                None => ObjectId::from(table.last_id + 1),
+            };
+            table.check_unique(schema, id, row)?;
+            let position = match table.position(id) {
+                Ok(_) => {
+                    return Err(Error::UniqueViolation(Box::new(UniqueViolationError {
+                        table_name: schema.table_name.to_string(),
+                        column_name: schema.id_column().to_string(),
+                    })))
+                }
+                Err(position) => position,
+            };
+
+            let layout = table.layout(schema)?;
+            let mut stored = vec![Value::Null; table.columns.len()];
+            for (value, index) in row.iter().zip(layout) {
+                stored[index] = value.clone().into_owned();
+            }
+            table.rows.insert(position, (id, stored));
+            table.last_id = table.last_id.max(id.into_i64());
+
+            let returned = match schema.has_managed_fields() {
+                true => Some(row.iter().map(|value| value.clone().into_owned()).collect()),
+                false => None,
+            };
+            Ok((id, returned))
+        })
+    }
+
+    fn insert_rows(
+        &self,
+        schema: &Schema,
+        rows: &[Row],
+    ) -> Result<Vec<(ObjectId, Option<Row<'static>>)>> {
+        rows.iter()
+            .map(|row| self.insert_row(schema, row))
+            .collect()
+    }
+
+    fn upsert_row(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        conflict_column: &str,
+    ) -> Result<(ObjectId, Row<'static>)> {
+        let conflict_index = schema
+            .column_names()
+            .position(|column| column == conflict_column)
+            .expect("conflict column should belong to the schema");
+        let existing = self.with_table(schema, |table| {
+            let index = table
+                .column(conflict_column)
+                .ok_or_else(|| Error::missing_column(schema, &schema.fields[conflict_index]))?;
+            Ok(table
+                .rows
+                .iter()
+                .find(|(_, stored)| stored[index] == row[conflict_index])
+                .map(|(id, _)| *id))
+        })?;
+
+        let id = match existing {
+            // an overwritten row keeps the time it was created at
+            Some(id) => {
+                let stored = self.select_row(id, schema)?;
+                let row = schema
+                    .fields
+                    .iter()
+                    .zip(row.iter().zip(stored))
+                    .map(|(field, (value, stored))| match field.stamp {
+                        Some(stamp) if !stamp.on_update => stored,
+                        _ => value.clone(),
+                    })
+                    .collect::<Row>();
+                self.update_row(id, schema, &row, None)?;
+                id
+            }
+            None => self.insert_row(schema, row)?.0,
+        };
+        Ok((id, self.select_row(id, schema)?))
+    }
+
+    fn upsert_rows(
+        &self,
+        schema: &Schema,
+        rows: &[Row],
+        conflict_column: &str,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>> {
+        rows.iter()
+            .map(|row| self.upsert_row(schema, row, conflict_column))
+            .collect()
+    }
+
+    fn update_row(
+        &self,
+        id: ObjectId,
+        schema: &Schema,
+        row: &RowSlice,
+        condition: Option<(&str, &Value)>,
+    ) -> Result<usize> {
+        self.with_table(schema, |table| {
+            table.check_unique(schema, id, row)?;
+            let layout = table.layout(schema)?;
+            let condition = match condition {
+                Some((column, expected)) => Some((
+                    table
+                        .column(column)
+                        .ok_or_else(|| unsupported("conditions on unknown columns"))?,
+                    expected,
+                )),
+                None => None,
+            };
+            let stored = match table.position(id) {
+                Ok(position) => &mut table.rows[position].1,
+                Err(_) => return Ok(0),
+            };
+            if let Some((index, expected)) = condition {
+                if stored[index] != *expected {
+                    return Ok(0);
+                }
+            }
+            let version = match schema.version_index() {
+                Some(index) if stored[layout[index]] != row[index] => return Ok(0),
+                Some(index) => match row[index] {
+                    Value::Int64(version) => Some((layout[index], version + 1)),
+                    _ => return Err(unsupported("versions other than integers")),
+                },
+                None => None,
+            };
+
+            let written = schema
+                .fields
+                .iter()
+                .zip(row.iter().zip(layout))
+                .filter(|(field, _)| {
+                    field.generated.is_none() && field.column_name != schema.id_column()
+                });
+            for (_, (value, index)) in written {
+                stored[index] = value.clone().into_owned();
+            }
+            if let Some((index, version)) = version {
+                stored[index] = Value::Int64(version);
+            }
+            Ok(1)
+        })
+    }
+
+    fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>> {
+        self.with_table(schema, |table| match table.position(id) {
+            Ok(position) => table.read(schema, &table.rows[position].1),
+            Err(_) => Err(Error::not_found(id, schema.type_name)),
+        })
+    }
+
+    fn row_exists(&self, id: ObjectId, schema: &Schema) -> Result<bool> {
+        self.with_table(schema, |table| Ok(table.position(id).is_ok()))
+    }
+
+    fn select_values(
+        &self,
+        id: ObjectId,
+        schema: &Schema,
+        fields: &[&Field],
+    ) -> Result<Row<'static>> {
+        self.with_table(schema, |table| {
+            let stored = match table.position(id) {
+                Ok(position) => &table.rows[position].1,
+                Err(_) => return Err(Error::not_found(id, schema.type_name)),
+            };
+            fields
+                .iter()
+                .map(|field| match table.column(field.column_name) {
+                    Some(index) => Ok(stored[index].clone()),
+                    None => Err(Error::missing_column(schema, field)),
+                })
+                .collect()
+        })
+    }
+
+    fn select_rows(
+        &self,
+        schema: &Schema,
+        where_clause: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>> {
+        let mut res = vec![];
+        self.for_each_row(schema, None, where_clause, params, &mut |id, row| {
+            res.push((id, row));
+            Ok(())
+        })?;
+        Ok(res)
+    }
+
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        _index: Option<&IndexHint>,
+        where_clause: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        let ids = self.matching(schema, where_clause, params)?;
+        // rows are copied first, so that `f` may use the transaction
+        let rows = self.with_table(schema, |table| {
+            ids.into_iter()
+                .map(|id| {
+                    let position = table.position(id).unwrap();
+                    Ok((id, table.read(schema, &table.rows[position].1)?))
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+        for (id, row) in rows {
+            f(id, row)?;
+        }
+        Ok(())
+    }
+
+    fn count_rows(
+        &self,
+        schema: &Schema,
+        _index: Option<&IndexHint>,
+        where_clause: &str,
+        params: &RowSlice,
+    ) -> Result<u64> {
+        Ok(self.matching(schema, where_clause, params)?.len() as u64)
+    }
+
+    fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
+        self.with_table(schema, |table| {
+            if let Ok(position) = table.position(id) {
+                table.rows.remove(position);
+            }
+            Ok(())
+        })
+    }
+
+    fn delete_rows(
+        &self,
+        schema: &Schema,
+        where_clause: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<ObjectId>> {
+        let ids = self.matching(schema, where_clause, params)?;
+        for id in ids.iter() {
+            self.delete_row(*id, schema)?;
+        }
+        Ok(ids)
+    }
+
+    fn delete_rows_limited(
+        &self,
+        schema: &Schema,
+        where_clause: &str,
+        params: &RowSlice,
+        limit: u64,
+        _native_limit: bool,
+    ) -> Result<Vec<ObjectId>> {
+        let mut ids = self.matching(schema, where_clause, params)?;
+        ids.truncate(limit as usize);
+        for id in ids.iter() {
+            self.delete_row(*id, schema)?;
+        }
+        Ok(ids)
+    }
+
+    fn execute_raw(&self, _sql: &str, _params: &RowSlice) -> Result<usize> {
+        Err(unsupported("raw SQL"))
+    }
+
+    // its rows are `rusqlite::Row`s, which only SQLite can produce
+    fn query_raw(
+        &self,
+        _sql: &str,
+        _params: &RowSlice,
+        _f: &mut dyn FnMut(&rusqlite::Row) -> Result<()>,
+    ) -> Result<()> {
+        Err(unsupported("raw SQL"))
+    }
+
+    fn commit(&self) -> Result<()> {
+        **self.committed.borrow_mut() = self.tables.borrow().clone();
+        self.savepoints.borrow_mut().clear();
+        Ok(())
+    }
+
+    fn rollback(&self) -> Result<()> {
+        *self.tables.borrow_mut() = self.committed.borrow().clone();
+        self.savepoints.borrow_mut().clear();
+        Ok(())
+    }
+
+    fn begin(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn savepoint(&self, name: &str) -> Result<()> {
+        let tables = self.tables.borrow().clone();
+        self.savepoints
+            .borrow_mut()
+            .push((name.to_string(), tables));
+        Ok(())
+    }
+
+    fn release(&self, name: &str) -> Result<()> {
+        let position = self.savepoint_position(name)?;
+        self.savepoints.borrow_mut().truncate(position);
+        Ok(())
+    }
+
+    fn rollback_to(&self, name: &str) -> Result<()> {
+        let position = self.savepoint_position(name)?;
+        let mut savepoints = self.savepoints.borrow_mut();
+        savepoints.truncate(position + 1);
+        *self.tables.borrow_mut() = savepoints[position].1.clone();
+        Ok(())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// A WHERE clause as `Transaction` and `Query` build it: conditions joined with AND and OR,
+// followed by ORDER BY, LIMIT and OFFSET. Conditions compare columns, parameters and literals
+// with `=`, `<>`, `<`, `<=`, `>`, `>=` and LIKE, or test them with `IS [NOT] NULL` and `IN`.
+// Anything else is refused rather than guessed at.
+struct Filter {
+    condition: Expr,
+    order: Vec<(Expr, Order, Option<Nulls>)>,
+    limit: Option<Expr>,
+    offset: Option<Expr>,
+}
+
+enum Expr {
+    Column(String),
+    Value(Value<'static>),
+    Compare(Box<Expr>, Op, Box<Expr>),
+    IsNull(Box<Expr>, bool),
+    In(Box<Expr>, Vec<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+impl Filter {
+    fn parse(sql: &str, params: &RowSlice) -> Result<Filter> {
+        let mut parser = Parser {
+            sql,
+            tokens: tokenize(sql)?,
+            position: 0,
+            params,
+            next_param: 0,
+        };
+        let filter = parser.filter()?;
+        if parser.next_param != params.len() {
+            return Err(storage_error(format!(
+                "{} parameters given to `{}`, which takes {}",
+                params.len(),
+                sql,
+                parser.next_param
+            )));
+        }
+        Ok(filter)
+    }
+
+    // Ids of the matching rows, ordered, then limited.
+    fn apply(&self, schema: &Schema, table: &Table) -> Result<Vec<ObjectId>> {
+        let mut matched = vec![];
+        for (id, stored) in table.rows.iter() {
+            let column = |name: &str| column_value(schema, table, *id, stored, name);
+            if truth(&self.condition.eval(&column)?) == Some(true) {
+                let keys = self
+                    .order
+                    .iter()
+                    .map(|(expr, _, _)| expr.eval(&column))
+                    .collect::<Result<Vec<_>>>()?;
+                matched.push((*id, keys));
+            }
+        }
+
+        // the sort is stable, so rows equal in every key stay ordered by id
+        matched.sort_by(|(_, a), (_, b)| {
+            a.iter()
+                .zip(b)
+                .zip(&self.order)
+                .map(|((a, b), (_, order, nulls))| sort_order(a, b, *order, *nulls))
+                .find(|ordering| *ordering != Ordering::Equal)
+                .unwrap_or(Ordering::Equal)
+        });
+
+        let offset = match &self.offset {
+            Some(offset) => integer(offset)?.max(0) as usize,
+            None => 0,
+        };
+        let ids = matched.into_iter().map(|(id, _)| id).skip(offset);
+        // a negative limit means "no limit"
+        Ok(match &self.limit {
+            Some(limit) if integer(limit)? >= 0 => ids.take(integer(limit)? as usize).collect(),
+            _ => ids.collect(),
+        })
+    }
+}
+
+impl Expr {
+    // Conditions evaluate to `Bool`, or to `Null` when SQL leaves them unknown.
+    fn eval(&self, column: &dyn Fn(&str) -> Result<Value<'static>>) -> Result<Value<'static>> {
+        Ok(match self {
+            Expr::Column(name) => column(name)?,
+            Expr::Value(value) => value.clone(),
+            Expr::Compare(left, op, right) => {
+                let (left, right) = (left.eval(column)?, right.eval(column)?);
+                if left == Value::Null || right == Value::Null {
+                    return Ok(Value::Null);
+                }
+                let ordering = compare(&left, &right);
+                Value::Bool(match op {
+                    Op::Eq => ordering == Ordering::Equal,
+                    Op::Ne => ordering != Ordering::Equal,
+                    Op::Lt => ordering == Ordering::Less,
+                    Op::Le => ordering != Ordering::Greater,
+                    Op::Gt => ordering == Ordering::Greater,
+                    Op::Ge => ordering != Ordering::Less,
+                    Op::Like => like(&text(&left), &text(&right)),
+                })
+            }
+            Expr::IsNull(expr, negated) => {
+                Value::Bool((expr.eval(column)? == Value::Null) != *negated)
+            }
+            Expr::In(expr, list) => {
+                let value = expr.eval(column)?;
+                if value == Value::Null {
+                    return Ok(Value::Null);
+                }
+                let mut res = Value::Bool(false);
+                for item in list {
+                    match item.eval(column)? {
+                        Value::Null => res = Value::Null,
+                        item if compare(&value, &item) == Ordering::Equal => {
+                            return Ok(Value::Bool(true))
+                        }
+                        _ => {}
+                    }
+                }
+                res
+            }
+            Expr::And(left, right) => {
+                match (truth(&left.eval(column)?), truth(&right.eval(column)?)) {
+                    (Some(false), _) | (_, Some(false)) => Value::Bool(false),
+                    (Some(true), Some(true)) => Value::Bool(true),
+                    _ => Value::Null,
+                }
+            }
+            Expr::Or(left, right) => {
+                match (truth(&left.eval(column)?), truth(&right.eval(column)?)) {
+                    (Some(true), _) | (_, Some(true)) => Value::Bool(true),
+                    (Some(false), Some(false)) => Value::Bool(false),
+                    _ => Value::Null,
+                }
+            }
+        })
+    }
+}
+
+fn column_value(
+    schema: &Schema,
+    table: &Table,
+    id: ObjectId,
+    stored: &RowSlice<'static>,
+    name: &str,
+) -> Result<Value<'static>> {
+    if name == schema.id_column() && schema.primary_key.is_none() {
+        return Ok(Value::Int64(id.into_i64()));
+    }
+    match table.column(name) {
+        Some(index) => Ok(stored[index].clone()),
+        None => Err(storage_error(format!("no such column: {}", name))),
+    }
+}
+
+// LIMIT and OFFSET take no columns.
+fn integer(expr: &Expr) -> Result<i64> {
+    let no_columns = |name: &str| Err(storage_error(format!("no such column: {}", name)));
+    match expr.eval(&no_columns)? {
+        Value::Int64(value) => Ok(value),
+        _ => Err(storage_error(
+            "LIMIT and OFFSET should be integers".to_string(),
+        )),
+    }
+}
+
+fn truth(value: &Value) -> Option<bool> {
+    match value {
+        Value::Null => None,
+        Value::Bool(value) => Some(*value),
+        Value::Int64(value) => Some(*value != 0),
+        Value::Float64(value) => Some(*value != 0.0),
+        _ => Some(false),
+    }
+}
+
+// Values of different types are ordered as SQLite does: NULLs, then numbers, text and blobs.
+fn compare(a: &Value, b: &Value) -> Ordering {
+    fn number(value: &Value) -> Option<f64> {
+        match value {
+            Value::Bool(value) => Some(*value as i64 as f64),
+            Value::Int64(value) => Some(*value as f64),
+            Value::Float64(value) => Some(*value),
+            _ => None,
+        }
+    }
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(_) | Value::Int64(_) | Value::Float64(_) => 1,
+            Value::String(_) => 2,
+            Value::Bytes(_) => 3,
+        }
+    }
+
+    match (a, b) {
+        (Value::Int64(a), Value::Int64(b)) => a.cmp(b),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Bytes(a), Value::Bytes(b)) => a.cmp(b),
+        _ => match (number(a), number(b)) {
+            (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+            _ => rank(a).cmp(&rank(b)),
+        },
+    }
+}
+
+fn sort_order(a: &Value, b: &Value, order: Order, nulls: Option<Nulls>) -> Ordering {
+    match (a == &Value::Null, b == &Value::Null, nulls) {
+        (true, true, _) => Ordering::Equal,
+        (true, false, Some(Nulls::First)) | (false, true, Some(Nulls::Last)) => Ordering::Less,
+        (true, false, Some(Nulls::Last)) | (false, true, Some(Nulls::First)) => Ordering::Greater,
+        // otherwise NULLs are the smallest values, as in `compare`
+        _ => match order {
+            Order::Asc => compare(a, b),
+            Order::Desc => compare(a, b).reverse(),
+        },
+    }
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Bool(value) => (*value as i64).to_string(),
+        Value::Int64(value) => value.to_string(),
+        Value::Float64(value) => value.to_string(),
+        Value::String(value) => value.to_string(),
+        Value::Bytes(value) => String::from_utf8_lossy(value).into_owned(),
+    }
+}
+
+// SQLite's LIKE: `%` matches any sequence, `_` any character, and ASCII letters match
+// regardless of case.
+fn like(text: &str, pattern: &str) -> bool {
+    let text = text
+        .chars()
+        .map(|c| c.to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    // `matches[i]` tells whether the pattern read so far matches the first `i` characters
+    let mut matches = vec![false; text.len() + 1];
+    matches[0] = true;
+    for p in pattern.chars().map(|c| c.to_ascii_lowercase()) {
+        let mut next = vec![false; text.len() + 1];
+        match p {
+            '%' => {
+                let mut any = false;
+                for (next, matched) in next.iter_mut().zip(&matches) {
+                    any |= *matched;
+                    *next = any;
+                }
+            }
+            _ => {
+                for i in 1..=text.len() {
+                    next[i] = matches[i - 1] && (p == '_' || text[i - 1] == p);
+                }
+            }
+        }
+        matches = next;
+    }
+    matches[text.len()]
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, PartialEq)]
+enum Token {
+    // a keyword or a bare identifier
+    Word(String),
+    // an identifier in double quotes
+    Quoted(String),
+    Literal(Value<'static>),
+    // a parameter, with its number if it is given
+    Param(Option<usize>),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 11] = ["<>", "!=", "<=", ">=", "==", "=", "<", ">", "(", ")", ","];
+
+fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut rest = sql;
+    while let Some(c) = rest.chars().next() {
+        let taken = |f: fn(char) -> bool| rest.find(|c| !f(c)).unwrap_or(rest.len());
+        let len =
+            match c {
+                _ if c.is_whitespace() => c.len_utf8(),
+                '?' => {
+                    let len = 1 + rest[1..]
+                        .find(|c: char| !c.is_ascii_digit())
+                        .unwrap_or(rest.len() - 1);
+                    tokens.push(Token::Param(rest[1..len].parse().ok()));
+                    len
+                }
+                '"' | '\'' => {
+                    let mut quoted = String::new();
+                    let mut chars = rest.char_indices().skip(1).peekable();
+                    let len = loop {
+                        match chars.next() {
+                            Some((_, q))
+                                if q == c && chars.peek().map(|(_, next)| *next) == Some(c) =>
+                            {
+                                chars.next();
+                                quoted.push(c);
+                            }
+                            Some((i, q)) if q == c => break i + 1,
+                            Some((_, q)) => quoted.push(q),
+                            None => return Err(unsupported(&format!("the clause `{}`", sql))),
+                        }
+                    };
+                    tokens.push(match c {
+                        '"' => Token::Quoted(quoted),
+                        _ => Token::Literal(Value::String(quoted.into())),
+                    });
+                    len
+                }
+                _ if c.is_ascii_digit() => {
+                    let len = taken(|c| c.is_ascii_digit() || c == '.');
+                    let number = &rest[..len];
+                    tokens.push(Token::Literal(match number.parse() {
+                        Ok(value) => Value::Int64(value),
+                        Err(_) => Value::Float64(number.parse().map_err(|_| {
+                            storage_error(format!("malformed number in `{}`", sql))
+                        })?),
+                    }));
+                    len
+                }
+                _ if c.is_alphabetic() || c == '_' => {
+                    let len = taken(|c| c.is_alphanumeric() || c == '_');
+                    tokens.push(Token::Word(rest[..len].to_string()));
+                    len
+                }
+                _ => match SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+                    Some(symbol) => {
+                        tokens.push(Token::Symbol(symbol));
+                        symbol.len()
+                    }
+                    None => return Err(unsupported(&format!("the clause `{}`", sql))),
+                },
+            };
+        rest = &rest[len..];
+    }
+    Ok(tokens)
+}
+
+struct Parser<'s, 'p> {
+    sql: &'s str,
+    tokens: Vec<Token>,
+    position: usize,
+    params: &'s RowSlice<'p>,
+    next_param: usize,
+}
+
+impl Parser<'_, '_> {
+    fn filter(&mut self) -> Result<Filter> {
+        let condition = self.expr()?;
+        let mut order = vec![];
+        if self.eat_word("ORDER") {
+            self.expect_word("BY")?;
+            loop {
+                let key = self.operand()?;
+                let direction = match self.eat_word("DESC") {
+                    true => Order::Desc,
+                    false => {
+                        self.eat_word("ASC");
+                        Order::Asc
+                    }
+                };
+                let nulls = match self.eat_word("NULLS") {
+                    true if self.eat_word("FIRST") => Some(Nulls::First),
+                    true => {
+                        self.expect_word("LAST")?;
+                        Some(Nulls::Last)
+                    }
+                    false => None,
+                };
+                order.push((key, direction, nulls));
+                if !self.eat(&Token::Symbol(",")) {
+                    break;
+                }
+            }
+        }
+        let (mut limit, mut offset) = (None, None);
+        if self.eat_word("LIMIT") {
+            limit = Some(self.operand()?);
+            if self.eat_word("OFFSET") {
+                offset = Some(self.operand()?);
+            }
+        }
+        match self.position == self.tokens.len() {
+            true => Ok(Filter {
+                condition,
+                order,
+                limit,
+                offset,
+            }),
+            false => Err(self.unexpected()),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let mut expr = self.conjunction()?;
+        while self.eat_word("OR") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.conjunction()?));
+        }
+        Ok(expr)
+    }
+
+    fn conjunction(&mut self) -> Result<Expr> {
+        let mut expr = self.predicate()?;
+        while self.eat_word("AND") {
+            expr = Expr::And(Box::new(expr), Box::new(self.predicate()?));
+        }
+        Ok(expr)
+    }
+
+    fn predicate(&mut self) -> Result<Expr> {
+        if self.eat(&Token::Symbol("(")) {
+            let expr = self.expr()?;
+            self.expect(&Token::Symbol(")"))?;
+            return Ok(expr);
+        }
+
+        let left = self.operand()?;
+        if self.eat_word("IS") {
+            let negated = self.eat_word("NOT");
+            self.expect_word("NULL")?;
+            return Ok(Expr::IsNull(Box::new(left), negated));
+        }
+        if self.eat_word("IN") {
+            self.expect(&Token::Symbol("("))?;
+            let mut list = vec![self.operand()?];
+            while self.eat(&Token::Symbol(",")) {
+                list.push(self.operand()?);
+            }
+            self.expect(&Token::Symbol(")"))?;
+            return Ok(Expr::In(Box::new(left), list));
+        }
+        let op = match self.tokens.get(self.position) {
+            Some(Token::Symbol("=" | "==")) => Op::Eq,
+            Some(Token::Symbol("<>" | "!=")) => Op::Ne,
+            Some(Token::Symbol("<")) => Op::Lt,
+            Some(Token::Symbol("<=")) => Op::Le,
+            Some(Token::Symbol(">")) => Op::Gt,
+            Some(Token::Symbol(">=")) => Op::Ge,
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("LIKE") => Op::Like,
+            // a lone operand, such as `1`, is a condition by itself
+            _ => return Ok(left),
+        };
+        self.position += 1;
+        Ok(Expr::Compare(Box::new(left), op, Box::new(self.operand()?)))
+    }
+
+    fn operand(&mut self) -> Result<Expr> {
+        let expr = match self.tokens.get(self.position) {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("NULL") => {
+                Expr::Value(Value::Null)
+            }
+            Some(Token::Word(word)) if !is_keyword(word) => Expr::Column(word.clone()),
+            Some(Token::Quoted(name)) => Expr::Column(name.clone()),
+            Some(Token::Literal(value)) => Expr::Value(value.clone()),
+            Some(Token::Param(number)) => {
+                let index = number.map_or(self.next_param, |number| number.saturating_sub(1));
+                self.next_param = self.next_param.max(index + 1);
+                match self.params.get(index) {
+                    Some(value) => Expr::Value(value.clone().into_owned()),
+                    None => {
+                        return Err(storage_error(format!(
+                            "{} parameters given to `{}`, which takes more",
+                            self.params.len(),
+                            self.sql
+                        )))
+                    }
+                }
+            }
+            _ => return Err(self.unexpected()),
+        };
+        self.position += 1;
+        Ok(expr)
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.tokens.get(self.position) == Some(token);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn eat_word(&mut self, keyword: &str) -> bool {
+        let found = matches!(
+            self.tokens.get(self.position),
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword)
+        );
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, token: &Token) -> Result<()> {
+        match self.eat(token) {
+            true => Ok(()),
+            false => Err(self.unexpected()),
+        }
+    }
+
+    fn expect_word(&mut self, keyword: &str) -> Result<()> {
+        match self.eat_word(keyword) {
+            true => Ok(()),
+            false => Err(self.unexpected()),
+        }
+    }
+
+    fn unexpected(&self) -> Error {
+        unsupported(&format!("the clause `{}`", self.sql))
+    }
+}
+
+fn is_keyword(word: &str) -> bool {
+    [
+        "AND", "OR", "NOT", "IS", "IN", "LIKE", "ORDER", "BY", "ASC", "DESC", "NULLS", "LIMIT",
+        "OFFSET",
+    ]
+    .iter()
+    .any(|keyword| word.eq_ignore_ascii_case(keyword))
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn unsupported(what: &str) -> Error {
+    storage_error(format!(
+        "{} is not supported by the in-memory storage",
+        what
+    ))
+}
+
+fn storage_error(message: String) -> Error {
+    Error::Storage(Box::new(StorageError {
+        source: message.into(),
+        sql: None,
+    }))
+}
@@ -1,20 +1,56 @@
 #![forbid(unsafe_code)]
-use crate::{data::DataType, storage::Row};
-use std::any::Any;
+use crate::{
+    data::{DataType, Value},
+    dialect::{Dialect, Sqlite},
+    error::Result,
+    storage::Row,
+};
+use std::{any::Any, time::SystemTime};
 
 ////////////////////////////////////////////////////////////////////////////////
 
 pub trait Object: Any + Sized {
-    fn as_row(&self) -> Row;
-    fn from_row(row: Row) -> Self;
+    fn as_row(&self) -> Row<'_>;
+    // Fails if a stored value can't be converted into the field, e.g. after the table was
+    // changed outside of the ORM.
+    fn from_row(row: Row) -> Result<Self>;
+
+    // Overwrites the stored fields with the row like `from_row`, but keeps `#[skip]`-ped ones as
+    // they are. Derived objects do so, others are just rebuilt with `from_row`.
+    fn update_from_row(&mut self, row: Row) -> Result<()> {
+        *self = Self::from_row(row)?;
+        Ok(())
+    }
+
+    // Overwrites only the field stored in the `index`-th column of the row.
+    fn set_column(&mut self, index: usize, value: Value) -> Result<()> {
+        let mut row = self
+            .as_row()
+            .into_iter()
+            .map(Value::into_owned)
+            .collect::<Vec<_>>();
+        row[index] = value.into_owned();
+        self.update_from_row(row)
+    }
 
     const SCHEMA: Schema;
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
+// Hook for filling `#[skip]`-ped or computed fields once an object is loaded. It is called from
+// `from_row` of types deriving `Object` with `#[after_load]` attribute.
+pub trait AfterLoad {
+    fn after_load(&mut self) {}
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
 pub trait Store: Any {
-    fn as_row(&self) -> Row;
+    fn as_row(&self) -> Row<'_>;
+    // Both keep `#[skip]`-ped fields, see `Object::update_from_row`.
+    fn set_row(&mut self, row: Row) -> Result<()>;
+    fn set_column(&mut self, index: usize, value: Value) -> Result<()>;
     fn schema(&self) -> &Schema;
 
     fn as_any(&self) -> &dyn Any;
@@ -22,10 +58,18 @@ pub trait Store: Any {
 }
 
 impl<T: Object> Store for T {
-    fn as_row(&self) -> Row {
+    fn as_row(&self) -> Row<'_> {
         self.as_row()
     }
 
+    fn set_row(&mut self, row: Row) -> Result<()> {
+        self.update_from_row(row)
+    }
+
+    fn set_column(&mut self, index: usize, value: Value) -> Result<()> {
+        Object::set_column(self, index, value)
+    }
+
     fn schema(&self) -> &Schema {
         &Self::SCHEMA
     }
@@ -45,11 +89,39 @@ pub struct Schema {
     pub type_name: &'static str,
     pub table_name: &'static str,
 
+    // developer-authored trigger definitions (everything after `CREATE TRIGGER <name>`),
+    // executed verbatim when the table is created
+    pub triggers: &'static [&'static str],
+
+    // temporary tables live only as long as the connection that created them
+    pub temporary: bool,
+
+    // column holding a human-readable name of the object, marked with `#[label]`
+    pub label: Option<&'static str>,
+
+    // integer field marked with `#[primary_key]`, which is used as the object's id instead of
+    // a synthetic `id` column
+    pub primary_key: Option<&'static str>,
+
+    // integer field marked with `#[version]`, which is incremented by every update and guards
+    // it against overwriting a concurrent one
+    pub version: Option<&'static str>,
+
     // static, because list is created at compile-time by derive macro
     pub fields: &'static [Field],
 }
 
 impl Schema {
+    // The column holding the object's id.
+    pub fn id_column(&self) -> &'static str {
+        self.primary_key.unwrap_or("id")
+    }
+
+    pub fn version_index(&self) -> Option<usize> {
+        self.version
+            .and_then(|version| self.column_names().position(|column| column == version))
+    }
+
     pub fn column_types(&self) -> impl Iterator<Item = DataType> {
         self.fields.iter().map(|field| field.column_type)
     }
@@ -57,6 +129,178 @@ impl Schema {
     pub fn column_names(&self) -> impl Iterator<Item = &'static str> {
         self.fields.iter().map(|field| field.column_name)
     }
+
+    pub fn field(&self, column_name: &str) -> Option<&'static Field> {
+        self.fields
+            .iter()
+            .find(|field| field.column_name == column_name)
+    }
+
+    pub fn indexed_fields(&self) -> impl Iterator<Item = &'static Field> {
+        self.fields.iter().filter(|field| field.indexed)
+    }
+
+    pub fn has_managed_fields(&self) -> bool {
+        self.fields.iter().any(|field| field.managed)
+    }
+
+    // Sets the `#[updated_at]` fields of the row of an update to `now`. Returns whether there
+    // were any. The fields of new objects are set by `Transaction` on the objects themselves.
+    pub(crate) fn stamp(&self, row: &mut Row, now: SystemTime) -> bool {
+        let mut stamped = false;
+        for (field, value) in self.fields.iter().zip(row.iter_mut()) {
+            match field.stamp {
+                Some(stamp) if stamp.on_update => {
+                    *value = (stamp.value)(now);
+                    stamped = true;
+                }
+                _ => (),
+            }
+        }
+        stamped
+    }
+
+    // The CREATE TABLE statement issued for the type, e.g. to print or compare schemas without
+    // a connection. Triggers and indexes are created by separate statements.
+    pub fn to_create_sql(&self) -> String {
+        self.create_sql(&Sqlite, self.table_name, "")
+    }
+
+    // `table` is the name to create, qualified with its database if needed, `prefix` is the one
+    // of the connection, which referenced tables have as well.
+    pub(crate) fn create_sql(&self, dialect: &dyn Dialect, table: &str, prefix: &str) -> String {
+        let synthetic_id = match self.primary_key {
+            Some(_) => None,
+            None => Some(dialect.id_column(self.id_column())),
+        };
+        let columns = synthetic_id
+            .into_iter()
+            .chain(
+                self.fields
+                    .iter()
+                    .map(|field| self.column_definition(dialect, field, prefix)),
+            )
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        format!(
+            "CREATE {}TABLE {} ({})",
+            if self.temporary { "TEMPORARY " } else { "" },
+            table,
+            columns
+        )
+    }
+
+    // The type the field's column is declared with.
+    pub(crate) fn column_type(&self, dialect: &dyn Dialect, field: &Field) -> &'static str {
+        match self.primary_key == Some(field.column_name) {
+            true => dialect.id_type(),
+            false => dialect.column_type(field.column_type),
+        }
+    }
+
+    // The definition of the field's column in CREATE TABLE or ALTER TABLE ... ADD COLUMN.
+    pub(crate) fn column_definition(
+        &self,
+        dialect: &dyn Dialect,
+        field: &Field,
+        prefix: &str,
+    ) -> String {
+        let mut column = match self.primary_key == Some(field.column_name) {
+            true => format!("{} {}", field.column_name, dialect.primary_key_clause()),
+            false => format!(
+                "{} {}",
+                field.column_name,
+                dialect.column_type(field.column_type)
+            ),
+        };
+        if field.unique {
+            column = format!("{} UNIQUE", column);
+        }
+        if let Some(default) = field.default {
+            column = format!("{} DEFAULT {}", column, default);
+        }
+        if let Some(references) = field.references {
+            let parent = references();
+            column = format!(
+                "{} REFERENCES {}{}({})",
+                column,
+                prefix,
+                parent.table_name,
+                parent.id_column()
+            );
+        }
+        if let Some(expr) = field.generated {
+            column = format!(
+                "{} GENERATED ALWAYS AS ({}) {}",
+                column,
+                expr,
+                if field.generated_stored {
+                    "STORED"
+                } else {
+                    "VIRTUAL"
+                }
+            );
+        }
+        column
+    }
+
+    // Why SQLite can't add the column of `field` to an existing table, if it can't. UNIQUE
+    // columns are added without the constraint and get a unique index instead.
+    pub(crate) fn unsupported_addition(&self, field: &Field) -> Option<&'static str> {
+        if self.primary_key == Some(field.column_name) {
+            Some("a primary key can't be added")
+        } else if field.generated.is_some() && field.generated_stored {
+            Some("a STORED generated column can't be added")
+        } else if field.default.is_some_and(|default| !is_constant(default)) {
+            Some("the default isn't a constant, so stored rows can't get it")
+        } else {
+            None
+        }
+    }
+}
+
+// Whether a DEFAULT expression is a literal: a number, a string, a blob, NULL, TRUE or FALSE.
+// CURRENT_TIMESTAMP and parenthesized expressions aren't.
+fn is_constant(default: &str) -> bool {
+    let default = default.trim();
+    let number = default.trim_start_matches(['+', '-']);
+    let quoted = default.len() >= 2 && default.starts_with('\'') && default.ends_with('\'');
+    let blob = default.len() >= 3
+        && (default.starts_with("X'") || default.starts_with("x'"))
+        && default.ends_with('\'');
+    quoted
+        || blob
+        || (!number.is_empty() && number.parse::<f64>().is_ok())
+        || ["NULL", "TRUE", "FALSE"]
+            .iter()
+            .any(|keyword| default.eq_ignore_ascii_case(keyword))
+}
+
+// Differences between a stored table and the type's schema, see `Transaction::verify_schema`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SchemaDiff {
+    // columns of fields which the table lacks
+    pub missing_columns: Vec<&'static str>,
+    // columns of the table which no field is stored in
+    pub extra_columns: Vec<String>,
+    pub type_mismatches: Vec<TypeMismatch>,
+}
+
+impl SchemaDiff {
+    pub fn is_empty(&self) -> bool {
+        self.missing_columns.is_empty()
+            && self.extra_columns.is_empty()
+            && self.type_mismatches.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeMismatch {
+    pub column: &'static str,
+    // the type the ORM would declare the column with
+    pub expected: &'static str,
+    pub actual: String,
 }
 
 #[derive(Clone)]
@@ -64,4 +308,54 @@ pub struct Field {
     pub attr_name: &'static str,
     pub column_name: &'static str,
     pub column_type: DataType,
+    pub nullable: bool,
+
+    // SQL expression used as the column's DEFAULT in CREATE TABLE
+    pub default: Option<&'static str>,
+    // the value is filled in by the database (DEFAULT, trigger), so it is never inserted
+    pub managed: bool,
+    // SQL expression of a generated column, which is never inserted or updated;
+    // `generated_stored` makes it STORED rather than VIRTUAL
+    pub generated: Option<&'static str>,
+    pub generated_stored: bool,
+    pub unique: bool,
+    // an index on the column is created along with the table or by `ensure_indexes`
+    pub indexed: bool,
+    // set for `#[created_at]` and `#[updated_at]` fields
+    pub stamp: Option<Stamp>,
+    // the schema of the type whose id the column holds, see `#[belongs_to]`; a function, so
+    // that types can refer to each other (or to themselves)
+    pub references: Option<fn() -> &'static Schema>,
+}
+
+// How a timestamp field is filled in with the current time.
+#[derive(Clone, Copy)]
+pub struct Stamp {
+    // `#[updated_at]` fields are stamped by every update, `#[created_at]` ones only on insert
+    pub on_update: bool,
+    // converts the time into the field's value
+    pub value: fn(SystemTime) -> Value<'static>,
+}
+
+impl Field {
+    pub const fn new(
+        attr_name: &'static str,
+        column_name: &'static str,
+        column_type: DataType,
+    ) -> Self {
+        Self {
+            attr_name,
+            column_name,
+            column_type,
+            nullable: false,
+            default: None,
+            managed: false,
+            generated: None,
+            generated_stored: false,
+            unique: false,
+            indexed: false,
+            stamp: None,
+            references: None,
+        }
+    }
 }
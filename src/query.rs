@@ -0,0 +1,308 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::Value,
+    error::{Error, Result},
+    object::Object,
+    storage::IndexHint,
+    transaction::{Transaction, Tx},
+};
+use std::marker::PhantomData;
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Like,
+}
+
+impl Op {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::Ne => "<>",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::Like => "LIKE",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+impl Order {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Order::Asc => "ASC",
+            Order::Desc => "DESC",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Nulls {
+    First,
+    Last,
+}
+
+impl Nulls {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Nulls::First => "NULLS FIRST",
+            Nulls::Last => "NULLS LAST",
+        }
+    }
+}
+
+// Ordering and paging of `Transaction::all_with`, for when a whole `Query` is not needed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SelectOptions<'a> {
+    pub order_by: Option<(&'a str, Order)>,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// Accumulates filters and orderings and renders them into a single SELECT. Every referenced
+// column is checked against `T::SCHEMA`; the first unknown one is reported when the query runs.
+pub struct Query<'t, T> {
+    tx: &'t Transaction<'t>,
+    filters: Vec<(&'static str, Op, Value<'t>)>,
+    order: Vec<(&'static str, Order, Option<Nulls>)>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    index: Option<IndexHint>,
+    error: Option<Error>,
+
+    _refers_object: PhantomData<T>,
+}
+
+impl<'t, T: Object> Query<'t, T> {
+    pub(crate) fn new(tx: &'t Transaction<'t>) -> Self {
+        Self {
+            tx,
+            filters: vec![],
+            order: vec![],
+            limit: None,
+            offset: None,
+            index: None,
+            error: None,
+            _refers_object: PhantomData,
+        }
+    }
+
+    // Comparing with NULL via `=` or `<>` is never true in SQL, so `Eq` and `Ne` with
+    // `Value::Null` are rendered as `IS NULL` and `IS NOT NULL` instead.
+    pub fn filter(mut self, column: &str, op: Op, value: Value<'t>) -> Self {
+        if let Some(column) = self.column(column) {
+            self.filters.push((column, op, value));
+        }
+        self
+    }
+
+    // Orderings are applied in the order they were added.
+    pub fn order_by(mut self, column: &str, order: Order) -> Self {
+        if let Some(column) = self.column(column) {
+            self.order.push((column, order, None));
+        }
+        self
+    }
+
+    // Breaks ties of the previous orderings by id, so that pages don't overlap.
+    pub(crate) fn then_by_id(mut self) -> Self {
+        self.order.push((T::SCHEMA.id_column(), Order::Asc, None));
+        self
+    }
+
+    // By default SQLite puts NULLs first in ascending order and last in descending one.
+    // These override it for the ordering added last, and do nothing if there is none.
+    pub fn nulls_first(self) -> Self {
+        self.nulls(Nulls::First)
+    }
+
+    pub fn nulls_last(self) -> Self {
+        self.nulls(Nulls::Last)
+    }
+
+    fn nulls(mut self, nulls: Nulls) -> Self {
+        if let Some((_, _, last)) = self.order.last_mut() {
+            *last = Some(nulls);
+        }
+        self
+    }
+
+    // Adds the filters and orderings of `scope`, as if they were added one by one.
+    pub fn scope(mut self, scope: &Scope<T>) -> Self {
+        for (column, op, value) in scope.filters.iter() {
+            self = self.filter(column, *op, value.clone());
+        }
+        for (column, order) in scope.order.iter() {
+            self = self.order_by(column, *order);
+        }
+        self
+    }
+
+    // Forces SQLite to look rows up with the given index (`INDEXED BY`), or to scan the table
+    // without any (`NOT INDEXED`). The index must exist, otherwise the query fails. It is a last
+    // resort for a planner picking a bad index: a wrong hint makes the query slower or, if the
+    // index can't be used for it at all, fails it.
+    pub fn indexed_by(mut self, index: &str) -> Self {
+        self.index = Some(IndexHint::IndexedBy(index.to_string()));
+        self
+    }
+
+    pub fn not_indexed(mut self) -> Self {
+        self.index = Some(IndexHint::NotIndexed);
+        self
+    }
+
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub fn all(mut self) -> Result<Vec<Tx<'t, T>>> {
+        let (sql, params) = self.render()?;
+        self.tx
+            .find_where_indexed(self.index.as_ref(), &sql, &params)
+    }
+
+    pub fn first(self) -> Result<Option<Tx<'t, T>>> {
+        Ok(self.limit(1).all()?.into_iter().next())
+    }
+
+    // Counts the matching rows without loading them, honoring the limit and offset. Only the
+    // stored rows are counted: local modifications and deletions are not seen until commit.
+    pub fn count(mut self) -> Result<u64> {
+        let (sql, params) = self.render()?;
+        self.tx
+            .count_where_raw::<T>(self.index.as_ref(), &sql, &params)
+    }
+
+    // Renders the WHERE clause along with the orderings and limits, and its parameters.
+    fn render(&mut self) -> Result<(String, Vec<Value<'t>>)> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+
+        let mut conditions = vec![];
+        let mut params = vec![];
+        for (column, op, value) in std::mem::take(&mut self.filters) {
+            let (condition, value) = condition(column, op, value);
+            conditions.push(condition);
+            params.extend(value);
+        }
+        let mut sql = if conditions.is_empty() {
+            "1".to_string()
+        } else {
+            conditions.join(" AND ")
+        };
+
+        if !self.order.is_empty() {
+            let order = self
+                .order
+                .iter()
+                .map(|(column, order, nulls)| match nulls {
+                    Some(nulls) => {
+                        format!("{} {} {}", quote(column), order.as_sql(), nulls.as_sql())
+                    }
+                    None => format!("{} {}", quote(column), order.as_sql()),
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            sql = format!("{} ORDER BY {}", sql, order);
+        }
+
+        // SQLite accepts OFFSET only after LIMIT, where a negative limit means "no limit"
+        if self.limit.is_some() || self.offset.is_some() {
+            sql = format!("{} LIMIT ?", sql);
+            params.push(Value::Int64(self.limit.map_or(-1, |limit| limit as i64)));
+        }
+        if let Some(offset) = self.offset {
+            sql = format!("{} OFFSET ?", sql);
+            params.push(Value::Int64(offset as i64));
+        }
+
+        Ok((sql, params))
+    }
+
+    fn column(&mut self, column: &str) -> Option<&'static str> {
+        let field = T::SCHEMA.field(column);
+        if field.is_none() && self.error.is_none() {
+            self.error = Some(Error::unknown_column(&T::SCHEMA, column));
+        }
+        field.map(|field| field.column_name)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// A reusable set of filters and orderings, applied to queries with `Query::scope`. Columns are
+// checked when the scope is applied, so an unknown one fails the query it is applied to.
+pub struct Scope<T> {
+    filters: Vec<(String, Op, Value<'static>)>,
+    order: Vec<(String, Order)>,
+
+    _refers_object: PhantomData<T>,
+}
+
+impl<T: Object> Scope<T> {
+    pub fn new() -> Self {
+        Self {
+            filters: vec![],
+            order: vec![],
+            _refers_object: PhantomData,
+        }
+    }
+
+    pub fn filter(mut self, column: &str, op: Op, value: Value) -> Self {
+        self.filters
+            .push((column.to_string(), op, value.into_owned()));
+        self
+    }
+
+    pub fn order_by(mut self, column: &str, order: Order) -> Self {
+        self.order.push((column.to_string(), order));
+        self
+    }
+}
+
+impl<T: Object> Default for Scope<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+// `column op ?` along with the parameter, see `Query::filter` for comparisons with NULL.
+pub(crate) fn condition<'a>(column: &str, op: Op, value: Value<'a>) -> (String, Option<Value<'a>>) {
+    match (op, &value) {
+        (Op::Eq, Value::Null) => (format!("{} IS NULL", quote(column)), None),
+        (Op::Ne, Value::Null) => (format!("{} IS NOT NULL", quote(column)), None),
+        _ => (format!("{} {} ?", quote(column), op.as_sql()), Some(value)),
+    }
+}
+
+// Any name (e.g. of an attached database, which is chosen by the user) can be put into SQL in
+// double quotes, with the quotes it contains doubled.
+pub(crate) fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
@@ -0,0 +1,88 @@
+#![forbid(unsafe_code)]
+use crate::{
+    data::{AsDataType, DataType, Value},
+    error::{Error, Result},
+    object::Object,
+    transaction::{CacheValue, ObjectState, Transaction, Tx},
+    ObjectId,
+};
+use std::{cell::RefCell, fmt, marker::PhantomData, rc::Weak};
+
+////////////////////////////////////////////////////////////////////////////////
+
+// A reference to the parent object stored as its id. The parent is loaded lazily on the first
+// `parent()` call and memoized, so subsequent calls within the same transaction return the same
+// handle without touching the transaction at all.
+pub struct BelongsTo<T> {
+    id: ObjectId,
+    loaded: RefCell<Option<(Weak<()>, CacheValue)>>,
+
+    _refers_object: PhantomData<T>,
+}
+
+impl<T: Object> BelongsTo<T> {
+    pub fn new(id: ObjectId) -> Self {
+        Self {
+            id,
+            loaded: RefCell::new(None),
+            _refers_object: PhantomData,
+        }
+    }
+
+    pub fn id(&self) -> ObjectId {
+        self.id
+    }
+
+    pub fn parent<'a>(&self, tx: &'a Transaction) -> Result<Tx<'a, T>> {
+        let token = tx.token();
+        if let Some((loaded_from, cached)) = &*self.loaded.borrow() {
+            if Weak::ptr_eq(loaded_from, &token) {
+                let parent = cached.tx::<T>(tx);
+                if parent.state() == ObjectState::Removed {
+                    return Err(Error::not_found(self.id, T::SCHEMA.type_name));
+                }
+                return Ok(parent);
+            }
+        }
+
+        let parent = tx.get::<T>(self.id)?;
+        *self.loaded.borrow_mut() = Some((token, parent.cached()));
+        Ok(parent)
+    }
+}
+
+impl<T: Object> From<ObjectId> for BelongsTo<T> {
+    fn from(id: ObjectId) -> Self {
+        Self::new(id)
+    }
+}
+
+impl<T: Object> Clone for BelongsTo<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.id)
+    }
+}
+
+impl<T> PartialEq for BelongsTo<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<T> fmt::Debug for BelongsTo<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("BelongsTo").field(&self.id).finish()
+    }
+}
+
+impl<T: Object> AsDataType for BelongsTo<T> {
+    const DATA_TYPE: DataType = DataType::Int64;
+
+    fn as_value(&self) -> Value<'_> {
+        Value::Int64(self.id.0)
+    }
+
+    fn from_value(value: &Value) -> Result<Self> {
+        Ok(Self::new(ObjectId(i64::from_value(value)?)))
+    }
+}
@@ -1,12 +1,14 @@
 #![forbid(unsafe_code)]
 use crate::{
-    data::{DataType, Value},
-    error::{Error, ErrorCtx, ErrorWithCtx, Result},
-    object::Schema,
+    data::{DataType, Value, ValueKey},
+    dialect::{placeholders, row_placeholders, Dialect, Sqlite},
+    error::{Error, ErrorCtx, ErrorWithCtx, Result, StorageError},
+    object::{Field, Schema},
+    query::quote,
     ObjectId,
 };
-use rusqlite::{params_from_iter, ToSql};
-use std::iter;
+use rusqlite::ToSql;
+use std::{collections::HashMap, iter, ops::Deref};
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -16,88 +18,764 @@ pub type RowSlice<'a> = [Value<'a>];
 ////////////////////////////////////////////////////////////////////////////////
 
 pub(crate) trait StorageTransaction {
-    fn table_exists(&self, table: &str) -> Result<bool>;
+    // The SQL dialect of the backend, e.g. to know the column types it declares.
+    fn dialect(&self) -> &'static dyn Dialect;
+
+    fn table_exists(&self, schema: &Schema) -> Result<bool>;
     fn create_table(&self, schema: &Schema) -> Result<()>;
+    // Names and declared types of the columns the table actually has, in the order of their
+    // definition.
+    fn existing_columns(&self, schema: &Schema) -> Result<Vec<(String, String)>>;
+    fn add_column(&self, schema: &Schema, field: &Field) -> Result<()>;
+    // Creates the missing indexes of `#[index]` fields, the table itself must exist.
+    fn create_indexes(&self, schema: &Schema) -> Result<()>;
+    // Whether the table has an index named `index`, as `IndexHint::IndexedBy` requires.
+    fn index_exists(&self, schema: &Schema, index: &str) -> Result<bool>;
+    fn drop_table(&self, schema: &Schema) -> Result<()>;
 
-    fn insert_row(&self, schema: &Schema, row: &RowSlice) -> Result<ObjectId>;
-    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<()>;
+    // If the schema has managed fields, the stored row is read back and returned as well.
+    fn insert_row(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+    ) -> Result<(ObjectId, Option<Row<'static>>)>;
+    // Inserts the rows with as few statements as possible, results are in the order of `rows`
+    // and are the same as those of `insert_row`.
+    fn insert_rows(
+        &self,
+        schema: &Schema,
+        rows: &[Row],
+    ) -> Result<Vec<(ObjectId, Option<Row<'static>>)>>;
+    // Inserts the row or updates the stored one which has the same `conflict_column` value,
+    // returning the stored row.
+    fn upsert_row(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        conflict_column: &str,
+    ) -> Result<(ObjectId, Row<'static>)>;
+    // Inserts the rows, updating the stored ones which have the same `conflict_column` value.
+    // Stored rows are returned in the order of `rows`.
+    fn upsert_rows(
+        &self,
+        schema: &Schema,
+        rows: &[Row],
+        conflict_column: &str,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>>;
+    // Returns the number of updated rows: 0 if there is no such row or if `condition`
+    // (a column and its expected stored value) doesn't hold. The `#[version]` column is
+    // incremented rather than written, and only if it still holds the value in `row`.
+    fn update_row(
+        &self,
+        id: ObjectId,
+        schema: &Schema,
+        row: &RowSlice,
+        condition: Option<(&str, &Value)>,
+    ) -> Result<usize>;
     fn select_row(&self, id: ObjectId, schema: &Schema) -> Result<Row<'static>>;
+    fn row_exists(&self, id: ObjectId, schema: &Schema) -> Result<bool>;
+    // Selects only the given columns of the row, in the order of `fields`.
+    fn select_values(
+        &self,
+        id: ObjectId,
+        schema: &Schema,
+        fields: &[&Field],
+    ) -> Result<Row<'static>>;
+    fn select_rows(
+        &self,
+        schema: &Schema,
+        where_clause: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>>;
+    // Like `select_rows`, but passes the rows to `f` one by one, stopping at the first error.
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        index: Option<&IndexHint>,
+        where_clause: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()>;
+    // Number of rows matching `where_clause`, which may also limit them.
+    fn count_rows(
+        &self,
+        schema: &Schema,
+        index: Option<&IndexHint>,
+        where_clause: &str,
+        params: &RowSlice,
+    ) -> Result<u64>;
     fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()>;
+    // Deletes the rows matching `where_clause` and returns their ids.
+    fn delete_rows(
+        &self,
+        schema: &Schema,
+        where_clause: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<ObjectId>>;
+    // `native_limit` tells whether SQLite supports `DELETE ... LIMIT` syntax.
+    fn delete_rows_limited(
+        &self,
+        schema: &Schema,
+        where_clause: &str,
+        params: &RowSlice,
+        limit: u64,
+        native_limit: bool,
+    ) -> Result<Vec<ObjectId>>;
+
+    fn execute_raw(&self, sql: &str, params: &RowSlice) -> Result<usize>;
+    // Runs the query and calls `f` for each resulting row, stopping at the first error.
+    fn query_raw(
+        &self,
+        sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(&rusqlite::Row) -> Result<()>,
+    ) -> Result<()>;
 
     fn commit(&self) -> Result<()>;
     fn rollback(&self) -> Result<()>;
+    // Starts a new transaction after the previous one was committed or rolled back.
+    fn begin(&self) -> Result<()>;
+
+    // Savepoints nest inside the transaction, `name` should be unique among the open ones.
+    fn savepoint(&self, name: &str) -> Result<()>;
+    fn release(&self, name: &str) -> Result<()>;
+    // Undoes the changes made after the savepoint, which stays open.
+    fn rollback_to(&self, name: &str) -> Result<()>;
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
-impl<'a> StorageTransaction for rusqlite::Transaction<'a> {
-    fn table_exists(&self, table: &str) -> Result<bool> {
-        let mut stmt = self.prepare("SELECT 1 FROM sqlite_master WHERE name = ?")?;
-        Ok(stmt.exists([table])?)
+// A SQLite transaction along with the connection options affecting how statements are run.
+pub(crate) struct SqliteTransaction<'a> {
+    tx: rusqlite::Transaction<'a>,
+
+    // on a type mismatch, try to convert the stored value before failing
+    lenient_reads: bool,
+    // whether logged statements show their parameters, see `Connection::log_sql_values`
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    log_values: bool,
+    // attached database holding the tables, the main one if not set, quoted as an identifier
+    database: Option<String>,
+    // prepended to the table names, see `ConnectionBuilder::table_prefix`
+    table_prefix: String,
+}
+
+impl<'a> SqliteTransaction<'a> {
+    pub fn new(
+        tx: rusqlite::Transaction<'a>,
+        lenient_reads: bool,
+        log_values: bool,
+        table_prefix: &str,
+        database: Option<&str>,
+    ) -> Self {
+        Self {
+            tx,
+            lenient_reads,
+            log_values,
+            database: database.map(quote),
+            table_prefix: table_prefix.to_string(),
+        }
     }
 
-    fn create_table(&self, schema: &Schema) -> Result<()> {
-        let columns = iter::once("id INTEGER PRIMARY KEY AUTOINCREMENT".to_string())
-            .chain(schema.fields.iter().map(|field| {
-                format!(
-                    "{} {}",
-                    field.column_name,
-                    data_type_as_sqlite(field.column_type)
-                )
-            }))
+    // The name of a table (or of its trigger or index) qualified with the database it lives in.
+    fn qualified(&self, schema: &Schema, name: &str) -> String {
+        match &self.database {
+            Some(database) if !schema.temporary => format!("{}.{}", database, name),
+            _ => name.to_string(),
+        }
+    }
+
+    // The name the table has in the database, i.e. with the prefix of the connection.
+    fn table_name(&self, schema: &Schema) -> String {
+        format!("{}{}", self.table_prefix, schema.table_name)
+    }
+
+    fn table(&self, schema: &Schema) -> String {
+        self.qualified(schema, &self.table_name(schema))
+    }
+
+    // Logs a statement about to be run. Parameters may hold personal data, so only their number
+    // is logged unless `Connection::log_sql_values` is enabled.
+    #[cfg(feature = "tracing")]
+    fn trace(&self, sql: &str, params: &[&dyn ToSql]) {
+        if self.log_values {
+            let values = params
+                .iter()
+                .map(|param| describe_param(*param))
+                .collect::<Vec<_>>()
+                .join(", ");
+            tracing::debug!(params = %values, "{}", sql);
+        } else {
+            tracing::debug!(params = params.len(), "{}", sql);
+        }
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    fn trace(&self, _sql: &str, _params: &[&dyn ToSql]) {}
+
+    // Upserts the rows with a single statement, see `StorageTransaction::upsert_rows`.
+    fn upsert_chunk(
+        &self,
+        schema: &Schema,
+        rows: &[&RowSlice],
+        conflict_column: &str,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>> {
+        let fields = schema
+            .fields
+            .iter()
+            .enumerate()
+            .filter(|(_, field)| !field.managed)
+            .collect::<Vec<_>>();
+        let conflict_index = schema
+            .fields
+            .iter()
+            .position(|field| field.column_name == conflict_column)
+            .ok_or_else(|| Error::unknown_column(schema, conflict_column))?;
+
+        let columns = fields
+            .iter()
+            .map(|(_, field)| field.column_name)
             .collect::<Vec<_>>()
             .join(", ");
+        // an overwritten row keeps the time it was created at
+        let updates = fields
+            .iter()
+            .filter(|(_, field)| !matches!(field.stamp, Some(stamp) if !stamp.on_update))
+            .map(|(_, field)| format!("{col} = excluded.{col}", col = field.column_name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let returning = iter::once(schema.id_column())
+            .chain(schema.column_names())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES {} ON CONFLICT({}) DO UPDATE SET {} RETURNING {}",
+            self.table(schema),
+            columns,
+            row_placeholders(self.dialect(), rows.len(), fields.len()),
+            conflict_column,
+            updates,
+            returning
+        );
+        let params = rows
+            .iter()
+            .flat_map(|row| fields.iter().map(move |(i, _)| &row[*i] as &dyn ToSql))
+            .collect::<Vec<_>>();
 
-        let sql = format!("CREATE TABLE {} ({})", schema.table_name, columns);
+        let ctx = ErrorCtx {
+            schema: Some(schema),
+            sql: Some(&sql),
+            ..Default::default()
+        };
+
+        self.trace(&sql, &params);
+        let mut stmt = self
+            .prepare(&sql)
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?;
+        let mut returned = stmt
+            .query(&*params)
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?;
+
+        let mut stored = HashMap::new();
+        while let Some(row) = returned
+            .next()
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?
+        {
+            let id = ObjectId(
+                row.get(0)
+                    .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?,
+            );
+            let ctx = ErrorCtx {
+                object_id: Some(id),
+                ..ctx.clone()
+            };
+            let row = read_row(schema, row, &ctx, self.lenient_reads)?;
+            stored.insert(ValueKey::from(&row[conflict_index]), (id, row));
+        }
+
+        // RETURNING doesn't guarantee any order, so match rows by the conflict column
+        rows.iter()
+            .map(|row| {
+                stored
+                    .remove(&ValueKey::from(&row[conflict_index]))
+                    .ok_or_else(|| not_returned(&sql))
+            })
+            .collect()
+    }
+
+    // Runs a statement returning ids of the rows in its first column.
+    fn query_ids(&self, schema: &Schema, sql: &str, params: &RowSlice) -> Result<Vec<ObjectId>> {
+        let ctx = ErrorCtx {
+            schema: Some(schema),
+            sql: Some(sql),
+            ..Default::default()
+        };
+
+        let params = as_params(params);
+        self.trace(sql, &params);
+        let mut stmt = self
+            .prepare(sql)
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?;
+        let ids = stmt
+            .query_map(&*params, |row| row.get(0))
+            .and_then(|rows| rows.map(|id| id.map(ObjectId)).collect())
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?;
+
+        Ok(ids)
+    }
+}
+
+impl<'a> Deref for SqliteTransaction<'a> {
+    type Target = rusqlite::Transaction<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tx
+    }
+}
+
+impl StorageTransaction for SqliteTransaction<'_> {
+    fn dialect(&self) -> &'static dyn Dialect {
+        &Sqlite
+    }
+
+    fn table_exists(&self, schema: &Schema) -> Result<bool> {
+        let table = self.table_name(schema);
+        let sql = format!(
+            "SELECT 1 FROM {}sqlite_master WHERE name = ?1 \
+            UNION ALL SELECT 1 FROM sqlite_temp_master WHERE name = ?1",
+            self.database
+                .as_ref()
+                .map_or(String::new(), |database| format!("{}.", database))
+        );
+        let ctx = ErrorCtx {
+            sql: Some(&sql),
+            ..Default::default()
+        };
+        self.trace(&sql, &[&table]);
+        self.prepare(&sql)
+            .and_then(|mut stmt| stmt.exists([&table]))
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx)))
+    }
+
+    fn create_table(&self, schema: &Schema) -> Result<()> {
+        let sql = schema.create_sql(self.dialect(), &self.table(schema), &self.table_prefix);
+        execute_sql(self, &sql, schema)?;
+
+        for (i, trigger) in schema.triggers.iter().enumerate() {
+            let sql = format!(
+                "CREATE TRIGGER {} {}",
+                self.qualified(schema, &trigger_name(&self.table_name(schema), i)),
+                trigger
+            );
+            execute_sql(self, &sql, schema)?;
+        }
+
+        self.create_indexes(schema)
+    }
 
-        self.execute(&sql, [])?;
+    // `table_xinfo` lists generated columns as well, which `table_info` leaves out
+    fn existing_columns(&self, schema: &Schema) -> Result<Vec<(String, String)>> {
+        let sql = format!(
+            "PRAGMA {}",
+            self.qualified(schema, &format!("table_xinfo({})", self.table_name(schema)))
+        );
+        let ctx = ErrorCtx {
+            schema: Some(schema),
+            sql: Some(&sql),
+            ..Default::default()
+        };
+        self.trace(&sql, &[]);
+        self.prepare(&sql)
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| Ok((row.get("name")?, row.get("type")?)))?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx)))
+    }
+
+    // SQLite can't add a UNIQUE column, so the uniqueness is enforced by an index
+    fn add_column(&self, schema: &Schema, field: &Field) -> Result<()> {
+        let column = Field {
+            unique: false,
+            ..field.clone()
+        };
+        let sql = format!(
+            "ALTER TABLE {} ADD COLUMN {}",
+            self.table(schema),
+            schema.column_definition(self.dialect(), &column, &self.table_prefix)
+        );
+        execute_sql(self, &sql, schema)?;
+
+        if field.unique {
+            let sql = format!(
+                "CREATE UNIQUE INDEX IF NOT EXISTS {} ON {} ({})",
+                self.qualified(
+                    schema,
+                    &unique_index_name(&self.table_name(schema), field.column_name)
+                ),
+                self.table_name(schema),
+                field.column_name
+            );
+            execute_sql(self, &sql, schema)?;
+        }
         Ok(())
     }
 
-    fn insert_row(&self, schema: &Schema, row: &RowSlice) -> Result<ObjectId> {
-        let columns = schema.column_names().collect::<Vec<_>>().join(", ");
-        let sql = if !schema.fields.is_empty() {
+    fn create_indexes(&self, schema: &Schema) -> Result<()> {
+        for field in schema.indexed_fields() {
+            let sql = format!(
+                "CREATE INDEX IF NOT EXISTS {} ON {} ({})",
+                self.qualified(
+                    schema,
+                    &index_name(&self.table_name(schema), field.column_name)
+                ),
+                // the table is looked up in the database of the index, so it isn't qualified
+                self.table_name(schema),
+                field.column_name
+            );
+            execute_sql(self, &sql, schema)?;
+        }
+
+        Ok(())
+    }
+
+    fn index_exists(&self, schema: &Schema, index: &str) -> Result<bool> {
+        let sql = format!(
+            "SELECT 1 FROM {}sqlite_master WHERE type = 'index' AND name = ?1 AND tbl_name = ?2 \
+            UNION ALL SELECT 1 FROM sqlite_temp_master \
+            WHERE type = 'index' AND name = ?1 AND tbl_name = ?2",
+            self.database
+                .as_ref()
+                .map_or(String::new(), |database| format!("{}.", database))
+        );
+        let ctx = ErrorCtx {
+            schema: Some(schema),
+            sql: Some(&sql),
+            ..Default::default()
+        };
+        let table = self.table_name(schema);
+        self.trace(&sql, &[&index, &table]);
+        self.prepare(&sql)
+            .and_then(|mut stmt| stmt.exists([index, &table]))
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx)))
+    }
+
+    fn drop_table(&self, schema: &Schema) -> Result<()> {
+        for i in 0..schema.triggers.len() {
+            let sql = format!(
+                "DROP TRIGGER IF EXISTS {}",
+                self.qualified(schema, &trigger_name(&self.table_name(schema), i))
+            );
+            execute_sql(self, &sql, schema)?;
+        }
+
+        let sql = format!("DROP TABLE IF EXISTS {}", self.table(schema));
+        execute_sql(self, &sql, schema)?;
+        Ok(())
+    }
+
+    fn insert_row(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+    ) -> Result<(ObjectId, Option<Row<'static>>)> {
+        let (fields, values): (Vec<_>, Vec<_>) = schema
+            .fields
+            .iter()
+            .zip(row.iter())
+            .filter(|(field, _)| !field.managed)
+            .unzip();
+
+        let columns = fields
+            .iter()
+            .map(|field| field.column_name)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut sql = if !fields.is_empty() {
             format!(
                 "INSERT INTO {} ({}) VALUES({})",
-                schema.table_name,
+                self.table(schema),
                 columns,
-                repeat_with_comma("?", schema.fields.len())
+                placeholders(self.dialect(), 1, fields.len())
             )
         } else {
-            format!("INSERT INTO {} DEFAULT VALUES", schema.table_name)
+            self.dialect().insert_defaults(&self.table(schema))
         };
 
+        let returning_row = schema.has_managed_fields();
+        if returning_row {
+            let columns = iter::once(schema.id_column())
+                .chain(schema.column_names())
+                .collect::<Vec<_>>()
+                .join(", ");
+            sql = format!("{} RETURNING {}", sql, columns);
+        }
+
         let ctx_with_schema = ErrorCtx {
             schema: Some(schema),
+            sql: Some(&sql),
             ..Default::default()
         };
 
+        let values = values
+            .into_iter()
+            .map(|value| value as &dyn ToSql)
+            .collect::<Vec<_>>();
+        self.trace(&sql, &values);
         let mut stmt = self
             .prepare(&sql)
             .map_err(|err| Error::from(ErrorWithCtx::new(err, ctx_with_schema.clone())))?;
 
-        match stmt.insert(params_from_iter(row.iter())) {
-            Ok(id) => Ok(ObjectId(id)),
-            Err(err) => Err(Error::from(ErrorWithCtx::new(err, ctx_with_schema))),
+        if !returning_row {
+            return match stmt.insert(&*values) {
+                Ok(id) => Ok((ObjectId(id), None)),
+                Err(err) => Err(Error::from(ErrorWithCtx::new(err, ctx_with_schema))),
+            };
         }
+
+        let mut rows = stmt
+            .query(&*values)
+            .map_err(|err| Error::from(ErrorWithCtx::new(err, ctx_with_schema.clone())))?;
+        let row = rows
+            .next()
+            .map_err(|err| Error::from(ErrorWithCtx::new(err, ctx_with_schema.clone())))?
+            .expect("INSERT ... RETURNING should return the inserted row");
+
+        let id = ObjectId(
+            row.get(0)
+                .map_err(|err| Error::from(ErrorWithCtx::new(err, ctx_with_schema.clone())))?,
+        );
+        let ctx = ErrorCtx {
+            object_id: Some(id),
+            ..ctx_with_schema
+        };
+        Ok((id, Some(read_row(schema, row, &ctx, self.lenient_reads)?)))
     }
 
-    fn update_row(&self, id: ObjectId, schema: &Schema, row: &RowSlice) -> Result<()> {
-        let columns = schema
-            .column_names()
-            .map(|col| format!("{} = ?", col))
+    fn insert_rows(
+        &self,
+        schema: &Schema,
+        rows: &[Row],
+    ) -> Result<Vec<(ObjectId, Option<Row<'static>>)>> {
+        let fields = schema
+            .fields
+            .iter()
+            .enumerate()
+            .filter(|(_, field)| !field.managed)
+            .collect::<Vec<_>>();
+        // `DEFAULT VALUES` can't be combined with several rows
+        if fields.is_empty() {
+            return rows
+                .iter()
+                .map(|row| self.insert_row(schema, row))
+                .collect();
+        }
+
+        let columns = fields
+            .iter()
+            .map(|(_, field)| field.column_name)
             .collect::<Vec<_>>()
             .join(", ");
-        let sql = format!("UPDATE {} SET {} WHERE id = ?", schema.table_name, columns);
-        let params = row_to_sql(row).chain(iter::once(&id.0 as &dyn ToSql));
+        let returning_row = schema.has_managed_fields();
+        let returning = match returning_row {
+            true => iter::once(schema.id_column())
+                .chain(schema.column_names())
+                .collect::<Vec<_>>()
+                .join(", "),
+            false => schema.id_column().to_string(),
+        };
+        let key = schema
+            .primary_key
+            .map(|pk| {
+                schema
+                    .column_names()
+                    .position(|column| column == pk)
+                    .ok_or_else(|| Error::unknown_column(schema, pk))
+            })
+            .transpose()?;
 
-        match self.execute(&sql, params_from_iter(params)) {
-            Ok(_) => Ok(()),
+        let mut res = Vec::with_capacity(rows.len());
+        for chunk in rows.chunks((MAX_PARAMS / fields.len()).max(1)) {
+            let sql = format!(
+                "INSERT INTO {} ({}) VALUES {} RETURNING {}",
+                self.table(schema),
+                columns,
+                row_placeholders(self.dialect(), chunk.len(), fields.len()),
+                returning
+            );
+            let params = chunk
+                .iter()
+                .flat_map(|row| fields.iter().map(move |(i, _)| &row[*i] as &dyn ToSql))
+                .collect::<Vec<_>>();
+
+            let ctx = ErrorCtx {
+                schema: Some(schema),
+                sql: Some(&sql),
+                ..Default::default()
+            };
+
+            self.trace(&sql, &params);
+            let mut stmt = self
+                .prepare(&sql)
+                .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?;
+            let mut rows = stmt
+                .query(&*params)
+                .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?;
+
+            let mut stored = vec![];
+            while let Some(row) = rows
+                .next()
+                .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?
+            {
+                let id = ObjectId(
+                    row.get(0)
+                        .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?,
+                );
+                let row = match returning_row {
+                    true => {
+                        let ctx = ErrorCtx {
+                            object_id: Some(id),
+                            ..ctx.clone()
+                        };
+                        Some(read_row(schema, row, &ctx, self.lenient_reads)?)
+                    }
+                    false => None,
+                };
+                stored.push((id, row));
+            }
+
+            // RETURNING doesn't guarantee any order. Synthetic ids are assigned in the order of
+            // the inserted rows and only grow, while primary keys are found among the rows.
+            match key {
+                None => {
+                    stored.sort_by_key(|(id, _)| id.0);
+                    res.extend(stored);
+                }
+                Some(key) => {
+                    let mut stored = stored
+                        .into_iter()
+                        .map(|(id, row)| (ValueKey::from(&Value::Int64(id.0)), (id, row)))
+                        .collect::<HashMap<_, _>>();
+                    for row in chunk {
+                        let inserted = stored
+                            .remove(&ValueKey::from(&row[key]))
+                            .ok_or_else(|| not_returned(&sql))?;
+                        res.push(inserted);
+                    }
+                }
+            }
+        }
+
+        Ok(res)
+    }
+
+    fn upsert_row(
+        &self,
+        schema: &Schema,
+        row: &RowSlice,
+        conflict_column: &str,
+    ) -> Result<(ObjectId, Row<'static>)> {
+        let mut stored = self.upsert_chunk(schema, &[row], conflict_column)?;
+        Ok(stored.remove(0))
+    }
+
+    fn upsert_rows(
+        &self,
+        schema: &Schema,
+        rows: &[Row],
+        conflict_column: &str,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>> {
+        let field_count = schema.fields.iter().filter(|field| !field.managed).count();
+        let mut res = Vec::with_capacity(rows.len());
+        for chunk in rows.chunks((MAX_PARAMS / field_count.max(1)).max(1)) {
+            let chunk = chunk.iter().map(|row| row.as_slice()).collect::<Vec<_>>();
+            res.extend(self.upsert_chunk(schema, &chunk, conflict_column)?);
+        }
+
+        Ok(res)
+    }
+
+    fn update_row(
+        &self,
+        id: ObjectId,
+        schema: &Schema,
+        row: &RowSlice,
+        condition: Option<(&str, &Value)>,
+    ) -> Result<usize> {
+        // generated columns can't be written at all, the key identifies the row to update,
+        // and the version is incremented by the statement itself
+        let id_column = schema.id_column();
+        let (fields, values): (Vec<_>, Vec<_>) = schema
+            .fields
+            .iter()
+            .zip(row.iter())
+            .filter(|(field, _)| {
+                field.generated.is_none()
+                    && field.column_name != id_column
+                    && Some(field.column_name) != schema.version
+            })
+            .unzip();
+
+        // `id = id` keeps the statement valid (and the row count meaningful) without columns
+        let dialect = self.dialect();
+        let mut columns = fields
+            .iter()
+            .enumerate()
+            .map(|(i, field)| format!("{} = {}", field.column_name, dialect.placeholder(i + 1)))
+            .collect::<Vec<_>>();
+        if let Some(version) = schema.version {
+            columns.push(format!("{version} = {version} + 1", version = version));
+        }
+        if columns.is_empty() {
+            columns.push(format!("{id} = {id}", id = id_column));
+        }
+        let mut sql = format!(
+            "UPDATE {} SET {} WHERE {} = {}",
+            self.table(schema),
+            columns.join(", "),
+            id_column,
+            dialect.placeholder(fields.len() + 1)
+        );
+
+        // the stored version should still be the one the object was read with
+        let conditions = condition
+            .into_iter()
+            .chain(
+                schema
+                    .version_index()
+                    .map(|index| (schema.fields[index].column_name, &row[index])),
+            )
+            .collect::<Vec<_>>();
+        // like in `Query::filter`, NULL is matched with `IS`
+        for (i, (column, value)) in conditions.iter().enumerate() {
+            sql = format!(
+                "{} AND {} {} {}",
+                sql,
+                column,
+                match value {
+                    Value::Null => "IS",
+                    _ => "=",
+                },
+                dialect.placeholder(fields.len() + 2 + i)
+            );
+        }
+        let params = values
+            .into_iter()
+            .map(|value| value as &dyn ToSql)
+            .chain(iter::once(&id.0 as &dyn ToSql))
+            .chain(conditions.iter().map(|(_, value)| *value as &dyn ToSql))
+            .collect::<Vec<_>>();
+
+        self.trace(&sql, &params);
+        match self.execute(&sql, &*params) {
+            Ok(updated) => Ok(updated),
             Err(error) => Err(Error::from(ErrorWithCtx::new(
                 error,
                 ErrorCtx {
                     object_id: Some(id),
                     schema: Some(schema),
+                    sql: Some(&sql),
+                    ..Default::default()
                 },
             ))),
         }
@@ -109,13 +787,22 @@ impl<'a> StorageTransaction for rusqlite::Transaction<'a> {
         } else {
             "*".to_string()
         };
-        let sql = format!("SELECT {} FROM {} WHERE id = ?", columns, schema.table_name);
+        let sql = format!(
+            "SELECT {} FROM {} WHERE {} = {}",
+            columns,
+            self.table(schema),
+            schema.id_column(),
+            self.dialect().placeholder(1)
+        );
 
         let ctx = ErrorCtx {
             schema: Some(schema),
             object_id: Some(id),
+            sql: Some(&sql),
+            ..Default::default()
         };
 
+        self.trace(&sql, &[&id.0]);
         let mut stmt = self
             .prepare(&sql)
             .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?;
@@ -130,20 +817,179 @@ impl<'a> StorageTransaction for rusqlite::Transaction<'a> {
             Err(error) => Err(Error::from(ErrorWithCtx::new(error, ctx.clone()))),
         }?;
 
-        let mut res = Row::with_capacity(schema.fields.len());
-        for field in schema.fields {
-            let val = extract_value_from_row(field.column_type, row, field.column_name)
-                .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?;
+        read_row(schema, row, &ctx, self.lenient_reads)
+    }
+
+    fn row_exists(&self, id: ObjectId, schema: &Schema) -> Result<bool> {
+        let sql = format!(
+            "SELECT 1 FROM {} WHERE {} = {} LIMIT 1",
+            self.table(schema),
+            schema.id_column(),
+            self.dialect().placeholder(1)
+        );
+
+        let ctx = ErrorCtx {
+            schema: Some(schema),
+            object_id: Some(id),
+            sql: Some(&sql),
+            ..Default::default()
+        };
+
+        self.trace(&sql, &[&id.0]);
+        self.prepare(&sql)
+            .and_then(|mut stmt| stmt.exists([id.0]))
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx)))
+    }
+
+    fn select_values(
+        &self,
+        id: ObjectId,
+        schema: &Schema,
+        fields: &[&Field],
+    ) -> Result<Row<'static>> {
+        let columns = fields
+            .iter()
+            .map(|field| field.column_name)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!(
+            "SELECT {} FROM {} WHERE {} = {}",
+            columns,
+            self.table(schema),
+            schema.id_column(),
+            self.dialect().placeholder(1)
+        );
 
-            res.push(val);
+        let ctx = ErrorCtx {
+            schema: Some(schema),
+            object_id: Some(id),
+            sql: Some(&sql),
+            ..Default::default()
+        };
+
+        self.trace(&sql, &[&id.0]);
+        let mut stmt = self
+            .prepare(&sql)
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?;
+        let mut rows = stmt
+            .query([id.0])
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?;
+
+        match rows.next() {
+            Ok(Some(row)) => read_fields(fields.iter().copied(), row, &ctx, self.lenient_reads),
+            Ok(None) => Err(Error::not_found(id, schema.type_name)),
+            Err(error) => Err(Error::from(ErrorWithCtx::new(error, ctx))),
         }
+    }
 
+    fn select_rows(
+        &self,
+        schema: &Schema,
+        where_clause: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<(ObjectId, Row<'static>)>> {
+        let mut res = vec![];
+        self.for_each_row(schema, None, where_clause, params, &mut |id, row| {
+            res.push((id, row));
+            Ok(())
+        })?;
         Ok(res)
     }
 
+    fn for_each_row(
+        &self,
+        schema: &Schema,
+        index: Option<&IndexHint>,
+        where_clause: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(ObjectId, Row<'static>) -> Result<()>,
+    ) -> Result<()> {
+        let columns = iter::once(schema.id_column())
+            .chain(schema.column_names())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let sql = format!(
+            "SELECT {} FROM {}{} WHERE {}",
+            columns,
+            self.table(schema),
+            index.map(IndexHint::as_sql).unwrap_or_default(),
+            where_clause
+        );
+
+        let ctx = ErrorCtx {
+            schema: Some(schema),
+            sql: Some(&sql),
+            ..Default::default()
+        };
+
+        let params = as_params(params);
+        self.trace(&sql, &params);
+        let mut stmt = self
+            .prepare(&sql)
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?;
+
+        let mut rows = stmt
+            .query(&*params)
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?;
+
+        while let Some(row) = rows
+            .next()
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?
+        {
+            let id = ObjectId(
+                row.get(0)
+                    .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?,
+            );
+            let ctx = ErrorCtx {
+                object_id: Some(id),
+                ..ctx.clone()
+            };
+            f(id, read_row(schema, row, &ctx, self.lenient_reads)?)?;
+        }
+
+        Ok(())
+    }
+
+    fn count_rows(
+        &self,
+        schema: &Schema,
+        index: Option<&IndexHint>,
+        where_clause: &str,
+        params: &RowSlice,
+    ) -> Result<u64> {
+        // the clause may end with LIMIT and OFFSET, which must apply to the rows, not to the count
+        let sql = format!(
+            "SELECT COUNT(*) FROM (SELECT 1 FROM {}{} WHERE {})",
+            self.table(schema),
+            index.map(IndexHint::as_sql).unwrap_or_default(),
+            where_clause
+        );
+
+        let params = as_params(params);
+        self.trace(&sql, &params);
+        self.query_row(&sql, &*params, |row| row.get::<_, i64>(0))
+            .map(|count| count as u64)
+            .map_err(|error| {
+                Error::from(ErrorWithCtx::new(
+                    error,
+                    ErrorCtx {
+                        schema: Some(schema),
+                        sql: Some(&sql),
+                        ..Default::default()
+                    },
+                ))
+            })
+    }
+
     fn delete_row(&self, id: ObjectId, schema: &Schema) -> Result<()> {
-        let sql = format!("DELETE FROM {} WHERE id = ?", schema.table_name);
+        let sql = format!(
+            "DELETE FROM {} WHERE {} = {}",
+            self.table(schema),
+            schema.id_column(),
+            self.dialect().placeholder(1)
+        );
 
+        self.trace(&sql, &[&id.0]);
         match self.execute(&sql, [id.0]) {
             Ok(_) => Ok(()),
             Err(error) => Err(Error::from(ErrorWithCtx::new(
@@ -151,34 +997,151 @@ impl<'a> StorageTransaction for rusqlite::Transaction<'a> {
                 ErrorCtx {
                     object_id: Some(id),
                     schema: Some(schema),
+                    sql: Some(&sql),
+                    ..Default::default()
                 },
             ))),
         }
     }
 
-    fn commit(&self) -> Result<()> {
-        self.execute("COMMIT", [])?;
+    fn delete_rows(
+        &self,
+        schema: &Schema,
+        where_clause: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<ObjectId>> {
+        let sql = format!(
+            "DELETE FROM {} WHERE {} RETURNING {}",
+            self.table(schema),
+            where_clause,
+            schema.id_column()
+        );
+        self.query_ids(schema, &sql, params)
+    }
+
+    fn delete_rows_limited(
+        &self,
+        schema: &Schema,
+        where_clause: &str,
+        params: &RowSlice,
+        limit: u64,
+        native_limit: bool,
+    ) -> Result<Vec<ObjectId>> {
+        let sql = if native_limit {
+            format!(
+                "DELETE FROM {} WHERE {} RETURNING {} LIMIT {}",
+                self.table(schema),
+                where_clause,
+                schema.id_column(),
+                limit
+            )
+        } else {
+            format!(
+                "DELETE FROM {table} WHERE {id} IN \
+                (SELECT {id} FROM {table} WHERE {} LIMIT {}) RETURNING {id}",
+                where_clause,
+                limit,
+                table = self.table(schema),
+                id = schema.id_column()
+            )
+        };
+        self.query_ids(schema, &sql, params)
+    }
+
+    fn execute_raw(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        let params = as_params(params);
+        self.trace(sql, &params);
+        self.execute(sql, &*params).map_err(|error| {
+            Error::from(ErrorWithCtx::new(
+                error,
+                ErrorCtx {
+                    sql: Some(sql),
+                    ..Default::default()
+                },
+            ))
+        })
+    }
+
+    fn query_raw(
+        &self,
+        sql: &str,
+        params: &RowSlice,
+        f: &mut dyn FnMut(&rusqlite::Row) -> Result<()>,
+    ) -> Result<()> {
+        let ctx = ErrorCtx {
+            sql: Some(sql),
+            ..Default::default()
+        };
+
+        let params = as_params(params);
+        self.trace(sql, &params);
+        let mut stmt = self
+            .prepare(sql)
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?;
+        let mut rows = stmt
+            .query(&*params)
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?;
+
+        while let Some(row) = rows
+            .next()
+            .map_err(|error| Error::from(ErrorWithCtx::new(error, ctx.clone())))?
+        {
+            f(row)?;
+        }
+
         Ok(())
     }
 
+    fn commit(&self) -> Result<()> {
+        execute_control(self, "COMMIT")
+    }
+
     fn rollback(&self) -> Result<()> {
-        self.execute("ROLLBACK", [])?;
-        Ok(())
+        execute_control(self, "ROLLBACK")
+    }
+
+    fn begin(&self) -> Result<()> {
+        execute_control(self, "BEGIN")
+    }
+
+    fn savepoint(&self, name: &str) -> Result<()> {
+        execute_control(self, &format!("SAVEPOINT {}", name))
+    }
+
+    fn release(&self, name: &str) -> Result<()> {
+        execute_control(self, &format!("RELEASE {}", name))
+    }
+
+    fn rollback_to(&self, name: &str) -> Result<()> {
+        execute_control(self, &format!("ROLLBACK TO {}", name))
     }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
-fn data_type_as_sqlite(data_type: DataType) -> &'static str {
-    match data_type {
-        DataType::String => "TEXT",
-        DataType::Bytes => "BLOB",
-        DataType::Int64 => "BIGINT",
-        DataType::Float64 => "REAL",
-        DataType::Bool => "TINYINT",
+// Overrides the choice of SQLite's query planner for a table in a SELECT.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum IndexHint {
+    // the index must exist, which `Transaction` checks beforehand
+    IndexedBy(String),
+    NotIndexed,
+}
+
+impl IndexHint {
+    fn as_sql(&self) -> String {
+        match self {
+            IndexHint::IndexedBy(index) => {
+                format!(" INDEXED BY \"{}\"", index.replace('"', "\"\""))
+            }
+            IndexHint::NotIndexed => " NOT INDEXED".to_string(),
+        }
     }
 }
 
+// Conservative limit on host parameters in a single statement: older SQLite builds don't
+// accept more than 999.
+pub(crate) const MAX_PARAMS: usize = 999;
+
 impl<'a> ToSql for Value<'a> {
     fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
         match self {
@@ -187,24 +1150,173 @@ impl<'a> ToSql for Value<'a> {
             Value::Int64(x) => x.to_sql(),
             Value::Float64(x) => x.to_sql(),
             Value::Bool(x) => x.to_sql(),
+            Value::Null => rusqlite::types::Null.to_sql(),
         }
     }
 }
 
-fn repeat_with_comma(pattern: &str, count: usize) -> String {
-    vec![pattern; count].join(", ")
+// Executes a schema-level statement, keeping the statement text in the error.
+fn execute_sql(tx: &SqliteTransaction, sql: &str, schema: &Schema) -> Result<()> {
+    tx.trace(sql, &[]);
+    match tx.execute(sql, []) {
+        Ok(_) => Ok(()),
+        Err(error) => Err(Error::from(ErrorWithCtx::new(
+            error,
+            ErrorCtx {
+                schema: Some(schema),
+                sql: Some(sql),
+                ..Default::default()
+            },
+        ))),
+    }
+}
+
+fn execute_control(tx: &SqliteTransaction, sql: &str) -> Result<()> {
+    tx.trace(sql, &[]);
+    tx.execute_batch(sql).map_err(|error| {
+        Error::from(ErrorWithCtx::new(
+            error,
+            ErrorCtx {
+                sql: Some(sql),
+                ..Default::default()
+            },
+        ))
+    })
+}
+
+fn trigger_name(table: &str, index: usize) -> String {
+    format!("{}_trigger_{}", table, index)
+}
+
+pub(crate) fn index_name(table: &str, column: &str) -> String {
+    format!("{}_{}_index", table, column)
+}
+
+pub(crate) fn unique_index_name(table: &str, column: &str) -> String {
+    format!("{}_{}_unique", table, column)
 }
 
-fn row_to_sql<'a>(row: &'a RowSlice<'a>) -> impl Iterator<Item = &'a dyn ToSql> {
-    row.iter().map(|val| val as &dyn ToSql)
+// `column IN (?, ..., ?)` with `count` parameters, which should not exceed `MAX_PARAMS`. Like
+// the other clauses built by `Transaction` and `Query`, it is in SQLite's syntax.
+pub(crate) fn in_clause(column: &str, count: usize) -> String {
+    format!("{} IN ({})", column, placeholders(&Sqlite, 1, count))
 }
 
-fn extract_value_from_row(
-    column_type: DataType,
+// An inserted or upserted row which didn't come back from RETURNING with its key unchanged.
+fn not_returned(sql: &str) -> Error {
+    Error::Storage(Box::new(StorageError {
+        source: "a written row was not returned".into(),
+        sql: Some(sql.to_string()),
+    }))
+}
+
+fn read_row(
+    schema: &Schema,
+    row: &rusqlite::Row,
+    ctx: &ErrorCtx,
+    lenient: bool,
+) -> Result<Row<'static>> {
+    read_fields(schema.fields.iter(), row, ctx, lenient)
+}
+
+fn read_fields<'a>(
+    fields: impl Iterator<Item = &'a Field>,
     row: &rusqlite::Row,
-    column_name: &str,
-) -> rusqlite::Result<Value<'static>> {
-    Ok(match column_type {
+    ctx: &ErrorCtx,
+    lenient: bool,
+) -> Result<Row<'static>> {
+    let mut res = Row::new();
+    for field in fields {
+        let val = extract_value_from_row(field, row).or_else(|error| match error {
+            error @ rusqlite::Error::InvalidColumnType(..) if lenient => row
+                .get_ref(field.column_name)
+                .ok()
+                .and_then(|value| coerce_value(field.column_type, value))
+                .ok_or(error),
+            error => Err(error),
+        });
+        let val = val.map_err(|error| match error {
+            // the column is absent from the result set, e.g. it was renamed in the query
+            rusqlite::Error::InvalidColumnName(_) => Error::missing_column(
+                ctx.schema.expect("Schema should be provided to context"),
+                field,
+            ),
+            error => {
+                let got_value = row.get_ref(field.column_name).ok().map(describe_value_ref);
+                Error::from(ErrorWithCtx::new(
+                    error,
+                    ErrorCtx {
+                        got_value,
+                        ..ctx.clone()
+                    },
+                ))
+            }
+        })?;
+
+        res.push(val);
+    }
+
+    Ok(res)
+}
+
+fn as_params<'a>(params: &'a RowSlice) -> Vec<&'a dyn ToSql> {
+    params.iter().map(|param| param as &dyn ToSql).collect()
+}
+
+// A parameter as logged by `SqliteTransaction::trace`.
+#[cfg(feature = "tracing")]
+fn describe_param(param: &dyn ToSql) -> String {
+    use rusqlite::types::ToSqlOutput;
+    match param.to_sql() {
+        Ok(ToSqlOutput::Borrowed(value)) => describe_value_ref(value),
+        Ok(ToSqlOutput::Owned(value)) => describe_value_ref((&value).into()),
+        _ => "?".to_string(),
+    }
+}
+
+fn describe_value_ref(value: rusqlite::types::ValueRef) -> String {
+    use rusqlite::types::ValueRef;
+    match value {
+        ValueRef::Null => "NULL".to_string(),
+        ValueRef::Integer(x) => x.to_string(),
+        ValueRef::Real(x) => x.to_string(),
+        ValueRef::Text(text) => format!("'{}'", String::from_utf8_lossy(text)),
+        ValueRef::Blob(bytes) => format!("<{} bytes>", bytes.len()),
+    }
+}
+
+// Best-effort conversion of a value stored with another type, e.g. a number stored as text.
+fn coerce_value(data_type: DataType, value: rusqlite::types::ValueRef) -> Option<Value<'static>> {
+    use rusqlite::types::ValueRef;
+    let text = match value {
+        ValueRef::Text(text) => std::str::from_utf8(text).ok().map(str::trim),
+        _ => None,
+    };
+
+    match (data_type, value) {
+        (DataType::String, ValueRef::Integer(x)) => Some(Value::String(x.to_string().into())),
+        (DataType::String, ValueRef::Real(x)) => Some(Value::String(x.to_string().into())),
+        (DataType::Bytes, ValueRef::Text(text)) => Some(Value::Bytes(text.to_vec().into())),
+        (DataType::Int64, ValueRef::Real(x)) if x.fract() == 0. => Some(Value::Int64(x as i64)),
+        (DataType::Int64, ValueRef::Text(_)) => text?.parse().ok().map(Value::Int64),
+        (DataType::Float64, ValueRef::Integer(x)) => Some(Value::Float64(x as f64)),
+        (DataType::Float64, ValueRef::Text(_)) => text?.parse().ok().map(Value::Float64),
+        (DataType::Bool, ValueRef::Text(_)) => match text? {
+            "0" | "false" => Some(Value::Bool(false)),
+            "1" | "true" => Some(Value::Bool(true)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn extract_value_from_row(field: &Field, row: &rusqlite::Row) -> rusqlite::Result<Value<'static>> {
+    let column_name = field.column_name;
+    if field.nullable && row.get_ref(column_name)? == rusqlite::types::ValueRef::Null {
+        return Ok(Value::Null);
+    }
+
+    Ok(match field.column_type {
         DataType::String => Value::String(row.get::<_, String>(column_name)?.into()),
         DataType::Bytes => Value::Bytes(row.get::<_, Vec<u8>>(column_name)?.into()),
         DataType::Int64 => Value::Int64(row.get(column_name)?),
@@ -1,63 +1,270 @@
 #![forbid(unsafe_code)]
 use crate::{
-    data::ObjectId,
-    error::{Error, Result},
-    object::{Object, Store},
-    storage::StorageTransaction,
+    connection::Settings,
+    data::{ObjectId, Value, ValueKey},
+    error::{
+        ConcurrentModificationError, Error, Result, TransactionTooLargeError, UniqueViolationError,
+    },
+    object::{Object, SchemaDiff, Store, TypeMismatch},
+    query::{condition, quote, Op, Query, SelectOptions},
+    storage::{in_clause, IndexHint, Row, RowSlice, StorageTransaction, MAX_PARAMS},
 };
 use std::{
     any::{Any, TypeId},
     cell::{Cell, Ref, RefCell, RefMut},
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    iter,
     marker::PhantomData,
-    rc::Rc,
+    rc::{Rc, Weak},
+    time::SystemTime,
 };
 
 ////////////////////////////////////////////////////////////////////////////////
 pub struct Transaction<'a> {
     inner: Box<dyn StorageTransaction + 'a>,
     cache: RefCell<HashMap<CacheKey, CacheValue>>,
+    settings: Settings,
+
+    // keys of cached objects by the time they were last used, tracked only when the cache size
+    // is limited by `Connection::object_cache_limit`
+    recently_used: RefCell<BTreeMap<u64, CacheKey>>,
+    clock: Cell<u64>,
+
+    // lets data memoized outside of the transaction (e.g. in `BelongsTo`) check that it was
+    // obtained from this very transaction
+    token: Rc<()>,
+
+    // objects created, modified or deleted since the last commit or checkpoint, shared with
+    // `Tx` handles so that they can count their changes as well
+    changes: Rc<Cell<usize>>,
+    // savepoints started so far, to give each a new name
+    savepoints: Cell<usize>,
+    // one for each open savepoint, the innermost last
+    snapshots: RefCell<Vec<Snapshot>>,
+    // rows written so far, see `commit_with_stats`
+    stats: Cell<CommitStats>,
+    // the last id given to an object whose insert is deferred, they count down from -1
+    provisional: Cell<i64>,
+    // keys of the objects whose insert is deferred, in the order they were created
+    staged: RefCell<Vec<CacheKey>>,
+    // set by `commit` and `rollback`, otherwise the transaction is rolled back on drop
+    finished: Cell<bool>,
+
+    name: Option<String>,
+    // everything logged on behalf of the transaction happens inside of this span, so that
+    // interleaved transactions can be told apart by their names
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
 impl<'a> Transaction<'a> {
-    pub(crate) fn new(inner: Box<dyn StorageTransaction + 'a>) -> Self {
-        Self {
+    pub(crate) fn new(
+        inner: Box<dyn StorageTransaction + 'a>,
+        settings: Settings,
+        name: Option<&str>,
+    ) -> Self {
+        let tx = Self {
             inner,
             cache: RefCell::new(HashMap::new()),
+            settings,
+            recently_used: RefCell::new(BTreeMap::new()),
+            clock: Cell::new(0),
+            token: Rc::new(()),
+            changes: Rc::new(Cell::new(0)),
+            savepoints: Cell::new(0),
+            snapshots: RefCell::new(vec![]),
+            stats: Cell::new(CommitStats::default()),
+            provisional: Cell::new(0),
+            staged: RefCell::new(vec![]),
+            finished: Cell::new(false),
+            name: name.map(str::to_string),
+            #[cfg(feature = "tracing")]
+            span: match name {
+                Some(name) => tracing::debug_span!("transaction", name),
+                None => tracing::debug_span!("transaction"),
+            },
+        };
+
+        #[cfg(feature = "tracing")]
+        tx.span.in_scope(|| tracing::debug!("begin"));
+
+        tx
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    #[cfg(feature = "tracing")]
+    fn enter(&self) -> tracing::span::Entered<'_> {
+        self.span.enter()
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    fn enter(&self) -> NoSpan {
+        NoSpan
+    }
+
+    pub(crate) fn token(&self) -> Weak<()> {
+        Rc::downgrade(&self.token)
+    }
+
+    // Fails if the changes pending in the transaction, together with `adding` new ones, don't fit
+    // into the limit set by `Connection::transaction_size_limit`.
+    fn check_size(&self, adding: usize) -> Result<()> {
+        match self.settings.max_changes {
+            Some(limit) if self.changes.get() + adding > limit => Err(Error::TransactionTooLarge(
+                Box::new(TransactionTooLargeError { limit }),
+            )),
+            _ => Ok(()),
         }
     }
 
     fn ensure_table<T: Object>(&self) -> Result<()> {
-        if self.inner.table_exists(T::SCHEMA.table_name)? {
+        if self.inner.table_exists(&T::SCHEMA)? {
             return Ok(());
         }
+        if !self.settings.auto_create_tables {
+            return Err(Error::missing_table(&T::SCHEMA));
+        }
 
         self.inner.create_table(&T::SCHEMA)
     }
 
+    // Creates indexes of `#[index]` fields which are missing, e.g. because the attribute was
+    // added after the table had been created. Existing indexes are left as is.
+    pub fn ensure_indexes<T: Object>(&self) -> Result<()> {
+        self.ensure_table::<T>()?;
+        self.inner.create_indexes(&T::SCHEMA)
+    }
+
+    // Adds the columns of fields missing from the table, e.g. because they were added to the type
+    // after the table had been created, and returns their names. Stored rows get the column's
+    // default or NULL. Only additive changes are made: columns of removed or renamed fields are
+    // left as is. Columns SQLite can't add to a table are `UnsupportedMigration`, checked before
+    // adding each of them.
+    pub fn migrate<T: Object>(&self) -> Result<Vec<&'static str>> {
+        self.ensure_table::<T>()?;
+        let existing = self.inner.existing_columns(&T::SCHEMA)?;
+        let mut added = vec![];
+        for field in T::SCHEMA.fields.iter() {
+            // column names are case-insensitive in SQLite
+            if !existing
+                .iter()
+                .any(|(column, _)| column.eq_ignore_ascii_case(field.column_name))
+            {
+                if let Some(reason) = T::SCHEMA.unsupported_addition(field) {
+                    return Err(Error::unsupported_migration(&T::SCHEMA, field, reason));
+                }
+                self.inner.add_column(&T::SCHEMA, field)?;
+                added.push(field.column_name);
+            }
+        }
+
+        self.inner.create_indexes(&T::SCHEMA)?;
+        Ok(added)
+    }
+
+    // Compares the stored table with `T::SCHEMA` without changing anything, e.g. to fail at
+    // startup if the database has drifted from the types. A missing table is `MissingTable`.
+    // Declared types are compared as text, so a column declared `INT` instead of `BIGINT` is
+    // a mismatch even though SQLite treats both alike.
+    pub fn verify_schema<T: Object>(&self) -> Result<SchemaDiff> {
+        if !self.inner.table_exists(&T::SCHEMA)? {
+            return Err(Error::missing_table(&T::SCHEMA));
+        }
+
+        let existing = self.inner.existing_columns(&T::SCHEMA)?;
+        let mut diff = SchemaDiff::default();
+        for field in T::SCHEMA.fields.iter() {
+            let expected = T::SCHEMA.column_type(self.inner.dialect(), field);
+            match existing
+                .iter()
+                .find(|(column, _)| column.eq_ignore_ascii_case(field.column_name))
+            {
+                None => diff.missing_columns.push(field.column_name),
+                Some((_, actual)) if !actual.eq_ignore_ascii_case(expected) => {
+                    diff.type_mismatches.push(TypeMismatch {
+                        column: field.column_name,
+                        expected,
+                        actual: actual.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+
+        let id_column = T::SCHEMA.id_column();
+        diff.extra_columns = existing
+            .into_iter()
+            .map(|(column, _)| column)
+            .filter(|column| {
+                !column.eq_ignore_ascii_case(id_column)
+                    && !T::SCHEMA
+                        .column_names()
+                        .any(|name| name.eq_ignore_ascii_case(column))
+            })
+            .collect();
+        Ok(diff)
+    }
+
     pub fn create<T: Object>(&self, src_obj: T) -> Result<Tx<'_, T>> {
+        self.check_size(1)?;
         self.ensure_table::<T>()?;
-        let id = self.inner.insert_row(&T::SCHEMA, &src_obj.as_row())?;
+        let src_obj = self.stamped(src_obj)?;
+        if self.settings.defer_inserts {
+            self.check_keys(std::slice::from_ref(&src_obj))?;
+            return Ok(self.stage(src_obj));
+        }
+        let (id, stored_row) = self.inner.insert_row(&T::SCHEMA, &src_obj.as_row())?;
+        let mut src_obj = src_obj;
+        if let Some(row) = stored_row {
+            merge_managed(&mut src_obj, row)?;
+        }
+        self.record(|stats| stats.inserted += 1);
 
-        let obj = Rc::new(RefCell::new(src_obj));
-        let state = Rc::new(Cell::new(ObjectState::Clean));
+        let cached = self.cache_value(id, src_obj);
+        cached.count();
+        let tx = cached.tx(self);
+        self.remember(TypeId::of::<T>(), &cached, || Before::Missing);
+        self.touch((TypeId::of::<T>(), id), &cached);
+        let mut borrowed_cache = self.cache.borrow_mut();
+        borrowed_cache.insert((TypeId::of::<T>(), id), cached);
+        self.evict(&mut borrowed_cache);
 
-        self.cache.borrow_mut().insert(
-            (TypeId::of::<T>(), id),
-            CacheValue {
-                state: state.clone(),
-                stored: obj.clone(),
-            },
-        );
+        Ok(tx)
+    }
+
+    // Like calling `create` for each object, but inserts them with a few multi-row statements.
+    pub fn create_many<T: Object>(&self, objs: Vec<T>) -> Result<Vec<Tx<'_, T>>> {
+        self.check_size(objs.len())?;
+        self.ensure_table::<T>()?;
+        let objs = self.stamped_all(objs)?;
+        if self.settings.defer_inserts {
+            self.check_keys(&objs)?;
+            return Ok(objs.into_iter().map(|obj| self.stage(obj)).collect());
+        }
+        let rows = objs.iter().map(|obj| obj.as_row()).collect::<Vec<_>>();
+        let stored = self.inner.insert_rows(&T::SCHEMA, &rows)?;
+        drop(rows);
+        self.record(|stats| stats.inserted += stored.len());
 
-        Ok(Tx {
-            obj,
-            state,
-            id,
+        let mut borrowed_cache = self.cache.borrow_mut();
+        let mut res = Vec::with_capacity(stored.len());
+        for ((id, stored_row), mut obj) in stored.into_iter().zip(objs) {
+            if let Some(row) = stored_row {
+                merge_managed(&mut obj, row)?;
+            }
+            let cached = self.cache_value(id, obj);
+            cached.count();
+            res.push(cached.tx(self));
+            self.remember(TypeId::of::<T>(), &cached, || Before::Missing);
+            self.touch((TypeId::of::<T>(), id), &cached);
+            borrowed_cache.insert((TypeId::of::<T>(), id), cached);
+        }
+        self.evict(&mut borrowed_cache);
 
-            _lifetime: PhantomData,
-            _refers_object: PhantomData,
-        })
+        Ok(res)
     }
 
     pub fn get<T: Object>(&self, id: ObjectId) -> Result<Tx<'_, T>> {
@@ -66,67 +273,1287 @@ impl<'a> Transaction<'a> {
         let cached = match borrowed_cache.entry((TypeId::of::<T>(), id)) {
             Entry::Occupied(entry) => entry.into_mut(),
             Entry::Vacant(entry) => {
-                let obj = T::from_row(self.inner.select_row(id, &T::SCHEMA)?);
-
-                entry.insert(CacheValue {
-                    state: Rc::new(Cell::new(ObjectState::Clean)),
-                    stored: Rc::new(RefCell::new(obj)),
-                })
+                let obj = T::from_row(self.inner.select_row(id, &T::SCHEMA)?)?;
+                entry.insert(self.cache_value(id, obj))
             }
         };
+        self.touch((TypeId::of::<T>(), id), cached);
 
         if cached.state.get() == ObjectState::Removed {
             return Err(Error::not_found(id, T::SCHEMA.type_name));
         }
 
-        Ok(Tx {
-            state: cached.state.clone(),
-            obj: cached.stored.clone(),
-            id,
+        let tx = cached.tx(self);
+        self.evict(&mut borrowed_cache);
+        Ok(tx)
+    }
 
-            _lifetime: PhantomData,
-            _refers_object: PhantomData,
+    // Checks for the object without loading it. Cached objects are answered for without a query,
+    // so an object removed in this transaction doesn't exist even before commit.
+    pub fn exists<T: Object>(&self, id: ObjectId) -> Result<bool> {
+        if let Some(cached) = self.cache.borrow().get(&(TypeId::of::<T>(), id)) {
+            return Ok(cached.state.get() != ObjectState::Removed);
+        }
+        self.ensure_table::<T>()?;
+        self.inner.row_exists(id, &T::SCHEMA)
+    }
+
+    // Falls back to creating the object built by `make` only if there is no object with the given
+    // id. Without a `#[primary_key]` field the created object gets a new id rather than `id`.
+    pub fn get_or_create<T: Object>(
+        &self,
+        id: ObjectId,
+        make: impl FnOnce() -> T,
+    ) -> Result<Tx<'_, T>> {
+        match self.get(id) {
+            Err(Error::NotFound(_)) => self.create(make()),
+            res => res,
+        }
+    }
+
+    // Replaces the object with `obj` as a whole, e.g. in a handler which has got a new version of
+    // it, and returns its handle. The row is updated at commit, like after `borrow_mut`. Fields
+    // the replacement can't change are kept as stored: the primary key, managed fields and
+    // `#[created_at]` timestamps.
+    pub fn update<T: Object>(&self, id: ObjectId, mut obj: T) -> Result<Tx<'_, T>> {
+        self.check_size(0)?;
+        let tx = self.get::<T>(id)?;
+        for (index, (field, stored)) in T::SCHEMA
+            .fields
+            .iter()
+            .zip(tx.borrow().as_row())
+            .enumerate()
+        {
+            let kept = field.managed
+                || T::SCHEMA.primary_key == Some(field.column_name)
+                || matches!(field.stamp, Some(stamp) if !stamp.on_update);
+            if kept {
+                obj.set_column(index, stored)?;
+            }
+        }
+        *tx.borrow_mut() = obj;
+        Ok(tx)
+    }
+
+    // Reads a single column of the stored row without loading the whole object, so local
+    // modifications of the object made in this transaction are not seen.
+    pub fn get_field<T: Object>(&self, id: ObjectId, column: &str) -> Result<Value<'static>> {
+        let field = T::SCHEMA
+            .field(column)
+            .ok_or_else(|| Error::unknown_column(&T::SCHEMA, column))?;
+
+        if self.is_removed::<T>(id) {
+            return Err(Error::not_found(id, T::SCHEMA.type_name));
+        }
+
+        self.ensure_table::<T>()?;
+        let mut row = self.inner.select_values(id, &T::SCHEMA, &[field])?;
+        Ok(row.remove(0))
+    }
+
+    // Loads only the given columns into a detached object, the rest of its fields are left
+    // as in `T::default()`. The object doesn't get into the transaction's cache.
+    pub fn get_columns<T: Object + Default>(&self, id: ObjectId, columns: &[&str]) -> Result<T> {
+        let mut indices = vec![];
+        let mut fields = vec![];
+        for column in columns {
+            let index = T::SCHEMA
+                .column_names()
+                .position(|column_name| column_name == *column)
+                .ok_or_else(|| Error::unknown_column(&T::SCHEMA, column))?;
+            indices.push(index);
+            fields.push(&T::SCHEMA.fields[index]);
+        }
+
+        if self.is_removed::<T>(id) {
+            return Err(Error::not_found(id, T::SCHEMA.type_name));
+        }
+
+        self.ensure_table::<T>()?;
+        // without columns there is nothing to select, but the object must still exist
+        let values = match fields.is_empty() {
+            true if !self.inner.row_exists(id, &T::SCHEMA)? => {
+                return Err(Error::not_found(id, T::SCHEMA.type_name))
+            }
+            true => vec![],
+            false => self.inner.select_values(id, &T::SCHEMA, &fields)?,
+        };
+
+        let default = T::default();
+        let mut row = default.as_row();
+        for (index, value) in indices.into_iter().zip(values) {
+            row[index] = value;
+        }
+        T::from_row(row)
+    }
+
+    // Inserts the object or, if an object with the same `conflict_column` value is already
+    // stored, overwrites it. `conflict_column` must be `#[unique]` or the primary key.
+    pub fn upsert<T: Object>(&self, conflict_column: &str, obj: T) -> Result<Tx<'_, T>> {
+        let field = T::SCHEMA
+            .field(conflict_column)
+            .ok_or_else(|| Error::unknown_column(&T::SCHEMA, conflict_column))?;
+
+        self.check_size(1)?;
+        self.ensure_table::<T>()?;
+        let obj = self.stamped(obj)?;
+        let (id, row) = self
+            .inner
+            .upsert_row(&T::SCHEMA, &obj.as_row(), field.column_name)?;
+        self.record(|stats| stats.upserted += 1);
+
+        self.cache_stored(&mut self.cache.borrow_mut(), id, obj, row)
+    }
+
+    // Like calling `upsert` for each object, but writes them with a few multi-row statements.
+    pub fn upsert_many<T: Object>(
+        &self,
+        objs: Vec<T>,
+        conflict_column: &str,
+    ) -> Result<Vec<Tx<'_, T>>> {
+        let field = T::SCHEMA
+            .field(conflict_column)
+            .ok_or_else(|| Error::unknown_column(&T::SCHEMA, conflict_column))?;
+
+        self.check_size(objs.len())?;
+        self.ensure_table::<T>()?;
+        let objs = self.stamped_all(objs)?;
+        let rows = objs.iter().map(|obj| obj.as_row()).collect::<Vec<_>>();
+        let stored = self
+            .inner
+            .upsert_rows(&T::SCHEMA, &rows, field.column_name)?;
+        drop(rows);
+        self.record(|stats| stats.upserted += stored.len());
+
+        let mut borrowed_cache = self.cache.borrow_mut();
+        stored
+            .into_iter()
+            .zip(objs)
+            .map(|((id, row), obj)| self.cache_stored(&mut borrowed_cache, id, obj, row))
+            .collect()
+    }
+
+    // A new object with its `#[created_at]` and `#[updated_at]` fields set to the current time.
+    fn stamped<T: Object>(&self, obj: T) -> Result<T> {
+        stamp_new(obj, (self.settings.clock)())
+    }
+
+    // Objects of one batch share the same time.
+    fn stamped_all<T: Object>(&self, objs: Vec<T>) -> Result<Vec<T>> {
+        let now = (self.settings.clock)();
+        objs.into_iter().map(|obj| stamp_new(obj, now)).collect()
+    }
+
+    // Caches a new object until `insert_pending` inserts its row. Objects with a `#[primary_key]`
+    // field are cached under their key, the rest under a provisional id.
+    fn stage<T: Object>(&self, obj: T) -> Tx<'_, T> {
+        let id = primary_key(&obj).unwrap_or_else(|| {
+            let id = ObjectId::from(self.provisional.get() - 1);
+            self.provisional.set(id.into_i64());
+            id
+        });
+        self.staged.borrow_mut().push((TypeId::of::<T>(), id));
+
+        let cached = self.cache_value(id, obj);
+        cached.count();
+        cached.state.set(ObjectState::New);
+        let tx = cached.tx(self);
+        self.remember(TypeId::of::<T>(), &cached, || Before::Missing);
+        self.touch((TypeId::of::<T>(), id), &cached);
+        let mut borrowed_cache = self.cache.borrow_mut();
+        borrowed_cache.insert((TypeId::of::<T>(), id), cached);
+        self.evict(&mut borrowed_cache);
+        tx
+    }
+
+    // Fails if any of the objects to stage has the primary key of a stored or cached object, or of
+    // another one of them, as inserting it would.
+    fn check_keys<T: Object>(&self, objs: &[T]) -> Result<()> {
+        let mut keys = HashSet::new();
+        for id in objs.iter().filter_map(primary_key) {
+            let taken = !keys.insert(id)
+                || self.cache.borrow().contains_key(&(TypeId::of::<T>(), id))
+                || self.inner.row_exists(id, &T::SCHEMA)?;
+            if taken {
+                return Err(Error::UniqueViolation(Box::new(UniqueViolationError {
+                    table_name: T::SCHEMA.table_name.to_string(),
+                    column_name: T::SCHEMA.id_column().to_string(),
+                })));
+            }
+        }
+        Ok(())
+    }
+
+    // Caches `obj` with the row just written for it to the storage, or updates the cached object
+    // with the row. Either keeps its `#[skip]`-ped fields. A cached object which is borrowed can't
+    // be updated, which fails with `Borrowed` after the row is written.
+    fn cache_stored<T: Object>(
+        &self,
+        cache: &mut HashMap<CacheKey, CacheValue>,
+        id: ObjectId,
+        mut obj: T,
+        row: Row<'static>,
+    ) -> Result<Tx<'_, T>> {
+        let cached = match cache.entry((TypeId::of::<T>(), id)) {
+            Entry::Occupied(entry) => {
+                let cached = entry.into_mut();
+                if cached.state.get() == ObjectState::Clean {
+                    self.remember(TypeId::of::<T>(), cached, || cached.current_row());
+                }
+                match cached.stored.try_borrow_mut() {
+                    Ok(mut stored) => stored.set_row(row)?,
+                    Err(_) => return Err(Error::borrowed(id, cached.type_name)),
+                }
+                cached.state.set(ObjectState::Clean);
+                cached
+            }
+            Entry::Vacant(entry) => {
+                obj.update_from_row(row)?;
+                let cached = entry.insert(self.cache_value(id, obj));
+                self.remember(TypeId::of::<T>(), cached, || Before::Unknown);
+                cached
+            }
+        };
+        cached.count();
+        self.touch((TypeId::of::<T>(), id), cached);
+
+        let tx = cached.tx(self);
+        self.evict(cache);
+        Ok(tx)
+    }
+
+    // Reconciles the table with `desired`, matching objects by `key_column`: objects with
+    // a new key are created, stored ones that differ in any written field are overwritten, and
+    // the ones whose key is missing from `desired` are deleted. Updates and deletions go through
+    // the cache, so they are applied at commit like any other modification. Keys repeated in
+    // `desired` are a `UniqueViolation`, found before anything is changed.
+    pub fn apply_graph<T: Object>(
+        &self,
+        desired: Vec<T>,
+        key_column: &str,
+    ) -> Result<GraphChanges> {
+        self.check_size(0)?;
+        let key = T::SCHEMA
+            .column_names()
+            .position(|column_name| column_name == key_column)
+            .ok_or_else(|| Error::unknown_column(&T::SCHEMA, key_column))?;
+
+        let mut keys = HashSet::new();
+        for obj in desired.iter() {
+            if !keys.insert(ValueKey::from(&obj.as_row()[key])) {
+                return Err(Error::UniqueViolation(Box::new(UniqueViolationError {
+                    table_name: T::SCHEMA.table_name.to_string(),
+                    column_name: T::SCHEMA.fields[key].column_name.to_string(),
+                })));
+            }
+        }
+
+        // stored objects sharing a key are matched once, the rest of them are deleted
+        let mut stored = HashMap::new();
+        let mut unmatched = vec![];
+        for tx in self.all::<T>()? {
+            let value = ValueKey::from(&tx.borrow().as_row()[key]);
+            match stored.entry(value) {
+                Entry::Vacant(entry) => {
+                    entry.insert(tx);
+                }
+                Entry::Occupied(_) => unmatched.push(tx),
+            }
+        }
+
+        let mut changes = GraphChanges::default();
+        for obj in desired {
+            let tx = match stored.remove(&ValueKey::from(&obj.as_row()[key])) {
+                Some(tx) => tx,
+                None => {
+                    self.create(obj)?;
+                    changes.inserted += 1;
+                    continue;
+                }
+            };
+
+            // managed fields are filled in by the database, so they are kept as stored
+            let changed = T::SCHEMA
+                .fields
+                .iter()
+                .zip(tx.borrow().as_row())
+                .zip(obj.as_row())
+                .enumerate()
+                .filter(|(_, ((field, current), value))| !field.managed && current != value)
+                .map(|(index, (_, value))| (index, value.into_owned()))
+                .collect::<Vec<_>>();
+            if !changed.is_empty() {
+                let mut stored_obj = tx.borrow_mut();
+                for (index, value) in changed {
+                    stored_obj.set_column(index, value)?;
+                }
+                changes.updated += 1;
+            }
+        }
+
+        for tx in stored.into_values().chain(unmatched) {
+            tx.delete()?;
+            changes.deleted += 1;
+        }
+
+        Ok(changes)
+    }
+
+    pub fn drop_table<T: Object>(&self) -> Result<()> {
+        self.cache
+            .borrow_mut()
+            .retain(|(type_id, _), _| *type_id != TypeId::of::<T>());
+        self.inner.drop_table(&T::SCHEMA)
+    }
+
+    // Deletes all the rows matching a single filter (see `Query::filter`) with one statement and
+    // returns their number. Cached objects of the deleted rows are marked as removed. Rows are
+    // matched by their stored values, local modifications which are not committed yet are ignored.
+    pub fn delete_where<T: Object>(&self, column: &str, op: Op, value: Value) -> Result<u64> {
+        let field = T::SCHEMA
+            .field(column)
+            .ok_or_else(|| Error::unknown_column(&T::SCHEMA, column))?;
+
+        self.check_size(0)?;
+        self.ensure_table::<T>()?;
+        let (where_clause, value) = condition(field.column_name, op, value);
+        let ids = self
+            .inner
+            .delete_rows(&T::SCHEMA, &where_clause, value.as_slice())?;
+
+        self.count_removed::<T>(&ids);
+        Ok(ids.len() as u64)
+    }
+
+    // Deletes at most `limit` rows whose `column` equals `value` and returns how many were
+    // deleted, so that big cleanups can be split into bounded chunks.
+    pub fn delete_where_limited<T: Object>(
+        &self,
+        column: &str,
+        value: Value,
+        limit: u64,
+    ) -> Result<u64> {
+        let field = T::SCHEMA
+            .field(column)
+            .ok_or_else(|| Error::unknown_column(&T::SCHEMA, column))?;
+
+        self.check_size(0)?;
+        self.ensure_table::<T>()?;
+        let (where_clause, value) = condition(field.column_name, Op::Eq, value);
+        let ids = self.inner.delete_rows_limited(
+            &T::SCHEMA,
+            &where_clause,
+            value.as_slice(),
+            limit,
+            self.settings.delete_limit,
+        )?;
+
+        self.count_removed::<T>(&ids);
+        Ok(ids.len() as u64)
+    }
+
+    // Runs arbitrary SQL inside the transaction, bypassing the cache: cached objects
+    // won't see the changes until they are reloaded.
+    pub fn execute_raw(&self, sql: &str, params: &RowSlice) -> Result<usize> {
+        self.inner.execute_raw(sql, params)
+    }
+
+    // Maps every row returned by arbitrary SQL with `f`, for reads that don't fit an `Object`.
+    // Like `execute_raw`, it bypasses the cache: modifications of cached objects which are not
+    // committed yet are not seen.
+    pub fn query_map<R>(
+        &self,
+        sql: &str,
+        params: &RowSlice,
+        mut f: impl FnMut(&rusqlite::Row) -> Result<R>,
+    ) -> Result<Vec<R>> {
+        let mut res = vec![];
+        self.inner.query_raw(sql, params, &mut |row| {
+            res.push(f(row)?);
+            Ok(())
+        })?;
+        Ok(res)
+    }
+
+    // Re-reads every unmodified cached object, see `Tx::reload`. If any of them is borrowed,
+    // nothing is reloaded and `Borrowed` is returned.
+    pub fn reload_all(&self) -> Result<()> {
+        let cache = self.cache.borrow();
+        let clean = cache
+            .iter()
+            .filter(|(_, cached)| cached.state.get() == ObjectState::Clean)
+            .collect::<Vec<_>>();
+        for ((_, id), cached) in clean.iter() {
+            if cached.stored.try_borrow_mut().is_err() {
+                return Err(Error::borrowed(*id, cached.type_name));
+            }
+        }
+
+        for ((type_id, id), cached) in clean {
+            let row = self
+                .inner
+                .select_row(*id, cached.stored.borrow().schema())?;
+            self.remember(*type_id, cached, || cached.current_row());
+            cached.stored.borrow_mut().set_row(row)?;
+        }
+
+        Ok(())
+    }
+
+    // `where_clause` is appended to the generated SELECT as is and is not validated, so it must
+    // never be built from user input: pass values through `params` instead.
+    pub fn find_where_raw<T: Object>(
+        &self,
+        where_clause: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<Tx<'_, T>>> {
+        self.find_where_indexed(None, where_clause, params)
+    }
+
+    // `find_where_raw` with the table's index forced or disabled, see `Query::indexed_by`.
+    pub(crate) fn find_where_indexed<T: Object>(
+        &self,
+        index: Option<&IndexHint>,
+        where_clause: &str,
+        params: &RowSlice,
+    ) -> Result<Vec<Tx<'_, T>>> {
+        self.ensure_table::<T>()?;
+        self.check_index::<T>(index)?;
+        let mut rows = vec![];
+        self.inner
+            .for_each_row(&T::SCHEMA, index, where_clause, params, &mut |id, row| {
+                rows.push((id, row));
+                Ok(())
+            })?;
+        self.hydrate(rows)
+    }
+
+    // `Query::count` for the table, see `find_where_indexed`.
+    pub(crate) fn count_where_raw<T: Object>(
+        &self,
+        index: Option<&IndexHint>,
+        where_clause: &str,
+        params: &RowSlice,
+    ) -> Result<u64> {
+        self.ensure_table::<T>()?;
+        self.check_index::<T>(index)?;
+        self.inner
+            .count_rows(&T::SCHEMA, index, where_clause, params)
+    }
+
+    // A forced index which doesn't exist fails with `MissingIndex`, rather than with whatever
+    // the storage makes of it.
+    fn check_index<T: Object>(&self, index: Option<&IndexHint>) -> Result<()> {
+        match index {
+            Some(IndexHint::IndexedBy(name)) if !self.inner.index_exists(&T::SCHEMA, name)? => {
+                Err(Error::missing_index(&T::SCHEMA, name))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Number of stored objects of the type, without loading them. Like `Query::count`, it doesn't
+    // see local modifications and deletions until commit.
+    pub fn count<T: Object>(&self) -> Result<u64> {
+        self.count_where_raw::<T>(None, "1", &[])
+    }
+
+    // `count` of objects matching a single filter, see `Query::filter`.
+    pub fn count_where<T: Object>(&self, column: &str, op: Op, value: Value) -> Result<u64> {
+        self.query::<T>().filter(column, op, value).count()
+    }
+
+    // Loads the whole table ordered by id, e.g. a small lookup table. Objects which are already
+    // cached are returned as they are, with their local modifications, and removed ones are
+    // left out.
+    pub fn all<T: Object>(&self) -> Result<Vec<Tx<'_, T>>> {
+        self.find_where_raw(&format!("1 ORDER BY {}", T::SCHEMA.id_column()), &[])
+    }
+
+    // Like `all`, but sorted by `options.order_by` (then by id) and paged with its limit and
+    // offset. An unknown column is an `UnknownColumn` error.
+    pub fn all_with<T: Object>(&self, options: SelectOptions) -> Result<Vec<Tx<'_, T>>> {
+        let mut query = self.query::<T>();
+        if let Some((column, order)) = options.order_by {
+            query = query.order_by(column, order);
+        }
+        if let Some(limit) = options.limit {
+            query = query.limit(limit);
+        }
+        if let Some(offset) = options.offset {
+            query = query.offset(offset);
+        }
+        query.then_by_id().all()
+    }
+
+    // Objects whose `column` equals `value`, ordered by id. Like in `Query::filter`, NULL is
+    // matched with `IS NULL`.
+    pub fn find_by<T: Object>(&self, column: &str, value: Value) -> Result<Vec<Tx<'_, T>>> {
+        let field = T::SCHEMA
+            .field(column)
+            .ok_or_else(|| Error::unknown_column(&T::SCHEMA, column))?;
+
+        let (condition, value) = condition(field.column_name, Op::Eq, value);
+        self.find_where_raw(
+            &format!("{} ORDER BY {}", condition, quote(T::SCHEMA.id_column())),
+            value.as_slice(),
+        )
+    }
+
+    // Finds objects whose `column` holds any of `values`, ordered by id. Long lists are split
+    // into several queries to stay within SQLite's limit on the number of parameters.
+    pub fn find_in<T: Object>(&self, column: &str, values: &RowSlice) -> Result<Vec<Tx<'_, T>>> {
+        let field = T::SCHEMA
+            .field(column)
+            .ok_or_else(|| Error::unknown_column(&T::SCHEMA, column))?;
+
+        self.ensure_table::<T>()?;
+        let mut rows = vec![];
+        for chunk in values.chunks(MAX_PARAMS) {
+            let where_clause = in_clause(field.column_name, chunk.len());
+            rows.extend(self.inner.select_rows(&T::SCHEMA, &where_clause, chunk)?);
+        }
+
+        // equal values from different chunks match the same rows
+        rows.sort_by_key(|(id, _)| id.into_i64());
+        rows.dedup_by_key(|(id, _)| *id);
+        self.hydrate(rows)
+    }
+
+    // Reads the whole table into detached objects ordered by id, leaving the cache alone, e.g.
+    // for exports. Like `get_columns`, it sees the stored rows, not the local modifications.
+    pub fn export_all<T: Object>(&self) -> Result<Vec<T>> {
+        self.ensure_table::<T>()?;
+        let where_clause = format!("1 ORDER BY {}", T::SCHEMA.id_column());
+        let rows = self.inner.select_rows(&T::SCHEMA, &where_clause, &[])?;
+        rows.into_iter().map(|(_, row)| T::from_row(row)).collect()
+    }
+
+    // Passes every stored object to `f` in the order of ids, stopping at the first error, without
+    // collecting them. Like `export_all`, it works with detached objects and bypasses the cache.
+    pub fn for_each<T: Object>(&self, mut f: impl FnMut(T) -> Result<()>) -> Result<()> {
+        self.ensure_table::<T>()?;
+        let where_clause = format!("1 ORDER BY {}", T::SCHEMA.id_column());
+        self.inner
+            .for_each_row(&T::SCHEMA, None, &where_clause, &[], &mut |_, row| {
+                f(T::from_row(row)?)
+            })
+    }
+
+    // Iterates over the whole table in pages of `page_size` objects, each page is put into the
+    // cache before being yielded. Pages are keyed by id, so objects created or deleted meanwhile
+    // don't shift the pages.
+    pub fn paged_stream<T: Object>(
+        &self,
+        page_size: u64,
+    ) -> impl Iterator<Item = Result<Vec<Tx<'_, T>>>> + '_ {
+        let where_clause = format!("{id} > ? ORDER BY {id} LIMIT ?", id = T::SCHEMA.id_column());
+        let mut last_id: Option<ObjectId> = None;
+        let mut done = false;
+        iter::from_fn(move || {
+            if done {
+                return None;
+            }
+
+            let rows = match last_id {
+                Some(_) => Ok(()),
+                None => self.ensure_table::<T>(),
+            }
+            .and_then(|_| {
+                self.inner.select_rows(
+                    &T::SCHEMA,
+                    &where_clause,
+                    &[
+                        Value::Int64(last_id.map_or(i64::MIN, ObjectId::into_i64)),
+                        Value::Int64(page_size as i64),
+                    ],
+                )
+            });
+
+            match rows {
+                Ok(rows) if rows.is_empty() => None,
+                Ok(rows) => {
+                    done = (rows.len() as u64) < page_size;
+                    last_id = rows.last().map(|(id, _)| *id);
+                    let page = self.hydrate(rows);
+                    done |= page.is_err();
+                    Some(page)
+                }
+                Err(err) => {
+                    done = true;
+                    Some(Err(err))
+                }
+            }
         })
     }
 
+    // Iterates over the whole table in the order of ids, yielding detached objects one by one.
+    // Rows are read in batches keyed by id, so memory use doesn't depend on the table's size:
+    // a cursor kept open across `next` calls would have to borrow the statement it reads.
+    // Like `export_all`, it bypasses the cache and stops after the first error.
+    pub fn stream<T: Object>(&self) -> Result<impl Iterator<Item = Result<T>> + '_> {
+        self.ensure_table::<T>()?;
+        let where_clause = format!("{id} > ? ORDER BY {id} LIMIT ?", id = T::SCHEMA.id_column());
+        let mut batch = Vec::new().into_iter();
+        let mut last_id = i64::MIN;
+        let mut done = false;
+        Ok(iter::from_fn(move || loop {
+            if let Some((id, row)) = batch.next() {
+                last_id = ObjectId::into_i64(id);
+                let obj = T::from_row(row);
+                if obj.is_err() {
+                    batch = Vec::new().into_iter();
+                    done = true;
+                }
+                return Some(obj);
+            }
+            if done {
+                return None;
+            }
+
+            let params = [
+                Value::Int64(last_id),
+                Value::Int64(STREAM_BATCH_SIZE as i64),
+            ];
+            match self.inner.select_rows(&T::SCHEMA, &where_clause, &params) {
+                Ok(rows) => {
+                    done = rows.len() < STREAM_BATCH_SIZE;
+                    batch = rows.into_iter();
+                }
+                Err(err) => {
+                    done = true;
+                    return Some(Err(err));
+                }
+            }
+        }))
+    }
+
+    pub fn query<T: Object>(&self) -> Query<'_, T> {
+        Query::new(self)
+    }
+
+    pub fn cache_len(&self) -> usize {
+        self.cache.borrow().len()
+    }
+
+    // Numbers of (clean, modified, removed) cached objects. New objects waiting for their insert
+    // are counted as modified.
+    pub fn cache_state_counts(&self) -> (usize, usize, usize) {
+        let mut counts = (0, 0, 0);
+        for cached in self.cache.borrow().values() {
+            match cached.state.get() {
+                ObjectState::Clean => counts.0 += 1,
+                ObjectState::New | ObjectState::Modified => counts.1 += 1,
+                ObjectState::Removed => counts.2 += 1,
+            }
+        }
+        counts
+    }
+
+    fn is_removed<T: Object>(&self, id: ObjectId) -> bool {
+        self.cache
+            .borrow()
+            .get(&(TypeId::of::<T>(), id))
+            .is_some_and(|cached| cached.state.get() == ObjectState::Removed)
+    }
+
+    // Cached objects are counted as deleted when their removal is applied, the rest right away.
+    fn count_removed<T: Object>(&self, ids: &[ObjectId]) {
+        let cache = self.cache.borrow();
+        let mut uncached = 0;
+        for id in ids {
+            match cache.get(&(TypeId::of::<T>(), *id)) {
+                Some(cached) => {
+                    if cached.state.get() == ObjectState::Clean {
+                        self.remember(TypeId::of::<T>(), cached, || cached.current_row());
+                    }
+                    cached.count();
+                    cached.state.set(ObjectState::Removed);
+                }
+                None => uncached += 1,
+            }
+        }
+        self.changes.set(self.changes.get() + uncached);
+        self.record(|stats| stats.deleted += uncached);
+    }
+
+    fn record(&self, f: impl FnOnce(&mut CommitStats)) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+
+    // Keeps what a cached object was like before its first change since each of the open
+    // savepoints, so that `Savepoint::rollback_to` restores only the objects changed after it.
+    fn remember(&self, type_id: TypeId, cached: &CacheValue, before: impl Fn() -> Before) {
+        for snapshot in self.snapshots.borrow_mut().iter_mut() {
+            if let Entry::Vacant(entry) = snapshot.entry(Rc::as_ptr(&cached.id)) {
+                entry.insert(Remembered {
+                    type_id,
+                    cached: cached.clone(),
+                    before: before(),
+                    counted: cached.counted.get(),
+                });
+            }
+        }
+    }
+
+    // Puts loaded rows into the cache, preferring already cached objects (they may hold
+    // local modifications) and skipping the removed ones.
+    fn hydrate<T: Object>(&self, rows: Vec<(ObjectId, Row<'static>)>) -> Result<Vec<Tx<'_, T>>> {
+        let mut borrowed_cache = self.cache.borrow_mut();
+        let mut res = Vec::with_capacity(rows.len());
+        for (id, row) in rows {
+            let cached = match borrowed_cache.entry((TypeId::of::<T>(), id)) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(self.cache_value(id, T::from_row(row)?)),
+            };
+            self.touch((TypeId::of::<T>(), id), cached);
+
+            if cached.state.get() != ObjectState::Removed {
+                res.push(cached.tx(self));
+            }
+        }
+        self.evict(&mut borrowed_cache);
+
+        Ok(res)
+    }
+
+    // Marks the cached object as the most recently used one.
+    fn touch(&self, key: CacheKey, cached: &CacheValue) {
+        if self.settings.max_cached.is_none() {
+            return;
+        }
+        let mut recently_used = self.recently_used.borrow_mut();
+        recently_used.remove(&cached.used.get());
+        let now = self.clock.get() + 1;
+        self.clock.set(now);
+        cached.used.set(now);
+        recently_used.insert(now, key);
+    }
+
+    // Drops the least recently used objects while there are more of them than the limit allows.
+    // Only clean objects nobody holds a `Tx` of can go: the others have changes to write at
+    // commit, or may get them. Such objects are treated as just used and kept.
+    fn evict(&self, cache: &mut HashMap<CacheKey, CacheValue>) {
+        let limit = match self.settings.max_cached {
+            Some(limit) => limit,
+            None => return,
+        };
+        let mut candidates = self.recently_used.borrow().len();
+        while cache.len() > limit && candidates > 0 {
+            candidates -= 1;
+            let (used, key) = match self.recently_used.borrow_mut().pop_first() {
+                Some(entry) => entry,
+                None => break,
+            };
+            // keys of objects dropped from the cache otherwise, e.g. deleted ones, are left behind
+            let cached = match cache.get(&key) {
+                Some(cached) if cached.used.get() == used => cached,
+                _ => continue,
+            };
+
+            if cached.state.get() == ObjectState::Clean && Rc::strong_count(&cached.stored) == 1 {
+                cache.remove(&key);
+            } else {
+                self.touch(key, cached);
+            }
+        }
+    }
+
+    fn cache_value<T: Object>(&self, id: ObjectId, obj: T) -> CacheValue {
+        CacheValue {
+            type_name: T::SCHEMA.type_name,
+            id: Rc::new(Cell::new(id)),
+            state: Rc::new(Cell::new(ObjectState::Clean)),
+            stored: Rc::new(RefCell::new(obj)),
+            condition: Rc::new(RefCell::new(None)),
+            original: Rc::new(RefCell::new(None)),
+            changes: self.changes.clone(),
+            counted: Rc::new(Cell::new(false)),
+            used: Cell::new(0),
+        }
+    }
+
+    // Inserts the objects staged by `create` with deferred inserts in the order they were created,
+    // and moves them to their real ids.
+    fn insert_pending(&self) -> Result<()> {
+        let mut cache = self.cache.borrow_mut();
+        // objects deleted or rolled back since they were staged are gone from the cache, and
+        // the ones inserted before a failure are clean
+        let pending = self.staged.borrow().clone();
+        for key in pending {
+            let cached = match cache.get(&key) {
+                Some(cached) if cached.state.get() == ObjectState::New => cached.clone(),
+                _ => continue,
+            };
+            let obj = match cached.stored.try_borrow() {
+                Ok(obj) => obj,
+                Err(_) => return Err(Error::borrowed(key.1, cached.type_name)),
+            };
+            let (id, stored_row) = self.inner.insert_row(obj.schema(), &obj.as_row())?;
+            drop(obj);
+            if let Some(row) = stored_row {
+                merge_managed(&mut *cached.stored.borrow_mut(), row)?;
+            }
+            self.record(|stats| stats.inserted += 1);
+
+            cached.id.set(id);
+            cached.state.set(ObjectState::Clean);
+            cached.condition.borrow_mut().take();
+            cache.remove(&key);
+            self.touch((key.0, id), &cached);
+            cache.insert((key.0, id), cached);
+        }
+
+        self.staged.borrow_mut().clear();
+        Ok(())
+    }
+
     fn try_apply(&self) -> Result<()> {
+        self.insert_pending()?;
+        let now = (self.settings.clock)();
         for ((_, id), cached) in self.cache.borrow().iter() {
-            let obj = (*cached.stored).borrow();
+            let obj = match cached.stored.try_borrow() {
+                Ok(obj) => obj,
+                Err(_) => return Err(Error::borrowed(*id, cached.type_name)),
+            };
             match cached.state.get() {
+                ObjectState::Modified if !cached.is_dirty() => (),
                 ObjectState::Modified => {
-                    self.inner.update_row(*id, obj.schema(), &obj.as_row())?;
+                    let condition = cached.condition.borrow();
+                    let condition = condition
+                        .as_ref()
+                        .map(|(column, expected)| (*column, expected));
+                    let mut row = obj.as_row();
+                    let stamped = obj.schema().stamp(&mut row, now);
+                    let updated = self.inner.update_row(*id, obj.schema(), &row, condition)?;
+                    let guard = condition.map(|(column, _)| column).or(obj.schema().version);
+                    self.record(|stats| stats.updated += updated);
+                    if let (Some(column_name), 0) = (guard, updated) {
+                        return Err(Error::ConcurrentModification(Box::new(
+                            ConcurrentModificationError {
+                                object_id: *id,
+                                type_name: obj.schema().type_name,
+                                column_name,
+                            },
+                        )));
+                    }
+
+                    // the object catches up with the stored timestamps and version, the rest of
+                    // its fields are left alone
+                    let mut caught_up = vec![];
+                    if stamped {
+                        for (index, field) in obj.schema().fields.iter().enumerate() {
+                            if field.stamp.is_some_and(|stamp| stamp.on_update) {
+                                caught_up.push((index, row[index].clone().into_owned()));
+                            }
+                        }
+                    }
+                    if let Some(index) = obj.schema().version_index() {
+                        if let Value::Int64(version) = row[index] {
+                            caught_up.push((index, Value::Int64(version + 1)));
+                        }
+                    }
+                    drop(row);
+                    drop(obj);
+                    let mut stored = cached.stored.borrow_mut();
+                    for (index, value) in caught_up {
+                        stored.set_column(index, value)?;
+                    }
                 }
                 ObjectState::Removed => {
                     self.inner.delete_row(*id, obj.schema())?;
+                    self.record(|stats| stats.deleted += 1);
                 }
-                ObjectState::Clean => (),
+                // new objects have been inserted above
+                ObjectState::New | ObjectState::Clean => (),
             }
         }
 
         Ok(())
     }
 
+    // Commits everything done so far and carries on in a fresh underlying transaction, so that
+    // huge writes can be made durable in parts. Atomicity only holds between checkpoints:
+    // a later rollback undoes the changes made after the last checkpoint only. Cached objects
+    // stay cached (deleted ones are dropped), so they are not re-read from the database.
+    pub fn checkpoint(&self) -> Result<()> {
+        let _span = self.enter();
+        self.flush()?;
+        self.inner.commit()?;
+        self.inner.begin()?;
+        self.changes.set(0);
+        for cached in self.cache.borrow().values() {
+            cached.counted.set(false);
+        }
+        // committing has ended the savepoints
+        self.snapshots.borrow_mut().clear();
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!("checkpoint");
+        Ok(())
+    }
+
+    // Starts a nested unit of work which can be undone on its own with `Savepoint::rollback_to`,
+    // leaving the rest of the transaction intact. Changes of cached objects are written first,
+    // so that the savepoint covers them as they are now. Savepoints may be nested.
+    pub fn savepoint(&self) -> Result<Savepoint<'_>> {
+        let _span = self.enter();
+        self.flush()?;
+        let name = format!("orm_savepoint_{}", self.savepoints.get());
+        self.savepoints.set(self.savepoints.get() + 1);
+        self.inner.savepoint(&name)?;
+        let mut snapshots = self.snapshots.borrow_mut();
+        let depth = snapshots.len();
+        snapshots.push(HashMap::new());
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(name, "savepoint");
+        Ok(Savepoint {
+            transaction: self,
+            name,
+            depth,
+            changes: self.changes.get(),
+            stats: self.stats.get(),
+            finished: false,
+        })
+    }
+
+    // Writes the changes of cached objects without committing them, after that all of the cached
+    // objects are clean. Deleted ones are dropped from the cache.
+    fn flush(&self) -> Result<()> {
+        self.try_apply()?;
+        self.cache
+            .borrow_mut()
+            .retain(|_, cached| match cached.state.get() {
+                ObjectState::Removed => false,
+                _ => {
+                    cached.state.set(ObjectState::Clean);
+                    cached.condition.borrow_mut().take();
+                    cached.original.borrow_mut().take();
+                    true
+                }
+            });
+        Ok(())
+    }
+
+    // Brings the objects changed since a savepoint back to what they were when it was started,
+    // after its rollback in storage. Objects created since then become removed.
+    fn restore(&self, snapshot: Snapshot) -> Result<()> {
+        let mut cache = self.cache.borrow_mut();
+        for remembered in snapshot.into_values() {
+            let Remembered {
+                type_id,
+                cached,
+                before,
+                counted,
+            } = remembered;
+            let key = (type_id, cached.id.get());
+            cached.condition.borrow_mut().take();
+            cached.original.borrow_mut().take();
+            let before = match before {
+                Before::Unknown => {
+                    let stored = cached.stored.borrow();
+                    match self.inner.select_row(key.1, stored.schema()) {
+                        Ok(row) => Before::Row(row),
+                        Err(Error::NotFound(_)) => Before::Missing,
+                        Err(err) => return Err(err),
+                    }
+                }
+                before => before,
+            };
+
+            match before {
+                Before::Row(row) => {
+                    cached.stored.borrow_mut().set_row(row)?;
+                    cached.state.set(ObjectState::Clean);
+                    cached.counted.set(counted);
+                    // e.g. deleted and written by a nested savepoint
+                    if let Entry::Vacant(entry) = cache.entry(key) {
+                        self.touch(key, &cached);
+                        entry.insert(cached);
+                    }
+                }
+                _ => {
+                    cached.state.set(ObjectState::Removed);
+                    cache.remove(&key);
+                }
+            }
+        }
+
+        self.evict(&mut cache);
+        Ok(())
+    }
+
     pub fn commit(self) -> Result<()> {
+        self.commit_with_stats().map(|_| ())
+    }
+
+    // Like `commit`, but also tells how many rows the transaction has written, checkpoints
+    // included. Rows written by `execute_raw` are not counted.
+    pub fn commit_with_stats(self) -> Result<CommitStats> {
+        let _span = self.enter();
         self.try_apply()?;
-        self.inner.commit()
+        self.inner.commit()?;
+        self.finished.set(true);
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!("commit");
+        Ok(self.stats.get())
     }
 
     pub fn rollback(self) -> Result<()> {
-        self.inner.rollback()
+        let _span = self.enter();
+        self.inner.rollback()?;
+        self.finished.set(true);
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!("rollback");
+        Ok(())
+    }
+}
+
+// A transaction which is neither committed nor rolled back, e.g. because of an early return,
+// is rolled back: the changes pending in its cache are lost, and so are the rows already written.
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if self.finished.get() {
+            return;
+        }
+
+        let _span = self.enter();
+        // there is no one to report an error to, and the storage rolls back on its own anyway
+        let _ = self.inner.rollback();
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!("rollback on drop");
     }
 }
 
+#[cfg(not(feature = "tracing"))]
+struct NoSpan;
+
+// Keeps or undoes the work done in the transaction since `Transaction::savepoint`. Dropping it
+// keeps the work, as `release` does.
+pub struct Savepoint<'a> {
+    transaction: &'a Transaction<'a>,
+    name: String,
+    // the position of its snapshot in `Transaction::snapshots`
+    depth: usize,
+    // changes counted by the transaction when the savepoint was started
+    changes: usize,
+    stats: CommitStats,
+    finished: bool,
+}
+
+impl Savepoint<'_> {
+    pub fn release(mut self) -> Result<()> {
+        let tx = self.transaction;
+        let _span = tx.enter();
+        self.finished = true;
+        tx.snapshots.borrow_mut().truncate(self.depth);
+        tx.inner.release(&self.name)?;
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(name = self.name, "release savepoint");
+        Ok(())
+    }
+
+    // Undoes the work done since the savepoint, including changes of cached objects: they get
+    // the state they had then, and objects created since then become removed. Fails with
+    // `Borrowed`, undoing nothing, if any of the objects to restore is borrowed.
+    pub fn rollback_to(mut self) -> Result<()> {
+        let tx = self.transaction;
+        let _span = tx.enter();
+        if let Some(snapshot) = tx.snapshots.borrow().get(self.depth) {
+            for Remembered { cached, .. } in snapshot.values() {
+                if cached.stored.try_borrow_mut().is_err() {
+                    return Err(Error::borrowed(cached.id.get(), cached.type_name));
+                }
+            }
+        }
+
+        self.finished = true;
+        let snapshot = {
+            let mut snapshots = tx.snapshots.borrow_mut();
+            let snapshot = snapshots.get_mut(self.depth).map(std::mem::take);
+            snapshots.truncate(self.depth);
+            snapshot.unwrap_or_default()
+        };
+        tx.inner.rollback_to(&self.name)?;
+        tx.inner.release(&self.name)?;
+        tx.restore(snapshot)?;
+        tx.changes.set(self.changes);
+        tx.stats.set(self.stats);
+
+        #[cfg(feature = "tracing")]
+        tracing::debug!(name = self.name, "rollback to savepoint");
+        Ok(())
+    }
+}
+
+impl Drop for Savepoint<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.transaction.snapshots.borrow_mut().truncate(self.depth);
+            // there is no one to report an error to, e.g. if a checkpoint has already ended
+            // the savepoint
+            let _ = self.transaction.inner.release(&self.name);
+        }
+    }
+}
+
+// Numbers of objects created, overwritten and deleted by `Transaction::apply_graph`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GraphChanges {
+    pub inserted: usize,
+    pub updated: usize,
+    pub deleted: usize,
+}
+
+// Numbers of rows written by a transaction, see `Transaction::commit_with_stats`. Upserted rows
+// are counted apart, since it isn't known whether they were inserted or overwritten.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CommitStats {
+    pub inserted: usize,
+    pub updated: usize,
+    pub upserted: usize,
+    pub deleted: usize,
+}
+
 type CacheKey = (TypeId, ObjectId);
 
-struct CacheValue {
+// The objects changed since a savepoint was started, keyed by their id cells since deferred
+// inserts change the ids, see `Transaction::remember`.
+type Snapshot = HashMap<*const Cell<ObjectId>, Remembered>;
+
+struct Remembered {
+    type_id: TypeId,
+    cached: CacheValue,
+    before: Before,
+    // see `CacheValue::count`
+    counted: bool,
+}
+
+// What an object changed since a savepoint was like when the savepoint was started.
+enum Before {
+    Row(Row<'static>),
+    // created since then
+    Missing,
+    // upserted since then, so its row may or may not have been stored before
+    Unknown,
+}
+
+// Fills in the fields of a just inserted object which the database has set (defaults,
+// generated columns) from its stored row, the rest of the object is left as it was built.
+fn merge_managed(obj: &mut dyn Store, row: Row<'static>) -> Result<()> {
+    let managed = obj.schema().fields.iter().map(|field| field.managed);
+    for (index, (managed, value)) in managed.zip(row).enumerate() {
+        if managed {
+            obj.set_column(index, value)?;
+        }
+    }
+    Ok(())
+}
+
+// The value of the object's `#[primary_key]` field, if it has one.
+fn primary_key<T: Object>(obj: &T) -> Option<ObjectId> {
+    let index = T::SCHEMA
+        .column_names()
+        .position(|column| Some(column) == T::SCHEMA.primary_key)?;
+    match obj.as_row()[index] {
+        Value::Int64(key) => Some(ObjectId::from(key)),
+        _ => None,
+    }
+}
+
+// Sets the `#[created_at]` and `#[updated_at]` fields of a new object to `now`.
+fn stamp_new<T: Object>(mut obj: T, now: SystemTime) -> Result<T> {
+    for (index, field) in T::SCHEMA.fields.iter().enumerate() {
+        if let Some(stamp) = field.stamp {
+            obj.set_column(index, (stamp.value)(now))?;
+        }
+    }
+    Ok(obj)
+}
+
+// number of rows `stream` reads at once
+const STREAM_BATCH_SIZE: usize = 1000;
+
+// A column and the value it is expected to hold in storage when the object is updated.
+type Condition = Option<(&'static str, Value<'static>)>;
+
+#[derive(Clone)]
+pub(crate) struct CacheValue {
+    type_name: &'static str,
+    // provisional until a deferred insert, see `Connection::defer_inserts`
+    id: Rc<Cell<ObjectId>>,
     state: Rc<Cell<ObjectState>>,
     stored: Rc<RefCell<dyn Store>>,
+    condition: Rc<RefCell<Condition>>,
+    // the row of a modified object as it was before the modification
+    original: Rc<RefCell<Option<Row<'static>>>>,
+    changes: Rc<Cell<usize>>,
+    // whether the object is among the `changes` yet
+    counted: Rc<Cell<bool>>,
+    // when the object was last used, see `Transaction::touch`
+    used: Cell<u64>,
+}
+
+impl CacheValue {
+    pub(crate) fn tx<'a, T>(&self, transaction: &'a Transaction<'a>) -> Tx<'a, T> {
+        Tx {
+            id: self.id.clone(),
+            state: self.state.clone(),
+            obj: self.stored.clone(),
+            condition: self.condition.clone(),
+            original: self.original.clone(),
+            changes: self.changes.clone(),
+            counted: self.counted.clone(),
+            transaction,
+
+            _refers_object: PhantomData,
+        }
+    }
+
+    // Objects are counted as changed once until the next checkpoint, however many times they
+    // are written, see `Transaction::check_size`.
+    fn count(&self) {
+        if !self.counted.replace(true) {
+            self.changes.set(self.changes.get() + 1);
+        }
+    }
+
+    fn current_row(&self) -> Before {
+        let stored = self.stored.borrow();
+        Before::Row(stored.as_row().into_iter().map(Value::into_owned).collect())
+    }
+
+    // Whether committing would write anything for the object. A modified object whose row is
+    // the same as before the modification (e.g. borrowed mutably but left as is) is not, unless
+    // its update is conditional.
+    fn is_dirty(&self) -> bool {
+        match self.state.get() {
+            ObjectState::Clean => false,
+            ObjectState::New | ObjectState::Removed => true,
+            ObjectState::Modified => {
+                self.condition.borrow().is_some()
+                    || match &*self.original.borrow() {
+                        Some(original) => *original != self.stored.borrow().as_row(),
+                        None => true,
+                    }
+            }
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum ObjectState {
+    // created with deferred inserts and not inserted yet
+    New,
     Clean,
     Modified,
     Removed,
@@ -134,23 +1561,34 @@ pub enum ObjectState {
 
 #[derive(Clone)]
 pub struct Tx<'a, T> {
+    id: Rc<Cell<ObjectId>>,
     state: Rc<Cell<ObjectState>>,
     obj: Rc<RefCell<dyn Store>>,
-    id: ObjectId,
+    condition: Rc<RefCell<Condition>>,
+    original: Rc<RefCell<Option<Row<'static>>>>,
+    changes: Rc<Cell<usize>>,
+    counted: Rc<Cell<bool>>,
+    transaction: &'a Transaction<'a>,
 
-    _lifetime: PhantomData<&'a Transaction<'a>>,
     _refers_object: PhantomData<Rc<RefCell<T>>>,
 }
 
 impl<'a, T: Any> Tx<'a, T> {
     pub fn id(&self) -> ObjectId {
-        self.id
+        self.id.get()
     }
 
     pub fn state(&self) -> ObjectState {
         self.state.get()
     }
 
+    // Whatever identifies the row in storage, for interoperating with other tools. A table is
+    // keyed either by the synthetic `id` or by its `#[primary_key]` field, which is an integer as
+    // well: both are aliases of SQLite's rowid, and the object's id holds the key.
+    pub fn storage_key(&self) -> Value<'static> {
+        Value::Int64(self.id().into_i64())
+    }
+
     pub fn borrow(&self) -> Ref<'_, T> {
         if self.state() == ObjectState::Removed {
             panic!("cannot borrow a removed object");
@@ -164,16 +1602,145 @@ impl<'a, T: Any> Tx<'a, T> {
         if self.state() == ObjectState::Removed {
             panic!("cannot borrow a removed object");
         }
-        self.state.set(ObjectState::Modified);
+        self.set_state(ObjectState::Modified);
         RefMut::map(self.obj.borrow_mut(), |stored| {
             stored.as_any_mut().downcast_mut::<T>().unwrap()
         })
     }
 
-    pub fn delete(self) {
-        match self.obj.try_borrow_mut() {
-            Ok(_) => self.state.set(ObjectState::Removed),
-            Err(_) => panic!("cannot delete a borrowed object"),
+    // The value of the `#[label]` field, or the type name with the id if there is none.
+    pub fn label(&self) -> String {
+        let obj = (*self.obj).borrow();
+        let schema = obj.schema();
+        let index = schema
+            .label
+            .and_then(|label| schema.column_names().position(|column| column == label));
+        match index {
+            Some(index) => obj.as_row()[index].to_string(),
+            None => format!("{} {}", schema.type_name, self.id()),
         }
     }
+
+    pub fn ptr_eq(&self, other: &Tx<'_, T>) -> bool {
+        Rc::ptr_eq(&self.obj, &other.obj)
+    }
+
+    fn cache_value(&self, type_name: &'static str) -> CacheValue {
+        CacheValue {
+            type_name,
+            id: self.id.clone(),
+            state: self.state.clone(),
+            stored: self.obj.clone(),
+            condition: self.condition.clone(),
+            original: self.original.clone(),
+            changes: self.changes.clone(),
+            counted: self.counted.clone(),
+            used: Cell::new(0),
+        }
+    }
+
+    // Objects are counted as changed when they stop being clean, unless they already are (e.g.
+    // created ones). The row of an object about to be modified is kept to tell whether it has
+    // actually changed.
+    fn set_state(&self, state: ObjectState) {
+        match self.state.get() {
+            ObjectState::Clean => {
+                let cached = self.cache_value((*self.obj).borrow().schema().type_name);
+                self.transaction
+                    .remember(TypeId::of::<T>(), &cached, || cached.current_row());
+                cached.count();
+                if state == ObjectState::Modified {
+                    let obj = self.obj.borrow();
+                    let row = obj.as_row().into_iter().map(Value::into_owned).collect();
+                    *self.original.borrow_mut() = Some(row);
+                }
+            }
+            // a new object is inserted as it is by then
+            ObjectState::New if state == ObjectState::Modified => return,
+            _ => (),
+        }
+        self.state.set(state);
+    }
+}
+
+impl<'a, T: Object> Tx<'a, T> {
+    pub(crate) fn cached(&self) -> CacheValue {
+        self.cache_value(T::SCHEMA.type_name)
+    }
+
+    // See `CacheValue::is_dirty`.
+    pub fn is_dirty(&self) -> bool {
+        self.cached().is_dirty()
+    }
+
+    // Only marks the object as removed, the row is deleted at commit. The object may still be
+    // borrowed meanwhile, but can't be borrowed again. A new object which is not inserted yet is
+    // just forgotten.
+    pub fn delete(self) -> Result<()> {
+        match self.state() {
+            ObjectState::Removed => return Err(Error::not_found(self.id(), T::SCHEMA.type_name)),
+            ObjectState::New => {
+                let key = (TypeId::of::<T>(), self.id());
+                self.transaction.cache.borrow_mut().remove(&key);
+            }
+            _ => (),
+        }
+        self.set_state(ObjectState::Removed);
+        Ok(())
+    }
+
+    // A detached copy of the object as it is now (including uncommitted modifications), which
+    // may outlive the transaction.
+    pub fn into_owned(self) -> Result<T>
+    where
+        T: Clone,
+    {
+        if self.state() == ObjectState::Removed {
+            return Err(Error::not_found(self.id(), T::SCHEMA.type_name));
+        }
+        let obj = self.borrow().clone();
+        Ok(obj)
+    }
+
+    // Re-reads the object from storage, discarding its local modifications, e.g. to see the
+    // changes committed by another connection since it was loaded. An object staged by a deferred
+    // insert has no row yet, so the staged objects are inserted first.
+    pub fn reload(&self) -> Result<()> {
+        match self.state() {
+            ObjectState::Removed => return Err(Error::not_found(self.id(), T::SCHEMA.type_name)),
+            ObjectState::New => self.transaction.insert_pending()?,
+            _ => (),
+        }
+        if self.obj.try_borrow_mut().is_err() {
+            return Err(Error::borrowed(self.id(), T::SCHEMA.type_name));
+        }
+
+        let row = self.transaction.inner.select_row(self.id(), &T::SCHEMA)?;
+        if self.state() == ObjectState::Clean {
+            let cached = self.cached();
+            self.transaction
+                .remember(TypeId::of::<T>(), &cached, || cached.current_row());
+        }
+        self.obj.borrow_mut().set_row(row)?;
+        *self.condition.borrow_mut() = None;
+        *self.original.borrow_mut() = None;
+        self.state.set(ObjectState::Clean);
+        Ok(())
+    }
+
+    // Makes the object's update at commit conditional: it is applied only if `column` still
+    // holds `expected` in storage, otherwise commit fails with `ConcurrentModification`.
+    pub fn update_if(&self, column: &str, expected: Value) -> Result<()> {
+        if self.state() == ObjectState::Removed {
+            return Err(Error::not_found(self.id(), T::SCHEMA.type_name));
+        }
+
+        let field = T::SCHEMA
+            .field(column)
+            .ok_or_else(|| Error::unknown_column(&T::SCHEMA, column))?;
+
+        *self.condition.borrow_mut() = Some((field.column_name, expected.into_owned()));
+        self.set_state(ObjectState::Modified);
+        Ok(())
+    }
 }
@@ -1,11 +1,11 @@
-use orm::{data::DataType, Connection, Object, ObjectId, ObjectState, Result, Tx};
+use orm::{data::DataType, BelongsTo, Connection, Object, ObjectId, ObjectState, Op, Result, Tx};
 
 use rusqlite::params;
 use tempfile::NamedTempFile;
 
 ////////////////////////////////////////////////////////////////////////////////
 
-#[derive(Object, PartialEq, Clone, Debug)]
+#[derive(Object, PartialEq, Clone, Debug, Default)]
 struct User {
     pub name: String,
     pub picture: Vec<u8>,
@@ -113,7 +113,7 @@ fn delete() {
     let tx_user = tx.get::<User>(user_id).unwrap();
     let tx_user_2 = tx_user.clone();
 
-    tx_user.delete();
+    tx_user.delete().unwrap();
     assert!(matches!(tx_user_2.state(), ObjectState::Removed));
 
     let res = tx.get::<User>(user_id);
@@ -143,7 +143,7 @@ fn create_delete() {
     let tx_user_2 = tx_user.clone();
     let user_id = tx_user.id();
 
-    tx_user.delete();
+    tx_user.delete().unwrap();
     assert!(matches!(tx_user_2.state(), ObjectState::Removed));
 
     let res = tx.get::<User>(user_id);
@@ -194,7 +194,7 @@ fn borrow_created_deleted() {
         .unwrap();
     let tx_user_2 = tx.get::<User>(tx_user.id()).unwrap();
 
-    tx_user.delete();
+    tx_user.delete().unwrap();
     tx_user_2.borrow();
 }
 
@@ -220,12 +220,11 @@ fn borrow_deleted() {
     let tx_user_1 = tx.get::<User>(user_id).unwrap();
     let tx_user_2 = tx.get::<User>(user_id).unwrap();
 
-    tx_user_1.delete();
+    tx_user_1.delete().unwrap();
     tx_user_2.borrow();
 }
 
 #[test]
-#[should_panic(expected = "cannot delete a borrowed object")]
 fn delete_borrowed() {
     let mut conn = Connection::open_in_memory().unwrap();
 
@@ -241,8 +240,20 @@ fn delete_borrowed() {
         .unwrap();
     let tx_user_2 = tx.get::<User>(tx_user.id()).unwrap();
 
-    let _r1 = tx_user.borrow();
-    tx_user_2.delete();
+    let user = tx_user.borrow();
+    tx_user_2.clone().delete().unwrap();
+    // the borrow stays valid, the row is deleted at commit
+    assert_eq!(user.name, "James");
+    drop(user);
+    assert!(matches!(tx_user.state(), ObjectState::Removed));
+
+    let res = tx_user_2.delete();
+    assert!(matches!(res, Err(orm::Error::NotFound(_))));
+    let user_id = tx_user.id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_not_found(tx.get::<User>(user_id), user_id, "User");
 }
 
 #[test]
@@ -492,7 +503,7 @@ fn sql_injection() {
 
         let tx = conn.new_transaction().unwrap();
 
-        tx.get::<User>(user_id).unwrap().delete();
+        tx.get::<User>(user_id).unwrap().delete().unwrap();
         tx.commit().unwrap();
     }
 }
@@ -667,6 +678,58 @@ fn rollback() {
     assert_eq!(tx_user.borrow().balance, 220.);
 }
 
+#[test]
+fn rollback_on_drop() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+    tx.commit().unwrap();
+
+    // an early return leaves the transaction unfinished
+    let import = |conn: &mut Connection| -> Result<()> {
+        let tx = conn.new_transaction()?;
+        tx.get::<Person>(ObjectId::from(1))?.borrow_mut().age = 31;
+        tx.get::<Person>(ObjectId::from(2))?.delete()?;
+        create_people(&tx);
+        tx.get::<Person>(ObjectId::from(100))?;
+        tx.commit()
+    };
+    assert!(matches!(import(&mut conn), Err(orm::Error::NotFound(_))));
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.count::<Person>().unwrap(), 5);
+    assert_eq!(
+        tx.get::<Person>(ObjectId::from(1)).unwrap().borrow().age,
+        30
+    );
+    assert!(tx.exists::<Person>(ObjectId::from(2)).unwrap());
+}
+
+#[test]
+fn flush_borrowed_object() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+    let ivan = tx.get::<Person>(ObjectId::from(1)).unwrap();
+
+    // writing an object which is being modified is an error rather than a panic
+    let mut borrowed = ivan.borrow_mut();
+    borrowed.age = 31;
+    match tx.checkpoint() {
+        Err(orm::Error::Borrowed(err)) => {
+            assert_eq!(err.object_id, ObjectId::from(1));
+            assert_eq!(err.type_name, "Person");
+        }
+        res => panic!("expected Error::Borrowed, got {}", fmt_res(&res)),
+    }
+    drop(borrowed);
+    tx.checkpoint().unwrap();
+    assert_eq!(
+        tx.get_field::<Person>(ivan.id(), "age").unwrap(),
+        orm::data::Value::Int64(31)
+    );
+}
+
 #[cfg(feature = "test-lifetimes-create")]
 #[test]
 fn lifetimes_create() {
@@ -694,3 +757,5223 @@ fn lifetimes_get() {
 
     eprintln!("is_tall: {}", order.borrow().is_tall);
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object, PartialEq, Clone, Debug)]
+#[discriminator("kind")]
+enum Event {
+    Click {
+        x: i64,
+        y: i64,
+    },
+    Key {
+        code: i64,
+        #[column_name("key_name")]
+        name: String,
+    },
+    Scroll(#[column_name("delta")] f64),
+    Close,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn enum_variants() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+
+    let events = [
+        Event::Click { x: 10, y: -20 },
+        Event::Key {
+            code: 13,
+            name: "Enter".into(),
+        },
+        Event::Scroll(0.5),
+        Event::Close,
+    ];
+
+    let mut conn = Connection::open_sqlite_file(&path).unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let ids = events
+        .iter()
+        .map(|event| tx.create(event.clone()).unwrap().id())
+        .collect::<Vec<_>>();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    for (event, id) in events.iter().zip(ids.iter()) {
+        assert_eq!(*tx.get::<Event>(*id).unwrap().borrow(), *event);
+    }
+
+    let tx_event = tx.get::<Event>(ids[0]).unwrap();
+    *tx_event.borrow_mut() = Event::Key {
+        code: 27,
+        name: "Escape".into(),
+    };
+    tx.commit().unwrap();
+
+    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
+    let (kind, x, code): (String, Option<i64>, Option<i64>) = sqlite_conn
+        .query_row(
+            "SELECT kind, x, code FROM Event WHERE id = ?",
+            [ids[0].into_i64()],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap();
+    assert_eq!(kind, "Key");
+    assert_eq!(x, None);
+    assert_eq!(code, Some(27));
+}
+
+#[test]
+fn reload_all() {
+    let mut conn = Connection::open_in_memory().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let user = User {
+        name: "Mike".into(),
+        picture: b"a8s7dyfvbnoweg"[..].into(),
+        visits: 5,
+        balance: 50.,
+        is_admin: false,
+    };
+    let clean = tx.create(user.clone()).unwrap();
+    let modified = tx.create(user.clone()).unwrap();
+    modified.borrow_mut().visits = 6;
+
+    let updated = tx
+        .execute_raw(
+            "UPDATE User SET visits = visits + ?",
+            &[orm::data::Value::Int64(100)],
+        )
+        .unwrap();
+    assert_eq!(updated, 2);
+    assert_eq!(clean.borrow().visits, 5);
+
+    // a borrowed object stops the reload of all of them
+    let borrowed = clean.borrow();
+    match tx.reload_all() {
+        Err(orm::Error::Borrowed(err)) => assert_eq!(err.object_id, clean.id()),
+        res => panic!("expected Error::Borrowed, got {}", fmt_res(&res)),
+    }
+    assert_eq!(borrowed.visits, 5);
+    drop(borrowed);
+
+    tx.reload_all().unwrap();
+    assert_eq!(clean.borrow().visits, 105);
+    assert_eq!(modified.borrow().visits, 6);
+
+    let modified_id = modified.id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.get::<User>(modified_id).unwrap().borrow().visits, 6);
+}
+
+#[test]
+fn reload() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let ivan = tx.get::<Person>(ObjectId::from(1)).unwrap();
+    let same_ivan = tx.get::<Person>(ObjectId::from(1)).unwrap();
+    ivan.borrow_mut().first_name = "Ivan Jr.".into();
+    tx.execute_raw("UPDATE Person SET age = age + 1", &[])
+        .unwrap();
+
+    // local modifications are discarded in favor of the stored row
+    ivan.reload().unwrap();
+    assert!(ivan.state() == ObjectState::Clean);
+    assert!(ivan.ptr_eq(&same_ivan));
+    assert_eq!(same_ivan.borrow().first_name, "Ivan");
+    assert_eq!(same_ivan.borrow().age, 31);
+
+    let anna = tx.get::<Person>(ObjectId::from(2)).unwrap();
+    tx.execute_raw("DELETE FROM Person WHERE id = 2", &[])
+        .unwrap();
+    assert!(matches!(anna.reload(), Err(orm::Error::NotFound(_))));
+    let boris = tx.get::<Person>(ObjectId::from(3)).unwrap();
+    boris.clone().delete().unwrap();
+    assert!(matches!(boris.reload(), Err(orm::Error::NotFound(_))));
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(
+        tx.get::<Person>(ObjectId::from(1))
+            .unwrap()
+            .borrow()
+            .first_name,
+        "Ivan"
+    );
+    assert_eq!(tx.count::<Person>().unwrap(), 3);
+
+    // a borrowed object can't be overwritten
+    let ivan = tx.get::<Person>(ObjectId::from(1)).unwrap();
+    let borrowed = ivan.borrow();
+    match ivan.reload() {
+        Err(orm::Error::Borrowed(err)) => assert_eq!(err.object_id, ObjectId::from(1)),
+        res => panic!("expected Error::Borrowed, got {}", fmt_res(&res)),
+    }
+    drop(borrowed);
+
+    // the object as it was before the reload comes back with the savepoint
+    let savepoint = tx.savepoint().unwrap();
+    tx.execute_raw("UPDATE Person SET age = 50 WHERE id = 1", &[])
+        .unwrap();
+    ivan.reload().unwrap();
+    assert_eq!(ivan.borrow().age, 50);
+    savepoint.rollback_to().unwrap();
+    assert_eq!(ivan.borrow().age, 31);
+    assert!(ivan.state() == ObjectState::Clean);
+    tx.rollback().unwrap();
+
+    // an object staged by a deferred insert is inserted to be reloaded
+    conn.defer_inserts(true);
+    let tx = conn.new_transaction().unwrap();
+    let vera = tx
+        .create(Person {
+            first_name: "Vera".into(),
+            last_name: "Orlova".into(),
+            age: 28,
+        })
+        .unwrap();
+    assert!(vera.state() == ObjectState::New);
+    vera.reload().unwrap();
+    assert!(vera.state() == ObjectState::Clean);
+    assert!(tx.exists::<Person>(vera.id()).unwrap());
+    assert_eq!(vera.borrow().first_name, "Vera");
+}
+
+#[test]
+fn savepoints() {
+    let person = |first_name: &str| Person {
+        first_name: first_name.into(),
+        last_name: "Orlova".into(),
+        age: 28,
+    };
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let ivan = tx.get::<Person>(ObjectId::from(1)).unwrap();
+    ivan.borrow_mut().age = 99;
+    let savepoint = tx.savepoint().unwrap();
+    // pending changes are written by the savepoint
+    assert!(ivan.state() == ObjectState::Clean);
+
+    ivan.borrow_mut().age = 100;
+    let vera = tx.create(person("Vera")).unwrap();
+    tx.get::<Person>(ObjectId::from(2))
+        .unwrap()
+        .delete()
+        .unwrap();
+    tx.execute_raw("UPDATE Person SET last_name = 'Smirnov' WHERE id = 3", &[])
+        .unwrap();
+    savepoint.rollback_to().unwrap();
+
+    assert!(ivan.state() == ObjectState::Clean);
+    assert_eq!(ivan.borrow().age, 99);
+    assert!(vera.state() == ObjectState::Removed);
+    assert!(tx.exists::<Person>(ObjectId::from(2)).unwrap());
+    assert_eq!(
+        tx.count_where::<Person>(
+            "last_name",
+            Op::Eq,
+            orm::data::Value::String("Smirnov".into())
+        )
+        .unwrap(),
+        0
+    );
+
+    // nested savepoints, the outer one released on drop
+    {
+        let _outer = tx.savepoint().unwrap();
+        tx.create(person("Olga")).unwrap();
+        let inner = tx.savepoint().unwrap();
+        tx.create(person("Nina")).unwrap();
+        inner.rollback_to().unwrap();
+    }
+    let savepoint = tx.savepoint().unwrap();
+    tx.create(person("Lena")).unwrap();
+    savepoint.release().unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(
+        tx.get::<Person>(ObjectId::from(1)).unwrap().borrow().age,
+        99
+    );
+    let names = tx
+        .export_all::<Person>()
+        .unwrap()
+        .into_iter()
+        .skip(5)
+        .map(|person| person.first_name)
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["Olga", "Lena"]);
+}
+
+#[test]
+fn savepoint_restores_changed_objects() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let ivan = tx.get::<Person>(ObjectId::from(1)).unwrap();
+    let anna = tx.get::<Person>(ObjectId::from(2)).unwrap();
+
+    // objects left alone since the savepoint may stay borrowed
+    let savepoint = tx.savepoint().unwrap();
+    ivan.borrow_mut().age = 100;
+    let anna_ref = anna.borrow();
+    savepoint.rollback_to().unwrap();
+    assert_eq!(ivan.borrow().age, 30);
+    assert_eq!(anna_ref.age, 25);
+    drop(anna_ref);
+
+    // nothing is undone while a changed object is borrowed
+    let savepoint = tx.savepoint().unwrap();
+    ivan.borrow_mut().age = 100;
+    let ivan_ref = ivan.borrow();
+    let res = savepoint.rollback_to();
+    match &res {
+        Err(orm::Error::Borrowed(err)) => {
+            assert_eq!(err.object_id, ObjectId::from(1));
+            assert_eq!(err.type_name, "Person");
+        }
+        res => panic!("expected Error::Borrowed, got {}", fmt_res(res)),
+    }
+    assert_eq!(ivan_ref.age, 100);
+    drop(ivan_ref);
+
+    // an object deleted and written by a nested savepoint is cached again
+    let savepoint = tx.savepoint().unwrap();
+    anna.clone().delete().unwrap();
+    tx.savepoint().unwrap().release().unwrap();
+    assert_eq!(tx.cache_len(), 1);
+    savepoint.rollback_to().unwrap();
+    assert!(anna.state() == ObjectState::Clean);
+    assert!(anna.ptr_eq(&tx.get::<Person>(ObjectId::from(2)).unwrap()));
+
+    // the row is restored, fields which are not stored are kept
+    let memo = tx
+        .create(Memo {
+            text: "a".into(),
+            revision: 0,
+            cursor: 0,
+        })
+        .unwrap();
+    let savepoint = tx.savepoint().unwrap();
+    memo.borrow_mut().text = "ab".into();
+    memo.borrow_mut().cursor = 7;
+    savepoint.rollback_to().unwrap();
+    assert_eq!(memo.borrow().text, "a");
+    assert_eq!(memo.borrow().cursor, 7);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(
+        tx.get::<Person>(ObjectId::from(1)).unwrap().borrow().age,
+        100
+    );
+}
+
+#[test]
+fn unexpected_type_value() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+
+    let mut orm_conn = Connection::open_sqlite_file(&path).unwrap();
+    let tx = orm_conn.new_transaction().unwrap();
+    let user_id = tx
+        .create(User {
+            name: "Carl".into(),
+            picture: b"0a9sd8f7g"[..].into(),
+            visits: 15,
+            balance: 12.5,
+            is_admin: false,
+        })
+        .unwrap()
+        .id();
+    tx.commit().unwrap();
+
+    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
+    sqlite_conn
+        .execute(
+            "UPDATE User SET visits = ? WHERE id = ?",
+            params!["N/A", user_id.into_i64()],
+        )
+        .unwrap();
+    sqlite_conn.close().unwrap();
+
+    let tx = orm_conn.new_transaction().unwrap();
+    match tx.get::<User>(user_id) {
+        Err(orm::Error::UnexpectedType(err)) => {
+            assert_eq!(err.attr_name, "visits");
+            assert_eq!(err.expected_type, DataType::Int64);
+            assert_eq!(err.got_type, "Text");
+            assert_eq!(err.got_value.as_deref(), Some("'N/A'"));
+            assert!(err.to_string().contains("got Text 'N/A'"));
+        }
+        res => panic!(
+            "expected Error::UnexpectedType at get(), got {}",
+            fmt_res(&res),
+        ),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object)]
+#[trigger(
+    "AFTER INSERT ON Visit BEGIN \
+        UPDATE visit_counter SET total = total + NEW.weight; \
+    END"
+)]
+struct Visit {
+    weight: i64,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn triggers() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+
+    let mut conn = Connection::open_sqlite_file(&path).unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.execute_raw("CREATE TABLE visit_counter (total BIGINT)", &[])
+        .unwrap();
+    tx.execute_raw("INSERT INTO visit_counter VALUES (0)", &[])
+        .unwrap();
+    for weight in 1..=3 {
+        tx.create(Visit { weight }).unwrap();
+    }
+    tx.commit().unwrap();
+
+    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
+    let total: i64 = sqlite_conn
+        .query_row("SELECT total FROM visit_counter", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(total, 6);
+
+    let tx = conn.new_transaction().unwrap();
+    tx.drop_table::<Visit>().unwrap();
+    tx.commit().unwrap();
+
+    let objects: i64 = sqlite_conn
+        .query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE tbl_name = 'Visit'",
+            [],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(objects, 0);
+}
+
+#[test]
+fn find_where_raw() {
+    let mut conn = Connection::open_in_memory().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let names = ["Alice", "ALICE", "Bob", "alice"];
+    let mut users = vec![];
+    for (visits, name) in names.iter().enumerate() {
+        users.push(
+            tx.create(User {
+                name: name.to_string(),
+                picture: b"sdv89ywerg"[..].into(),
+                visits: visits as i64,
+                balance: 0.,
+                is_admin: false,
+            })
+            .unwrap(),
+        );
+    }
+    users[0].borrow_mut().is_admin = true;
+    users[3].clone().delete().unwrap();
+
+    let found = tx
+        .find_where_raw::<User>(
+            "LOWER(name) = ? ORDER BY visits",
+            &[orm::data::Value::String("alice".into())],
+        )
+        .unwrap();
+    assert_eq!(
+        found.iter().map(|user| user.id()).collect::<Vec<_>>(),
+        vec![users[0].id(), users[1].id()]
+    );
+    assert!(found[0].borrow().is_admin);
+}
+
+#[test]
+fn object_id_conversions() {
+    assert_eq!(i64::from(ObjectId::from(-5)), -5);
+    assert_eq!(i64::from(ObjectId::from(i64::MIN)), i64::MIN);
+
+    assert_eq!(ObjectId::try_from(0u64).unwrap(), ObjectId(0));
+    assert_eq!(
+        ObjectId::try_from(i64::MAX as u64).unwrap(),
+        ObjectId(i64::MAX)
+    );
+    assert!(ObjectId::try_from(i64::MAX as u64 + 1).is_err());
+    assert!(ObjectId::try_from(u64::MAX).is_err());
+
+    let id = ObjectId(42);
+    let raw: i64 = id.into();
+    assert_eq!(raw, id.into_i64());
+    assert_eq!(id, ObjectId(42));
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Post {
+    author: BelongsTo<User>,
+    title: String,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn belongs_to_lazy() {
+    let mut conn = Connection::open_in_memory().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let author_id = tx
+        .create(User {
+            name: "Leo".into(),
+            picture: b"asdfg9087"[..].into(),
+            visits: 1,
+            balance: 0.,
+            is_admin: false,
+        })
+        .unwrap()
+        .id();
+    let post_id = tx
+        .create(Post {
+            author: author_id.into(),
+            title: "Hello".into(),
+        })
+        .unwrap()
+        .id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let post = tx.get::<Post>(post_id).unwrap();
+    let first = post.borrow().author.parent(&tx).unwrap();
+    assert_eq!(first.borrow().name, "Leo");
+    assert!(first.ptr_eq(&tx.get::<User>(author_id).unwrap()));
+
+    // The parent is memoized, so the second call doesn't need the table at all.
+    tx.execute_raw("DROP TABLE User", &[]).unwrap();
+    let second = post.borrow().author.parent(&tx).unwrap();
+    assert!(first.ptr_eq(&second));
+    drop((first, second, post));
+    tx.rollback().unwrap();
+
+    // Memoized parents never leak into another transaction.
+    let author = BelongsTo::<User>::new(author_id);
+    let tx = conn.new_transaction().unwrap();
+    author.parent(&tx).unwrap().borrow_mut().visits = 2;
+    tx.rollback().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let parent = author.parent(&tx).unwrap();
+    assert_eq!(parent.borrow().visits, 1);
+    assert!(parent.ptr_eq(&tx.get::<User>(author_id).unwrap()));
+
+    parent.delete().unwrap();
+    assert!(matches!(author.parent(&tx), Err(orm::Error::NotFound(_))));
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Chapter {
+    #[belongs_to(User)]
+    author: BelongsTo<User>,
+    // a type may refer to itself
+    #[belongs_to(Chapter)]
+    previous: Option<i64>,
+}
+
+#[test]
+fn foreign_keys() {
+    use orm::Object;
+
+    assert_eq!(
+        Chapter::SCHEMA.to_create_sql(),
+        "CREATE TABLE Chapter (id INTEGER PRIMARY KEY AUTOINCREMENT, \
+         author BIGINT REFERENCES User(id), previous BIGINT REFERENCES Chapter(id))"
+    );
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let author = tx.create(User::default()).unwrap().id();
+    let first = tx
+        .create(Chapter {
+            author: author.into(),
+            previous: None,
+        })
+        .unwrap()
+        .id();
+    tx.create(Chapter {
+        author: author.into(),
+        previous: Some(first.into_i64()),
+    })
+    .unwrap();
+
+    let res = tx.create(Chapter {
+        author: ObjectId::from(42).into(),
+        previous: None,
+    });
+    match res {
+        Err(orm::Error::ForeignKeyViolation(err)) => {
+            assert_eq!(err.type_name, Some("Chapter"));
+            assert_eq!(err.table_name, Some("Chapter"));
+        }
+        res => panic!("expected Error::ForeignKeyViolation, got {}", fmt_res(&res)),
+    }
+    tx.commit().unwrap();
+
+    // the author still has chapters
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<User>(author).unwrap().delete().unwrap();
+    assert!(matches!(
+        tx.commit(),
+        Err(orm::Error::ForeignKeyViolation(_))
+    ));
+
+    // keys can still be left unchecked
+    let mut conn = Connection::builder()
+        .foreign_keys(false)
+        .open_in_memory()
+        .unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.create(Chapter {
+        author: ObjectId::from(42).into(),
+        previous: Some(42),
+    })
+    .unwrap();
+    tx.commit().unwrap();
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object)]
+struct Comment {
+    text: String,
+    #[default("CURRENT_TIMESTAMP")]
+    created_at: String,
+    #[default("10")]
+    rating: i64,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn default_columns() {
+    let mut conn = Connection::open_in_memory().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let comment = tx
+        .create(Comment {
+            text: "First!".into(),
+            created_at: String::new(),
+            rating: -1,
+        })
+        .unwrap();
+    assert_eq!(comment.borrow().text, "First!");
+    assert_eq!(comment.borrow().rating, 10);
+    assert_eq!(
+        comment.borrow().created_at.len(),
+        "YYYY-MM-DD HH:MM:SS".len()
+    );
+
+    let comment_id = comment.id();
+    let created_at = comment.borrow().created_at.clone();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let comment = tx.get::<Comment>(comment_id).unwrap();
+    assert_eq!(comment.borrow().created_at, created_at);
+    assert_eq!(comment.borrow().rating, 10);
+}
+
+#[derive(Object)]
+struct Review {
+    text: String,
+    #[default("10")]
+    rating: i64,
+    #[skip]
+    draft: Option<String>,
+}
+
+#[test]
+fn default_columns_keep_skipped_fields() {
+    let review = |text: &str| Review {
+        text: text.into(),
+        rating: -1,
+        draft: Some(format!("{}...", text)),
+    };
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+
+    // only the columns filled in by the database are taken from the stored row
+    let created = tx.create(review("Good")).unwrap();
+    assert_eq!(created.borrow().rating, 10);
+    assert_eq!(created.borrow().draft.as_deref(), Some("Good..."));
+    let created = tx.create_many(vec![review("Bad")]).unwrap();
+    assert_eq!(created[0].borrow().rating, 10);
+    assert_eq!(created[0].borrow().draft.as_deref(), Some("Bad..."));
+}
+
+#[test]
+fn shared_memory() {
+    let mut conn_one = Connection::open_shared_memory("shared_memory_test").unwrap();
+    let mut conn_two = Connection::open_shared_memory("shared_memory_test").unwrap();
+    let mut conn_other = Connection::open_shared_memory("shared_memory_other").unwrap();
+
+    let tx = conn_one.new_transaction().unwrap();
+    let order_id = tx.create(Order { is_tall: true }).unwrap().id();
+    tx.commit().unwrap();
+
+    let tx = conn_two.new_transaction().unwrap();
+    let order = tx.get::<Order>(order_id).unwrap();
+    assert!(order.borrow().is_tall);
+    order.borrow_mut().is_tall = false;
+    tx.commit().unwrap();
+
+    let tx = conn_one.new_transaction().unwrap();
+    assert!(!tx.get::<Order>(order_id).unwrap().borrow().is_tall);
+    tx.commit().unwrap();
+
+    let tx = conn_other.new_transaction().unwrap();
+    assert!(matches!(
+        tx.get::<Order>(order_id),
+        Err(orm::Error::NotFound(_))
+    ));
+
+    drop(tx);
+
+    // names are taken literally, characters special in URIs included
+    let names = [
+        "tenant",
+        "tenant?a",
+        "tenant#a",
+        "tenant%3Fa",
+        "tenant&cache=private",
+    ];
+    let mut conns = names
+        .iter()
+        .map(|name| Connection::open_shared_memory(name).unwrap())
+        .collect::<Vec<_>>();
+    for (i, conn) in conns.iter_mut().enumerate() {
+        let tx = conn.new_transaction().unwrap();
+        for _ in 0..=i {
+            tx.create(Order { is_tall: true }).unwrap();
+        }
+        tx.commit().unwrap();
+    }
+    for (i, name) in names.iter().enumerate() {
+        let mut conn = Connection::open_shared_memory(name).unwrap();
+        let tx = conn.new_transaction().unwrap();
+        assert_eq!(tx.count::<Order>().unwrap(), i as u64 + 1, "{}", name);
+    }
+}
+
+#[test]
+fn table_const() {
+    assert_eq!(User::TABLE, "User");
+    assert_eq!(Order::TABLE, "order_table");
+    assert_eq!(Event::TABLE, "Event");
+}
+
+#[test]
+fn delete_where_limited() {
+    let mut conn = Connection::open_in_memory().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let mut orders = vec![];
+    for i in 0..7 {
+        orders.push(tx.create(Order { is_tall: i < 5 }).unwrap());
+    }
+
+    let is_tall = orm::data::Value::Bool(true);
+    assert_eq!(
+        tx.delete_where_limited::<Order>("IsTall", is_tall.clone(), 3)
+            .unwrap(),
+        3
+    );
+    assert_eq!(
+        tx.delete_where_limited::<Order>("IsTall", is_tall.clone(), 3)
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        tx.delete_where_limited::<Order>("IsTall", is_tall, 3)
+            .unwrap(),
+        0
+    );
+
+    for (i, order) in orders.iter().enumerate() {
+        let expected = if i < 5 {
+            ObjectState::Removed
+        } else {
+            ObjectState::Clean
+        };
+        assert!(order.state() == expected);
+    }
+
+    assert!(matches!(
+        tx.delete_where_limited::<Order>("is_tall", orm::data::Value::Bool(false), 1),
+        Err(orm::Error::UnknownColumn(_))
+    ));
+
+    // NULL is matched with IS NULL
+    for nickname in [None, Some("vanya"), None] {
+        tx.create(Profile {
+            login: "ivan".into(),
+            nickname: nickname.map(Into::into),
+            height: None,
+        })
+        .unwrap();
+    }
+    let deleted = tx
+        .delete_where_limited::<Profile>("nickname", orm::data::Value::Null, 5)
+        .unwrap();
+    assert_eq!(deleted, 2);
+    assert_eq!(tx.count::<Profile>().unwrap(), 1);
+}
+
+#[test]
+fn delete_where() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+    let people = tx.all::<Person>().unwrap();
+
+    assert_eq!(
+        tx.delete_where::<Person>("age", Op::Lt, orm::data::Value::Int64(30))
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        tx.delete_where::<Person>("age", Op::Lt, orm::data::Value::Int64(30))
+            .unwrap(),
+        0
+    );
+    let removed = people
+        .iter()
+        .filter(|person| person.state() == ObjectState::Removed)
+        .count();
+    assert_eq!(removed, 2);
+    assert_eq!(tx.count::<Person>().unwrap(), 3);
+    assert_eq!(tx.all::<Person>().unwrap().len(), 3);
+
+    assert!(matches!(
+        tx.delete_where::<Person>("surname", Op::Eq, orm::data::Value::Null),
+        Err(orm::Error::UnknownColumn(_))
+    ));
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert!(tx
+        .all::<Person>()
+        .unwrap()
+        .iter()
+        .all(|person| person.borrow().age >= 30));
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object)]
+#[table_name("User")]
+struct UserSummary {
+    name: String,
+    visits: i64,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn table_projection() {
+    let mut conn = Connection::open_in_memory().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let user = User {
+        name: "Nina".into(),
+        picture: b"q3w4e5r6t7"[..].into(),
+        visits: 3,
+        balance: 1000.,
+        is_admin: true,
+    };
+    let user_id = tx.create(user.clone()).unwrap().id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let summary = tx.get::<UserSummary>(user_id).unwrap();
+    assert_eq!(summary.borrow().name, "Nina");
+    assert_eq!(summary.borrow().visits, 3);
+
+    let found = tx
+        .find_where_raw::<UserSummary>("visits > ?", &[orm::data::Value::Int64(2)])
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert!(found[0].ptr_eq(&summary));
+
+    summary.borrow_mut().visits += 1;
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let full = tx.get::<User>(user_id).unwrap();
+    assert_eq!(*full.borrow(), User { visits: 4, ..user });
+}
+
+#[test]
+fn storage_error_sql() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+
+    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
+    sqlite_conn
+        .execute("CREATE VIEW order_table AS SELECT 1 AS id, 0 AS IsTall", [])
+        .unwrap();
+
+    let mut conn = Connection::open_sqlite_file(&path).unwrap();
+    let tx = conn.new_transaction().unwrap();
+    match tx.create(Order { is_tall: true }) {
+        Err(orm::Error::Storage(err)) => {
+            assert_eq!(
+                err.sql.as_deref(),
+                Some("INSERT INTO order_table (IsTall) VALUES(?)")
+            );
+            assert!(err
+                .to_string()
+                .contains("INSERT INTO order_table (IsTall) VALUES(?)"));
+        }
+        res => panic!("Expected Storage, got {}", fmt_res(&res)),
+    }
+
+    match tx.execute_raw("SELEC 1", &[]) {
+        Err(err @ orm::Error::Storage(_)) => assert!(err.to_string().contains("SELEC 1")),
+        res => panic!("Expected Storage, got {}", fmt_res(&res)),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object)]
+#[temporary]
+struct Scratch {
+    value: i64,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn temporary_table() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+
+    let mut conn = Connection::open_sqlite_file(&path).unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let scratch_id = tx.create(Scratch { value: 42 }).unwrap().id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.get::<Scratch>(scratch_id).unwrap().borrow().value, 42);
+    tx.commit().unwrap();
+
+    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
+    assert!(!sqlite_conn
+        .prepare("SELECT 1 FROM sqlite_master WHERE name = 'Scratch'")
+        .unwrap()
+        .exists([])
+        .unwrap());
+
+    drop(conn);
+    let mut conn = Connection::open_sqlite_file(&path).unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert!(matches!(
+        tx.get::<Scratch>(scratch_id),
+        Err(orm::Error::NotFound(_))
+    ));
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Product {
+    #[unique]
+    #[label]
+    sku: String,
+    price: i64,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn upsert_many() {
+    let mut conn = Connection::open_in_memory().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let apple_id = tx
+        .create(Product {
+            sku: "apple".into(),
+            price: 10,
+        })
+        .unwrap()
+        .id();
+    let pear_id = tx
+        .create(Product {
+            sku: "pear".into(),
+            price: 20,
+        })
+        .unwrap()
+        .id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let apple = tx.get::<Product>(apple_id).unwrap();
+
+    // enough rows to be split into several statements
+    let mut batch = (0..1000)
+        .map(|i| Product {
+            sku: format!("item-{}", i),
+            price: i,
+        })
+        .collect::<Vec<_>>();
+    batch.insert(
+        500,
+        Product {
+            sku: "apple".into(),
+            price: 11,
+        },
+    );
+    batch.push(Product {
+        sku: "pear".into(),
+        price: 21,
+    });
+
+    let upserted = tx.upsert_many(batch.clone(), "sku").unwrap();
+    assert_eq!(upserted.len(), batch.len());
+    for (product, expected) in upserted.iter().zip(batch.iter()) {
+        assert_eq!(*product.borrow(), *expected);
+    }
+    assert!(upserted[500].ptr_eq(&apple));
+    assert_eq!(apple.borrow().price, 11);
+    assert_eq!(upserted[1001].id(), pear_id);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.find_where_raw::<Product>("1", &[]).unwrap().len(), 1002);
+    assert_eq!(tx.get::<Product>(pear_id).unwrap().borrow().price, 21);
+
+    assert!(matches!(
+        tx.upsert_many::<Product>(vec![], "name"),
+        Err(orm::Error::UnknownColumn(_))
+    ));
+}
+
+#[test]
+fn upsert() {
+    let product = |sku: &str, price| Product {
+        sku: sku.into(),
+        price,
+    };
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let apple_id = tx.upsert("sku", product("apple", 10)).unwrap().id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let apple = tx.get::<Product>(apple_id).unwrap();
+    assert_eq!(*apple.borrow(), product("apple", 10));
+    let upserted = tx.upsert("sku", product("apple", 12)).unwrap();
+    // the cached object reflects the stored state
+    assert!(upserted.ptr_eq(&apple));
+    assert_eq!(apple.borrow().price, 12);
+    assert!(upserted.state() == ObjectState::Clean);
+    let pear = tx.upsert("sku", product("pear", 20)).unwrap();
+    assert_ne!(pear.id(), upserted.id());
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let stored = tx.export_all::<Product>().unwrap();
+    assert_eq!(stored, [product("apple", 12), product("pear", 20)]);
+
+    // the primary key can be used as the conflict column too
+    let account = tx
+        .upsert(
+            "user_id",
+            Account {
+                user_id: 42,
+                email: "ann@example.com".into(),
+            },
+        )
+        .unwrap();
+    assert_eq!(account.id(), ObjectId::from(42));
+    tx.upsert(
+        "user_id",
+        Account {
+            user_id: 42,
+            email: "anna@example.com".into(),
+        },
+    )
+    .unwrap();
+    assert_eq!(account.borrow().email, "anna@example.com");
+    assert_eq!(tx.count::<Account>().unwrap(), 1);
+
+    assert!(matches!(
+        tx.upsert("name", product("plum", 5)),
+        Err(orm::Error::UnknownColumn(_))
+    ));
+    // a column without a unique constraint can't be a conflict target
+    assert!(tx.upsert("price", product("plum", 5)).is_err());
+
+    // the cached object can't be overwritten while it is borrowed
+    let apple = tx.get::<Product>(apple_id).unwrap();
+    let borrowed = apple.borrow();
+    match tx.upsert("sku", product("apple", 14)) {
+        Err(orm::Error::Borrowed(err)) => assert_eq!(err.object_id, apple_id),
+        res => panic!("expected Error::Borrowed, got {}", fmt_res(&res)),
+    }
+    assert_eq!(borrowed.price, 12);
+}
+
+#[test]
+fn unique_violation() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let apple = |price| Product {
+        sku: "apple".into(),
+        price,
+    };
+    tx.create(apple(10)).unwrap();
+
+    match tx.create(apple(11)) {
+        Err(orm::Error::UniqueViolation(err)) => {
+            assert_eq!(err.table_name, "Product");
+            assert_eq!(err.column_name, "sku");
+        }
+        res => panic!("expected Error::UniqueViolation, got {}", fmt_res(&res)),
+    }
+
+    let pear = tx
+        .create(Product {
+            sku: "pear".into(),
+            price: 20,
+        })
+        .unwrap();
+    pear.borrow_mut().sku = "apple".into();
+    assert!(matches!(tx.commit(), Err(orm::Error::UniqueViolation(_))));
+}
+
+#[test]
+fn constraint_violation() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.execute_raw(
+        "CREATE TABLE Profile(id INTEGER PRIMARY KEY AUTOINCREMENT, \
+            login TEXT, nickname TEXT NOT NULL, height REAL CHECK (height > 0))",
+        &[],
+    )
+    .unwrap();
+
+    let profile = |nickname: Option<&str>, height| Profile {
+        login: "ann".into(),
+        nickname: nickname.map(str::to_string),
+        height: Some(height),
+    };
+    match tx.create(profile(Some("Annie"), -1.)) {
+        Err(orm::Error::ConstraintViolation(err)) => {
+            assert_eq!(err.message, "CHECK constraint failed: height > 0");
+            assert_eq!(err.type_name, Some("Profile"));
+        }
+        res => panic!("expected Error::ConstraintViolation, got {}", fmt_res(&res)),
+    }
+    assert!(matches!(
+        tx.create(profile(None, 1.7)),
+        Err(orm::Error::ConstraintViolation(_))
+    ));
+
+    // raw SQL isn't attributed to any object
+    match tx.execute_raw("INSERT INTO Profile(nickname, height) VALUES ('x', 0)", &[]) {
+        Err(orm::Error::ConstraintViolation(err)) => assert_eq!(err.table_name, None),
+        res => panic!("expected Error::ConstraintViolation, got {}", fmt_res(&res)),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct LegacyAccount {
+    #[bool_as("YN")]
+    active: bool,
+    #[bool_as("text")]
+    verified: bool,
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn bool_encodings() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+
+    let mut conn = Connection::open_sqlite_file(&path).unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let account = LegacyAccount {
+        active: true,
+        verified: false,
+    };
+    let account_id = tx.create(account.clone()).unwrap().id();
+    tx.commit().unwrap();
+
+    let sqlite_conn = rusqlite::Connection::open(&path).unwrap();
+    let stored: (String, String) = sqlite_conn
+        .query_row(
+            "SELECT active, verified FROM LegacyAccount WHERE id = ?",
+            [account_id.into_i64()],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!(stored, ("Y".to_string(), "false".to_string()));
+    sqlite_conn
+        .execute(
+            "INSERT INTO LegacyAccount (active, verified) VALUES ('N', 'true')",
+            [],
+        )
+        .unwrap();
+    let legacy_id = ObjectId::from(sqlite_conn.last_insert_rowid());
+    drop(sqlite_conn);
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(
+        *tx.get::<LegacyAccount>(account_id).unwrap().borrow(),
+        account
+    );
+    let legacy = tx.get::<LegacyAccount>(legacy_id).unwrap();
+    assert_eq!(
+        *legacy.borrow(),
+        LegacyAccount {
+            active: false,
+            verified: true,
+        }
+    );
+
+    legacy.borrow_mut().active = true;
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert!(tx.get::<LegacyAccount>(legacy_id).unwrap().borrow().active);
+}
+
+#[test]
+fn in_transaction() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    assert!(!conn.in_transaction());
+
+    let tx = conn.new_transaction().unwrap();
+    tx.create(Order { is_tall: true }).unwrap();
+    tx.commit().unwrap();
+    assert!(!conn.in_transaction());
+
+    let tx = conn.new_transaction().unwrap();
+    tx.create(Order { is_tall: false }).unwrap();
+    tx.rollback().unwrap();
+    assert!(!conn.in_transaction());
+
+    // a dropped transaction is rolled back
+    let tx = conn.new_transaction().unwrap();
+    tx.create(Order { is_tall: false }).unwrap();
+    drop(tx);
+    assert!(!conn.in_transaction());
+
+    // a live transaction borrows the connection, so an open one can only be observed leaked:
+    // it is open as soon as it is started...
+    let mut other = Connection::open_in_memory().unwrap();
+    std::mem::forget(other.new_transaction().unwrap());
+    assert!(other.in_transaction());
+
+    // ...and stays open with its changes, as it is never finished
+    let tx = conn.new_transaction().unwrap();
+    tx.create(Order { is_tall: false }).unwrap();
+    std::mem::forget(tx);
+    assert!(conn.in_transaction());
+    assert!(conn.new_transaction().is_err());
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Person {
+    first_name: String,
+    last_name: String,
+    age: i64,
+}
+
+fn create_people(tx: &orm::Transaction) {
+    for (first_name, last_name, age) in [
+        ("Ivan", "Petrov", 30),
+        ("Anna", "Sidorova", 25),
+        ("Boris", "Petrov", 41),
+        ("Anna", "Petrov", 19),
+        ("Oleg", "Ivanov", 35),
+    ] {
+        tx.create(Person {
+            first_name: first_name.into(),
+            last_name: last_name.into(),
+            age,
+        })
+        .unwrap();
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn query_order_by_columns() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+
+    let names = |people: Vec<Tx<Person>>| {
+        people
+            .iter()
+            .map(|person| {
+                let person = person.borrow();
+                format!("{} {}", person.first_name, person.last_name)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let people = tx
+        .query::<Person>()
+        .order_by("last_name", orm::Order::Asc)
+        .order_by("first_name", orm::Order::Asc)
+        .all()
+        .unwrap();
+    assert_eq!(
+        names(people),
+        [
+            "Oleg Ivanov",
+            "Anna Petrov",
+            "Boris Petrov",
+            "Ivan Petrov",
+            "Anna Sidorova"
+        ]
+    );
+
+    let people = tx
+        .query::<Person>()
+        .filter("age", Op::Gt, orm::data::Value::Int64(20))
+        .order_by("last_name", orm::Order::Desc)
+        .order_by("first_name", orm::Order::Desc)
+        .limit(2)
+        .offset(1)
+        .all()
+        .unwrap();
+    assert_eq!(names(people), ["Ivan Petrov", "Boris Petrov"]);
+
+    assert!(matches!(
+        tx.query::<Person>()
+            .order_by("last_name", orm::Order::Asc)
+            .order_by("name", orm::Order::Asc)
+            .all(),
+        Err(orm::Error::UnknownColumn(_))
+    ));
+}
+
+#[test]
+fn all_with_options() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+
+    let ages = |options: orm::SelectOptions| {
+        tx.all_with::<Person>(options)
+            .unwrap()
+            .iter()
+            .map(|person| person.borrow().age)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(ages(Default::default()), [30, 25, 41, 19, 35]);
+    assert_eq!(
+        ages(orm::SelectOptions {
+            order_by: Some(("age", orm::Order::Asc)),
+            ..Default::default()
+        }),
+        [19, 25, 30, 35, 41]
+    );
+    assert_eq!(
+        ages(orm::SelectOptions {
+            order_by: Some(("age", orm::Order::Desc)),
+            limit: Some(2),
+            ..Default::default()
+        }),
+        [41, 35]
+    );
+    assert_eq!(
+        ages(orm::SelectOptions {
+            order_by: Some(("age", orm::Order::Desc)),
+            limit: Some(2),
+            offset: Some(2),
+        }),
+        [30, 25]
+    );
+    assert_eq!(
+        ages(orm::SelectOptions {
+            offset: Some(3),
+            ..Default::default()
+        }),
+        [19, 35]
+    );
+
+    // ties are broken by id, so the pages don't overlap
+    let first_ids = |offset| {
+        tx.all_with::<Person>(orm::SelectOptions {
+            order_by: Some(("first_name", orm::Order::Asc)),
+            limit: Some(1),
+            offset: Some(offset),
+        })
+        .unwrap()
+        .iter()
+        .map(|person| person.id())
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(first_ids(0), [ObjectId::from(2)]);
+    assert_eq!(first_ids(1), [ObjectId::from(4)]);
+
+    // results go through the cache
+    let anna = tx.get::<Person>(ObjectId::from(2)).unwrap();
+    anna.borrow_mut().age = 26;
+    assert_eq!(
+        ages(orm::SelectOptions {
+            limit: Some(2),
+            ..Default::default()
+        }),
+        [30, 26]
+    );
+
+    assert!(matches!(
+        tx.all_with::<Person>(orm::SelectOptions {
+            order_by: Some(("name", orm::Order::Asc)),
+            ..Default::default()
+        }),
+        Err(orm::Error::UnknownColumn(_))
+    ));
+}
+
+#[test]
+fn query_count() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.query::<Person>().count().unwrap(), 0);
+    create_people(&tx);
+
+    assert_eq!(tx.query::<Person>().count().unwrap(), 5);
+    let petrovs = || {
+        tx.query::<Person>()
+            .filter(
+                "last_name",
+                Op::Eq,
+                orm::data::Value::String("Petrov".into()),
+            )
+            .order_by("age", orm::Order::Asc)
+    };
+    assert_eq!(petrovs().count().unwrap(), 3);
+    assert_eq!(petrovs().limit(2).count().unwrap(), 2);
+    assert_eq!(petrovs().offset(2).count().unwrap(), 1);
+    assert_eq!(petrovs().offset(5).count().unwrap(), 0);
+
+    assert!(matches!(
+        tx.query::<Person>()
+            .filter("surname", Op::Eq, orm::data::Value::Int64(1))
+            .count(),
+        Err(orm::Error::UnknownColumn(_))
+    ));
+}
+
+#[test]
+fn count_objects() {
+    use orm::AsDataType;
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.count::<Person>().unwrap(), 0);
+    create_people(&tx);
+
+    assert_eq!(tx.count::<Person>().unwrap(), 5);
+    let petrov = "Petrov".to_string();
+    assert_eq!(
+        tx.count_where::<Person>("last_name", Op::Eq, petrov.as_value())
+            .unwrap(),
+        3
+    );
+    assert_eq!(
+        tx.count_where::<Person>("age", Op::Lt, orm::data::Value::Int64(30))
+            .unwrap(),
+        2
+    );
+    assert!(matches!(
+        tx.count_where::<Person>("surname", Op::Eq, petrov.as_value()),
+        Err(orm::Error::UnknownColumn(_))
+    ));
+
+    tx.commit().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.count::<Person>().unwrap(), 5);
+}
+
+#[test]
+fn checkpoint() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+
+    let mut conn = Connection::open_sqlite_file(&path).unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let first = tx
+        .create(Product {
+            sku: "item-0".into(),
+            price: 0,
+        })
+        .unwrap();
+    for i in 1..100 {
+        tx.create(Product {
+            sku: format!("item-{}", i),
+            price: i,
+        })
+        .unwrap();
+        if i % 10 == 0 {
+            first.borrow_mut().price += 1;
+            tx.checkpoint().unwrap();
+            assert!(first.state() == ObjectState::Clean);
+        }
+    }
+    assert_eq!(first.borrow().price, 9);
+    let first_id = first.id();
+    drop(first);
+
+    // only the rows created after the last checkpoint are rolled back
+    tx.rollback().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let products = tx
+        .query::<Product>()
+        .order_by("price", orm::Order::Asc)
+        .all()
+        .unwrap();
+    assert_eq!(products.len(), 91);
+    assert_eq!(products[0].borrow().sku, "item-1");
+    assert_eq!(products[90].borrow().sku, "item-90");
+    assert_eq!(tx.get::<Product>(first_id).unwrap().borrow().price, 9);
+}
+
+#[test]
+fn query_nulls() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let events = [
+        Event::Key {
+            code: 2,
+            name: "b".into(),
+        },
+        Event::Click { x: 1, y: 1 },
+        Event::Key {
+            code: 1,
+            name: "a".into(),
+        },
+    ];
+    for event in events.iter() {
+        tx.create(event.clone()).unwrap();
+    }
+
+    let codes = |events: Vec<Tx<Event>>| {
+        events
+            .iter()
+            .map(|event| match *event.borrow() {
+                Event::Key { code, .. } => Some(code),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let ordered = tx.query::<Event>().order_by("code", orm::Order::Asc);
+    assert_eq!(codes(ordered.all().unwrap()), [None, Some(1), Some(2)]);
+    let ordered = tx
+        .query::<Event>()
+        .order_by("code", orm::Order::Asc)
+        .nulls_last();
+    assert_eq!(codes(ordered.all().unwrap()), [Some(1), Some(2), None]);
+    let ordered = tx
+        .query::<Event>()
+        .order_by("code", orm::Order::Desc)
+        .nulls_first();
+    assert_eq!(codes(ordered.all().unwrap()), [None, Some(2), Some(1)]);
+    let unordered = tx.query::<Event>().nulls_last();
+    assert_eq!(unordered.all().unwrap().len(), 3);
+
+    let clicks = tx
+        .query::<Event>()
+        .filter("code", Op::Eq, orm::data::Value::Null)
+        .all()
+        .unwrap();
+    assert_eq!(codes(clicks), [None]);
+    let keys = tx
+        .query::<Event>()
+        .filter("code", Op::Ne, orm::data::Value::Null)
+        .all()
+        .unwrap();
+    assert_eq!(keys.len(), 2);
+}
+
+#[test]
+fn label() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+
+    let product = tx
+        .create(Product {
+            sku: "apple".into(),
+            price: 10,
+        })
+        .unwrap();
+    assert_eq!(Product::SCHEMA.label, Some("sku"));
+    assert_eq!(product.label(), "apple");
+    product.borrow_mut().sku = "green apple".into();
+    assert_eq!(product.label(), "green apple");
+
+    let person = tx
+        .create(Person {
+            first_name: "Anna".into(),
+            last_name: "Petrova".into(),
+            age: 20,
+        })
+        .unwrap();
+    assert_eq!(person.label(), format!("Person {}", person.id()));
+}
+
+#[test]
+fn get_field() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let user = tx
+        .create(User {
+            name: "Nina".into(),
+            picture: b"q3w4e5r6t7"[..].into(),
+            visits: 3,
+            balance: 1000.,
+            is_admin: true,
+        })
+        .unwrap();
+    let user_id = user.id();
+
+    let name = tx.get_field::<User>(user_id, "name").unwrap();
+    assert!(name == orm::data::Value::String("Nina".into()));
+    let visits = tx.get_field::<User>(user_id, "visits").unwrap();
+    assert!(visits == orm::data::Value::Int64(3));
+
+    assert!(matches!(
+        tx.get_field::<User>(user_id, "email"),
+        Err(orm::Error::UnknownColumn(_))
+    ));
+    assert!(matches!(
+        tx.get_field::<User>(ObjectId(user_id.into_i64() + 1), "name"),
+        Err(orm::Error::NotFound(_))
+    ));
+    user.delete().unwrap();
+    assert!(matches!(
+        tx.get_field::<User>(user_id, "name"),
+        Err(orm::Error::NotFound(_))
+    ));
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object)]
+#[after_load]
+struct Rectangle {
+    width: i64,
+    height: i64,
+    #[skip]
+    area: i64,
+}
+
+impl orm::AfterLoad for Rectangle {
+    fn after_load(&mut self) {
+        self.area = self.width * self.height;
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn after_load() {
+    assert_eq!(
+        Rectangle::SCHEMA.column_names().collect::<Vec<_>>(),
+        ["width", "height"]
+    );
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let rectangle_id = tx
+        .create(Rectangle {
+            width: 3,
+            height: 4,
+            area: 0,
+        })
+        .unwrap()
+        .id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let rectangle = tx.get::<Rectangle>(rectangle_id).unwrap();
+    assert_eq!(rectangle.borrow().area, 12);
+
+    rectangle.borrow_mut().width = 5;
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let found = tx.query::<Rectangle>().all().unwrap();
+    assert_eq!(found[0].borrow().area, 20);
+}
+
+#[test]
+fn get_columns() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let user_id = tx
+        .create(User {
+            name: "Nina".into(),
+            picture: b"q3w4e5r6t7"[..].into(),
+            visits: 3,
+            balance: 1000.,
+            is_admin: true,
+        })
+        .unwrap()
+        .id();
+
+    let user = tx
+        .get_columns::<User>(user_id, &["visits", "name"])
+        .unwrap();
+    assert_eq!(
+        user,
+        User {
+            name: "Nina".into(),
+            visits: 3,
+            ..Default::default()
+        }
+    );
+
+    assert!(matches!(
+        tx.get_columns::<User>(user_id, &["email"]),
+        Err(orm::Error::UnknownColumn(_))
+    ));
+    assert!(matches!(
+        tx.get_columns::<User>(ObjectId(user_id.into_i64() + 1), &["name"]),
+        Err(orm::Error::NotFound(_))
+    ));
+
+    // no columns give the default object, as long as it is stored
+    let user = tx.get_columns::<User>(user_id, &[]).unwrap();
+    assert_eq!(user, User::default());
+    assert!(matches!(
+        tx.get_columns::<User>(ObjectId(user_id.into_i64() + 1), &[]),
+        Err(orm::Error::NotFound(_))
+    ));
+}
+
+#[test]
+fn table_exists_error() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.create(Order { is_tall: true }).unwrap();
+
+    match tx.execute_raw("CREATE TABLE order_table (IsShort TINYINT)", &[]) {
+        Err(orm::Error::TableExists(err)) => assert_eq!(err.table_name, "order_table"),
+        res => panic!("Expected TableExists, got {}", fmt_res(&res)),
+    }
+}
+
+#[test]
+fn cache_state_counts() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.cache_len(), 0);
+    assert_eq!(tx.cache_state_counts(), (0, 0, 0));
+
+    let mut orders = (0..5)
+        .map(|i| {
+            tx.create(Order {
+                is_tall: i % 2 == 0,
+            })
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(tx.cache_len(), 5);
+    assert_eq!(tx.cache_state_counts(), (5, 0, 0));
+
+    orders[0].borrow_mut().is_tall = false;
+    orders[1].borrow_mut().is_tall = true;
+    orders.remove(2).delete().unwrap();
+    assert_eq!(tx.cache_len(), 5);
+    assert_eq!(tx.cache_state_counts(), (2, 2, 1));
+}
+
+#[test]
+fn as_row_borrows() {
+    let user = User {
+        name: "Nina".into(),
+        picture: vec![7; 1 << 20],
+        ..Default::default()
+    };
+
+    let row = user.as_row();
+    match (&row[0], &row[1]) {
+        (
+            orm::data::Value::String(std::borrow::Cow::Borrowed(name)),
+            orm::data::Value::Bytes(std::borrow::Cow::Borrowed(picture)),
+        ) => {
+            assert!(std::ptr::eq(name.as_ptr(), user.name.as_ptr()));
+            assert!(std::ptr::eq(picture.as_ptr(), user.picture.as_ptr()));
+        }
+        _ => panic!("expected borrowed values"),
+    }
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let user_id = tx.create(user.clone()).unwrap().id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(*tx.get::<User>(user_id).unwrap().borrow(), user);
+}
+
+#[test]
+fn update_if() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let apple_id = tx
+        .create(Product {
+            sku: "apple".into(),
+            price: 10,
+        })
+        .unwrap()
+        .id();
+    let pear_id = tx
+        .create(Product {
+            sku: "pear".into(),
+            price: 20,
+        })
+        .unwrap()
+        .id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let apple = tx.get::<Product>(apple_id).unwrap();
+    apple
+        .update_if("price", orm::data::Value::Int64(10))
+        .unwrap();
+    apple.borrow_mut().price = 11;
+    drop(apple);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.get::<Product>(apple_id).unwrap().borrow().price, 11);
+
+    let pear = tx.get::<Product>(pear_id).unwrap();
+    pear.borrow_mut().price = 21;
+    pear.update_if("price", orm::data::Value::Int64(20))
+        .unwrap();
+    assert!(matches!(
+        pear.update_if("cost", orm::data::Value::Int64(20)),
+        Err(orm::Error::UnknownColumn(_))
+    ));
+    drop(pear);
+    tx.execute_raw(
+        "UPDATE Product SET price = 25 WHERE id = ?",
+        &[orm::data::Value::Int64(pear_id.into_i64())],
+    )
+    .unwrap();
+    match tx.commit() {
+        Err(orm::Error::ConcurrentModification(err)) => {
+            assert_eq!(err.object_id, pear_id);
+            assert_eq!(err.type_name, "Product");
+            assert_eq!(err.column_name, "price");
+        }
+        res => panic!("Expected ConcurrentModification, got {}", fmt_res(&res)),
+    }
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.get::<Product>(pear_id).unwrap().borrow().price, 20);
+
+    // a column expected to be NULL
+    let profile = tx
+        .create(Profile {
+            login: "ivan".into(),
+            nickname: None,
+            height: None,
+        })
+        .unwrap();
+    profile
+        .update_if("nickname", orm::data::Value::Null)
+        .unwrap();
+    profile.borrow_mut().nickname = Some("vanya".into());
+    let profile_id = profile.id();
+    drop(profile);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let profile = tx.get::<Profile>(profile_id).unwrap();
+    assert_eq!(profile.borrow().nickname.as_deref(), Some("vanya"));
+    profile
+        .update_if("nickname", orm::data::Value::Null)
+        .unwrap();
+    profile.borrow_mut().height = Some(1.8);
+    drop(profile);
+    let res = tx.commit();
+    assert!(
+        matches!(res, Err(orm::Error::ConcurrentModification(_))),
+        "{}",
+        fmt_res(&res)
+    );
+
+    // a deleted object can't be updated
+    let tx = conn.new_transaction().unwrap();
+    let apple = tx.get::<Product>(apple_id).unwrap();
+    apple.clone().delete().unwrap();
+    match apple.update_if("price", orm::data::Value::Int64(11)) {
+        Err(orm::Error::NotFound(err)) => assert_eq!(err.object_id, apple_id),
+        res => panic!("expected Error::NotFound, got {}", fmt_res(&res)),
+    }
+}
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Article {
+    title: String,
+    #[version]
+    revision: i64,
+}
+
+#[test]
+fn version_column() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx
+        .create(Article {
+            title: "Draft".into(),
+            revision: 0,
+        })
+        .unwrap()
+        .id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let article = tx.get::<Article>(id).unwrap();
+    article.borrow_mut().title = "First".into();
+    tx.checkpoint().unwrap();
+    assert_eq!(article.borrow().revision, 1);
+    article.borrow_mut().title = "Second".into();
+    drop(article);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let article = tx.get::<Article>(id).unwrap();
+    assert_eq!(
+        *article.borrow(),
+        Article {
+            title: "Second".into(),
+            revision: 2,
+        }
+    );
+
+    // someone else saves the article meanwhile, so this write is stale
+    article.borrow_mut().title = "Mine".into();
+    drop(article);
+    tx.execute_raw(
+        "UPDATE Article SET title = 'Theirs', revision = revision + 1",
+        &[],
+    )
+    .unwrap();
+    match tx.commit() {
+        Err(orm::Error::ConcurrentModification(err)) => {
+            assert_eq!(err.object_id, id);
+            assert_eq!(err.type_name, "Article");
+            assert_eq!(err.column_name, "revision");
+        }
+        res => panic!("Expected ConcurrentModification, got {}", fmt_res(&res)),
+    }
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.get::<Article>(id).unwrap().borrow().title, "Second");
+}
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Memo {
+    text: String,
+    #[version]
+    revision: i64,
+    #[skip]
+    cursor: usize,
+}
+
+#[test]
+fn version_keeps_skipped_fields() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let memo = tx
+        .create(Memo {
+            text: "a".into(),
+            revision: 0,
+            cursor: 0,
+        })
+        .unwrap();
+
+    // catching up with the new version doesn't reset the rest of the object
+    memo.borrow_mut().cursor = 7;
+    memo.borrow_mut().text = "ab".into();
+    tx.checkpoint().unwrap();
+    assert_eq!(memo.borrow().revision, 1);
+    assert_eq!(memo.borrow().cursor, 7);
+
+    memo.borrow_mut().text = "abc".into();
+    let savepoint = tx.savepoint().unwrap();
+    assert_eq!(memo.borrow().revision, 2);
+    assert_eq!(memo.borrow().cursor, 7);
+    savepoint.release().unwrap();
+}
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Ticket {
+    #[unique]
+    title: String,
+    #[created_at]
+    created_at: i64,
+    #[updated_at]
+    updated_at: i64,
+}
+
+#[test]
+fn timestamps() {
+    let ticket = |title: &str| Ticket {
+        title: title.into(),
+        created_at: 0,
+        updated_at: 0,
+    };
+    let stamps = |ticket: &Tx<Ticket>| {
+        let ticket = ticket.borrow();
+        (ticket.created_at, ticket.updated_at)
+    };
+    let time = std::rc::Rc::new(std::cell::Cell::new(100));
+    let mut conn = Connection::open_in_memory().unwrap();
+    conn.clock({
+        let time = time.clone();
+        move || std::time::UNIX_EPOCH + std::time::Duration::from_secs(time.get())
+    });
+
+    let tx = conn.new_transaction().unwrap();
+    let first = tx.create(ticket("first")).unwrap();
+    assert_eq!(stamps(&first), (100, 100));
+    time.set(150);
+    let second = tx.create_many(vec![ticket("second")]).unwrap();
+    assert_eq!(stamps(&second[0]), (150, 150));
+    drop((first, second));
+    tx.commit().unwrap();
+
+    time.set(200);
+    let tx = conn.new_transaction().unwrap();
+    let first = tx.get::<Ticket>(ObjectId::from(1)).unwrap();
+    first.borrow_mut().title = "first, edited".into();
+    let second = tx.get::<Ticket>(ObjectId::from(2)).unwrap();
+    tx.checkpoint().unwrap();
+    // only the modified object is stamped, and the cached one sees its new time
+    assert_eq!(stamps(&first), (100, 200));
+    assert_eq!(stamps(&second), (150, 150));
+    drop((first, second));
+
+    // an upsert overwriting a row keeps its creation time
+    time.set(300);
+    let upserted = tx.upsert("title", ticket("second")).unwrap();
+    assert_eq!(stamps(&upserted), (150, 300));
+    drop(upserted);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let stored = tx
+        .all::<Ticket>()
+        .unwrap()
+        .iter()
+        .map(stamps)
+        .collect::<Vec<_>>();
+    assert_eq!(stored, [(100, 200), (150, 300)]);
+}
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct PageView {
+    #[unique]
+    page: String,
+    #[created_at]
+    created_at: i64,
+    #[updated_at]
+    updated_at: i64,
+    #[skip]
+    scroll: u32,
+}
+
+#[test]
+fn timestamps_keep_skipped_fields() {
+    let page_view = |page: &str| PageView {
+        page: page.into(),
+        created_at: 0,
+        updated_at: 0,
+        scroll: 40,
+    };
+    let mut conn = Connection::open_in_memory().unwrap();
+    conn.clock(|| std::time::UNIX_EPOCH + std::time::Duration::from_secs(100));
+    let tx = conn.new_transaction().unwrap();
+
+    let first = tx.create(page_view("/")).unwrap();
+    assert_eq!(first.borrow().created_at, 100);
+    assert_eq!(first.borrow().scroll, 40);
+    let many = tx.create_many(vec![page_view("/about")]).unwrap();
+    assert_eq!(many[0].borrow().scroll, 40);
+    let upserted = tx.upsert("page", page_view("/news")).unwrap();
+    assert_eq!(upserted.borrow().scroll, 40);
+
+    first.borrow_mut().page = "/home".into();
+    tx.checkpoint().unwrap();
+    assert_eq!(first.borrow().updated_at, 100);
+    assert_eq!(first.borrow().scroll, 40);
+}
+
+#[test]
+fn unchanged_objects_are_not_written() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let ivan = tx.get::<Person>(ObjectId::from(1)).unwrap();
+    assert!(!ivan.is_dirty());
+    let _ = ivan.borrow_mut();
+    assert!(ivan.state() == ObjectState::Modified);
+    assert!(!ivan.is_dirty());
+    ivan.borrow_mut().age = 31;
+    assert!(ivan.is_dirty());
+    // changed back, so there is nothing to write again
+    ivan.borrow_mut().age = 30;
+    assert!(!ivan.is_dirty());
+
+    let anna = tx.get::<Person>(ObjectId::from(2)).unwrap();
+    anna.borrow_mut().age += 1;
+    let boris = tx.get::<Person>(ObjectId::from(3)).unwrap();
+    boris.clone().delete().unwrap();
+    assert!(boris.is_dirty());
+    drop((ivan, anna, boris));
+
+    // an UPDATE of Ivan would overwrite this
+    tx.execute_raw("UPDATE Person SET age = 99 WHERE id = 1", &[])
+        .unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let ages = tx
+        .all::<Person>()
+        .unwrap()
+        .iter()
+        .map(|person| person.borrow().age)
+        .collect::<Vec<_>>();
+    assert_eq!(ages, [99, 26, 19, 35]);
+}
+
+#[test]
+fn commit_stats() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+    let stats = tx.commit_with_stats().unwrap();
+    assert_eq!(
+        stats,
+        orm::CommitStats {
+            inserted: 5,
+            ..Default::default()
+        }
+    );
+
+    let tx = conn.new_transaction().unwrap();
+    tx.get::<Person>(ObjectId::from(1))
+        .unwrap()
+        .borrow_mut()
+        .age = 31;
+    // neither of these is written
+    let _ = tx.get::<Person>(ObjectId::from(2)).unwrap().borrow_mut();
+    tx.execute_raw("UPDATE Person SET age = 20 WHERE id = 4", &[])
+        .unwrap();
+
+    tx.get::<Person>(ObjectId::from(3))
+        .unwrap()
+        .delete()
+        .unwrap();
+    let _oleg = tx.get::<Person>(ObjectId::from(5)).unwrap();
+    // Boris and Oleg are cached, so they are counted once
+    tx.delete_where::<Person>("age", Op::Gt, orm::data::Value::Int64(34))
+        .unwrap();
+    tx.delete_where::<Person>("age", Op::Eq, orm::data::Value::Int64(20))
+        .unwrap();
+
+    for price in [10, 12] {
+        let apple = Product {
+            sku: "apple".into(),
+            price,
+        };
+        tx.upsert("sku", apple).unwrap();
+    }
+    let savepoint = tx.savepoint().unwrap();
+    create_people(&tx);
+    savepoint.rollback_to().unwrap();
+
+    let stats = tx.commit_with_stats().unwrap();
+    assert_eq!(
+        stats,
+        orm::CommitStats {
+            inserted: 0,
+            updated: 1,
+            upserted: 2,
+            deleted: 3,
+        }
+    );
+}
+
+#[test]
+fn deferred_inserts() {
+    let person = |first_name: &str| Person {
+        first_name: first_name.into(),
+        last_name: "Orlova".into(),
+        age: 28,
+    };
+
+    let mut conn = Connection::builder()
+        .defer_inserts(true)
+        .open_in_memory()
+        .unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let olga = tx.create(person("Olga")).unwrap();
+    assert!(olga.state() == ObjectState::New);
+    assert!(olga.id().into_i64() < 0);
+    assert!(tx.get::<Person>(olga.id()).unwrap().ptr_eq(&olga));
+    assert_eq!(tx.count::<Person>().unwrap(), 0);
+
+    // modified before the insert, it is inserted as it is
+    olga.borrow_mut().age = 29;
+    assert!(olga.state() == ObjectState::New);
+    let vera = tx.create(person("Vera")).unwrap();
+    let vera_id = vera.id();
+    vera.delete().unwrap();
+    assert!(!tx.exists::<Person>(vera_id).unwrap());
+    let nina = tx.create_many(vec![person("Nina")]).unwrap().remove(0);
+
+    tx.checkpoint().unwrap();
+    assert!(olga.state() == ObjectState::Clean);
+    assert_eq!(olga.id(), ObjectId::from(1));
+    assert_eq!(nina.id(), ObjectId::from(2));
+    assert!(tx.get::<Person>(ObjectId::from(1)).unwrap().ptr_eq(&olga));
+
+    // rolled back before it is inserted
+    let savepoint = tx.savepoint().unwrap();
+    let lena = tx.create(person("Lena")).unwrap();
+    savepoint.rollback_to().unwrap();
+    assert!(lena.state() == ObjectState::Removed);
+    drop((olga, nina, lena));
+    let stats = tx.commit_with_stats().unwrap();
+    assert_eq!(stats.inserted, 2);
+    assert_eq!(stats.deleted, 0);
+
+    let tx = conn.new_transaction().unwrap();
+    let people = tx
+        .all::<Person>()
+        .unwrap()
+        .iter()
+        .map(|person| (person.borrow().first_name.clone(), person.borrow().age))
+        .collect::<Vec<_>>();
+    assert_eq!(people, [("Olga".to_string(), 29), ("Nina".to_string(), 28)]);
+
+    // objects with a primary key are staged under their key, which must not be taken
+    let account = |user_id: i64| Account {
+        user_id,
+        email: format!("{}@example.com", user_id),
+    };
+    let alice = tx.create(account(7)).unwrap();
+    assert!(alice.state() == ObjectState::New);
+    assert_eq!(alice.id(), ObjectId::from(7));
+    assert!(tx.get::<Account>(ObjectId::from(7)).unwrap().ptr_eq(&alice));
+    let res = tx.create(account(7));
+    assert!(
+        matches!(res, Err(orm::Error::UniqueViolation(_))),
+        "{}",
+        fmt_res(&res)
+    );
+    let res = tx.create_many(vec![account(8), account(8)]);
+    assert!(
+        matches!(res, Err(orm::Error::UniqueViolation(_))),
+        "{}",
+        fmt_res(&res)
+    );
+    assert!(!tx.exists::<Account>(ObjectId::from(8)).unwrap());
+    tx.create(account(3)).unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let res = tx.create(account(3));
+    assert!(
+        matches!(res, Err(orm::Error::UniqueViolation(_))),
+        "{}",
+        fmt_res(&res)
+    );
+    let ids = tx
+        .all::<Account>()
+        .unwrap()
+        .iter()
+        .map(|account| account.id())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, [ObjectId::from(3), ObjectId::from(7)]);
+}
+
+#[test]
+fn paged_stream() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.paged_stream::<Product>(10).count(), 0);
+
+    let mut ids = vec![];
+    for i in 0..25 {
+        let product = tx
+            .create(Product {
+                sku: format!("item-{}", i),
+                price: i,
+            })
+            .unwrap();
+        ids.push(product.id());
+    }
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let cached = tx.get::<Product>(ids[12]).unwrap();
+    cached.borrow_mut().price = 100;
+    tx.get::<Product>(ids[3]).unwrap().delete().unwrap();
+
+    let mut page_sizes = vec![];
+    let mut seen = vec![];
+    for page in tx.paged_stream::<Product>(10) {
+        let page = page.unwrap();
+        page_sizes.push(page.len());
+        for product in page {
+            if product.id() == ids[12] {
+                assert!(product.ptr_eq(&cached));
+            }
+            seen.push(product.id());
+        }
+    }
+    // the deleted object is skipped, so the first page is one object shorter
+    assert_eq!(page_sizes, [9, 10, 5]);
+    assert_eq!(seen, [&ids[..3], &ids[4..]].concat());
+    assert_eq!(cached.borrow().price, 100);
+}
+
+#[derive(Object)]
+struct Gadget {
+    // a qualified name is valid in SELECT, but the result column is named just `price`
+    #[column_name("Gadget.price")]
+    price: i64,
+}
+
+#[test]
+fn missing_column_in_result_set() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.execute_raw(
+        "CREATE TABLE Gadget(id INTEGER PRIMARY KEY AUTOINCREMENT, price INTEGER)",
+        &[],
+    )
+    .unwrap();
+    tx.execute_raw("INSERT INTO Gadget(price) VALUES (10)", &[])
+        .unwrap();
+
+    match tx.get::<Gadget>(ObjectId::from(1)) {
+        Err(orm::Error::MissingColumn(err)) => {
+            assert_eq!(err.type_name, "Gadget");
+            assert_eq!(err.attr_name, "price");
+            assert_eq!(err.table_name, "Gadget");
+            assert_eq!(err.column_name, "Gadget.price");
+        }
+        res => panic!("expected Error::MissingColumn, got {}", fmt_res(&res)),
+    }
+}
+
+#[derive(Object)]
+#[trigger(
+    "AFTER INSERT ON Gauge BEGIN \
+        UPDATE Gauge SET reading = NEW.missing WHERE id = NEW.id; \
+    END"
+)]
+struct Gauge {
+    reading: i64,
+}
+
+#[test]
+fn missing_column_messages() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+
+    // the column referenced by the trigger doesn't belong to the object
+    match tx.create(Gauge { reading: 1 }) {
+        Err(orm::Error::Storage(err)) => {
+            assert!(err.to_string().contains("no such column: NEW.missing"))
+        }
+        res => panic!("expected Error::Storage, got {}", fmt_res(&res)),
+    }
+    tx.rollback().unwrap();
+
+    // "table Gauge has no column named reading"
+    let tx = conn.new_transaction().unwrap();
+    tx.execute_raw(
+        "CREATE TABLE Gauge(id INTEGER PRIMARY KEY AUTOINCREMENT, value INTEGER)",
+        &[],
+    )
+    .unwrap();
+    match tx.create(Gauge { reading: 1 }) {
+        Err(orm::Error::MissingColumn(err)) => {
+            assert_eq!(err.attr_name, "reading");
+            assert_eq!(err.column_name, "reading");
+        }
+        res => panic!("expected Error::MissingColumn, got {}", fmt_res(&res)),
+    }
+}
+
+#[test]
+fn query_map() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let mut users = vec![];
+    for name in ["Leo", "Ann"] {
+        let user = tx
+            .create(User {
+                name: name.into(),
+                ..Default::default()
+            })
+            .unwrap();
+        users.push(user);
+    }
+    for (author, title) in [(0, "Hello"), (0, "Again"), (1, "Hi")] {
+        tx.create(Post {
+            author: users[author].id().into(),
+            title: title.into(),
+        })
+        .unwrap();
+    }
+
+    let sql = "SELECT User.name, COUNT(*), MAX(length(Post.title)) \
+        FROM Post JOIN User ON Post.author = User.id \
+        WHERE User.name <> ? GROUP BY User.id ORDER BY User.name";
+    let map = |row: &rusqlite::Row| -> Result<(String, i64, i64)> {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    };
+    let stats = tx
+        .query_map(sql, &[orm::data::Value::String("Bob".into())], map)
+        .unwrap();
+    assert_eq!(stats, [("Ann".into(), 1, 2), ("Leo".into(), 2, 5)]);
+
+    // the cache is bypassed, so the uncommitted rename isn't seen
+    users[1].borrow_mut().name = "Bob".into();
+    let stats = tx
+        .query_map(sql, &[orm::data::Value::String("Bob".into())], map)
+        .unwrap();
+    assert_eq!(stats.len(), 2);
+
+    let res = tx.query_map(
+        "SELECT name FROM User",
+        &[],
+        |row| Ok(row.get::<_, i64>(0)?),
+    );
+    assert!(matches!(res, Err(orm::Error::Storage(_))));
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Note {
+    title: String,
+    #[serialize_with("rle_encode")]
+    #[deserialize_with("rle_decode")]
+    body: String,
+}
+
+// Run-length encoding as (count, byte) pairs.
+fn rle_encode(text: &str) -> Vec<u8> {
+    let mut res: Vec<u8> = vec![];
+    for byte in text.bytes() {
+        match res.len() {
+            len if len > 0 && res[len - 1] == byte && res[len - 2] < u8::MAX => res[len - 2] += 1,
+            _ => res.extend([1, byte]),
+        }
+    }
+    res
+}
+
+fn rle_decode(bytes: Vec<u8>) -> String {
+    let text = bytes
+        .chunks(2)
+        .flat_map(|pair| std::iter::repeat_n(pair[1], pair[0] as usize))
+        .collect();
+    String::from_utf8(text).unwrap()
+}
+
+#[test]
+fn serialize_with() {
+    assert_eq!(Note::SCHEMA.fields[1].column_type, DataType::Bytes);
+
+    let note = Note {
+        title: "Zzz".into(),
+        body: format!("{}!", "z".repeat(300)),
+    };
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx.create(note.clone()).unwrap().id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(*tx.get::<Note>(id).unwrap().borrow(), note);
+    let stored = tx
+        .query_map("SELECT body FROM Note", &[], |row| {
+            Ok(row.get::<_, Vec<u8>>(0)?)
+        })
+        .unwrap();
+    assert_eq!(stored, [vec![255, b'z', 45, b'z', 1, b'!']]);
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object)]
+#[table_name("Shipment")]
+struct UnindexedShipment {
+    destination: String,
+    weight: i64,
+}
+
+#[derive(Object)]
+struct Shipment {
+    #[index]
+    destination: String,
+    #[index]
+    weight: i64,
+}
+
+fn shipment_indexes(tx: &orm::Transaction) -> Vec<String> {
+    tx.query_map(
+        "SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = 'Shipment' \
+            ORDER BY name",
+        &[],
+        |row| Ok(row.get(0)?),
+    )
+    .unwrap()
+}
+
+#[test]
+fn ensure_indexes() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.create(UnindexedShipment {
+        destination: "Oslo".into(),
+        weight: 10,
+    })
+    .unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert!(shipment_indexes(&tx).is_empty());
+    tx.ensure_indexes::<Shipment>().unwrap();
+    tx.ensure_indexes::<Shipment>().unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let expected = ["Shipment_destination_index", "Shipment_weight_index"];
+    assert_eq!(shipment_indexes(&tx), expected);
+    let shipments = tx
+        .find_where_raw::<Shipment>(
+            "destination = ?",
+            &[orm::data::Value::String("Oslo".into())],
+        )
+        .unwrap();
+    assert_eq!(shipments.len(), 1);
+    drop(shipments);
+
+    // a freshly created table gets its indexes right away
+    tx.drop_table::<Shipment>().unwrap();
+    assert!(shipment_indexes(&tx).is_empty());
+    tx.create(Shipment {
+        destination: "Rome".into(),
+        weight: 5,
+    })
+    .unwrap();
+    assert_eq!(shipment_indexes(&tx), expected);
+}
+
+#[test]
+fn storage_key() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    for weight in [3, 7] {
+        tx.create(Shipment {
+            destination: "Oslo".into(),
+            weight,
+        })
+        .unwrap();
+    }
+    let shipment = tx
+        .query::<Shipment>()
+        .filter("weight", Op::Eq, orm::data::Value::Int64(7))
+        .first()
+        .unwrap()
+        .unwrap();
+
+    let rowid = tx
+        .query_map("SELECT rowid FROM Shipment WHERE weight = 7", &[], |row| {
+            Ok(orm::data::Value::Int64(row.get(0)?))
+        })
+        .unwrap();
+    assert!(shipment.storage_key() == rowid[0]);
+    assert!(shipment.storage_key() == orm::data::Value::Int64(shipment.id().into_i64()));
+
+    // a `#[primary_key]` field replaces the synthetic id
+    let account = tx
+        .create(Account {
+            user_id: 42,
+            email: "bob@example.com".into(),
+        })
+        .unwrap();
+    let rowid = tx
+        .query_map("SELECT rowid FROM Account", &[], |row| {
+            Ok(orm::data::Value::Int64(row.get(0)?))
+        })
+        .unwrap();
+    assert!(account.storage_key() == orm::data::Value::Int64(42));
+    assert!(account.storage_key() == rowid[0]);
+}
+
+#[test]
+fn auto_create_tables() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    conn.auto_create_tables(false);
+
+    let tx = conn.new_transaction().unwrap();
+    let product = Product {
+        sku: "A-1".into(),
+        price: 10,
+    };
+    for res in [
+        tx.create(product.clone()).map(|_| ()),
+        tx.get::<Product>(ObjectId::from(1)).map(|_| ()),
+        tx.query::<Product>().all().map(|_| ()),
+    ] {
+        match res {
+            Err(orm::Error::MissingTable(err)) => {
+                assert_eq!(err.type_name, "Product");
+                assert_eq!(err.table_name, "Product");
+            }
+            res => panic!("expected Error::MissingTable, got {}", fmt_res(&res)),
+        }
+    }
+    tx.rollback().unwrap();
+
+    conn.auto_create_tables(true);
+    let tx = conn.new_transaction().unwrap();
+    let id = tx.create(product.clone()).unwrap().id();
+    tx.commit().unwrap();
+
+    // existing tables are used as usual
+    conn.auto_create_tables(false);
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(*tx.get::<Product>(id).unwrap().borrow(), product);
+}
+
+#[test]
+fn query_scope() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+
+    let names = |people: Vec<Tx<Person>>| {
+        people
+            .iter()
+            .map(|person| {
+                let person = person.borrow();
+                format!("{} {}", person.first_name, person.last_name)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let adults = orm::Scope::<Person>::new()
+        .filter("age", Op::Ge, orm::data::Value::Int64(21))
+        .order_by("first_name", orm::Order::Asc);
+
+    let petrovs = tx
+        .query::<Person>()
+        .scope(&adults)
+        .filter(
+            "last_name",
+            Op::Eq,
+            orm::data::Value::String("Petrov".into()),
+        )
+        .all()
+        .unwrap();
+    assert_eq!(names(petrovs), ["Boris Petrov", "Ivan Petrov"]);
+
+    let first_adults = tx.query::<Person>().scope(&adults).limit(2).all().unwrap();
+    assert_eq!(names(first_adults), ["Anna Sidorova", "Boris Petrov"]);
+
+    let broken = orm::Scope::<Person>::new().filter("height", Op::Gt, orm::data::Value::Int64(1));
+    assert!(matches!(
+        tx.query::<Person>().scope(&broken).all(),
+        Err(orm::Error::UnknownColumn(_))
+    ));
+}
+
+#[test]
+fn export_all() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert!(tx.export_all::<Person>().unwrap().is_empty());
+    create_people(&tx);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let people = tx.export_all::<Person>().unwrap();
+    assert_eq!(tx.cache_len(), 0);
+    let names = people
+        .iter()
+        .map(|person| person.first_name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["Ivan", "Anna", "Boris", "Anna", "Oleg"]);
+
+    // cached objects are neither used nor added
+    let first = tx.query::<Person>().first().unwrap().unwrap();
+    first.borrow_mut().first_name = "Ivan II".into();
+    assert_eq!(tx.export_all::<Person>().unwrap(), people);
+    assert_eq!(tx.cache_len(), 1);
+}
+
+#[test]
+fn disk_errors() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.create(Note {
+        title: "".into(),
+        body: "".into(),
+    })
+    .unwrap();
+    tx.query_map("PRAGMA max_page_count = 8", &[], |_| Ok(()))
+        .unwrap();
+
+    let res = tx.create(Note {
+        title: "x".repeat(100_000),
+        body: "".into(),
+    });
+    assert!(
+        matches!(res, Err(orm::Error::DiskFull)),
+        "{}",
+        fmt_res(&res)
+    );
+
+    let io_error = rusqlite::Error::SqliteFailure(
+        rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_IOERR_WRITE),
+        None,
+    );
+    assert!(matches!(orm::Error::from(io_error), orm::Error::IoFailure));
+}
+
+#[test]
+fn find_in() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let products = (0..1500)
+        .map(|i| Product {
+            sku: format!("item-{}", i),
+            price: i,
+        })
+        .collect::<Vec<_>>();
+    tx.upsert_many(products, "sku").unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let sku = |i: i64| orm::data::Value::String(format!("item-{}", i).into());
+    // odd prices from the end, some values twice and some missing, 1502 values in total
+    let mut values = (0..1400)
+        .rev()
+        .filter(|i| i % 2 == 1)
+        .map(sku)
+        .collect::<Vec<_>>();
+    values.extend((0..800).map(|i| sku(2 * i + 1)));
+    values.extend((1500..1502).map(sku));
+
+    let found = tx.find_in::<Product>("sku", &values).unwrap();
+    let prices = found
+        .iter()
+        .map(|product| product.borrow().price)
+        .collect::<Vec<_>>();
+    assert_eq!(prices, (0..1500).filter(|i| i % 2 == 1).collect::<Vec<_>>());
+    assert!(found
+        .windows(2)
+        .all(|pair| pair[0].id().into_i64() < pair[1].id().into_i64()));
+
+    assert!(tx.find_in::<Product>("sku", &[]).unwrap().is_empty());
+    assert!(matches!(
+        tx.find_in::<Product>("name", &values),
+        Err(orm::Error::UnknownColumn(_))
+    ));
+}
+
+#[test]
+fn transaction_size_limit() {
+    let is_too_large = |res: Result<()>| match res {
+        Err(orm::Error::TransactionTooLarge(err)) => err.limit == 3,
+        _ => false,
+    };
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    conn.transaction_size_limit(Some(3));
+    let tx = conn.new_transaction().unwrap();
+    let person = |age| Person {
+        first_name: "Ann".into(),
+        last_name: "Lee".into(),
+        age,
+    };
+    for age in 0..3 {
+        tx.create(person(age)).unwrap();
+    }
+    assert!(is_too_large(tx.create(person(3)).map(|_| ())));
+    tx.commit().unwrap();
+
+    conn.transaction_size_limit(None);
+    let tx = conn.new_transaction().unwrap();
+    tx.create(person(3)).unwrap();
+    tx.commit().unwrap();
+
+    conn.transaction_size_limit(Some(3));
+    let tx = conn.new_transaction().unwrap();
+    let people = tx.query::<Person>().all().unwrap();
+    people[0].borrow_mut().age += 1;
+    people[0].borrow_mut().age += 1;
+    people[1].borrow_mut().age += 1;
+    people[2].clone().delete().unwrap();
+    // changes are counted per object, so the limit is reached but not exceeded
+    assert!(tx.query::<Person>().first().is_ok());
+    assert!(is_too_large(tx.create(person(4)).map(|_| ())));
+
+    // over the limit, reads still work but writes are refused
+    people[3].borrow_mut().age += 1;
+    assert!(tx.get::<Person>(people[0].id()).is_ok());
+    assert!(tx.verify_schema::<Person>().is_ok());
+    assert!(is_too_large(
+        tx.update(people[0].id(), person(5)).map(|_| ())
+    ));
+    assert!(is_too_large(
+        tx.delete_where::<Person>("age", Op::Eq, orm::data::Value::Int64(0))
+            .map(|_| ())
+    ));
+
+    // created objects are not counted again when modified
+    tx.checkpoint().unwrap();
+    let created = tx.create(person(5)).unwrap();
+    created.borrow_mut().age += 1;
+    tx.create(person(6)).unwrap();
+    tx.create(person(7)).unwrap();
+    assert!(is_too_large(tx.create(person(8)).map(|_| ())));
+}
+
+#[test]
+fn lenient_reads() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    // columns without a declared type keep values as they were inserted
+    tx.execute_raw(
+        "CREATE TABLE Person(id INTEGER PRIMARY KEY AUTOINCREMENT, first_name, last_name, age)",
+        &[],
+    )
+    .unwrap();
+    tx.execute_raw(
+        "INSERT INTO Person(first_name, last_name, age) VALUES (7, 'Lee', ' 42 '), \
+            ('Ann', 'Lee', 'old')",
+        &[],
+    )
+    .unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let res = tx.get::<Person>(ObjectId::from(1));
+    assert!(matches!(res, Err(orm::Error::UnexpectedType(_))));
+    tx.rollback().unwrap();
+
+    conn.lenient_reads(true);
+    let tx = conn.new_transaction().unwrap();
+    let person = tx.get::<Person>(ObjectId::from(1)).unwrap();
+    assert_eq!(person.borrow().first_name, "7");
+    assert_eq!(person.borrow().age, 42);
+
+    match tx.get::<Person>(ObjectId::from(2)) {
+        Err(orm::Error::UnexpectedType(err)) => {
+            assert_eq!(err.column_name, "age");
+            assert_eq!(err.got_value.as_deref(), Some("'old'"));
+        }
+        res => panic!("expected Error::UnexpectedType, got {}", fmt_res(&res)),
+    }
+}
+
+#[test]
+fn connection_builder() {
+    use orm::{JournalMode, Synchronous};
+    use rusqlite::types::Value;
+    use std::time::Duration;
+
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut conn = Connection::builder()
+        .busy_timeout(Duration::from_millis(1500))
+        .journal_mode(JournalMode::Wal)
+        .synchronous(Synchronous::Normal)
+        .foreign_keys(true)
+        .cache_size(-4000)
+        .auto_create_tables(false)
+        .open(&path)
+        .unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let pragma = |name: &str| {
+        let sql = format!("PRAGMA {}", name);
+        tx.query_map(&sql, &[], |row| Ok(row.get::<_, Value>(0)?))
+            .unwrap()
+            .remove(0)
+    };
+    assert_eq!(pragma("journal_mode"), Value::Text("wal".into()));
+    assert_eq!(pragma("busy_timeout"), Value::Integer(1500));
+    assert_eq!(pragma("synchronous"), Value::Integer(1));
+    assert_eq!(pragma("foreign_keys"), Value::Integer(1));
+    assert_eq!(pragma("cache_size"), Value::Integer(-4000));
+
+    let res = tx.query::<Person>().all();
+    assert!(matches!(res, Err(orm::Error::MissingTable(_))));
+    tx.rollback().unwrap();
+
+    // options which aren't set keep the defaults
+    let mut conn = Connection::builder()
+        .transaction_size_limit(1)
+        .open_in_memory()
+        .unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let person = |age| Person {
+        first_name: "Ann".into(),
+        last_name: "Lee".into(),
+        age,
+    };
+    tx.create(person(1)).unwrap();
+    let res = tx.create(person(2));
+    assert!(matches!(res, Err(orm::Error::TransactionTooLarge(_))));
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Contact {
+    first: String,
+    last: String,
+    #[generated("first || ' ' || last", stored)]
+    #[index]
+    full_name: String,
+    #[generated("length(first)")]
+    first_len: i64,
+}
+
+#[test]
+fn generated_columns() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let contact = tx
+        .create(Contact {
+            first: "Ann".into(),
+            last: "Lee".into(),
+            full_name: "ignored".into(),
+            first_len: 0,
+        })
+        .unwrap();
+    assert_eq!(contact.borrow().full_name, "Ann Lee");
+    assert_eq!(contact.borrow().first_len, 3);
+
+    // writes to generated fields are ignored
+    contact.borrow_mut().first = "Anna".into();
+    contact.borrow_mut().full_name = "ignored".into();
+    let id = contact.id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let found = tx
+        .query::<Contact>()
+        .filter(
+            "full_name",
+            Op::Eq,
+            orm::data::Value::String("Anna Lee".into()),
+        )
+        .all()
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id(), id);
+    assert_eq!(found[0].borrow().first_len, 4);
+
+    let sql = "SELECT sql FROM sqlite_master WHERE name = 'Contact_full_name_index'";
+    let indexes = tx
+        .query_map(sql, &[], |row| Ok(row.get::<_, String>(0)?))
+        .unwrap();
+    assert_eq!(indexes.len(), 1);
+}
+
+#[test]
+fn query_index_hints() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    for (first, last) in [("Ann", "Lee"), ("Bob", "Lee"), ("Ann", "Kim")] {
+        tx.create(Contact {
+            first: first.into(),
+            last: last.into(),
+            full_name: String::new(),
+            first_len: 0,
+        })
+        .unwrap();
+    }
+
+    let full_name = || orm::data::Value::String("Ann Lee".into());
+    let found = tx
+        .query::<Contact>()
+        .filter("full_name", Op::Eq, full_name())
+        .indexed_by("Contact_full_name_index")
+        .all()
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].borrow().last, "Lee");
+
+    let found = tx
+        .query::<Contact>()
+        .filter("first", Op::Eq, orm::data::Value::String("Ann".into()))
+        .not_indexed()
+        .all()
+        .unwrap();
+    assert_eq!(found.len(), 2);
+
+    match tx
+        .query::<Contact>()
+        .filter("full_name", Op::Eq, full_name())
+        .indexed_by("Contact_missing_index")
+        .all()
+    {
+        Err(orm::Error::MissingIndex(err)) => {
+            assert_eq!(err.index_name, "Contact_missing_index");
+            assert_eq!(err.table_name, "Contact");
+        }
+        res => panic!("expected Error::MissingIndex, got {}", fmt_res(&res)),
+    }
+    let res = tx
+        .query::<Contact>()
+        .indexed_by("Contact_missing_index")
+        .count();
+    assert!(matches!(res, Err(orm::Error::MissingIndex(_))));
+}
+
+#[test]
+fn table_prefix() {
+    let mut conn = Connection::builder()
+        .table_prefix("app_")
+        .open_in_memory()
+        .unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let contact = tx
+        .create(Contact {
+            first: "Ann".into(),
+            last: "Lee".into(),
+            full_name: String::new(),
+            first_len: 0,
+        })
+        .unwrap()
+        .id();
+    let author = tx.create(User::default()).unwrap().id();
+    tx.create(Chapter {
+        author: author.into(),
+        previous: None,
+    })
+    .unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let names = tx
+        .query_map(
+            "SELECT name FROM sqlite_master WHERE name NOT LIKE 'sqlite_%' ORDER BY name",
+            &[],
+            |row| Ok(row.get::<_, String>(0)?),
+        )
+        .unwrap();
+    assert_eq!(
+        names,
+        [
+            "app_Chapter",
+            "app_Contact",
+            "app_Contact_full_name_index",
+            "app_User"
+        ]
+    );
+
+    // the existing tables are found again, indexes are named with the prefix
+    let found = tx
+        .query::<Contact>()
+        .filter(
+            "full_name",
+            Op::Eq,
+            orm::data::Value::String("Ann Lee".into()),
+        )
+        .indexed_by("app_Contact_full_name_index")
+        .all()
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id(), contact);
+
+    // foreign keys refer to the prefixed tables
+    let res = tx.create(Chapter {
+        author: ObjectId::from(42).into(),
+        previous: None,
+    });
+    assert!(matches!(res, Err(orm::Error::ForeignKeyViolation(_))));
+}
+
+#[test]
+fn attached_databases() {
+    let tenant_a = NamedTempFile::new().unwrap().into_temp_path();
+    let tenant_b = NamedTempFile::new().unwrap().into_temp_path();
+    let mut conn = Connection::open_in_memory().unwrap();
+    conn.attach(&tenant_a, "tenant_a").unwrap();
+    conn.attach(&tenant_b, "tenant_b").unwrap();
+
+    let contact = |first: &str| Contact {
+        first: first.into(),
+        last: "Lee".into(),
+        full_name: String::new(),
+        first_len: 0,
+    };
+    for (database, names) in [("tenant_a", ["Ann", "Bob"]), ("tenant_b", ["Eve", "Max"])] {
+        let tx = conn.transaction_in(database).unwrap();
+        for name in names {
+            tx.create(contact(name)).unwrap();
+        }
+        tx.commit().unwrap();
+    }
+
+    let tx = conn.transaction_in("tenant_b").unwrap();
+    let contacts = tx.query::<Contact>().all().unwrap();
+    let names = contacts
+        .iter()
+        .map(|contact| contact.borrow().first.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["Eve", "Max"]);
+    contacts[0].clone().delete().unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.transaction_in("tenant_a").unwrap();
+    assert_eq!(tx.query::<Contact>().all().unwrap().len(), 2);
+    // the index is created in the attached database along with the table
+    let sql = "SELECT name FROM tenant_a.sqlite_master WHERE type = 'index'";
+    let indexes = tx
+        .query_map(sql, &[], |row| Ok(row.get::<_, String>(0)?))
+        .unwrap();
+    assert_eq!(indexes, ["Contact_full_name_index"]);
+    tx.commit().unwrap();
+
+    // nothing is stored in the main database
+    let tx = conn.new_transaction().unwrap();
+    assert!(tx.query::<Contact>().all().unwrap().is_empty());
+    tx.commit().unwrap();
+
+    // names are not interpreted as SQL
+    let tenant_c = NamedTempFile::new().unwrap().into_temp_path();
+    let name = "tenant \"c\"; --";
+    conn.attach(&tenant_c, name).unwrap();
+    let tx = conn.transaction_in(name).unwrap();
+    tx.create(contact("Kim")).unwrap();
+    assert_eq!(tx.query::<Contact>().all().unwrap().len(), 1);
+    tx.commit().unwrap();
+    conn.detach(name).unwrap();
+
+    conn.detach("tenant_b").unwrap();
+    let mut conn = Connection::open_sqlite_file(&tenant_b).unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.query::<Contact>().all().unwrap().len(), 1);
+}
+
+#[test]
+fn apply_graph() {
+    let product = |sku: &str, price| Product {
+        sku: sku.into(),
+        price,
+    };
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let changes = tx
+        .apply_graph(
+            vec![product("A", 1), product("B", 2), product("C", 3)],
+            "sku",
+        )
+        .unwrap();
+    assert_eq!(changes.inserted, 3);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let desired = vec![
+        product("D", 4),
+        product("C", 30),
+        product("A", 1),
+        product("E", 5),
+    ];
+    let changes = tx.apply_graph(desired, "sku").unwrap();
+    assert_eq!(
+        changes,
+        orm::GraphChanges {
+            inserted: 2,
+            updated: 1,
+            deleted: 1,
+        }
+    );
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let products = tx
+        .query::<Product>()
+        .order_by("sku", orm::Order::Asc)
+        .all()
+        .unwrap()
+        .iter()
+        .map(|product| product.borrow().clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        products,
+        [
+            product("A", 1),
+            product("C", 30),
+            product("D", 4),
+            product("E", 5)
+        ]
+    );
+
+    let res = tx.apply_graph(vec![product("A", 1)], "name");
+    assert!(matches!(res, Err(orm::Error::UnknownColumn(_))));
+
+    // repeated keys are refused before anything is written
+    let desired = vec![product("F", 6), product("G", 7), product("F", 8)];
+    match tx.apply_graph(desired, "sku") {
+        Err(orm::Error::UniqueViolation(err)) => assert_eq!(err.column_name, "sku"),
+        res => panic!("expected Error::UniqueViolation, got {}", fmt_res(&res)),
+    }
+    assert_eq!(tx.count::<Product>().unwrap(), 4);
+
+    // only the changed columns are written into stored objects, the rest of them is kept
+    let view = tx
+        .create(PageView {
+            page: "/".into(),
+            created_at: 0,
+            updated_at: 0,
+            scroll: 80,
+        })
+        .unwrap();
+    let mut desired = view.borrow().clone();
+    desired.updated_at += 1;
+    desired.scroll = 0;
+    let changes = tx.apply_graph(vec![desired], "page").unwrap();
+    assert_eq!(changes.updated, 1);
+    assert_eq!(view.borrow().scroll, 80);
+}
+
+#[test]
+fn user_version() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut conn = Connection::open_sqlite_file(&path).unwrap();
+    assert_eq!(conn.user_version().unwrap(), 0);
+    conn.set_user_version(3).unwrap();
+    assert_eq!(conn.user_version().unwrap(), 3);
+    drop(conn);
+
+    let mut conn = Connection::open_sqlite_file(&path).unwrap();
+    assert_eq!(conn.user_version().unwrap(), 3);
+    conn.set_user_version(-1).unwrap();
+    assert_eq!(conn.user_version().unwrap(), -1);
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Profile {
+    login: String,
+    nickname: Option<String>,
+    height: Option<f64>,
+}
+
+#[test]
+fn optional_fields() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let with_nickname = Profile {
+        login: "ann".into(),
+        nickname: Some("Annie".into()),
+        height: None,
+    };
+    let without_nickname = Profile {
+        login: "bob".into(),
+        nickname: None,
+        height: Some(1.8),
+    };
+    let id_1 = tx.create(with_nickname.clone()).unwrap().id();
+    let id_2 = tx.create(without_nickname.clone()).unwrap().id();
+    tx.commit().unwrap();
+
+    assert!(Profile::SCHEMA.fields[1].nullable);
+    assert!(!Profile::SCHEMA.fields[0].nullable);
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(*tx.get::<Profile>(id_1).unwrap().borrow(), with_nickname);
+    let profile = tx.get::<Profile>(id_2).unwrap();
+    assert_eq!(*profile.borrow(), without_nickname);
+
+    let found = tx
+        .query::<Profile>()
+        .filter("nickname", Op::Eq, orm::data::Value::Null)
+        .all()
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id(), id_2);
+
+    profile.borrow_mut().nickname = Some("Bobby".into());
+    tx.get::<Profile>(id_1).unwrap().borrow_mut().nickname = None;
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.get::<Profile>(id_1).unwrap().borrow().nickname, None);
+    assert_eq!(
+        tx.get::<Profile>(id_2)
+            .unwrap()
+            .borrow()
+            .nickname
+            .as_deref(),
+        Some("Bobby")
+    );
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Counters {
+    a: i32,
+    b: u32,
+    c: i16,
+    d: u16,
+    e: u8,
+}
+
+fn fits<T: orm::AsDataType>(x: i64) -> bool {
+    T::from_value(&orm::data::Value::Int64(x)).is_ok()
+}
+
+#[test]
+fn narrow_integers() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let max = Counters {
+        a: i32::MAX,
+        b: u32::MAX,
+        c: i16::MAX,
+        d: u16::MAX,
+        e: u8::MAX,
+    };
+    let min = Counters {
+        a: i32::MIN,
+        b: 0,
+        c: i16::MIN,
+        d: 0,
+        e: 0,
+    };
+    let max_id = tx.create(max.clone()).unwrap().id();
+    let min_id = tx.create(min.clone()).unwrap().id();
+    tx.commit().unwrap();
+
+    assert!(Counters::SCHEMA
+        .column_types()
+        .all(|data_type| data_type == DataType::Int64));
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(*tx.get::<Counters>(max_id).unwrap().borrow(), max);
+    assert_eq!(*tx.get::<Counters>(min_id).unwrap().borrow(), min);
+
+    // values out of range are never wrapped
+    for (min, max, fits) in [
+        (
+            i32::MIN as i64,
+            i32::MAX as i64,
+            fits::<i32> as fn(i64) -> bool,
+        ),
+        (0, u32::MAX as i64, fits::<u32>),
+        (i16::MIN as i64, i16::MAX as i64, fits::<i16>),
+        (0, u16::MAX as i64, fits::<u16>),
+        (0, u8::MAX as i64, fits::<u8>),
+    ] {
+        assert!(fits(min) && fits(max));
+        assert!(!fits(min - 1) && !fits(max + 1));
+    }
+}
+
+#[test]
+fn for_each() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+
+    let mut total_age = 0;
+    let mut count = 0;
+    tx.for_each(|person: Person| {
+        total_age += person.age;
+        count += 1;
+        Ok(())
+    })
+    .unwrap();
+    let people = tx.query::<Person>().all().unwrap();
+    assert_eq!(count, people.len());
+    let expected = people.iter().map(|person| person.borrow().age).sum::<i64>();
+    assert_eq!(total_age, expected);
+
+    // the first error stops the iteration
+    let mut seen = 0;
+    let res = tx.for_each(|_: Person| {
+        seen += 1;
+        match seen {
+            2 => Err(orm::Error::LockConflict),
+            _ => Ok(()),
+        }
+    });
+    assert!(matches!(res, Err(orm::Error::LockConflict)));
+    assert_eq!(seen, 2);
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Reading {
+    value: f32,
+}
+
+#[test]
+fn f32_fields() {
+    let values = [0.1f32, -273.15, f32::MIN_POSITIVE, f32::MAX, f32::EPSILON];
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let ids = values
+        .iter()
+        .map(|value| tx.create(Reading { value: *value }).unwrap().id())
+        .collect::<Vec<_>>();
+    tx.commit().unwrap();
+
+    assert_eq!(Reading::SCHEMA.fields[0].column_type, DataType::Float64);
+    let tx = conn.new_transaction().unwrap();
+    for (id, value) in ids.into_iter().zip(values) {
+        let stored = tx.get::<Reading>(id).unwrap().borrow().value;
+        assert_eq!(stored.to_bits(), value.to_bits());
+    }
+}
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Grade {
+    letter: char,
+    modifier: Option<char>,
+}
+
+#[test]
+fn char_fields() {
+    let grades = [
+        Grade {
+            letter: 'A',
+            modifier: Some('+'),
+        },
+        Grade {
+            letter: 'é',
+            modifier: None,
+        },
+    ];
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    for grade in grades.iter() {
+        tx.create(grade.clone()).unwrap();
+    }
+    tx.commit().unwrap();
+
+    assert_eq!(Grade::SCHEMA.fields[0].column_type, DataType::String);
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.export_all::<Grade>().unwrap(), grades);
+    let texts = tx
+        .query_map("SELECT letter FROM Grade ORDER BY id", &[], |row| {
+            row.get::<_, String>(0).map_err(Into::into)
+        })
+        .unwrap();
+    assert_eq!(texts, ["A", "é"]);
+
+    for letter in ["AB", ""] {
+        tx.execute_raw(
+            "UPDATE Grade SET letter = ? WHERE id = 1",
+            &[orm::data::Value::String(letter.into())],
+        )
+        .unwrap();
+        match tx.get::<Grade>(ObjectId::from(1)) {
+            Err(orm::Error::UnexpectedType(err)) => {
+                assert_eq!(err.attr_name, "letter");
+                assert_eq!(
+                    err.got_type,
+                    format!("String '{}', which isn't a single character", letter)
+                );
+            }
+            res => panic!("expected Error::UnexpectedType, got {}", fmt_res(&res)),
+        }
+    }
+}
+
+#[test]
+fn tx_into_owned() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let product = tx
+        .create(Product {
+            sku: "A-1".into(),
+            price: 10,
+        })
+        .unwrap();
+    product.borrow_mut().price = 12;
+    let removed = tx
+        .create(Product {
+            sku: "A-2".into(),
+            price: 20,
+        })
+        .unwrap();
+    removed.clone().delete().unwrap();
+
+    let owned = product.into_owned().unwrap();
+    assert!(matches!(removed.into_owned(), Err(orm::Error::NotFound(_))));
+    // the copy is a clone, so fields which aren't stored are copied as well
+    let view = PageView {
+        page: "/".into(),
+        created_at: 0,
+        updated_at: 0,
+        scroll: 80,
+    };
+    let view = tx.create(view).unwrap().into_owned().unwrap();
+    assert_eq!(view.scroll, 80);
+    tx.commit().unwrap();
+    drop(conn);
+
+    assert_eq!(
+        owned,
+        Product {
+            sku: "A-1".into(),
+            price: 12,
+        }
+    );
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Account {
+    #[primary_key]
+    user_id: i64,
+    email: String,
+}
+
+#[test]
+fn primary_key() {
+    let account = |user_id, email: &str| Account {
+        user_id,
+        email: email.into(),
+    };
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let created = tx.create(account(42, "ann@example.com")).unwrap();
+    assert_eq!(created.id(), ObjectId::from(42));
+    tx.create(account(7, "bob@example.com")).unwrap();
+    tx.create(account(100, "eve@example.com")).unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let sql = "SELECT name, pk FROM pragma_table_info('Account') ORDER BY cid";
+    let columns = tx
+        .query_map(sql, &[], |row| Ok((row.get::<_, String>(0)?, row.get(1)?)))
+        .unwrap();
+    assert_eq!(columns, [("user_id".into(), 1), ("email".into(), 0)]);
+
+    let ann = tx.get::<Account>(ObjectId::from(42)).unwrap();
+    assert_eq!(ann.borrow().email, "ann@example.com");
+    ann.borrow_mut().email = "anna@example.com".into();
+    tx.get::<Account>(ObjectId::from(7))
+        .unwrap()
+        .delete()
+        .unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let mut ids = vec![];
+    for page in tx.paged_stream::<Account>(1) {
+        ids.extend(page.unwrap().iter().map(|account| account.id().into_i64()));
+    }
+    assert_eq!(ids, [42, 100]);
+    assert_eq!(
+        tx.export_all::<Account>().unwrap(),
+        [
+            account(42, "anna@example.com"),
+            account(100, "eve@example.com")
+        ]
+    );
+    assert!(matches!(
+        tx.get::<Account>(ObjectId::from(7)),
+        Err(orm::Error::NotFound(_))
+    ));
+    match tx.create(account(42, "other@example.com")) {
+        Err(orm::Error::UniqueViolation(err)) => assert_eq!(err.column_name, "user_id"),
+        res => panic!("expected Error::UniqueViolation, got {}", fmt_res(&res)),
+    }
+}
+
+#[test]
+fn update_with_replacement() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+    tx.create(Account {
+        user_id: 7,
+        email: "bob@example.com".into(),
+    })
+    .unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let anna = tx.get::<Person>(ObjectId::from(2)).unwrap();
+    let replaced = tx
+        .update(
+            ObjectId::from(2),
+            Person {
+                first_name: "Anna".into(),
+                last_name: "Ivanova".into(),
+                age: 26,
+            },
+        )
+        .unwrap();
+    assert!(replaced.state() == ObjectState::Modified);
+    assert_eq!(anna.borrow().last_name, "Ivanova");
+
+    // the primary key stays as stored
+    let bob = tx
+        .update(
+            ObjectId::from(7),
+            Account {
+                user_id: 99,
+                email: "robert@example.com".into(),
+            },
+        )
+        .unwrap();
+    assert_eq!(bob.borrow().user_id, 7);
+
+    let missing = Person {
+        first_name: "Nobody".into(),
+        last_name: "Nobody".into(),
+        age: 0,
+    };
+    let res = tx.update(ObjectId::from(100), missing);
+    assert!(
+        matches!(res, Err(orm::Error::NotFound(_))),
+        "{}",
+        fmt_res(&res)
+    );
+
+    // only the fields it can't change are copied into the replacement, its skipped ones stay
+    let view = tx
+        .create(PageView {
+            page: "/".into(),
+            created_at: 0,
+            updated_at: 0,
+            scroll: 0,
+        })
+        .unwrap();
+    let created_at = view.borrow().created_at;
+    let replacement = PageView {
+        page: "/home".into(),
+        created_at: created_at + 1,
+        updated_at: 0,
+        scroll: 80,
+    };
+    tx.update(view.id(), replacement).unwrap();
+    assert_eq!(view.borrow().page, "/home");
+    assert_eq!(view.borrow().created_at, created_at);
+    assert_eq!(view.borrow().scroll, 80);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let anna = tx.get::<Person>(ObjectId::from(2)).unwrap();
+    assert_eq!(anna.borrow().last_name, "Ivanova");
+    assert_eq!(anna.borrow().age, 26);
+    let bob = tx.get::<Account>(ObjectId::from(7)).unwrap();
+    assert_eq!(bob.borrow().email, "robert@example.com");
+    assert_eq!(tx.count::<Account>().unwrap(), 1);
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Object, Clone, Debug, PartialEq)]
+#[rename_all("camelCase")]
+struct LegacyOrder {
+    order_date: String,
+    total_amount_cents: i64,
+    #[column_name("EXT_ID")]
+    external_id: String,
+}
+
+#[derive(Object, Clone, Debug, PartialEq)]
+#[rename_all("PascalCase")]
+struct LegacyInvoice {
+    due_date: String,
+    paid: bool,
+}
+
+#[test]
+fn rename_all() {
+    assert_eq!(
+        LegacyOrder::SCHEMA.column_names().collect::<Vec<_>>(),
+        ["orderDate", "totalAmountCents", "EXT_ID"]
+    );
+    assert_eq!(
+        LegacyInvoice::SCHEMA.column_names().collect::<Vec<_>>(),
+        ["DueDate", "Paid"]
+    );
+    assert_eq!(LegacyOrder::SCHEMA.fields[0].attr_name, "order_date");
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let order = LegacyOrder {
+        order_date: "2024-01-01".into(),
+        total_amount_cents: 1299,
+        external_id: "X-1".into(),
+    };
+    let id = tx.create(order.clone()).unwrap().id();
+    let totals = tx
+        .query_map("SELECT totalAmountCents FROM LegacyOrder", &[], |row| {
+            Ok(row.get::<_, i64>(0)?)
+        })
+        .unwrap();
+    assert_eq!(totals, [1299]);
+    assert_eq!(*tx.get::<LegacyOrder>(id).unwrap().borrow(), order);
+}
+
+#[derive(Object, Clone, Debug, PartialEq)]
+#[table_case(plural_snake)]
+struct Company {
+    name: String,
+}
+
+#[derive(Object, Clone, Debug, PartialEq)]
+#[table_case(plural_snake)]
+struct OrderItem {
+    quantity: i64,
+}
+
+#[derive(Object, Clone, Debug, PartialEq)]
+#[table_case(snake)]
+struct HTTPAddress {
+    host: String,
+}
+
+#[derive(Object, Clone, Debug, PartialEq)]
+#[table_case(plural_snake)]
+#[table_name("firms")]
+struct Firm {
+    name: String,
+}
+
+#[test]
+fn table_case() {
+    assert_eq!(Company::SCHEMA.table_name, "companies");
+    assert_eq!(OrderItem::SCHEMA.table_name, "order_items");
+    assert_eq!(HTTPAddress::SCHEMA.table_name, "http_address");
+    assert_eq!(Firm::SCHEMA.table_name, "firms");
+    // without the attribute the type name is used as is
+    assert_eq!(Person::SCHEMA.table_name, "Person");
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx
+        .create(Company {
+            name: "Acme".into(),
+        })
+        .unwrap()
+        .id();
+    let names = tx
+        .query_map("SELECT name FROM companies", &[], |row| {
+            Ok(row.get::<_, String>(0)?)
+        })
+        .unwrap();
+    assert_eq!(names, ["Acme"]);
+    assert_eq!(tx.get::<Company>(id).unwrap().borrow().name, "Acme");
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+struct Handle;
+
+fn new_handle() -> std::sync::mpsc::Sender<Handle> {
+    std::sync::mpsc::channel().0
+}
+
+#[derive(Object)]
+struct Session {
+    token: String,
+    #[skip]
+    conn: Option<std::rc::Rc<Handle>>,
+    #[skip(default = "new_handle")]
+    events: std::sync::mpsc::Sender<Handle>,
+    #[skip(default = "std::time::Instant::now")]
+    loaded_at: std::time::Instant,
+}
+
+#[test]
+fn skip_with_default() {
+    assert_eq!(
+        Session::SCHEMA.column_names().collect::<Vec<_>>(),
+        ["token"]
+    );
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let started = std::time::Instant::now();
+    let id = tx
+        .create(Session {
+            token: "abc".into(),
+            conn: Some(std::rc::Rc::new(Handle)),
+            events: new_handle(),
+            loaded_at: started,
+        })
+        .unwrap()
+        .id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let session = tx.get::<Session>(id).unwrap();
+    let session = session.borrow();
+    assert_eq!(session.token, "abc");
+    assert!(session.conn.is_none());
+    assert!(session.loaded_at >= started);
+    // the channel is disconnected, as a fresh one was created on load
+    assert!(session.events.send(Handle).is_err());
+}
+
+#[test]
+fn create_many() {
+    let person = |age| Person {
+        first_name: format!("Name{}", age),
+        last_name: "Lee".into(),
+        age,
+    };
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    // more rows than fit into a single statement
+    let people = (0..1000).map(person).collect::<Vec<_>>();
+    let created = tx.create_many(people.clone()).unwrap();
+    assert_eq!(created.len(), people.len());
+    assert_eq!(tx.cache_len(), people.len());
+    for (tx_person, person) in created.iter().zip(people.iter()) {
+        assert_eq!(*tx_person.borrow(), *person);
+    }
+    let ids = created.iter().map(|person| person.id()).collect::<Vec<_>>();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    for (id, person) in ids.into_iter().zip(people).step_by(99) {
+        assert_eq!(*tx.get::<Person>(id).unwrap().borrow(), person);
+    }
+
+    // values filled in by the database are read back
+    let comments = tx
+        .create_many(vec![
+            Comment {
+                text: "a".into(),
+                created_at: String::new(),
+                rating: 0,
+            },
+            Comment {
+                text: "b".into(),
+                created_at: String::new(),
+                rating: 0,
+            },
+        ])
+        .unwrap();
+    assert!(comments.iter().all(|comment| comment.borrow().rating == 10));
+    assert_eq!(comments[1].borrow().text, "b");
+
+    let accounts = tx
+        .create_many(vec![
+            Account {
+                user_id: 9,
+                email: "x".into(),
+            },
+            Account {
+                user_id: 3,
+                email: "y".into(),
+            },
+        ])
+        .unwrap();
+    assert_eq!(accounts[0].id(), ObjectId::from(9));
+    assert_eq!(accounts[1].borrow().email, "y");
+}
+
+#[test]
+fn all_objects() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let people = tx.all::<Person>().unwrap();
+    let count = people.len();
+    assert!(people
+        .windows(2)
+        .all(|pair| pair[0].id().into_i64() < pair[1].id().into_i64()));
+
+    people[0].borrow_mut().age = 1000;
+    people[1].clone().delete().unwrap();
+    let people = tx.all::<Person>().unwrap();
+    assert_eq!(people.len(), count - 1);
+    assert_eq!(people[0].borrow().age, 1000);
+    assert!(people[0].ptr_eq(&tx.get::<Person>(people[0].id()).unwrap()));
+}
+
+#[test]
+fn find_by() {
+    use orm::AsDataType;
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+
+    let found = tx
+        .find_by::<Person>("last_name", "Petrov".to_string().as_value())
+        .unwrap();
+    assert_eq!(found.len(), 3);
+    assert!(found
+        .iter()
+        .all(|person| person.borrow().last_name == "Petrov"));
+    assert!(found[0].ptr_eq(&tx.get::<Person>(found[0].id()).unwrap()));
+
+    let found = tx
+        .find_by::<Person>("last_name", "Nobody".to_string().as_value())
+        .unwrap();
+    assert!(found.is_empty());
+
+    let res = tx.find_by::<Person>("email", orm::data::Value::Null);
+    assert!(matches!(res, Err(orm::Error::UnknownColumn(_))));
+}
+
+#[test]
+fn invalid_stored_values() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.create(Counters {
+        a: 0,
+        b: 0,
+        c: 0,
+        d: 0,
+        e: 0,
+    })
+    .unwrap();
+    tx.create(LegacyAccount {
+        active: true,
+        verified: true,
+    })
+    .unwrap();
+    tx.create(Event::Close).unwrap();
+    tx.execute_raw("UPDATE Counters SET e = 256", &[]).unwrap();
+    tx.execute_raw("UPDATE LegacyAccount SET active = 'maybe'", &[])
+        .unwrap();
+    tx.execute_raw("UPDATE Event SET kind = 'Drag'", &[])
+        .unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    match tx.get::<Counters>(ObjectId::from(1)) {
+        Err(orm::Error::UnexpectedType(err)) => {
+            assert_eq!(err.attr_name, "e");
+            assert_eq!(err.expected_type, DataType::Int64);
+            assert_eq!(err.got_type, "Int64 256, which isn't u8");
+        }
+        res => panic!("expected Error::UnexpectedType, got {}", fmt_res(&res)),
+    }
+    match tx.all::<LegacyAccount>() {
+        Err(orm::Error::UnexpectedType(err)) => {
+            assert_eq!(err.attr_name, "active");
+            assert_eq!(err.got_type, "String 'maybe', which isn't 'Y' or 'N'");
+        }
+        res => panic!("expected Error::UnexpectedType, got {}", fmt_res(&res)),
+    }
+    match tx.get::<Event>(ObjectId::from(1)) {
+        Err(orm::Error::UnexpectedType(err)) => {
+            assert_eq!(err.attr_name, "variant");
+            assert_eq!(err.column_name, "kind");
+        }
+        res => panic!("expected Error::UnexpectedType, got {}", fmt_res(&res)),
+    }
+
+    // the failed reads leave the transaction usable
+    assert_eq!(tx.count::<Counters>().unwrap(), 1);
+}
+
+#[test]
+fn migrate() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.execute_raw(
+        "CREATE TABLE Profile(id INTEGER PRIMARY KEY AUTOINCREMENT, LOGIN TEXT)",
+        &[],
+    )
+    .unwrap();
+    tx.execute_raw("INSERT INTO Profile(login) VALUES ('ann')", &[])
+        .unwrap();
+
+    assert!(matches!(
+        tx.create(Profile {
+            login: "bob".into(),
+            nickname: None,
+            height: None,
+        }),
+        Err(orm::Error::MissingColumn(_))
+    ));
+    assert_eq!(tx.migrate::<Profile>().unwrap(), ["nickname", "height"]);
+    assert!(tx.migrate::<Profile>().unwrap().is_empty());
+
+    let bob = Profile {
+        login: "bob".into(),
+        nickname: Some("Bobby".into()),
+        height: Some(1.8),
+    };
+    tx.create(bob.clone()).unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let profiles = tx.all::<Profile>().unwrap();
+    assert_eq!(
+        *profiles[0].borrow(),
+        Profile {
+            login: "ann".into(),
+            nickname: None,
+            height: None,
+        }
+    );
+    assert_eq!(*profiles[1].borrow(), bob);
+}
+
+#[derive(Object, Clone, Debug, PartialEq)]
+#[table_name("Subscriber")]
+struct SubscriberWithEmail {
+    login: String,
+    #[unique]
+    email: Option<String>,
+}
+
+#[derive(Object, Clone, Debug, PartialEq)]
+#[table_name("Subscriber")]
+struct SubscriberWithJoinDate {
+    login: String,
+    #[default("CURRENT_TIMESTAMP")]
+    joined: String,
+}
+
+#[test]
+fn migrate_constraints() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.execute_raw(
+        "CREATE TABLE Subscriber(id INTEGER PRIMARY KEY AUTOINCREMENT, login TEXT)",
+        &[],
+    )
+    .unwrap();
+    tx.execute_raw("INSERT INTO Subscriber(login) VALUES ('ann'), ('bob')", &[])
+        .unwrap();
+
+    // the column is added without UNIQUE, which SQLite refuses, and gets a unique index
+    assert_eq!(tx.migrate::<SubscriberWithEmail>().unwrap(), ["email"]);
+    let subscriber = |login: &str, email: &str| SubscriberWithEmail {
+        login: login.into(),
+        email: Some(email.into()),
+    };
+    tx.create(subscriber("eve", "eve@example.com")).unwrap();
+    match tx.create(subscriber("mallory", "eve@example.com")) {
+        Err(orm::Error::UniqueViolation(err)) => {
+            assert_eq!(err.table_name, "Subscriber");
+            assert_eq!(err.column_name, "email");
+        }
+        res => panic!("expected Error::UniqueViolation, got {}", fmt_res(&res)),
+    }
+
+    // stored rows can't get a non-constant default
+    match tx.migrate::<SubscriberWithJoinDate>() {
+        Err(orm::Error::UnsupportedMigration(err)) => {
+            assert_eq!(err.table_name, "Subscriber");
+            assert_eq!(err.column_name, "joined");
+        }
+        res => panic!(
+            "expected Error::UnsupportedMigration, got {}",
+            fmt_res(&res)
+        ),
+    }
+    assert_eq!(
+        tx.verify_schema::<SubscriberWithJoinDate>()
+            .unwrap()
+            .missing_columns,
+        ["joined"]
+    );
+}
+
+#[test]
+fn verify_schema() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert!(matches!(
+        tx.verify_schema::<Profile>(),
+        Err(orm::Error::MissingTable(_))
+    ));
+    // the check doesn't create the table
+    assert!(matches!(
+        tx.verify_schema::<Profile>(),
+        Err(orm::Error::MissingTable(_))
+    ));
+
+    tx.execute_raw(
+        "CREATE TABLE Profile(id INTEGER PRIMARY KEY AUTOINCREMENT, LOGIN TEXT, \
+         height TEXT, password BLOB)",
+        &[],
+    )
+    .unwrap();
+    let diff = tx.verify_schema::<Profile>().unwrap();
+    assert!(!diff.is_empty());
+    assert_eq!(diff.missing_columns, ["nickname"]);
+    assert_eq!(diff.extra_columns, ["password"]);
+    assert_eq!(
+        diff.type_mismatches,
+        [orm::TypeMismatch {
+            column: "height",
+            expected: "REAL",
+            actual: "TEXT".into(),
+        }]
+    );
+
+    // tables created by the ORM match, generated columns and primary keys included
+    tx.create(Contact {
+        first: "Ann".into(),
+        last: "Lee".into(),
+        full_name: String::new(),
+        first_len: 0,
+    })
+    .unwrap();
+    tx.create(Account {
+        user_id: 1,
+        email: "ann@example.com".into(),
+    })
+    .unwrap();
+    assert!(tx.verify_schema::<Contact>().unwrap().is_empty());
+    assert!(tx.verify_schema::<Account>().unwrap().is_empty());
+    tx.execute_raw("ALTER TABLE Account ADD COLUMN phone TEXT", &[])
+        .unwrap();
+    assert_eq!(
+        tx.verify_schema::<Account>().unwrap(),
+        orm::SchemaDiff {
+            extra_columns: vec!["phone".into()],
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn with_retry() {
+    let path = NamedTempFile::new().unwrap().into_temp_path();
+    let mut conn = Connection::open_sqlite_file(&path).unwrap();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+    tx.commit().unwrap();
+
+    // another process holds the write lock for a while
+    let (locked_sender, locked) = std::sync::mpsc::channel();
+    let holder = {
+        let path = path.to_path_buf();
+        std::thread::spawn(move || {
+            let sqlite_conn = rusqlite::Connection::open(path).unwrap();
+            sqlite_conn.execute_batch("BEGIN IMMEDIATE").unwrap();
+            locked_sender.send(()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            sqlite_conn.execute_batch("COMMIT").unwrap();
+        })
+    };
+    locked.recv().unwrap();
+
+    let mut attempts = 0;
+    let res = conn.with_retry(2, std::time::Duration::from_millis(1), |tx| {
+        attempts += 1;
+        tx.create(Person {
+            first_name: "Vera".into(),
+            last_name: "Orlova".into(),
+            age: 28,
+        })
+        .map(|_| ())
+    });
+    assert!(matches!(res, Err(orm::Error::LockConflict)));
+    assert_eq!(attempts, 2);
+
+    attempts = 0;
+    let count = conn
+        .with_retry(10, std::time::Duration::from_millis(10), |tx| {
+            attempts += 1;
+            tx.create(Person {
+                first_name: "Vera".into(),
+                last_name: "Orlova".into(),
+                age: 28,
+            })?;
+            tx.count::<Person>()
+        })
+        .unwrap();
+    holder.join().unwrap();
+    assert!(attempts > 1);
+    assert_eq!(count, 6);
+
+    // other errors aren't retried
+    attempts = 0;
+    let res = conn.with_retry(10, std::time::Duration::from_millis(10), |tx| {
+        attempts += 1;
+        tx.count_where::<Person>("name", Op::Eq, orm::data::Value::Null)
+    });
+    assert!(matches!(res, Err(orm::Error::UnknownColumn(_))));
+    assert_eq!(attempts, 1);
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.count::<Person>().unwrap(), 6);
+}
+
+#[test]
+fn stream() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.stream::<Product>().unwrap().count(), 0);
+
+    // enough rows for several batches
+    let products = (0..2500)
+        .map(|i| Product {
+            sku: format!("item-{}", i),
+            price: i,
+        })
+        .collect::<Vec<_>>();
+    let created = tx.create_many(products.clone()).unwrap();
+    created[0].borrow_mut().price = -1;
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let cached = tx.get::<Product>(ObjectId::from(2)).unwrap();
+    cached.borrow_mut().price = -2;
+    let mut stored = products;
+    stored[0].price = -1;
+    let streamed = tx
+        .stream::<Product>()
+        .unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    // local modifications are not seen
+    assert_eq!(streamed, stored);
+
+    // the first error stops the iteration
+    let counters = Counters {
+        a: 0,
+        b: 0,
+        c: 0,
+        d: 0,
+        e: 0,
+    };
+    tx.create_many(vec![counters.clone(); 3]).unwrap();
+    tx.execute_raw("UPDATE Counters SET e = 300 WHERE id = 2", &[])
+        .unwrap();
+    let mut stream = tx.stream::<Counters>().unwrap();
+    assert_eq!(stream.next().unwrap().unwrap(), counters);
+    assert!(matches!(
+        stream.next(),
+        Some(Err(orm::Error::UnexpectedType(_)))
+    ));
+    assert!(stream.next().is_none());
+}
+
+#[test]
+fn get_or_create() {
+    let account = |user_id, email: &str| Account {
+        user_id,
+        email: email.into(),
+    };
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.create(account(7, "bob@example.com")).unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    // hit: the stored object is returned and `make` isn't called
+    let bob = tx
+        .get_or_create(ObjectId::from(7), || -> Account { unreachable!() })
+        .unwrap();
+    assert_eq!(bob.borrow().email, "bob@example.com");
+    assert!(bob.state() == ObjectState::Clean);
+
+    // miss: a caller-controlled primary key keeps the requested id
+    let ann = tx
+        .get_or_create(ObjectId::from(42), || account(42, "ann@example.com"))
+        .unwrap();
+    assert_eq!(ann.id(), ObjectId::from(42));
+    assert!(ann.ptr_eq(&tx.get::<Account>(ObjectId::from(42)).unwrap()));
+
+    // miss without a primary key: the object gets a new id
+    create_people(&tx);
+    let person = tx
+        .get_or_create(ObjectId::from(100), || Person {
+            first_name: "Vera".into(),
+            last_name: "Orlova".into(),
+            age: 28,
+        })
+        .unwrap();
+    assert_eq!(person.id(), ObjectId::from(6));
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.count::<Account>().unwrap(), 2);
+    assert_eq!(tx.count::<Person>().unwrap(), 6);
+
+    // other errors are passed through
+    tx.execute_raw("UPDATE Person SET age = 'old' WHERE id = 1", &[])
+        .unwrap();
+    let res = tx.get_or_create(ObjectId::from(1), || -> Person { unreachable!() });
+    assert!(matches!(res, Err(orm::Error::UnexpectedType(_))));
+    assert_eq!(tx.count::<Person>().unwrap(), 6);
+}
+
+#[test]
+fn exists() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    assert!(!tx.exists::<Person>(ObjectId::from(1)).unwrap());
+    create_people(&tx);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert!(tx.exists::<Person>(ObjectId::from(1)).unwrap());
+    assert!(!tx.exists::<Person>(ObjectId::from(6)).unwrap());
+    // the object isn't loaded
+    assert_eq!(tx.cache_len(), 0);
+
+    // cached objects are checked without a query
+    let ivan = tx.get::<Person>(ObjectId::from(1)).unwrap();
+    tx.execute_raw("DELETE FROM Person WHERE id = 1", &[])
+        .unwrap();
+    assert!(tx.exists::<Person>(ObjectId::from(1)).unwrap());
+    ivan.delete().unwrap();
+    assert!(!tx.exists::<Person>(ObjectId::from(1)).unwrap());
+    tx.get::<Person>(ObjectId::from(2))
+        .unwrap()
+        .delete()
+        .unwrap();
+    assert!(!tx.exists::<Person>(ObjectId::from(2)).unwrap());
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert!(!tx.exists::<Person>(ObjectId::from(2)).unwrap());
+    assert!(tx.exists::<Person>(ObjectId::from(3)).unwrap());
+}
+
+#[test]
+fn object_cache_limit() {
+    let product = |i| Product {
+        sku: format!("item-{}", i),
+        price: i,
+    };
+
+    let mut conn = Connection::builder()
+        .object_cache_limit(10)
+        .open_in_memory()
+        .unwrap();
+    let tx = conn.new_transaction().unwrap();
+    // objects with live handles are kept
+    let created = tx.create_many((1..=100).map(product).collect()).unwrap();
+    assert_eq!(tx.cache_len(), 100);
+    drop(created);
+    tx.get::<Product>(ObjectId::from(1)).unwrap();
+    assert_eq!(tx.cache_len(), 10);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let mut scanned = 0;
+    for page in tx.paged_stream::<Product>(7) {
+        scanned += page.unwrap().len();
+        assert!(tx.cache_len() <= 10);
+    }
+    assert_eq!(scanned, 100);
+
+    // the least recently used objects go first
+    for id in 1..=10 {
+        tx.get::<Product>(ObjectId::from(id)).unwrap();
+    }
+    tx.get::<Product>(ObjectId::from(1)).unwrap();
+    tx.get::<Product>(ObjectId::from(11)).unwrap();
+    tx.execute_raw("UPDATE Product SET price = 0 WHERE id IN (1, 2)", &[])
+        .unwrap();
+    let price = |tx: &orm::Transaction, id| {
+        let product = tx.get::<Product>(ObjectId::from(id)).unwrap();
+        let price = product.borrow().price;
+        price
+    };
+    assert_eq!(price(&tx, 1), 1);
+    assert_eq!(price(&tx, 2), 0);
+
+    // as well as the ones with pending changes
+    tx.get::<Product>(ObjectId::from(50))
+        .unwrap()
+        .borrow_mut()
+        .price = 500;
+    tx.get::<Product>(ObjectId::from(60))
+        .unwrap()
+        .delete()
+        .unwrap();
+    assert_eq!(tx.all::<Product>().unwrap().len(), 99);
+    assert_eq!(tx.cache_len(), 100);
+    tx.get::<Product>(ObjectId::from(1)).unwrap();
+    assert_eq!(tx.cache_len(), 10);
+    assert_eq!(tx.cache_state_counts(), (8, 1, 1));
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(price(&tx, 50), 500);
+    assert_eq!(tx.count::<Product>().unwrap(), 99);
+}
+
+#[derive(orm::DbEnum, Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Active,
+    Suspended,
+    Banned = 10,
+}
+
+#[derive(orm::DbEnum, Clone, Copy, Debug, PartialEq)]
+#[db_enum(as = "text")]
+enum Role {
+    Reader,
+    Editor,
+}
+
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Member {
+    login: String,
+    status: Status,
+    role: Option<Role>,
+}
+
+#[test]
+fn db_enum_fields() {
+    assert_eq!(Member::SCHEMA.fields[1].column_type, DataType::Int64);
+    assert_eq!(Member::SCHEMA.fields[2].column_type, DataType::String);
+
+    let members = vec![
+        Member {
+            login: "ann".into(),
+            status: Status::Active,
+            role: Some(Role::Editor),
+        },
+        Member {
+            login: "bob".into(),
+            status: Status::Banned,
+            role: None,
+        },
+    ];
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let created = tx.create_many(members.clone()).unwrap();
+    created[1].borrow_mut().status = Status::Suspended;
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let stored = tx
+        .query_map("SELECT status, role FROM Member ORDER BY id", &[], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Option<String>>(1)?))
+        })
+        .unwrap();
+    assert_eq!(stored, [(0, Some("Editor".to_string())), (1, None)]);
+    let loaded = tx.all::<Member>().unwrap();
+    assert_eq!(*loaded[0].borrow(), members[0]);
+    assert_eq!(loaded[1].borrow().status, Status::Suspended);
+    tx.commit().unwrap();
+
+    // unknown values are reported instead of panicking
+    let tx = conn.new_transaction().unwrap();
+    tx.execute_raw("UPDATE Member SET status = 3 WHERE id = 1", &[])
+        .unwrap();
+    tx.execute_raw("UPDATE Member SET role = 'Admin' WHERE id = 2", &[])
+        .unwrap();
+    for (id, column) in [(1, "status"), (2, "role")] {
+        match tx.get::<Member>(ObjectId::from(id)) {
+            Err(orm::Error::UnexpectedType(err)) => assert_eq!(err.attr_name, column),
+            res => panic!("expected Error::UnexpectedType, got {}", fmt_res(&res)),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn value_formatting() {
+    use orm::data::Value;
+
+    let values = [
+        Value::String("it's".into()),
+        Value::Bytes(vec![0, 1, 0xab, 0xff].into()),
+        Value::Int64(-7),
+        Value::Float64(2.0),
+        Value::Bool(true),
+        Value::Null,
+    ];
+    assert_eq!(
+        values
+            .iter()
+            .map(|v| format!("{:?}", v))
+            .collect::<Vec<_>>(),
+        [
+            "String(\"it's\")",
+            "Bytes(0001abff)",
+            "Int64(-7)",
+            "Float64(2.0)",
+            "Bool(true)",
+            "Null"
+        ]
+    );
+    assert_eq!(
+        values.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+        ["it's", "<4 bytes>", "-7", "2", "true", "NULL"]
+    );
+
+    // only a prefix of a large blob is shown
+    let blob = Value::Bytes(vec![0xcd; 1 << 20].into());
+    assert_eq!(
+        format!("{:?}", blob),
+        format!("Bytes({}... 1048576 bytes)", "cd".repeat(32))
+    );
+    assert_eq!(blob.to_string(), "<1048576 bytes>");
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[test]
+fn create_table_sql() {
+    use orm::Object;
+
+    assert_eq!(
+        User::SCHEMA.to_create_sql(),
+        "CREATE TABLE User (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT, picture BLOB, \
+         visits BIGINT, balance REAL, is_admin TINYINT)"
+    );
+    assert_eq!(
+        Account::SCHEMA.to_create_sql(),
+        "CREATE TABLE Account (user_id INTEGER PRIMARY KEY, email TEXT)"
+    );
+    assert_eq!(
+        Product::SCHEMA.to_create_sql(),
+        "CREATE TABLE Product (id INTEGER PRIMARY KEY AUTOINCREMENT, sku TEXT UNIQUE, \
+         price BIGINT)"
+    );
+
+    // the statement is the one the ORM issues itself
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.execute_raw(&User::SCHEMA.to_create_sql(), &[]).unwrap();
+    let user = tx.create(User::default()).unwrap();
+    assert_eq!(user.id(), ObjectId::from(1));
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(feature = "compress")]
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Document {
+    title: String,
+    #[compress]
+    body: String,
+    #[compress]
+    attachment: Vec<u8>,
+}
+
+#[cfg(feature = "compress")]
+#[test]
+fn compress() {
+    assert_eq!(Document::SCHEMA.fields[1].column_type, DataType::Bytes);
+
+    let big = Document {
+        title: "Big".into(),
+        body: "All work and no play makes Jack a dull boy. ".repeat(1000),
+        attachment: vec![7; 10_000],
+    };
+    let empty = Document {
+        title: "Empty".into(),
+        body: "".into(),
+        attachment: vec![],
+    };
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let ids = [
+        tx.create(big.clone()).unwrap().id(),
+        tx.create(empty.clone()).unwrap().id(),
+    ];
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(*tx.get::<Document>(ids[0]).unwrap().borrow(), big);
+    assert_eq!(*tx.get::<Document>(ids[1]).unwrap().borrow(), empty);
+
+    let sizes = tx
+        .query_map(
+            "SELECT length(body), length(attachment) FROM Document ORDER BY id",
+            &[],
+            |row| Ok((row.get::<_, usize>(0)?, row.get::<_, usize>(1)?)),
+        )
+        .unwrap();
+    assert!(sizes[0].0 < big.body.len() / 10);
+    assert!(sizes[0].1 < big.attachment.len() / 10);
+    assert_eq!(sizes[1], (0, 0));
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(feature = "chrono")]
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Meeting {
+    day: chrono::NaiveDate,
+    starts_at: chrono::NaiveDateTime,
+    ends_at: Option<chrono::NaiveDateTime>,
+}
+
+#[cfg(feature = "chrono")]
+#[test]
+fn chrono_fields() {
+    use chrono::NaiveDate;
+
+    let day = NaiveDate::from_ymd_opt(2024, 2, 29).unwrap();
+    let meetings = [
+        Meeting {
+            day,
+            starts_at: day.and_hms_micro_opt(23, 59, 59, 999_999).unwrap(),
+            ends_at: Some(day.succ_opt().unwrap().and_hms_opt(0, 30, 0).unwrap()),
+        },
+        Meeting {
+            day: NaiveDate::from_ymd_opt(1969, 12, 31).unwrap(),
+            starts_at: NaiveDate::from_ymd_opt(1970, 1, 1)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            ends_at: None,
+        },
+    ];
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    for meeting in meetings.iter() {
+        tx.create(meeting.clone()).unwrap();
+    }
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let stored = tx.export_all::<Meeting>().unwrap();
+    assert_eq!(stored, meetings);
+
+    let text = tx
+        .query_map(
+            "SELECT day, starts_at FROM Meeting ORDER BY id",
+            &[],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+        )
+        .unwrap();
+    assert_eq!(
+        text[0],
+        ("2024-02-29".into(), "2024-02-29 23:59:59.999999".into())
+    );
+    assert_eq!(text[1], ("1969-12-31".into(), "1970-01-01 00:00:00".into()));
+
+    // both separators are accepted, malformed values are an error
+    tx.execute_raw(
+        "UPDATE Meeting SET starts_at = '2024-03-01T08:15:00' WHERE id = 2",
+        &[],
+    )
+    .unwrap();
+    assert_eq!(
+        tx.get::<Meeting>(ObjectId::from(2))
+            .unwrap()
+            .borrow()
+            .starts_at,
+        NaiveDate::from_ymd_opt(2024, 3, 1)
+            .unwrap()
+            .and_hms_opt(8, 15, 0)
+            .unwrap()
+    );
+    tx.execute_raw("UPDATE Meeting SET day = '2024-02-30' WHERE id = 1", &[])
+        .unwrap();
+    assert!(matches!(
+        tx.get::<Meeting>(ObjectId::from(1)),
+        Err(orm::Error::UnexpectedType(_))
+    ));
+}
+
+#[cfg(feature = "chrono")]
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Announcement {
+    text: String,
+    #[created_at]
+    published_at: chrono::NaiveDateTime,
+}
+
+#[cfg(feature = "chrono")]
+#[test]
+fn chrono_timestamps() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    conn.clock(|| std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_500));
+    let tx = conn.new_transaction().unwrap();
+    tx.create(Announcement {
+        text: "Hello".into(),
+        published_at: Default::default(),
+    })
+    .unwrap();
+    let published = tx
+        .query_map("SELECT published_at FROM Announcement", &[], |row| {
+            row.get::<_, String>(0).map_err(Into::into)
+        })
+        .unwrap();
+    assert_eq!(published, ["2023-11-14 22:13:20.500"]);
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(feature = "uuid")]
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Device {
+    #[unique]
+    serial: uuid::Uuid,
+    owner: Option<uuid::Uuid>,
+}
+
+#[cfg(feature = "uuid")]
+#[test]
+fn uuid_fields() {
+    use orm::AsDataType;
+    use uuid::Uuid;
+
+    let device = Device {
+        serial: Uuid::new_v4(),
+        owner: Some(Uuid::new_v4()),
+    };
+    let ownerless = Device {
+        serial: Uuid::new_v4(),
+        owner: None,
+    };
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.create(device.clone()).unwrap();
+    tx.create(ownerless.clone()).unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let found = tx
+        .find_by::<Device>("serial", device.serial.as_value())
+        .unwrap();
+    assert_eq!(*found[0].borrow(), device);
+    assert_eq!(tx.export_all::<Device>().unwrap(), [device, ownerless]);
+    let sizes = tx
+        .query_map("SELECT length(serial) FROM Device", &[], |row| {
+            row.get::<_, usize>(0).map_err(Into::into)
+        })
+        .unwrap();
+    assert_eq!(sizes, [16, 16]);
+
+    tx.execute_raw("UPDATE Device SET owner = x'0102' WHERE id = 2", &[])
+        .unwrap();
+    match tx.get::<Device>(ObjectId::from(2)) {
+        Err(orm::Error::UnexpectedType(err)) => assert_eq!(err.attr_name, "owner"),
+        res => panic!("expected Error::UnexpectedType, got {}", fmt_res(&res)),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(feature = "json")]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+struct Dimensions {
+    width: u32,
+    height: u32,
+    tags: Vec<String>,
+}
+
+#[cfg(feature = "json")]
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Asset {
+    name: String,
+    metadata: orm::data::Json<std::collections::HashMap<String, String>>,
+    dimensions: Option<orm::data::Json<Dimensions>>,
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_fields() {
+    use orm::data::Json;
+
+    let asset = Asset {
+        name: "logo".into(),
+        metadata: Json([("author".to_string(), "Ann".to_string())].into()),
+        dimensions: Some(Json(Dimensions {
+            width: 640,
+            height: 480,
+            tags: vec!["png".into(), "transparent".into()],
+        })),
+    };
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let id = tx.create(asset.clone()).unwrap().id();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let stored = tx.get::<Asset>(id).unwrap();
+    assert_eq!(*stored.borrow(), asset);
+    stored
+        .borrow_mut()
+        .metadata
+        .insert("license".into(), "MIT".into());
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let width = tx
+        .query_map(
+            "SELECT json_extract(dimensions, '$.width'), metadata->>'license' FROM Asset",
+            &[],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        )
+        .unwrap();
+    assert_eq!(width, [(640, "MIT".to_string())]);
+
+    tx.execute_raw("UPDATE Asset SET metadata = '{\"author\": 1}'", &[])
+        .unwrap();
+    match tx.get::<Asset>(id) {
+        Err(orm::Error::UnexpectedType(err)) => assert_eq!(err.attr_name, "metadata"),
+        res => panic!("expected Error::UnexpectedType, got {}", fmt_res(&res)),
+    }
+}
+
+#[cfg(feature = "json")]
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Grid {
+    cells: orm::data::Json<std::collections::HashMap<(i64, i64), String>>,
+}
+
+// JSON objects only have string keys, so such a map can't be written
+#[cfg(feature = "json")]
+#[test]
+#[should_panic(expected = "can't be serialized into JSON")]
+fn json_unserializable() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    let cells = [((0, 0), "x".to_string())].into();
+    let _ = tx.create(Grid {
+        cells: orm::data::Json(cells),
+    });
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(feature = "decimal")]
+#[derive(Object, Clone, Debug, PartialEq)]
+struct Payment {
+    amount: rust_decimal::Decimal,
+    fee: Option<rust_decimal::Decimal>,
+}
+
+#[cfg(feature = "decimal")]
+#[test]
+fn decimal_fields() {
+    use rust_decimal::Decimal;
+
+    let decimal = |s: &str| s.parse::<Decimal>().unwrap();
+    // 0.1 + 0.2 is 0.30000000000000004 in f64
+    let payment = Payment {
+        amount: decimal("0.1") + decimal("0.2"),
+        fee: Some(decimal("-12345678901234567890.000001")),
+    };
+    assert_ne!(0.1 + 0.2, 0.3);
+
+    let mut conn = Connection::open_in_memory().unwrap();
+    let tx = conn.new_transaction().unwrap();
+    tx.create(payment.clone()).unwrap();
+    tx.create(Payment {
+        amount: Decimal::ZERO,
+        fee: None,
+    })
+    .unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let stored = tx.get::<Payment>(ObjectId::from(1)).unwrap();
+    assert_eq!(*stored.borrow(), payment);
+    assert_eq!(stored.borrow().amount, decimal("0.3"));
+    let texts = tx
+        .query_map("SELECT amount FROM Payment ORDER BY id", &[], |row| {
+            row.get::<_, String>(0).map_err(Into::into)
+        })
+        .unwrap();
+    assert_eq!(texts, ["0.3", "0"]);
+
+    tx.execute_raw("UPDATE Payment SET fee = '1,5' WHERE id = 2", &[])
+        .unwrap();
+    match tx.get::<Payment>(ObjectId::from(2)) {
+        Err(orm::Error::UnexpectedType(err)) => {
+            assert_eq!(err.attr_name, "fee");
+            assert_eq!(err.got_type, "String '1,5', which isn't a decimal number");
+        }
+        res => panic!("expected Error::UnexpectedType, got {}", fmt_res(&res)),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(feature = "test-backend")]
+#[test]
+fn mock_backend() {
+    let mut conn = Connection::open_mock();
+    let tx = conn.new_transaction().unwrap();
+    create_people(&tx);
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.count::<Person>().unwrap(), 5);
+    let anna = tx.get::<Person>(ObjectId::from(2)).unwrap();
+    assert_eq!(anna.borrow().last_name, "Sidorova");
+    anna.borrow_mut().age = 26;
+    tx.get::<Person>(ObjectId::from(5))
+        .unwrap()
+        .delete()
+        .unwrap();
+    tx.commit().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    let ages = tx
+        .all::<Person>()
+        .unwrap()
+        .iter()
+        .map(|person| person.borrow().age)
+        .collect::<Vec<_>>();
+    assert_eq!(ages, [30, 26, 41, 19]);
+    tx.create(Person {
+        first_name: "Vera".into(),
+        last_name: "Orlova".into(),
+        age: 28,
+    })
+    .unwrap();
+    let savepoint = tx.savepoint().unwrap();
+    tx.get::<Person>(ObjectId::from(1))
+        .unwrap()
+        .delete()
+        .unwrap();
+    savepoint.rollback_to().unwrap();
+    assert!(tx.exists::<Person>(ObjectId::from(1)).unwrap());
+    assert!(tx.exists::<Person>(ObjectId::from(6)).unwrap());
+    tx.rollback().unwrap();
+
+    let tx = conn.new_transaction().unwrap();
+    assert_eq!(tx.count::<Person>().unwrap(), 4);
+    assert!(!tx.exists::<Person>(ObjectId::from(6)).unwrap());
+
+    let account = tx
+        .create(Account {
+            user_id: 42,
+            email: "bob@example.com".into(),
+        })
+        .unwrap();
+    assert_eq!(account.id(), ObjectId::from(42));
+    tx.create(Product {
+        sku: "A-1".into(),
+        price: 10,
+    })
+    .unwrap();
+    let res = tx.create(Product {
+        sku: "A-1".into(),
+        price: 20,
+    });
+    assert!(matches!(res, Err(orm::Error::UniqueViolation(_))));
+
+    // there is no SQL behind the mock
+    let res = tx.execute_raw("DELETE FROM Person", &[]);
+    assert!(matches!(res, Err(orm::Error::Storage(_))));
+}
+
+// The same queries give the same answers on SQLite and in memory.
+#[cfg(feature = "test-backend")]
+#[test]
+fn mock_backend_queries() {
+    fn run(mut conn: Connection) -> Vec<String> {
+        let tx = conn.new_transaction().unwrap();
+        create_people(&tx);
+        for (login, nickname, height) in [
+            ("ann", Some("Annie"), Some(1.7)),
+            ("bob", None, Some(1.8)),
+            ("cid", Some("ace"), None),
+        ] {
+            tx.create(Profile {
+                login: login.into(),
+                nickname: nickname.map(Into::into),
+                height,
+            })
+            .unwrap();
+        }
+        let names = |people: Vec<Tx<Person>>| {
+            people
+                .iter()
+                .map(|person| person.borrow().first_name.clone())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let logins = |profiles: Vec<Tx<Profile>>| {
+            profiles
+                .iter()
+                .map(|profile| profile.borrow().login.clone())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        let mut res = vec![];
+        let query = tx
+            .query::<Person>()
+            .filter(
+                "last_name",
+                Op::Eq,
+                orm::data::Value::String("Petrov".into()),
+            )
+            .filter("age", Op::Gt, orm::data::Value::Int64(20))
+            .order_by("age", orm::Order::Desc);
+        res.push(names(query.all().unwrap()));
+        let query = tx
+            .query::<Person>()
+            .filter(
+                "first_name",
+                Op::Like,
+                orm::data::Value::String("a%".into()),
+            )
+            .order_by("last_name", orm::Order::Asc)
+            .limit(1)
+            .offset(1);
+        res.push(names(query.all().unwrap()));
+        let query = tx
+            .query::<Person>()
+            .filter("age", Op::Le, orm::data::Value::Int64(30))
+            .limit(2);
+        res.push(query.count().unwrap().to_string());
+        let query = tx
+            .query::<Profile>()
+            .order_by("nickname", orm::Order::Desc)
+            .nulls_first();
+        res.push(logins(query.all().unwrap()));
+        let query = tx.query::<Profile>().order_by("height", orm::Order::Asc);
+        res.push(logins(query.all().unwrap()));
+        let query = tx
+            .query::<Profile>()
+            .filter("nickname", Op::Ne, orm::data::Value::Null);
+        res.push(logins(query.all().unwrap()));
+        res.push(names(
+            tx.find_by("last_name", orm::data::Value::String("Petrov".into()))
+                .unwrap(),
+        ));
+        res.push(logins(
+            tx.find_by("nickname", orm::data::Value::Null).unwrap(),
+        ));
+        res.push(names(
+            tx.find_in(
+                "age",
+                &[
+                    orm::data::Value::Int64(19),
+                    orm::data::Value::Int64(35),
+                    orm::data::Value::Int64(99),
+                ],
+            )
+            .unwrap(),
+        ));
+        let pages = tx
+            .paged_stream::<Person>(2)
+            .map(|page| names(page.unwrap()))
+            .collect::<Vec<_>>();
+        res.push(pages.join("|"));
+        let ages = tx
+            .stream::<Person>()
+            .unwrap()
+            .map(|person| person.unwrap().age.to_string())
+            .collect::<Vec<_>>();
+        res.push(ages.join(","));
+
+        res.push(
+            tx.delete_where_limited::<Person>(
+                "last_name",
+                orm::data::Value::String("Petrov".into()),
+                2,
+            )
+            .unwrap()
+            .to_string(),
+        );
+        res.push(
+            tx.delete_where::<Person>("age", Op::Lt, orm::data::Value::Int64(30))
+                .unwrap()
+                .to_string(),
+        );
+        res.push(names(tx.all().unwrap()));
+        res
+    }
+
+    let expected = [
+        "Boris,Ivan",
+        "Anna",
+        "2",
+        "bob,cid,ann",
+        "cid,ann,bob",
+        "ann,cid",
+        "Ivan,Boris,Anna",
+        "bob",
+        "Anna,Oleg",
+        "Ivan,Anna|Boris,Anna|Oleg",
+        "30,25,41,19,35",
+        "2",
+        "2",
+        "Oleg",
+    ];
+    assert_eq!(run(Connection::open_in_memory().unwrap()), expected);
+    assert_eq!(run(Connection::open_mock()), expected);
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(feature = "tracing")]
+#[derive(Clone, Default)]
+struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(feature = "tracing")]
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn capture_logs(f: impl FnOnce()) -> String {
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer({
+            let logs = logs.clone();
+            move || logs.clone()
+        })
+        .finish();
+
+    tracing::subscriber::with_default(subscriber, f);
+    let logs = logs.0.lock().unwrap().clone();
+    String::from_utf8(logs).unwrap()
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn transaction_named() {
+    let logs = capture_logs(|| {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let tx = conn.transaction_named("nightly-import").unwrap();
+        assert_eq!(tx.name(), Some("nightly-import"));
+        tx.create(Product {
+            sku: "A-1".to_string(),
+            price: 10,
+        })
+        .unwrap();
+        tx.commit().unwrap();
+
+        let tx = conn.new_transaction().unwrap();
+        assert_eq!(tx.name(), None);
+        tx.rollback().unwrap();
+    });
+
+    // statements are logged by `orm::storage`
+    let lines = logs
+        .lines()
+        .filter(|line| line.contains("orm::transaction"))
+        .collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4, "{}", logs);
+    assert!(lines[0].contains("transaction{name=\"nightly-import\"}: orm::transaction: begin"));
+    assert!(lines[1].contains("transaction{name=\"nightly-import\"}: orm::transaction: commit"));
+    assert!(lines[2].contains("transaction: orm::transaction: begin"));
+    assert!(lines[3].contains("transaction: orm::transaction: rollback"));
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn sql_logging() {
+    let create = |conn: &mut Connection| {
+        let tx = conn.new_transaction().unwrap();
+        tx.create(Product {
+            sku: "A-1".to_string(),
+            price: 10,
+        })
+        .unwrap();
+        tx.commit().unwrap();
+    };
+
+    let logs = capture_logs(|| create(&mut Connection::open_in_memory().unwrap()));
+    assert!(
+        logs.contains("orm::storage: CREATE TABLE Product"),
+        "{}",
+        logs
+    );
+    assert!(logs.contains("orm::storage: INSERT INTO Product (sku, price) VALUES(?, ?) params=2"));
+    assert!(logs.contains("orm::storage: COMMIT params=0"));
+    assert!(!logs.contains("A-1"));
+
+    let logs = capture_logs(|| {
+        let mut conn = Connection::builder()
+            .log_sql_values(true)
+            .open_in_memory()
+            .unwrap();
+        create(&mut conn);
+    });
+    assert!(logs.contains("VALUES(?, ?) params='A-1', 10"), "{}", logs);
+}